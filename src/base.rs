@@ -1,38 +1,233 @@
+use std::cmp::Ordering;
 use std::fmt;
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct DistinctId(pub usize);
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct FileId(pub usize);
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct FilterId(pub usize);
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct GroupId(pub usize);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct RangeId(pub usize);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct SetId(pub usize);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct SkipId(pub usize);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct SortId(pub usize);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct TagId(pub usize);
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum Id {
     Distinct(DistinctId),
     File(FileId),
     Filter(FilterId),
+    Group(GroupId),
+    Range(RangeId),
+    Set(SetId),
+    Skip(SkipId),
+    Sort(SortId),
     Tag(TagId),
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+impl Id {
+    /// Lowercase name of the kind of object the ID refers to
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Id::Distinct(_) => "distinct",
+            Id::File(_) => "file",
+            Id::Filter(_) => "filter",
+            Id::Group(_) => "group",
+            Id::Range(_) => "range",
+            Id::Set(_) => "set",
+            Id::Skip(_) => "skip",
+            Id::Sort(_) => "sort",
+            Id::Tag(_) => "tag",
+        }
+    }
+}
+
+/// Short form used in plans, `Filter(3)`
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Id::Distinct(id) => write!(f, "Distinct({})", id.0),
+            Id::File(id) => write!(f, "File({})", id.0),
+            Id::Filter(id) => write!(f, "Filter({})", id.0),
+            Id::Group(id) => write!(f, "Group({})", id.0),
+            Id::Range(id) => write!(f, "Range({})", id.0),
+            Id::Set(id) => write!(f, "Set({})", id.0),
+            Id::Skip(id) => write!(f, "Skip({})", id.0),
+            Id::Sort(id) => write!(f, "Sort({})", id.0),
+            Id::Tag(id) => write!(f, "Tag({})", id.0),
+        }
+    }
+}
+
+impl From<DistinctId> for Id {
+    fn from(id: DistinctId) -> Id {
+        Id::Distinct(id)
+    }
+}
+
+impl From<FileId> for Id {
+    fn from(id: FileId) -> Id {
+        Id::File(id)
+    }
+}
+
+impl From<FilterId> for Id {
+    fn from(id: FilterId) -> Id {
+        Id::Filter(id)
+    }
+}
+
+impl From<GroupId> for Id {
+    fn from(id: GroupId) -> Id {
+        Id::Group(id)
+    }
+}
+
+impl From<RangeId> for Id {
+    fn from(id: RangeId) -> Id {
+        Id::Range(id)
+    }
+}
+
+impl From<SetId> for Id {
+    fn from(id: SetId) -> Id {
+        Id::Set(id)
+    }
+}
+
+impl From<SkipId> for Id {
+    fn from(id: SkipId) -> Id {
+        Id::Skip(id)
+    }
+}
+
+impl From<SortId> for Id {
+    fn from(id: SortId) -> Id {
+        Id::Sort(id)
+    }
+}
+
+impl From<TagId> for Id {
+    fn from(id: TagId) -> Id {
+        Id::Tag(id)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum Comparator {
     Equal,
+    /// Like `Equal`, but two missing values are also equal
+    NullSafeEqual,
     NotEqual,
     GreaterThan,
     GreaterThanEqual,
     LessThan,
     LessThanEqual,
+    /// The value contains the operand as a substring
+    Contains,
+    NotContains,
+    /// The tag has no value, such as a regex that didn't match, takes no operand
+    IsNull,
+    /// The tag has a value, takes no operand
+    IsNotNull,
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+impl Comparator {
+    /// Whether `ordering` satisfies the comparator, substring comparators never match by
+    /// ordering alone
+    pub fn matches(&self, ordering: Ordering) -> bool {
+        match self {
+            Comparator::Equal | Comparator::NullSafeEqual => ordering == Ordering::Equal,
+            Comparator::NotEqual => ordering != Ordering::Equal,
+            Comparator::GreaterThan => ordering == Ordering::Greater,
+            Comparator::GreaterThanEqual => ordering != Ordering::Less,
+            Comparator::LessThan => ordering == Ordering::Less,
+            Comparator::LessThanEqual => ordering != Ordering::Greater,
+            Comparator::Contains | Comparator::NotContains => false,
+            Comparator::IsNull | Comparator::IsNotNull => false,
+        }
+    }
+
+    /// Whether the comparator compares the value with an operand, rather than only testing
+    /// whether there is a value
+    pub fn takes_operand(&self) -> bool {
+        !matches!(self, Comparator::IsNull | Comparator::IsNotNull)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum CompareMode {
+    Lexical,
+    /// Compare as `f64` when both sides parse, falling back to `Lexical`
+    Numeric,
+    /// `Lexical` after folding ASCII letters to lowercase, other characters must match exactly
+    IgnoreCase,
+    /// Compare as byte sizes such as `512KB` or `1.5GiB`, a side that doesn't parse never
+    /// matches
+    Size,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum CaptureGroup {
+    Index(usize),
+    Name(String),
+}
+
+impl Default for CaptureGroup {
+    fn default() -> Self {
+        CaptureGroup::Index(1)
+    }
+}
+
+/// How `group` and `aggregate` reduce lines to a single number. Every aggregator but a bare
+/// `Count` reads a second, numeric tag whose values are parsed as `f64`, skipping lines where it
+/// is missing or not a finite number. Sums past 2^53 lose precision and very large ones
+/// overflow to infinity rather than failing.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum Aggregator {
+    /// Number of lines, or of numeric values when reading a tag
+    Count,
+    Min,
+    Max,
+    Sum,
+    /// Mean of the numeric values
+    Avg,
+}
+
+/// Order of the buckets printed by `group`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum GroupOrder {
+    /// By value
+    Key,
+    /// By aggregate, smallest first, ties broken by value
+    Ascending,
+    /// By aggregate, largest first, ties broken by value
+    Descending,
+}
 
+/// Direction of the rows ordered by `sort`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum Order {
+    Ascending,
+    Descending,
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -40,6 +235,34 @@ pub enum Aggregator {
 pub struct Interval(pub usize, pub usize);
 
 impl Interval {
+    /// The lines in both intervals, empty when they don't overlap
+    pub fn intersect(&self, other: Interval) -> Interval {
+        let start = std::cmp::max(self.0, other.0);
+        Interval(start, std::cmp::max(start, std::cmp::min(self.1, other.1)))
+    }
+
+    /// The smallest interval covering both along with any lines between them, which is what a
+    /// cache becomes once extended to another interval
+    pub fn union(&self, other: Interval) -> Interval {
+        Interval(
+            std::cmp::min(self.0, other.0),
+            std::cmp::max(self.1, other.1),
+        )
+    }
+
+    /// The part of the interval within `bounds`, an interval entirely outside of them becomes
+    /// empty at the nearest edge so it can still be used to slice
+    pub fn clamp_to(&self, bounds: Interval) -> Interval {
+        let start = std::cmp::min(std::cmp::max(self.0, bounds.0), bounds.1);
+        Interval(start, std::cmp::max(start, std::cmp::min(self.1, bounds.1)))
+    }
+
+    /// Whether lines lie between the two intervals, a cache can't be extended from one to the
+    /// other without reading them
+    pub fn is_separated_from(&self, other: Interval) -> bool {
+        other.1 < self.0 || other.0 > self.1
+    }
+
     pub fn is_empty(&self) -> bool {
         self.0 == self.1
     }
@@ -101,3 +324,55 @@ impl fmt::Display for Interval {
         write!(f, "[{}, {})", self.0, self.1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intersect() {
+        assert!(Interval(0, 5).intersect(Interval(7, 9)).is_empty());
+        assert!(Interval(7, 9).intersect(Interval(0, 5)).is_empty());
+        assert_eq!(Interval(0, 5).intersect(Interval(3, 9)), Interval(3, 5));
+        assert_eq!(Interval(0, 9).intersect(Interval(3, 5)), Interval(3, 5));
+        assert!(Interval(0, 5).intersect(Interval(5, 9)).is_empty());
+    }
+
+    #[test]
+    fn union() {
+        assert_eq!(Interval(0, 5).union(Interval(7, 9)), Interval(0, 9));
+        assert_eq!(Interval(3, 9).union(Interval(0, 5)), Interval(0, 9));
+        assert_eq!(Interval(0, 9).union(Interval(3, 5)), Interval(0, 9));
+        assert_eq!(Interval(0, 5).union(Interval(5, 9)), Interval(0, 9));
+    }
+
+    #[test]
+    fn clamp_to() {
+        assert_eq!(Interval(3, 9).clamp_to(Interval(0, 5)), Interval(3, 5));
+        assert_eq!(Interval(0, 9).clamp_to(Interval(3, 5)), Interval(3, 5));
+        assert_eq!(Interval(7, 9).clamp_to(Interval(0, 5)), Interval(5, 5));
+        assert_eq!(Interval(0, 2).clamp_to(Interval(3, 5)), Interval(3, 3));
+        assert_eq!(Interval(5, 9).clamp_to(Interval(0, 5)), Interval(5, 5));
+    }
+
+    #[test]
+    fn missing_parts() {
+        let cache = Interval(3, 5);
+        assert_eq!(cache.missing_before(Interval(0, 9)), Interval(0, 3));
+        assert_eq!(cache.missing_after(Interval(0, 9)), Interval(5, 9));
+        assert!(cache.missing_before(Interval(5, 9)).is_empty());
+        assert!(cache.missing_after(Interval(0, 3)).is_empty());
+        assert!(cache.contains(Interval(3, 5)));
+        assert!(!cache.contains(Interval(4, 6)));
+    }
+
+    #[test]
+    fn is_separated_from() {
+        let cache = Interval(3, 5);
+        assert!(cache.is_separated_from(Interval(0, 2)));
+        assert!(cache.is_separated_from(Interval(6, 9)));
+        assert!(!cache.is_separated_from(Interval(0, 3)));
+        assert!(!cache.is_separated_from(Interval(5, 9)));
+        assert!(!cache.is_separated_from(Interval(4, 4)));
+    }
+}
@@ -1,1170 +1,6871 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::path;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use bit_set;
 use ethbloom;
 use regex;
+use serde::{Deserialize, Serialize};
 
 use crate::base::{
-    Aggregator, Comparator, DistinctId, FileId, FilterId, Id, Interval, TagId,
+    Aggregator, CaptureGroup, CompareMode, Comparator, DistinctId, FileId, FilterId, GroupId,
+    GroupOrder, Id, Interval, Order, RangeId, SetId, SkipId, SortId, TagId,
 };
 use crate::error::{Error, Result};
+use crate::rotation;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Command {
     Load(path::PathBuf),
+    /// Several files loaded as one log, in order
+    LoadMany(Vec<path::PathBuf>),
     Script(String),
+    /// Group the lines of a file into records, each starting at a line matching the regex
+    RecordStart(FileId, String),
 
     Tag(FileId, String),
-    Regex(TagId, String),
+    Regex(TagId, String, CaptureGroup),
+    RegexAll(TagId, String, CaptureGroup),
+    SplitRegex(TagId, String, usize),
+    Tokenize(TagId),
+    /// Parse every line as JSON and read the value at a dotted path
+    JsonPath(TagId, String),
+    /// Read the characters from the start column up to the end column, 0-indexed
+    Columns(TagId, usize, usize),
     Transform(TagId, String),
+    Retag(TagId, String, Option<String>),
 
-    DirectFilter(Id, Comparator, String),
+    DirectFilter(Id, Comparator, CompareMode, String),
+    CustomFilter(Id, String, String),
     ScriptedFilter(Id, String),
+    RegexFilter(Id, String),
+    InFilter(Id, SetId),
+    /// Compare each line's value with the value of the line that many lines earlier, multiplied
+    /// by the factor
+    LagFilter(Id, usize, f64, Comparator, CompareMode),
+    /// A filter keeping the lines kept by either filter, both must test the same file
+    Or(Id, Id),
+    /// A filter keeping the lines kept by both filters, both must test the same file
+    And(Id, Id),
 
     Distinct(Id),
-
-    Group(Id, Aggregator),
-
+    /// Every value of the tag tested by `Id` among its selected rows, with how many rows had
+    /// each when the flag is set. A distinct lists the values of the rows it deduplicates.
+    DistinctValues(Id, bool),
+
+    /// Aggregate the lines of each value of the first tag, reading the numbers of the second
+    Group(TagId, Aggregator, Option<TagId>, GroupOrder),
+    /// A group of the buckets whose aggregate passes the comparison, in the same order
+    FilterGroups(GroupId, Comparator, CompareMode, String),
+    /// Aggregate the rows selected by `Id` into a single number
+    Aggregate(Id, Aggregator, Option<TagId>),
+    Top(TagId, usize),
+
+    MatchRate(TagId, TagId, Comparator, CompareMode),
+
+    Range(Id, usize, usize),
+    Skip(Id, usize),
+    /// Order the rows selected by `Id` by a tag, a take of them reads the whole range first
+    Sort(Id, TagId, Order),
     Take(Id, usize),
+    /// The last rows selected by `Id` in file order, reads the whole range first
+    TakeLast(Id, usize),
+    Follow(Id, usize),
+    /// Write up to `usize` rows selected by `Id` to a file, as raw lines or rendered with their
+    /// tags like `take` when the flag is set
+    Write(Id, path::PathBuf, usize, bool),
+    Materialize(Id),
+    /// Save the lines selected by a query as a filter of its file
+    SaveSelection(Id),
+    Explain(Id),
+    /// Versions of the crate, Lua and regex engine with the enabled features, for bug reports
+    Info,
+    /// Hex and ASCII dump of the bytes of a line, counted from 0
+    Hexdump(FileId, usize),
+
+    Unload(Id),
 }
 
-struct File {
-    index: usize,
-    reader: io::BufReader<fs::File>,
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// ANSI escapes used when color is enabled
+const MATCH_COLOR: &str = "\x1b[1;31m";
+const TAG_COLOR: &str = "\x1b[36m";
+const RESET_COLOR: &str = "\x1b[0m";
+
+/// Where the lines of a file come from
+enum LineSource {
+    Plain(path::PathBuf),
+    Gzip(path::PathBuf),
+    /// Several files read one after the other as a single log, such as the members of a
+    /// rotated log oldest first
+    Concatenated(Vec<path::PathBuf>),
+    /// Lines selected by another query, kept in memory
+    Memory(Arc<[u8]>),
+    /// Standard input, every byte read from it is kept in memory so it can be read again
+    Stdin(Arc<Mutex<Vec<u8>>>),
 }
 
-impl File {
-    fn new(path: path::PathBuf) -> Result<File> {
-        let file = fs::File::open(&path)?;
-        Ok(File {
-            index: 0,
-            reader: io::BufReader::new(file),
-        })
+impl LineSource {
+    fn name(&self) -> String {
+        match self {
+            LineSource::Plain(path) | LineSource::Gzip(path) => path.display().to_string(),
+            LineSource::Concatenated(paths) => paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+            LineSource::Memory(_) => "<materialized>".to_string(),
+            LineSource::Stdin(_) => "<stdin>".to_string(),
+        }
     }
+}
 
-    fn read(&mut self, interval: Interval) -> Result<Vec<String>> {
-        let offset = interval.0 as i64 - self.index as i64;
+enum Reader {
+    Plain(io::BufReader<fs::File>),
+    /// Decompressed stream, which can only be read forwards
+    Gzip(io::BufReader<flate2::read::GzDecoder<fs::File>>),
+    /// Concatenated members, which can only be read forwards
+    Concatenated(io::BufReader<Members>),
+    Memory(io::Cursor<Arc<[u8]>>),
+    Stdin(io::BufReader<Replay>),
+}
 
-        if offset != 0 {
-            // FIXME: use seek_relative when it's in stable
-            self.reader.seek(io::SeekFrom::Current(offset))?;
+impl Reader {
+    fn open(source: &LineSource, position: u64) -> Result<Reader> {
+        match source {
+            LineSource::Plain(path) => {
+                let mut reader = io::BufReader::new(fs::File::open(path)?);
+                reader.seek(io::SeekFrom::Start(position))?;
+                Ok(Reader::Plain(reader))
+            }
+            LineSource::Gzip(path) => {
+                let file = fs::File::open(path)?;
+                let mut reader = io::BufReader::new(flate2::read::GzDecoder::new(file));
+                io::copy(&mut (&mut reader).take(position), &mut io::sink())?;
+                Ok(Reader::Gzip(reader))
+            }
+            LineSource::Concatenated(paths) => {
+                let mut reader = io::BufReader::new(Members::new(paths.clone()));
+                io::copy(&mut (&mut reader).take(position), &mut io::sink())?;
+                Ok(Reader::Concatenated(reader))
+            }
+            LineSource::Memory(bytes) => {
+                let mut reader = io::Cursor::new(bytes.clone());
+                reader.set_position(position);
+                Ok(Reader::Memory(reader))
+            }
+            LineSource::Stdin(bytes) => {
+                let mut reader = io::BufReader::new(Replay::new(bytes.clone()));
+                reader.seek(io::SeekFrom::Start(position))?;
+                Ok(Reader::Stdin(reader))
+            }
         }
+    }
 
-        let mut result = Vec::with_capacity(interval.len());
-        for idx in interval.iter() {
-            self.index = idx;
+    fn read_until(&mut self, byte: u8, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        match self {
+            Reader::Plain(reader) => reader.read_until(byte, buffer),
+            Reader::Gzip(reader) => reader.read_until(byte, buffer),
+            Reader::Concatenated(reader) => reader.read_until(byte, buffer),
+            Reader::Memory(reader) => reader.read_until(byte, buffer),
+            Reader::Stdin(reader) => reader.read_until(byte, buffer),
+        }
+    }
 
-            let mut buffer = String::new();
-            let bytes_read = self.reader.read_line(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
+    /// Read the rest of a line, keeping at most `room` bytes of it before its newline. Returns
+    /// the bytes kept, how many bytes were read and whether the newline was reached.
+    fn read_line_limited(&mut self, room: usize) -> io::Result<(Vec<u8>, usize, bool)> {
+        let mut kept = vec![];
+        let mut read = 0;
+        loop {
+            let available = self.fill_buf()?;
+            if available.is_empty() {
+                return Ok((kept, read, false));
             }
 
-            result.push(buffer);
-        }
+            let newline = available.iter().position(|byte| *byte == b'\n');
+            let content = &available[..newline.unwrap_or(available.len())];
+            let keep = std::cmp::min(content.len(), room - kept.len());
+            kept.extend_from_slice(&content[..keep]);
 
-        Ok(result)
+            let consumed = newline.map(|end| end + 1).unwrap_or(available.len());
+            self.consume(consumed);
+            read += consumed;
+            if newline.is_some() {
+                return Ok((kept, read, true));
+            }
+        }
     }
-}
-
-struct Tag {
-    name: String,
-    regex: Option<regex::Regex>,
-    transform: Option<String>,
-}
 
-impl Tag {
-    fn new<S: Into<String>>(name: S) -> Tag {
-        Tag {
-            name: name.into(),
-            regex: None,
-            transform: None,
+    fn read_line(&mut self, buffer: &mut String) -> io::Result<usize> {
+        match self {
+            Reader::Plain(reader) => reader.read_line(buffer),
+            Reader::Gzip(reader) => reader.read_line(buffer),
+            Reader::Concatenated(reader) => reader.read_line(buffer),
+            Reader::Memory(reader) => reader.read_line(buffer),
+            Reader::Stdin(reader) => reader.read_line(buffer),
         }
     }
 
-    fn with_regex(&mut self, regex: &str) -> Result<()> {
-        self.regex = Some(regex::Regex::new(regex)?);
-        Ok(())
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Reader::Plain(reader) => reader.fill_buf(),
+            Reader::Gzip(reader) => reader.fill_buf(),
+            Reader::Concatenated(reader) => reader.fill_buf(),
+            Reader::Memory(reader) => reader.fill_buf(),
+            Reader::Stdin(reader) => reader.fill_buf(),
+        }
     }
 
-    fn with_transform(&mut self, transform: String) {
-        self.transform = Some(transform);
+    fn consume(&mut self, amount: usize) {
+        match self {
+            Reader::Plain(reader) => reader.consume(amount),
+            Reader::Gzip(reader) => reader.consume(amount),
+            Reader::Concatenated(reader) => reader.consume(amount),
+            Reader::Memory(reader) => reader.consume(amount),
+            Reader::Stdin(reader) => reader.consume(amount),
+        }
     }
 }
 
-enum Filter {
-    Direct(Comparator, String),
-    Scripted(String),
+/// Reads standard input, which can't seek, keeping every byte read in `bytes` so that seeking
+/// back within what was already read replays it from memory
+struct Replay {
+    bytes: Arc<Mutex<Vec<u8>>>,
+    position: u64,
 }
 
-trait Cache {
-    fn bounds(&self) -> Interval;
-    fn size(&self) -> usize;
+impl Replay {
+    fn new(bytes: Arc<Mutex<Vec<u8>>>) -> Replay {
+        Replay { bytes, position: 0 }
+    }
 }
 
-#[derive(Default)]
-struct FileCache {
-    start: usize,
-    loaded: Vec<String>,
-}
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut bytes = self.bytes.lock().unwrap();
+        while self.position as usize >= bytes.len() {
+            let mut chunk = [0; 8192];
+            let count = io::stdin().read(&mut chunk)?;
+            if count == 0 {
+                return Ok(0);
+            }
+            bytes.extend_from_slice(&chunk[..count]);
+        }
 
-impl Cache for FileCache {
-    fn bounds(&self) -> Interval {
-        Interval(self.start, self.loaded.len())
+        let start = self.position as usize;
+        let count = std::cmp::min(buf.len(), bytes.len() - start);
+        buf[..count].copy_from_slice(&bytes[start..start + count]);
+        self.position += count as u64;
+        Ok(count)
     }
+}
 
-    fn size(&self) -> usize {
-        std::mem::size_of_val(&self.loaded)
-            + self
-                .loaded
-                .iter()
-                .map(|s| std::mem::size_of_val(s) + s.capacity())
-                .sum::<usize>()
+impl Seek for Replay {
+    fn seek(&mut self, position: io::SeekFrom) -> io::Result<u64> {
+        self.position = match position {
+            io::SeekFrom::Start(offset) => offset,
+            io::SeekFrom::Current(offset) if offset >= -(self.position as i64) => {
+                (self.position as i64 + offset) as u64
+            }
+            io::SeekFrom::Current(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "seek before the start of standard input",
+                ))
+            }
+            io::SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "standard input has no known end",
+                ))
+            }
+        };
+        Ok(self.position)
     }
 }
 
-type TagValue = Option<String>;
-
-#[derive(Default)]
-struct TagCache {
-    start: usize,
-    loaded: Vec<TagValue>,
+/// Reads several files as a single stream, decompressing gzip members and ending each one
+/// with a newline. The last member is kept open once reached so appended lines can be followed.
+struct Members {
+    paths: VecDeque<path::PathBuf>,
+    current: Option<Box<dyn Read>>,
+    last_byte: Option<u8>,
 }
 
-impl Cache for TagCache {
-    fn bounds(&self) -> Interval {
-        Interval(self.start, self.loaded.len())
+impl Members {
+    fn new(paths: Vec<path::PathBuf>) -> Members {
+        Members {
+            paths: paths.into(),
+            current: None,
+            last_byte: None,
+        }
     }
+}
 
-    fn size(&self) -> usize {
-        std::mem::size_of_val(&self.loaded)
-            + self
-                .loaded
-                .iter()
-                .map(|s_opt| {
-                    std::mem::size_of_val(s_opt) + s_opt.as_ref().map(|s| s.capacity()).unwrap_or(0)
-                })
-                .sum::<usize>()
+impl Read for Members {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.current.is_none() {
+                let path = match self.paths.pop_front() {
+                    Some(path) => path,
+                    None => return Ok(0),
+                };
+                let file = fs::File::open(&path)?;
+                self.current = if is_gzip(&path).map_err(|_| io::ErrorKind::InvalidData)? {
+                    Some(Box::new(flate2::read::GzDecoder::new(file)))
+                } else {
+                    Some(Box::new(file))
+                };
+            }
+
+            let count = self.current.as_mut().unwrap().read(buf)?;
+            if count > 0 {
+                self.last_byte = Some(buf[count - 1]);
+                return Ok(count);
+            }
+            if self.paths.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+
+            self.current = None;
+            if self.last_byte.is_some_and(|byte| byte != b'\n') {
+                buf[0] = b'\n';
+                self.last_byte = Some(b'\n');
+                return Ok(1);
+            }
+        }
     }
 }
 
-#[derive(Default)]
-struct FilterCache {
-    start: usize,
-    end: usize,
-    loaded: bit_set::BitSet,
+struct File {
+    source: LineSource,
+    index: usize,
+    position: u64,
+    /// Byte offset of the start of every line read so far
+    line_offsets: Vec<u64>,
+    reader: Option<Reader>,
+    /// Hold back a trailing line without a newline until the rest of it is appended
+    follow: bool,
+    /// Bytes of an incomplete trailing line already read past `position`, or of the lines
+    /// following a record
+    partial: String,
+    /// Lines matching this pattern start a new record, the lines in between are joined to the
+    /// record before them
+    record_start: Option<regex::Regex>,
+    /// Lines longer than this are cut short and marked, the rest of them is skipped
+    max_line_bytes: Option<usize>,
+    /// Bytes of the file skipped by truncated lines in `partial`, along with the offset in
+    /// `partial` of the end of each truncated line read so far
+    skipped: Vec<(usize, i64)>,
+    /// The last line of `partial` was cut short and has no newline yet
+    truncating: bool,
+    /// Read every `\r\n` as `\n`, inside records as well as at the end of lines
+    normalize_crlf: bool,
 }
 
-impl FilterCache {
-    fn count(&self) -> usize {
-        self.loaded.iter().count()
+impl File {
+    /// Open `path`, which may contain wildcards matching the members of a rotated log. Lines
+    /// longer than `max_line_bytes` are truncated.
+    fn new(path: path::PathBuf, max_line_bytes: Option<usize>) -> Result<File> {
+        File::concat(&[path], max_line_bytes)
     }
-}
 
-impl Cache for FilterCache {
-    fn bounds(&self) -> Interval {
-        Interval(self.start, self.end)
+    /// A file reading standard input, `bytes` holds what was already read from it and is
+    /// shared by every file reading it
+    fn stdin(bytes: Arc<Mutex<Vec<u8>>>, max_line_bytes: Option<usize>) -> Result<File> {
+        File::open(LineSource::Stdin(bytes), max_line_bytes)
     }
 
-    fn size(&self) -> usize {
-        std::mem::size_of_val(&self.loaded)
+    /// Open `paths` as one log read in the given order, each path is expanded like in `new`
+    fn concat(paths: &[path::PathBuf], max_line_bytes: Option<usize>) -> Result<File> {
+        let mut paths = paths
+            .iter()
+            .map(|path| rotation::expand(path))
+            .collect::<Result<Vec<Vec<path::PathBuf>>>>()?
+            .concat();
+        let source = if paths.len() > 1 {
+            LineSource::Concatenated(paths)
+        } else {
+            let path = paths.remove(0);
+            if is_gzip(&path)? {
+                LineSource::Gzip(path)
+            } else {
+                LineSource::Plain(path)
+            }
+        };
+        File::open(source, max_line_bytes)
     }
-}
 
-#[derive(Default)]
-struct DistinctCache {
-    start: usize,
-    end: usize,
-    loaded: bit_set::BitSet,
-    bloom: ethbloom::Bloom,
-}
+    /// A file reading `lines` from memory, each one is terminated by a newline if it isn't already
+    fn from_lines(lines: &[String]) -> Result<File> {
+        let mut bytes = Vec::new();
+        for line in lines {
+            bytes.extend_from_slice(line.as_bytes());
+            if !line.ends_with('\n') {
+                bytes.push(b'\n');
+            }
+        }
+        File::open(LineSource::Memory(bytes.into()), None)
+    }
 
-impl DistinctCache {
-    fn count(&self) -> usize {
-        self.loaded.iter().count()
+    fn open(source: LineSource, max_line_bytes: Option<usize>) -> Result<File> {
+        let reader = Reader::open(&source, 0)?;
+        Ok(File {
+            source,
+            index: 0,
+            position: 0,
+            line_offsets: vec![0],
+            reader: Some(reader),
+            follow: false,
+            partial: String::new(),
+            record_start: None,
+            max_line_bytes,
+            skipped: vec![],
+            truncating: false,
+            normalize_crlf: false,
+        })
     }
-}
 
-impl Cache for DistinctCache {
-    fn bounds(&self) -> Interval {
-        Interval(self.start, self.end)
+    /// Drop the underlying handle, it will be reopened at the same position on the next read
+    fn close(&mut self) {
+        self.reader = None;
+        self.partial.clear();
+        self.skipped.clear();
+        self.truncating = false;
     }
 
-    fn size(&self) -> usize {
-        std::mem::size_of_val(&self.loaded) + std::mem::size_of_val(&self.bloom)
+    /// Group lines into records starting at every line matching `record_start`, or read single
+    /// lines again. Indices change meaning, so the offsets read so far are forgotten.
+    fn set_record_start(&mut self, record_start: Option<regex::Regex>) {
+        self.close();
+        self.record_start = record_start;
+        self.index = 0;
+        self.position = 0;
+        self.line_offsets = vec![0];
     }
-}
 
-#[derive(Debug, Default)]
-pub struct IntervalStats {
-    distincts: HashMap<DistinctId, Vec<Interval>>,
-    files: HashMap<FileId, Vec<Interval>>,
-    filters: HashMap<FilterId, Vec<Interval>>,
-    tags: HashMap<TagId, Vec<Interval>>,
-}
+    /// Read CRLF line endings as LF, which only changes the lines read, not their offsets
+    fn set_normalize_crlf(&mut self, normalize_crlf: bool) {
+        self.normalize_crlf = normalize_crlf;
+    }
 
-impl IntervalStats {
-    fn add(&mut self, id: Id, interval: Interval) {
-        match id {
-            Id::Distinct(did) => self
-                .distincts
-                .entry(did)
-                .or_insert_with(Vec::new)
-                .push(interval),
-            Id::File(fid) => self
-                .files
-                .entry(fid)
-                .or_insert_with(Vec::new)
-                .push(interval),
-            Id::Filter(fid) => self
-                .filters
-                .entry(fid)
-                .or_insert_with(Vec::new)
-                .push(interval),
-            Id::Tag(tid) => self.tags.entry(tid).or_insert_with(Vec::new).push(interval),
-        }
+    /// Whether the line, without its line ending, starts a record
+    fn starts_record(&self, record_start: &regex::Regex, line: &str) -> bool {
+        let line = line.trim_end_matches('\n');
+        let line = if self.normalize_crlf {
+            line.trim_end_matches('\r')
+        } else {
+            line
+        };
+        record_start.is_match(line)
     }
-}
 
-impl fmt::Display for IntervalStats {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fn write_intervals(f: &mut fmt::Formatter<'_>, intervals: &[Interval]) -> fmt::Result {
-            write!(f, "[")?;
-            for interval in intervals {
-                write!(f, "{}, ", interval)?;
-            }
-            writeln!(f, "]")
+    /// Whether earlier lines can be read again by seeking to them, compressed and concatenated
+    /// files are read again from their start instead
+    fn seeks_backward(&self) -> bool {
+        !matches!(
+            self.source,
+            LineSource::Gzip(_) | LineSource::Concatenated(_)
+        )
+    }
+
+    /// Count the lines up to the end of the file, which records the offset of every one of them
+    /// without keeping any line
+    fn line_count(&mut self) -> Result<usize> {
+        self.seek_line(usize::MAX)?;
+        Ok(self.index)
+    }
+
+    fn reader(&mut self) -> Result<&mut Reader> {
+        if self.reader.is_none() {
+            self.reader = Some(Reader::open(&self.source, self.position)?);
         }
+        Ok(self.reader.as_mut().unwrap())
+    }
 
-        fn write_interval_kind<T: fmt::Debug + std::cmp::Ord>(
-            f: &mut fmt::Formatter<'_>,
-            name: &str,
-            kinds: &HashMap<T, Vec<Interval>>,
-        ) -> fmt::Result {
-            if kinds.is_empty() {
-                return Ok(())
+    fn read(&mut self, interval: Interval) -> Result<Vec<String>> {
+        self.seek_line(interval.0)?;
+
+        let mut result = Vec::with_capacity(interval.len());
+        for _ in interval.iter() {
+            match self.read_line()? {
+                Some(line) => result.push(line),
+                None => break,
             }
-            writeln!(f, "{}: {{", name)?;
+        }
 
-            let mut kinds_vec: Vec<(&T, &Vec<Interval>)> = kinds.iter().collect();
-            kinds_vec.sort_by_key(|&(id, _)| id);
+        Ok(result)
+    }
 
-            for (id, intervals) in kinds_vec {
-                write!(f, "  {:?}: ", id)?;
-                write_intervals(f, intervals)?;
+    /// Read the next line, or the next record when lines are grouped into records
+    fn read_line(&mut self) -> Result<Option<String>> {
+        let mut buffer = std::mem::take(&mut self.partial);
+        let end = match self.record_start.clone() {
+            Some(record_start) => self.read_record(&mut buffer, &record_start)?,
+            None => {
+                self.read_line_part(&mut buffer, 0)?;
+                if self.follow && !buffer.ends_with('\n') {
+                    None
+                } else {
+                    Some(buffer.len())
+                }
             }
+        };
+        let end = match end {
+            Some(end) if end > 0 => end,
+            _ => {
+                self.partial = buffer;
+                return Ok(None);
+            }
+        };
+        self.partial = buffer.split_off(end);
+
+        // Truncated lines span more of the file than their length
+        let mut skipped = 0;
+        self.skipped.retain_mut(|(offset, bytes)| {
+            if *offset <= end {
+                skipped += *bytes;
+                false
+            } else {
+                *offset -= end;
+                true
+            }
+        });
 
-            writeln!(f, "}}")
+        self.index += 1;
+        self.position += (buffer.len() as i64 + skipped) as u64;
+        if self.index == self.line_offsets.len() {
+            self.line_offsets.push(self.position);
         }
 
-        write_interval_kind(f, "files", &self.files)?;
-        write_interval_kind(f, "tags", &self.tags)?;
-        write_interval_kind(f, "filters", &self.filters)?;
-        write_interval_kind(f, "distincts", &self.distincts)
+        if self.normalize_crlf && buffer.contains('\r') {
+            buffer = buffer.replace("\r\n", "\n");
+        }
+        Ok(Some(buffer))
     }
-}
 
-#[derive(Debug, Default)]
-pub struct SizeStats {
-    distincts: HashMap<DistinctId, usize>,
-    files: HashMap<FileId, usize>,
-    filters: HashMap<FilterId, usize>,
-    tags: HashMap<TagId, usize>,
-}
+    /// The bytes of line `index` as they are in the file, or `None` past its end. A separate
+    /// handle is used so the file's own position is unchanged, and the bytes don't have to be
+    /// valid UTF-8.
+    fn read_raw(&self, index: usize) -> Result<Option<Vec<u8>>> {
+        let known = std::cmp::min(index, self.line_offsets.len() - 1);
+        let mut reader = Reader::open(&self.source, self.line_offsets[known])?;
+        let starts_record = |line: &[u8]| match &self.record_start {
+            Some(record_start) => self.starts_record(record_start, &String::from_utf8_lossy(line)),
+            None => true,
+        };
 
-impl SizeStats {
-    fn add(&mut self, id: Id, size: usize) {
-        match id {
-            Id::Distinct(did) => *self.distincts.entry(did).or_insert(0) = size,
-            Id::File(fid) => *self.files.entry(fid).or_insert(0) = size,
-            Id::Filter(fid) => *self.filters.entry(fid).or_insert(0) = size,
-            Id::Tag(tid) => *self.tags.entry(tid).or_insert(0) = size,
+        let mut current = known;
+        let mut record = vec![];
+        let mut line = vec![];
+        loop {
+            line.clear();
+            let count = reader.read_until(b'\n', &mut line)?;
+            if !record.is_empty() && (count == 0 || starts_record(&line)) {
+                if current == index {
+                    return Ok(Some(record));
+                }
+                current += 1;
+                record.clear();
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            record.extend_from_slice(&line);
         }
     }
-}
 
-impl fmt::Display for SizeStats {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fn write_size_kind<T: fmt::Debug + std::cmp::Ord>(
-            f: &mut fmt::Formatter<'_>,
-            name: &str,
-            kinds: &HashMap<T, usize>,
-        ) -> fmt::Result {
-            if kinds.is_empty() {
-                return Ok(());
+    /// Append the rest of the line starting at `line_start` in `buffer`, returning how many bytes
+    /// were read. Past `max_line_bytes` the line is cut short and marked, and the rest of it up to
+    /// its newline is read but skipped.
+    fn read_line_part(&mut self, buffer: &mut String, line_start: usize) -> Result<usize> {
+        let max_line_bytes = match self.max_line_bytes {
+            Some(max_line_bytes) => max_line_bytes,
+            None => return Ok(self.reader()?.read_line(buffer)?),
+        };
+        let room = max_line_bytes.saturating_sub(buffer.len() - line_start);
+        let (kept, read, complete) = self.reader()?.read_line_limited(room)?;
+        let cut = read > kept.len() + complete as usize;
+
+        let length = buffer.len();
+        let kept = match String::from_utf8(kept) {
+            Ok(kept) => kept,
+            // The cut may have split the last character
+            Err(err) if cut && err.utf8_error().error_len().is_none() => {
+                let valid = err.utf8_error().valid_up_to();
+                let mut kept = err.into_bytes();
+                kept.truncate(valid);
+                String::from_utf8(kept).unwrap()
             }
-            writeln!(f, "{}: {{", name)?;
-
-            let mut kinds_vec: Vec<(&T, &usize)> = kinds.iter().collect();
-            kinds_vec.sort_by_key(|&(id, _)| id);
-
-            for (id, size) in kinds_vec {
-                writeln!(f, "  {:?}: {:.3} MB", id, *size as f64 / 1_000_000.0)?;
+            Err(_) => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "stream did not contain valid UTF-8",
+                )))
             }
+        };
+        buffer.push_str(&kept);
+        if cut && !self.truncating {
+            buffer.push_str(TRUNCATED_LINE_MARKER);
+            self.truncating = true;
+        }
+        if complete {
+            buffer.push('\n');
+            self.truncating = false;
+        }
 
-            writeln!(f, "}}")
+        let skipped = read as i64 - (buffer.len() - length) as i64;
+        if skipped != 0 {
+            self.skipped.push((buffer.len(), skipped));
         }
+        Ok(read)
+    }
 
-        write_size_kind(f, "files", &self.files)?;
-        write_size_kind(f, "tags", &self.tags)?;
-        write_size_kind(f, "filters", &self.filters)?;
-        write_size_kind(f, "distincts", &self.distincts)
+    /// Read lines into `buffer` until the one starting the next record, returning where the
+    /// first record of `buffer` ends. When following, the last record of the file is only
+    /// complete once the next one starts.
+    fn read_record(
+        &mut self,
+        buffer: &mut String,
+        record_start: &regex::Regex,
+    ) -> Result<Option<usize>> {
+        // Start of the first line not yet known to continue the record
+        let mut scanned = 0;
+        loop {
+            match buffer[scanned..].find('\n') {
+                Some(newline) => {
+                    let line = &buffer[scanned..scanned + newline];
+                    if scanned > 0 && self.starts_record(record_start, line) {
+                        return Ok(Some(scanned));
+                    }
+                    scanned += newline + 1;
+                }
+                None => {
+                    if self.read_line_part(buffer, scanned)? > 0 {
+                        continue;
+                    }
+                    if self.follow {
+                        return Ok(None);
+                    }
+                    if scanned > 0
+                        && scanned < buffer.len()
+                        && self.starts_record(record_start, &buffer[scanned..])
+                    {
+                        return Ok(Some(scanned));
+                    }
+                    return Ok(Some(buffer.len()));
+                }
+            }
+        }
     }
-}
 
-#[derive(Debug)]
-pub struct Stats {
-    intervals: Option<IntervalStats>,
-    sizes: Option<SizeStats>,
-}
+    /// Position the reader at the start of line `index`, reading forward from the furthest
+    /// known line when it hasn't been reached yet
+    fn seek_line(&mut self, index: usize) -> Result<()> {
+        let known = std::cmp::min(index, self.line_offsets.len() - 1);
+        let skipped = self.skipped.iter().map(|(_, bytes)| bytes).sum::<i64>();
+        let reader_position = (self.position as i64 + self.partial.len() as i64 + skipped) as u64;
+        let offset = self.line_offsets[known] as i64 - reader_position as i64;
 
-impl Stats {
-    fn enabled() -> Self {
-        Self {
-            intervals: Some(IntervalStats::default()),
-            sizes: Some(SizeStats::default()),
+        if offset != 0 {
+            self.partial.clear();
+            self.skipped.clear();
+            self.truncating = false;
+            match self.reader()? {
+                // Relative seeks within the buffer avoid discarding it
+                Reader::Plain(reader) => reader.seek_relative(offset)?,
+                Reader::Stdin(reader) => reader.seek_relative(offset)?,
+                Reader::Memory(reader) => {
+                    reader.seek(io::SeekFrom::Current(offset))?;
+                }
+                // Opened again from the start of the first member, reading forward up to the line
+                Reader::Gzip(_) | Reader::Concatenated(_) if offset < 0 => {
+                    self.reader = Some(Reader::open(&self.source, self.line_offsets[known])?);
+                }
+                Reader::Gzip(reader) => {
+                    io::copy(&mut reader.take(offset as u64), &mut io::sink())?;
+                }
+                Reader::Concatenated(reader) => {
+                    io::copy(&mut reader.take(offset as u64), &mut io::sink())?;
+                }
+            }
+            self.position = self.line_offsets[known];
         }
-    }
+        self.index = known;
 
-    fn disabled() -> Self {
-        Self {
-            intervals: None,
-            sizes: None,
-        }
+        self.skip_lines(index)
     }
 
-    fn add_interval(&mut self, id: Id, interval: Interval) {
-        if let Some(intervals) = &mut self.intervals {
-            intervals.add(id, interval);
-        }
-    }
+    /// Read forward to line `index` by counting newlines in the reader's buffer, which avoids
+    /// allocating a `String` for every discarded line. Records are read whole.
+    fn skip_lines(&mut self, index: usize) -> Result<()> {
+        while self.index < index {
+            if self.partial.is_empty() && self.record_start.is_none() {
+                self.reader()?;
+                let reader = self.reader.as_mut().unwrap();
+                let buffer = reader.fill_buf()?;
+                if buffer.is_empty() {
+                    break;
+                }
 
-    fn add_size(&mut self, id: Id, size: usize) {
-        if let Some(sizes) = &mut self.sizes {
-            sizes.add(id, size);
+                let mut consumed = 0;
+                while self.index < index {
+                    match buffer[consumed..].iter().position(|byte| *byte == b'\n') {
+                        Some(end) => {
+                            consumed += end + 1;
+                            self.index += 1;
+                            if self.index == self.line_offsets.len() {
+                                self.line_offsets.push(self.position + consumed as u64);
+                            }
+                        }
+                        None => break,
+                    }
+                }
+
+                reader.consume(consumed);
+                self.position += consumed as u64;
+                if consumed > 0 {
+                    continue;
+                }
+            }
+
+            // The next line straddles the end of the buffer, read it whole
+            if self.read_line()?.is_none() {
+                break;
+            }
         }
+
+        Ok(())
     }
 }
 
-impl fmt::Display for Stats {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if let Some(intervals) = &self.intervals {
-            write!(f, "\nintervals\n---------\n{}", intervals)?;
+/// Pad every cell to the widest in its column, truncating cells longer than `MAX_COLUMN_WIDTH`
+fn render_table(columns: &[String], rows: &[Vec<String>]) -> Vec<String> {
+    let truncate = |cell: &str| {
+        if cell.chars().count() > MAX_COLUMN_WIDTH {
+            let mut truncated = cell.chars().take(MAX_COLUMN_WIDTH - 3).collect::<String>();
+            truncated.push_str("...");
+            truncated
+        } else {
+            cell.to_string()
         }
-        if let Some(sizes) = &self.sizes {
-            write!(f, "\nsizes\n-----\n{}", sizes)?;
+    };
+
+    let header = columns.iter().map(|name| format!("[{}]", name)).collect();
+    let cells = std::iter::once(header)
+        .chain(
+            rows.iter()
+                .map(|row| row.iter().map(|cell| truncate(cell)).collect()),
+        )
+        .collect::<Vec<Vec<String>>>();
+
+    let mut widths = vec![0; columns.len()];
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = std::cmp::max(*width, cell.chars().count());
         }
-        Ok(())
     }
+
+    cells
+        .iter()
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{: <1$}", cell, width))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect()
 }
 
-pub struct Output {
-    pub id: Option<Id>,
-    pub lines: Vec<String>,
-    pub stats: Stats,
+/// Rows of 16 bytes, each with the offset of its first byte, the bytes in hex and the printable
+/// ASCII characters among them
+fn hexdump(bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(row, chunk)| {
+            let mut hex = String::new();
+            for (idx, byte) in chunk.iter().enumerate() {
+                if idx == 8 {
+                    hex.push(' ');
+                }
+                hex.push_str(&format!("{:02x} ", byte));
+            }
+            let ascii = chunk
+                .iter()
+                .map(|byte| match byte {
+                    0x20..=0x7e => *byte as char,
+                    _ => '.',
+                })
+                .collect::<String>();
+            format!("{:08x}  {:<49} |{}|", row * 16, hex, ascii)
+        })
+        .collect()
 }
 
-impl Output {
-    fn with_message(id: Option<Id>, message: String) -> Output {
-        Output {
-            id,
-            lines: vec![message],
-            stats: Stats::disabled(),
-        }
+fn is_gzip(path: &path::Path) -> Result<bool> {
+    let mut magic = [0; 2];
+    let mut file = fs::File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == GZIP_MAGIC),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
     }
+}
 
-    fn with_results(lines: Vec<String>, stats: Stats) -> Output {
-        Output {
-            id: None,
-            lines,
-            stats,
-        }
+/// Lua source compiled once into a function stored in the registry, called with `chunk` and,
+/// for transforms, the `groups` of the regex match the chunk was captured from
+struct CompiledChunk {
+    key: rlua::RegistryKey,
+    source: String,
+}
+
+impl CompiledChunk {
+    fn new(lua: &rlua::Lua, source: &str) -> Result<CompiledChunk> {
+        let key = lua.context(|lua_ctx| {
+            let function = lua_ctx
+                .load(&format!("local chunk, groups = ...\nreturn {}", source))
+                .into_function()
+                .or_else(|_| {
+                    lua_ctx
+                        .load(&format!("local chunk, groups = ...\n{}", source))
+                        .into_function()
+                })?;
+            lua_ctx.create_registry_value(function)
+        })?;
+
+        Ok(CompiledChunk {
+            key,
+            source: source.to_string(),
+        })
     }
 }
 
-struct ReadIntervals {
-    index: usize,
-    next: usize,
-    max: usize,
+/// Capture groups of the regex match a value was extracted from
+#[derive(Default)]
+struct Groups<'a> {
+    /// By group number, 0 being the whole match
+    numbered: Vec<Option<&'a str>>,
+    named: Vec<(&'a str, &'a str)>,
 }
 
-impl ReadIntervals {
-    fn new(min: usize, max: usize) -> ReadIntervals {
-        ReadIntervals {
-            index: 0,
-            next: std::cmp::min(min, max),
-            max,
+impl<'a> Groups<'a> {
+    fn new(regex: &'a regex::Regex, captures: &regex::Captures<'a>) -> Groups<'a> {
+        Groups {
+            numbered: captures.iter().map(|m| m.map(|m| m.as_str())).collect(),
+            named: regex
+                .capture_names()
+                .flatten()
+                .filter_map(|name| captures.name(name).map(|m| (name, m.as_str())))
+                .collect(),
         }
     }
-}
-
-impl Iterator for ReadIntervals {
-    type Item = Interval;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let interval = Interval(self.index, self.index + self.next);
-        self.index += self.next;
-        self.next = std::cmp::min(self.max, self.next * 2);
-        Some(interval)
+    /// A table of the groups by number and by name, unmatched groups are left out
+    fn to_table<'lua>(&self, lua_ctx: rlua::Context<'lua>) -> rlua::Result<rlua::Table<'lua>> {
+        let table = lua_ctx.create_table()?;
+        for (index, group) in self.numbered.iter().enumerate() {
+            if let Some(group) = group {
+                table.set(index, *group)?;
+            }
+        }
+        for (name, group) in &self.named {
+            table.set(*name, *group)?;
+        }
+        Ok(table)
     }
 }
 
-const MAX_BATCH_SIZE: usize = 1024;
-
-#[derive(Debug)]
-struct Plan {
-    steps: Vec<Id>,
+enum Extractor {
+    Line,
+    Regex(regex::Regex, CaptureGroup),
+    /// Every match of the regex in the line, making the tag repeated
+    RegexAll(regex::Regex, CaptureGroup),
+    /// Split the line on a delimiter regex and take the 0-indexed field
+    Split(regex::Regex, usize),
+    /// Number of whitespace separated tokens in the line
+    Tokens,
+    /// Value at a path of object keys or array indexes in a line holding a JSON document
+    Json(Vec<String>),
+    /// Characters from the start up to the end index, lines ending before the end have no value
+    Columns(usize, usize),
 }
 
-impl Plan {
-    fn new(steps: Vec<Id>) -> Plan {
-        Plan { steps }
+impl Extractor {
+    /// Every value of the line in order, at most one unless the tag is repeated
+    fn extract<'a>(&self, line: &'a str) -> Vec<Cow<'a, str>> {
+        match self {
+            Extractor::Line => vec![Cow::Borrowed(line)],
+            Extractor::Regex(regex, group) => regex
+                .captures(line)
+                .and_then(|captures| Extractor::group(&captures, group))
+                .into_iter()
+                .collect(),
+            Extractor::RegexAll(regex, group) => regex
+                .captures_iter(line)
+                .filter_map(|captures| Extractor::group(&captures, group))
+                .collect(),
+            Extractor::Split(delimiter, index) => delimiter
+                .split(line.trim_end_matches(&['\n', '\r'][..]))
+                .nth(*index)
+                .map(Cow::Borrowed)
+                .into_iter()
+                .collect(),
+            Extractor::Tokens => vec![Cow::Owned(line.split_whitespace().count().to_string())],
+            Extractor::Json(path) => Extractor::json_value(line, path)
+                .map(Cow::Owned)
+                .into_iter()
+                .collect(),
+            Extractor::Columns(start, end) => Extractor::columns(line, *start, *end)
+                .map(Cow::Borrowed)
+                .into_iter()
+                .collect(),
+        }
     }
 
-    fn file_id(&self) -> FileId {
-        match self.steps[0] {
-            Id::File(file_id) => file_id,
-            _ => panic!(),
+    /// Like `extract`, along with the capture groups each value was taken from, only regex
+    /// extractors have any
+    fn extract_with_groups<'a>(&'a self, line: &'a str) -> Vec<(Cow<'a, str>, Groups<'a>)> {
+        let with_groups = |regex, captures: regex::Captures<'a>, group| {
+            Extractor::group(&captures, group).map(|value| (value, Groups::new(regex, &captures)))
+        };
+        match self {
+            Extractor::Regex(regex, group) => regex
+                .captures(line)
+                .and_then(|captures| with_groups(regex, captures, group))
+                .into_iter()
+                .collect(),
+            Extractor::RegexAll(regex, group) => regex
+                .captures_iter(line)
+                .filter_map(|captures| with_groups(regex, captures, group))
+                .collect(),
+            _ => self
+                .extract(line)
+                .into_iter()
+                .map(|value| (value, Groups::default()))
+                .collect(),
         }
     }
 
-    fn filter_ids(&self) -> Vec<FilterId> {
-        self.steps
-            .iter()
-            .filter_map(|step| match step {
-                Id::Filter(filter_id) => Some(*filter_id),
-                _ => None,
-            })
-            .collect()
-    }
+    /// The value at `path` as text: strings unquoted, numbers and booleans as written by
+    /// `serde_json`, objects and arrays as compact JSON. A line that isn't JSON, a missing key
+    /// and `null` all have no value.
+    fn json_value(line: &str, path: &[String]) -> Option<String> {
+        let document = serde_json::from_str::<serde_json::Value>(line).ok()?;
+        let mut value = &document;
+        for key in path {
+            value = match value {
+                serde_json::Value::Object(object) => object.get(key)?,
+                serde_json::Value::Array(array) => array.get(key.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
 
-    fn distinct_ids(&self) -> Vec<DistinctId> {
-        self.steps
-            .iter()
-            .filter_map(|step| match step {
-                Id::Distinct(distinct_id) => Some(*distinct_id),
-                _ => None,
-            })
-            .collect()
+        match value {
+            serde_json::Value::Null => None,
+            serde_json::Value::String(string) => Some(string.clone()),
+            value => Some(value.to_string()),
+        }
     }
-}
-
-pub struct Engine {
-    debug: bool,
-    last_id: usize,
-    lua: rlua::Lua,
 
-    files: HashMap<FileId, File>,
-    file_caches: HashMap<FileId, FileCache>,
+    /// The characters `start..end` of the line without its line ending, by char rather than
+    /// byte index so multibyte characters are never cut
+    fn columns(line: &str, start: usize, end: usize) -> Option<&str> {
+        let line = line.trim_end_matches(&['\n', '\r'][..]);
+        let mut indices = line
+            .char_indices()
+            .map(|(index, _)| index)
+            .chain(Some(line.len()));
+        let from = indices.nth(start)?;
+        let to = indices.nth(end - start - 1)?;
+        Some(&line[from..to])
+    }
 
-    tags: HashMap<TagId, Tag>,
-    tag_caches: HashMap<TagId, TagCache>,
-    tag_to_file: HashMap<TagId, FileId>,
+    fn group<'a>(captures: &regex::Captures<'a>, group: &CaptureGroup) -> Option<Cow<'a, str>> {
+        Extractor::capture(captures, group).map(|m| Cow::Borrowed(m.as_str()))
+    }
 
-    filters: HashMap<FilterId, Filter>,
-    filter_caches: HashMap<FilterId, FilterCache>,
-    filter_to_parent: HashMap<FilterId, Id>,
+    fn capture<'a>(
+        captures: &regex::Captures<'a>,
+        group: &CaptureGroup,
+    ) -> Option<regex::Match<'a>> {
+        match group {
+            CaptureGroup::Index(index) => captures.get(*index),
+            CaptureGroup::Name(name) => captures.name(name),
+        }
+    }
 
-    distinct_caches: HashMap<DistinctId, DistinctCache>,
-    distinct_to_parent: HashMap<DistinctId, Id>,
+    /// Byte ranges of the line the values were captured from, only regex extractors have them
+    fn spans(&self, line: &str) -> Vec<(usize, usize)> {
+        let span = |m: regex::Match| (m.start(), m.end());
+        match self {
+            Extractor::Regex(regex, group) => regex
+                .captures(line)
+                .and_then(|captures| Extractor::capture(&captures, group).map(span))
+                .into_iter()
+                .collect(),
+            Extractor::RegexAll(regex, group) => regex
+                .captures_iter(line)
+                .filter_map(|captures| Extractor::capture(&captures, group).map(span))
+                .collect(),
+            _ => vec![],
+        }
+    }
 }
 
-impl Engine {
-    pub fn new() -> Self {
-        Engine {
-            debug: false,
-            last_id: 0,
-            lua: rlua::Lua::new(),
+struct Tag {
+    name: String,
+    extractor: Extractor,
+    /// Applied in order to every extracted value
+    transforms: Vec<CompiledChunk>,
+}
 
-            files: HashMap::new(),
-            file_caches: HashMap::new(),
+impl Tag {
+    fn new<S: Into<String>>(name: S) -> Tag {
+        Tag {
+            name: name.into(),
+            extractor: Extractor::Line,
+            transforms: vec![],
+        }
+    }
 
-            tags: HashMap::new(),
-            tag_caches: HashMap::new(),
-            tag_to_file: HashMap::new(),
+    fn with_regex(&mut self, regex: regex::Regex, group: CaptureGroup) -> Result<()> {
+        self.check_capture_group(&regex, &group)?;
+        self.extractor = Extractor::Regex(regex, group);
+        Ok(())
+    }
 
-            filters: HashMap::new(),
-            filter_caches: HashMap::new(),
-            filter_to_parent: HashMap::new(),
+    fn with_regex_all(&mut self, regex: regex::Regex, group: CaptureGroup) -> Result<()> {
+        self.check_capture_group(&regex, &group)?;
+        self.extractor = Extractor::RegexAll(regex, group);
+        Ok(())
+    }
 
-            distinct_caches: HashMap::new(),
-            distinct_to_parent: HashMap::new(),
+    /// Check that `regex` has the capture `group`. Without a group the tag reads the first one,
+    /// so a regex without any is pointed out as such rather than as a missing group.
+    fn check_capture_group(&self, regex: &regex::Regex, group: &CaptureGroup) -> Result<()> {
+        let is_valid = match group {
+            CaptureGroup::Index(index) => *index < regex.captures_len(),
+            CaptureGroup::Name(ref name) => regex.capture_names().any(|n| n == Some(name)),
+        };
+        if is_valid {
+            return Ok(());
         }
+        if *group == CaptureGroup::default() && regex.captures_len() == 1 {
+            return Err(Error::InvalidCaptureGroup(format!(
+                "regex {:?} of tag '{} has no capture group, wrap the part to keep in parentheses",
+                regex.as_str(),
+                self.name
+            )));
+        }
+        Err(Error::InvalidCaptureGroup(format!(
+            "{:?} in regex {:?} of tag '{}",
+            group,
+            regex.as_str(),
+            self.name
+        )))
     }
 
-    pub fn new_debug() -> Self {
-        let mut engine = Self::new();
-        engine.debug = true;
-        engine
+    fn with_split(&mut self, delimiter: regex::Regex, index: usize) {
+        self.extractor = Extractor::Split(delimiter, index);
     }
 
-    pub fn run_command(&mut self, command: &Command) -> Result<Output> {
-        match command {
-            Command::Load(path) => {
-                let id = self.next_file_id();
-                self.files.insert(id, File::new(path.clone())?);
-                Ok(Output::with_message(
-                    Some(Id::File(id)),
-                    format!("file loaded: {:?} {:?}", id, path),
-                ))
-            }
-            Command::Script(script) => {
-                self.run_script(script)?;
-                Ok(Output::with_message(None, "script loaded".to_string()))
-            }
-
-            Command::Tag(file_id, tag_name) => {
-                let tag_id = self.next_tag_id();
-                self.tags.insert(tag_id, Tag::new(tag_name));
-                self.tag_to_file.insert(tag_id, *file_id);
-                Ok(Output::with_message(
-                    Some(Id::Tag(tag_id)),
-                    format!("tag loaded: {} {}", tag_id.0, tag_name),
-                ))
-            }
-            Command::Regex(tag_id, regex) => {
-                let tag = self
-                    .tags
-                    .get_mut(tag_id)
-                    .ok_or_else(|| Error::MissingId(Id::Tag(*tag_id)))?;
-                tag.with_regex(regex)?;
-                Ok(Output::with_message(
-                    Some(Id::Tag(*tag_id)),
-                    format!("regex added to: {}", tag_id.0),
-                ))
-            }
-            Command::Transform(tag_id, transform) => {
-                let tag = self
-                    .tags
-                    .get_mut(tag_id)
-                    .ok_or_else(|| Error::MissingId(Id::Tag(*tag_id)))?;
-                tag.with_transform(transform.clone());
-                Ok(Output::with_message(
-                    Some(Id::Tag(*tag_id)),
-                    format!("transform added to: {}", tag_id.0),
-                ))
-            }
+    fn with_tokens(&mut self) {
+        self.extractor = Extractor::Tokens;
+    }
 
-            Command::DirectFilter(id, comparator, value) => {
-                let filter_id = self.next_filter_id();
-                let filter = Filter::Direct(*comparator, value.clone());
+    /// Read the value at `path`, a dotted list of object keys and array indexes
+    fn with_json_path(&mut self, path: &str) -> Result<()> {
+        let keys = path.split('.').map(str::to_string).collect::<Vec<String>>();
+        if keys.iter().any(|key| key.is_empty()) {
+            return Err(Error::InvalidArgument(format!("json path: {:?}", path)));
+        }
+        self.extractor = Extractor::Json(keys);
+        Ok(())
+    }
 
-                self.filters.insert(filter_id, filter);
-                self.filter_to_parent.insert(filter_id, *id);
+    fn with_columns(&mut self, start: usize, end: usize) -> Result<()> {
+        if start >= end {
+            return Err(Error::InvalidArgument(format!(
+                "columns {}..{} of tag '{}, the start must be before the end",
+                start, end, self.name
+            )));
+        }
+        self.extractor = Extractor::Columns(start, end);
+        Ok(())
+    }
 
-                Ok(Output::with_message(
-                    Some(Id::Filter(filter_id)),
-                    format!("filter loaded: {}", filter_id.0),
-                ))
-            }
-            Command::ScriptedFilter(id, test) => {
-                let filter_id = self.next_filter_id();
-                let filter = Filter::Scripted(test.clone());
+    /// Add a transform applied after the existing ones, `retag` resets them
+    fn with_transform(&mut self, transform: CompiledChunk) {
+        self.transforms.push(transform);
+    }
+}
 
-                self.filters.insert(filter_id, filter);
-                self.filter_to_parent.insert(filter_id, *id);
+enum Filter {
+    Direct(Comparator, CompareMode, String),
+    /// A global Lua function called as `func(left, right)`
+    Custom(String, String),
+    /// A Lua test compiled when the filter is made, called with each value as `chunk`
+    Scripted(CompiledChunk),
+    Regex(regex::Regex),
+    /// Keep values that are members of a set, copied from a `top` result when the filter is made
+    In(HashSet<String>),
+    /// Compare with the value `lag` lines earlier scaled by a factor, the first `lag` lines and
+    /// lines without either value never pass
+    Lag(usize, f64, Comparator, CompareMode),
+    /// Lines kept by either filter. Each one is tested on its own, without the filters it was
+    /// piped from.
+    Or(FilterId, FilterId),
+    /// Lines kept by both filters, tested like `Or`
+    And(FilterId, FilterId),
+    /// The lines a query selected when it was saved, later changes to the query or the file
+    /// don't change them
+    Selection(bit_set::BitSet),
+}
 
-                Ok(Output::with_message(
-                    Some(Id::Filter(filter_id)),
-                    format!("filter loaded: {}", filter_id.0),
-                ))
+impl Filter {
+    /// Whether both filters keep the same values, regexes are compared by their pattern. Filters
+    /// calling Lua are never equivalent, as a script may have redefined the functions they call
+    /// since the other one was cached.
+    fn is_equivalent(&self, other: &Filter) -> bool {
+        match (self, other) {
+            (Filter::Direct(comp, mode, value), Filter::Direct(o_comp, o_mode, o_value)) => {
+                comp == o_comp && mode == o_mode && value == o_value
             }
-
-            Command::Distinct(id) => {
-                let distinct_id = self.next_distinct_id();
-                self.distinct_to_parent.insert(distinct_id, *id);
-                Ok(Output::with_message(
-                    Some(Id::Distinct(distinct_id)),
-                    format!("distinct loaded: {}", distinct_id.0),
-                ))
+            (Filter::Regex(regex), Filter::Regex(o_regex)) => regex.as_str() == o_regex.as_str(),
+            (Filter::In(values), Filter::In(o_values)) => values == o_values,
+            (
+                Filter::Lag(lag, factor, comp, mode),
+                Filter::Lag(o_lag, o_factor, o_comp, o_mode),
+            ) => lag == o_lag && factor == o_factor && comp == o_comp && mode == o_mode,
+            (Filter::Or(left, right), Filter::Or(o_left, o_right))
+            | (Filter::And(left, right), Filter::And(o_left, o_right)) => {
+                left == o_left && right == o_right
             }
+            (Filter::Selection(lines), Filter::Selection(o_lines)) => lines == o_lines,
+            _ => false,
+        }
+    }
 
-            Command::Group(id, aggregator) => unimplemented!(),
-
-            Command::Take(id, count) => Ok(self.take(&self.plan(*id), *count)?),
+    /// The filters combined by an `Or` or an `And`
+    fn operands(&self) -> Option<(FilterId, FilterId)> {
+        match self {
+            Filter::Or(left, right) | Filter::And(left, right) => Some((*left, *right)),
+            _ => None,
         }
     }
 
+    /// Whether the filter tests the values of its parent tag, rather than other filters' lines
+    /// or saved ones
+    fn tests_values(&self) -> bool {
+        !matches!(
+            self,
+            Filter::Or(_, _) | Filter::And(_, _) | Filter::Selection(_)
+        )
+    }
+
+    /// How many lines before an interval the filter reads to decide its first lines
+    fn lookback(&self) -> usize {
+        match self {
+            Filter::Lag(lag, _, _, _) => *lag,
+            _ => 0,
+        }
+    }
+}
+
+trait Cache {
+    fn bounds(&self) -> Interval;
+    fn size(&self) -> usize;
+}
+
+/// Bounds of a cache about to be extended to `interval`. A cache stays contiguous, so one that
+/// doesn't reach `interval` is started over at it rather than read up to it.
+fn extended_bounds(bounds: Option<Interval>, interval: Interval) -> Interval {
+    match bounds {
+        Some(bounds) if !bounds.is_separated_from(interval) => bounds,
+        _ => Interval(interval.0, interval.0),
+    }
+}
+
+#[derive(Default)]
+struct FileCache {
+    start: usize,
+    loaded: Vec<String>,
+}
+
+impl FileCache {
+    fn starting_at(start: usize) -> FileCache {
+        FileCache {
+            start,
+            ..FileCache::default()
+        }
+    }
+}
+
+impl Cache for FileCache {
+    fn bounds(&self) -> Interval {
+        Interval(self.start, self.start + self.loaded.len())
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(&self.loaded)
+            + self
+                .loaded
+                .iter()
+                .map(|s| std::mem::size_of_val(s) + s.capacity())
+                .sum::<usize>()
+    }
+}
+
+type TagValue = Option<String>;
+
+/// Values after the first of the lines of a repeated tag with more than one, by line
+type RepeatedValues = BTreeMap<usize, Vec<String>>;
+
+/// The first value of a line and its further values when the tag is repeated, as one cell
+fn join_values(value: &str, more: &[String]) -> String {
+    let mut joined = value.to_string();
+    for value in more {
+        joined.push_str(", ");
+        joined.push_str(value);
+    }
+    joined
+}
+
+#[derive(Default)]
+struct TagCache {
+    start: usize,
+    /// First value of every line
+    loaded: Vec<TagValue>,
+    repeated: RepeatedValues,
+}
+
+impl TagCache {
+    fn starting_at(start: usize) -> TagCache {
+        TagCache {
+            start,
+            ..TagCache::default()
+        }
+    }
+}
+
+impl Cache for TagCache {
+    fn bounds(&self) -> Interval {
+        Interval(self.start, self.start + self.loaded.len())
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(&self.loaded)
+            + self
+                .loaded
+                .iter()
+                .map(|s_opt| {
+                    std::mem::size_of_val(s_opt) + s_opt.as_ref().map(|s| s.capacity()).unwrap_or(0)
+                })
+                .sum::<usize>()
+            + self
+                .repeated
+                .values()
+                .flatten()
+                .map(|s| std::mem::size_of_val(s) + s.capacity())
+                .sum::<usize>()
+    }
+}
+
+#[derive(Default)]
+struct FilterCache {
+    start: usize,
+    end: usize,
+    loaded: bit_set::BitSet,
+}
+
+impl Cache for FilterCache {
+    fn bounds(&self) -> Interval {
+        Interval(self.start, self.end)
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(&self.loaded)
+    }
+}
+
+impl FilterCache {
+    fn starting_at(start: usize) -> FilterCache {
+        FilterCache {
+            start,
+            end: start,
+            ..FilterCache::default()
+        }
+    }
+
+    /// Extend the cache to `interval` with the lines passing before and after it. Both bounds
+    /// are recomputed from the old ones, so extending only one side keeps the other.
+    fn extend(
+        &mut self,
+        interval: Interval,
+        prefix: Option<bit_set::BitSet>,
+        suffix: Option<bit_set::BitSet>,
+    ) {
+        let bounds = self.bounds().union(interval);
+        if let Some(mut prefix) = prefix {
+            prefix.union_with(&self.loaded);
+            self.loaded = prefix;
+        }
+
+        if let Some(suffix) = suffix {
+            self.loaded.union_with(&suffix);
+        }
+
+        self.start = bounds.0;
+        self.end = bounds.1;
+        debug_assert!(self.start <= self.end, "filter cache bounds {}", bounds);
+    }
+
+    /// The number of lines of the cached interval that passed the filter
+    fn count(&self) -> usize {
+        self.loaded.len()
+    }
+
+    /// Every line of the cached interval was examined, `count` of them passed
+    fn selectivity(&self) -> Selectivity {
+        Selectivity {
+            examined: self.bounds().len(),
+            passed: self.count(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct DistinctCache {
+    start: usize,
+    end: usize,
+    loaded: bit_set::BitSet,
+    /// Fast pre-check, a value missing from the bloom has never been seen
+    bloom: ethbloom::Bloom,
+    /// Every value seen so far, consulted when the bloom reports a possible match
+    seen: HashSet<String>,
+}
+
+impl Cache for DistinctCache {
+    fn bounds(&self) -> Interval {
+        Interval(self.start, self.end)
+    }
+
+    /// The bloom alone would stay a fixed 256 bytes but drops values on false positives, so
+    /// the cache also grows with a copy of every distinct value
+    fn size(&self) -> usize {
+        std::mem::size_of_val(&self.loaded)
+            + std::mem::size_of_val(&self.bloom)
+            + std::mem::size_of_val(&self.seen)
+            + self
+                .seen
+                .iter()
+                .map(|s| std::mem::size_of_val(s) + s.capacity())
+                .sum::<usize>()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct IntervalStats {
+    distincts: HashMap<DistinctId, Vec<Interval>>,
+    files: HashMap<FileId, Vec<Interval>>,
+    filters: HashMap<FilterId, Vec<Interval>>,
+    tags: HashMap<TagId, Vec<Interval>>,
+}
+
+impl IntervalStats {
+    fn add(&mut self, id: Id, interval: Interval) {
+        match id {
+            Id::Distinct(did) => self
+                .distincts
+                .entry(did)
+                .or_insert_with(Vec::new)
+                .push(interval),
+            Id::File(fid) => self
+                .files
+                .entry(fid)
+                .or_insert_with(Vec::new)
+                .push(interval),
+            Id::Filter(fid) => self
+                .filters
+                .entry(fid)
+                .or_insert_with(Vec::new)
+                .push(interval),
+            Id::Tag(tid) => self.tags.entry(tid).or_insert_with(Vec::new).push(interval),
+            Id::Group(_) | Id::Range(_) | Id::Set(_) | Id::Skip(_) | Id::Sort(_) => {}
+        }
+    }
+}
+
+/// Number of bytes in a human readable size such as `1.5MB`, `512 KiB` or `2g`. Units are case
+/// insensitive, SI units are powers of 1000 and IEC units powers of 1024, no unit means bytes.
+fn parse_size(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number.parse::<f64>().ok()?;
+
+    let multiplier: f64 = match unit.trim_start().to_ascii_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "k" | "kb" => 1e3,
+        "m" | "mb" => 1e6,
+        "g" | "gb" => 1e9,
+        "t" | "tb" => 1e12,
+        "p" | "pb" => 1e15,
+        "ki" | "kib" => 1024.0,
+        "mi" | "mib" => 1024f64.powi(2),
+        "gi" | "gib" => 1024f64.powi(3),
+        "ti" | "tib" => 1024f64.powi(4),
+        "pi" | "pib" => 1024f64.powi(5),
+        _ => return None,
+    };
+    Some(number * multiplier)
+}
+
+/// Running totals of the lines or numbers aggregated by `group` and `aggregate`
+#[derive(Default)]
+struct Accumulator {
+    count: usize,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Accumulator {
+    fn add_line(&mut self) {
+        self.count += 1;
+    }
+
+    fn add_number(&mut self, number: Option<f64>) {
+        if let Some(number) = number {
+            self.count += 1;
+            self.sum += number;
+            self.min = Some(self.min.map_or(number, |min| min.min(number)));
+            self.max = Some(self.max.map_or(number, |max| max.max(number)));
+        }
+    }
+
+    /// `None` when nothing but lines without a number were added
+    fn result(&self, aggregator: Aggregator) -> Option<f64> {
+        match aggregator {
+            Aggregator::Count => Some(self.count as f64),
+            Aggregator::Min => self.min,
+            Aggregator::Max => self.max,
+            Aggregator::Sum if self.count > 0 => Some(self.sum),
+            Aggregator::Avg if self.count > 0 => Some(self.sum / self.count as f64),
+            Aggregator::Sum | Aggregator::Avg => None,
+        }
+    }
+}
+
+/// The buckets computed by `group`, copied so later commands can filter them without reading the
+/// tag again
+struct GroupCache {
+    tag_id: TagId,
+    aggregator: Aggregator,
+    buckets: Vec<(String, Option<f64>)>,
+}
+
+/// Order of a tag value under `sort`, numbers come first ordered by value, then every other
+/// value ordered lexically
+enum SortKey<'a> {
+    Number(f64),
+    Text(&'a str),
+}
+
+impl<'a> SortKey<'a> {
+    fn new(value: &'a str) -> SortKey<'a> {
+        match value.trim().parse::<f64>() {
+            Ok(number) => SortKey::Number(number),
+            Err(_) => SortKey::Text(value),
+        }
+    }
+}
+
+impl<'a> Ord for SortKey<'a> {
+    fn cmp(&self, other: &SortKey<'a>) -> std::cmp::Ordering {
+        match (self, other) {
+            (SortKey::Number(left), SortKey::Number(right)) => left.total_cmp(right),
+            (SortKey::Number(_), SortKey::Text(_)) => std::cmp::Ordering::Less,
+            (SortKey::Text(_), SortKey::Number(_)) => std::cmp::Ordering::Greater,
+            (SortKey::Text(left), SortKey::Text(right)) => left.cmp(right),
+        }
+    }
+}
+
+impl<'a> PartialOrd for SortKey<'a> {
+    fn partial_cmp(&self, other: &SortKey<'a>) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> PartialEq for SortKey<'a> {
+    fn eq(&self, other: &SortKey<'a>) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl<'a> Eq for SortKey<'a> {}
+
+fn format_aggregate(aggregate: Option<f64>) -> String {
+    aggregate
+        .map(|aggregate| aggregate.to_string())
+        .unwrap_or_else(|| "N/A".to_string())
+}
+
+/// Reverse symbol table used to print ids by the name they were bound to
+pub type Names = HashMap<Id, String>;
+
+fn id_key<T: Copy + fmt::Debug + Into<Id>>(names: &Names, id: T) -> String {
+    match names.get(&id.into()) {
+        Some(name) => format!("'{}", name),
+        None => format!("{:?}", id),
+    }
+}
+
+fn write_id<T: Copy + fmt::Debug + Into<Id>>(
+    f: &mut fmt::Formatter<'_>,
+    names: &Names,
+    id: T,
+) -> fmt::Result {
+    write!(f, "{}", id_key(names, id))
+}
+
+/// A JSON object from IDs to values, keyed the same way ids are printed
+fn json_by_id<T, V, F>(names: &Names, kinds: &HashMap<T, V>, to_json: F) -> serde_json::Value
+where
+    T: Copy + fmt::Debug + Into<Id>,
+    F: Fn(&V) -> serde_json::Value,
+{
+    serde_json::Value::Object(
+        kinds
+            .iter()
+            .map(|(id, value)| (id_key(names, *id), to_json(value)))
+            .collect(),
+    )
+}
+
+impl IntervalStats {
+    fn write(&self, f: &mut fmt::Formatter<'_>, names: &Names) -> fmt::Result {
+        fn write_intervals(f: &mut fmt::Formatter<'_>, intervals: &[Interval]) -> fmt::Result {
+            write!(f, "[")?;
+            for interval in intervals {
+                write!(f, "{}, ", interval)?;
+            }
+            writeln!(f, "]")
+        }
+
+        fn write_interval_kind<T: Copy + fmt::Debug + std::cmp::Ord + Into<Id>>(
+            f: &mut fmt::Formatter<'_>,
+            names: &Names,
+            name: &str,
+            kinds: &HashMap<T, Vec<Interval>>,
+        ) -> fmt::Result {
+            if kinds.is_empty() {
+                return Ok(())
+            }
+            writeln!(f, "{}: {{", name)?;
+
+            let mut kinds_vec: Vec<(&T, &Vec<Interval>)> = kinds.iter().collect();
+            kinds_vec.sort_by_key(|&(id, _)| id);
+
+            for (id, intervals) in kinds_vec {
+                write!(f, "  ")?;
+                write_id(f, names, *id)?;
+                write!(f, ": ")?;
+                write_intervals(f, intervals)?;
+            }
+
+            writeln!(f, "}}")
+        }
+
+        write_interval_kind(f, names, "files", &self.files)?;
+        write_interval_kind(f, names, "tags", &self.tags)?;
+        write_interval_kind(f, names, "filters", &self.filters)?;
+        write_interval_kind(f, names, "distincts", &self.distincts)
+    }
+}
+
+impl IntervalStats {
+    fn to_json(&self, names: &Names) -> serde_json::Value {
+        let intervals = |intervals: &Vec<Interval>| {
+            serde_json::json!(intervals
+                .iter()
+                .map(|interval| [interval.0, interval.1])
+                .collect::<Vec<_>>())
+        };
+        serde_json::json!({
+            "files": json_by_id(names, &self.files, intervals),
+            "tags": json_by_id(names, &self.tags, intervals),
+            "filters": json_by_id(names, &self.filters, intervals),
+            "distincts": json_by_id(names, &self.distincts, intervals),
+        })
+    }
+}
+
+impl fmt::Display for IntervalStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, &Names::new())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SizeStats {
+    distincts: HashMap<DistinctId, usize>,
+    files: HashMap<FileId, usize>,
+    filters: HashMap<FilterId, usize>,
+    tags: HashMap<TagId, usize>,
+}
+
+impl SizeStats {
+    fn add(&mut self, id: Id, size: usize) {
+        match id {
+            Id::Distinct(did) => *self.distincts.entry(did).or_insert(0) = size,
+            Id::File(fid) => *self.files.entry(fid).or_insert(0) = size,
+            Id::Filter(fid) => *self.filters.entry(fid).or_insert(0) = size,
+            Id::Tag(tid) => *self.tags.entry(tid).or_insert(0) = size,
+            Id::Group(_) | Id::Range(_) | Id::Set(_) | Id::Skip(_) | Id::Sort(_) => {}
+        }
+    }
+}
+
+impl SizeStats {
+    fn write(&self, f: &mut fmt::Formatter<'_>, names: &Names) -> fmt::Result {
+        fn write_size_kind<T: Copy + fmt::Debug + std::cmp::Ord + Into<Id>>(
+            f: &mut fmt::Formatter<'_>,
+            names: &Names,
+            name: &str,
+            kinds: &HashMap<T, usize>,
+        ) -> fmt::Result {
+            if kinds.is_empty() {
+                return Ok(());
+            }
+            writeln!(f, "{}: {{", name)?;
+
+            let mut kinds_vec: Vec<(&T, &usize)> = kinds.iter().collect();
+            kinds_vec.sort_by_key(|&(id, _)| id);
+
+            for (id, size) in kinds_vec {
+                write!(f, "  ")?;
+                write_id(f, names, *id)?;
+                writeln!(f, ": {:.3} MB", *size as f64 / 1_000_000.0)?;
+            }
+
+            writeln!(f, "}}")
+        }
+
+        write_size_kind(f, names, "files", &self.files)?;
+        write_size_kind(f, names, "tags", &self.tags)?;
+        write_size_kind(f, names, "filters", &self.filters)?;
+        write_size_kind(f, names, "distincts", &self.distincts)
+    }
+}
+
+impl SizeStats {
+    fn to_json(&self, names: &Names) -> serde_json::Value {
+        let bytes = |size: &usize| serde_json::json!(size);
+        serde_json::json!({
+            "files": json_by_id(names, &self.files, bytes),
+            "tags": json_by_id(names, &self.tags, bytes),
+            "filters": json_by_id(names, &self.filters, bytes),
+            "distincts": json_by_id(names, &self.distincts, bytes),
+        })
+    }
+}
+
+impl fmt::Display for SizeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, &Names::new())
+    }
+}
+
+/// How many lines of its materialized interval a filter examined and how many passed
+#[derive(Debug, Clone, Copy)]
+pub struct Selectivity {
+    examined: usize,
+    passed: usize,
+}
+
+impl Selectivity {
+    /// The fraction of examined lines that passed, `None` before any line was examined
+    fn ratio(&self) -> Option<f64> {
+        if self.examined == 0 {
+            None
+        } else {
+            Some(self.passed as f64 / self.examined as f64)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SelectivityStats {
+    filters: HashMap<FilterId, Selectivity>,
+}
+
+impl SelectivityStats {
+    fn add(&mut self, filter_id: FilterId, selectivity: Selectivity) {
+        self.filters.insert(filter_id, selectivity);
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, names: &Names) -> fmt::Result {
+        if self.filters.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "filters: {{")?;
+
+        let mut filters: Vec<(&FilterId, &Selectivity)> = self.filters.iter().collect();
+        filters.sort_by_key(|&(id, _)| id);
+
+        for (id, selectivity) in filters {
+            write!(f, "  ")?;
+            write_id(f, names, *id)?;
+            write!(f, ": {} / {}", selectivity.passed, selectivity.examined)?;
+            match selectivity.ratio() {
+                Some(ratio) => writeln!(f, " ({:.2}%)", ratio * 100.0)?,
+                None => writeln!(f)?,
+            }
+        }
+
+        writeln!(f, "}}")
+    }
+
+    fn to_json(&self, names: &Names) -> serde_json::Value {
+        let selectivity = |selectivity: &Selectivity| {
+            serde_json::json!({
+                "examined": selectivity.examined,
+                "passed": selectivity.passed,
+                "ratio": selectivity.ratio(),
+            })
+        };
+        serde_json::json!({
+            "filters": json_by_id(names, &self.filters, selectivity),
+        })
+    }
+}
+
+impl fmt::Display for SelectivityStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, &Names::new())
+    }
+}
+
+/// Calls to a Lua chunk and the time spent in them
+#[derive(Clone, Copy, Debug, Default)]
+struct LuaTiming {
+    calls: usize,
+    elapsed: Duration,
+}
+
+impl LuaTiming {
+    /// Run `call`, timing it when profiling
+    fn time<T>(&mut self, profile: bool, call: impl FnOnce() -> T) -> T {
+        if !profile {
+            return call();
+        }
+        let started = Instant::now();
+        let result = call();
+        self.calls += 1;
+        self.elapsed += started.elapsed();
+        result
+    }
+}
+
+/// Time spent in each distinct Lua chunk, keyed by its source or custom filter function name
+#[derive(Debug, Default)]
+pub struct LuaStats {
+    scripts: HashMap<String, LuaTiming>,
+}
+
+impl LuaStats {
+    fn add(&mut self, script: &str, timing: LuaTiming) {
+        if timing.calls == 0 {
+            return;
+        }
+        let total = self.scripts.entry(script.to_string()).or_default();
+        total.calls += timing.calls;
+        total.elapsed += timing.elapsed;
+    }
+
+    /// Scripts from the most to the least time spent in them
+    fn by_elapsed(&self) -> Vec<(&String, &LuaTiming)> {
+        let mut scripts: Vec<(&String, &LuaTiming)> = self.scripts.iter().collect();
+        scripts.sort_by(|(a_script, a), (b_script, b)| {
+            b.elapsed.cmp(&a.elapsed).then(a_script.cmp(b_script))
+        });
+        scripts
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.scripts.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "scripts: {{")?;
+        for (script, timing) in self.by_elapsed().into_iter().take(LUA_PROFILE_TOP) {
+            writeln!(
+                f,
+                "  {:?}: {:.3} ms in {} call(s)",
+                script,
+                timing.elapsed.as_secs_f64() * 1000.0,
+                timing.calls
+            )?;
+        }
+        if self.scripts.len() > LUA_PROFILE_TOP {
+            writeln!(f, "  ... {} more", self.scripts.len() - LUA_PROFILE_TOP)?;
+        }
+        writeln!(f, "}}")
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let scripts = self
+            .by_elapsed()
+            .into_iter()
+            .map(|(script, timing)| {
+                serde_json::json!({
+                    "script": script,
+                    "calls": timing.calls,
+                    "elapsed_ms": timing.elapsed.as_secs_f64() * 1000.0,
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({ "scripts": scripts })
+    }
+}
+
+impl fmt::Display for LuaStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f)
+    }
+}
+
+#[derive(Debug)]
+pub struct Stats {
+    intervals: Option<IntervalStats>,
+    sizes: Option<SizeStats>,
+    selectivity: Option<SelectivityStats>,
+    lua: Option<LuaStats>,
+    started: Option<Instant>,
+    elapsed: Option<Duration>,
+}
+
+impl Stats {
+    fn enabled() -> Self {
+        Self {
+            intervals: Some(IntervalStats::default()),
+            sizes: Some(SizeStats::default()),
+            selectivity: Some(SelectivityStats::default()),
+            lua: None,
+            started: Some(Instant::now()),
+            elapsed: None,
+        }
+    }
+
+    fn disabled() -> Self {
+        Self {
+            intervals: None,
+            sizes: None,
+            selectivity: None,
+            lua: None,
+            started: None,
+            elapsed: None,
+        }
+    }
+
+    /// Record the time since the stats were created
+    fn stop(&mut self) {
+        self.elapsed = self.started.map(|started| started.elapsed());
+    }
+
+    fn add_interval(&mut self, id: Id, interval: Interval) {
+        if let Some(intervals) = &mut self.intervals {
+            intervals.add(id, interval);
+        }
+    }
+
+    fn add_size(&mut self, id: Id, size: usize) {
+        if let Some(sizes) = &mut self.sizes {
+            sizes.add(id, size);
+        }
+    }
+
+    fn add_selectivity(&mut self, filter_id: FilterId, selectivity: Selectivity) {
+        if let Some(selectivities) = &mut self.selectivity {
+            selectivities.add(filter_id, selectivity);
+        }
+    }
+
+    /// Track the time spent in each Lua chunk, independently of the other stats
+    fn profile_lua(&mut self) {
+        self.lua = Some(LuaStats::default());
+    }
+
+    fn profiles_lua(&self) -> bool {
+        self.lua.is_some()
+    }
+
+    fn add_lua(&mut self, script: &str, timing: LuaTiming) {
+        if let Some(lua) = &mut self.lua {
+            lua.add(script, timing);
+        }
+    }
+}
+
+impl Stats {
+    /// Display the stats using the names ids are bound to, falling back to the raw ids
+    pub fn named<'a>(&'a self, names: &'a Names) -> NamedStats<'a> {
+        NamedStats { stats: self, names }
+    }
+
+    /// The stats as a single JSON object, `None` when stats aren't being tracked
+    pub fn to_json(&self, names: &Names) -> Option<serde_json::Value> {
+        let lua = self.lua.as_ref().map(LuaStats::to_json);
+        let (intervals, sizes, selectivity) =
+            match (&self.intervals, &self.sizes, &self.selectivity) {
+                (Some(intervals), Some(sizes), Some(selectivity)) => {
+                    (intervals, sizes, selectivity)
+                }
+                _ => return lua.map(|lua| serde_json::json!({ "lua": lua })),
+            };
+        let mut json = serde_json::json!({
+            "intervals": intervals.to_json(names),
+            "sizes": sizes.to_json(names),
+            "selectivity": selectivity.to_json(names),
+            "elapsed_ms": self.elapsed.map(|elapsed| elapsed.as_secs_f64() * 1000.0),
+        });
+        if let Some(lua) = lua {
+            json["lua"] = lua;
+        }
+        Some(json)
+    }
+}
+
+pub struct NamedStats<'a> {
+    stats: &'a Stats,
+    names: &'a Names,
+}
+
+impl<'a> fmt::Display for NamedStats<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(intervals) = &self.stats.intervals {
+            write!(f, "\nintervals\n---------\n")?;
+            intervals.write(f, self.names)?;
+        }
+        if let Some(sizes) = &self.stats.sizes {
+            write!(f, "\nsizes\n-----\n")?;
+            sizes.write(f, self.names)?;
+        }
+        if let Some(selectivity) = &self.stats.selectivity {
+            write!(f, "\nselectivity\n-----------\n")?;
+            selectivity.write(f, self.names)?;
+        }
+        if let Some(lua) = &self.stats.lua {
+            write!(f, "\nlua\n---\n")?;
+            lua.write(f)?;
+        }
+        if let Some(elapsed) = self.stats.elapsed {
+            write!(f, "\nelapsed: {:.3} ms\n", elapsed.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.named(&Names::new()).fmt(f)
+    }
+}
+
+pub struct Output {
+    pub id: Option<Id>,
+    pub lines: Vec<String>,
+    pub stats: Stats,
+    /// Informational lines such as "file loaded" rather than query results
+    pub is_message: bool,
+}
+
+impl Output {
+    pub fn with_message(id: Option<Id>, message: String) -> Output {
+        Output {
+            id,
+            lines: vec![message],
+            stats: Stats::disabled(),
+            is_message: true,
+        }
+    }
+
+    fn with_results(lines: Vec<String>, stats: Stats) -> Output {
+        Output {
+            id: None,
+            lines,
+            stats,
+            is_message: false,
+        }
+    }
+}
+
+struct ReadIntervals {
+    index: usize,
+    next: usize,
+    max: usize,
+}
+
+impl ReadIntervals {
+    fn new(min: usize, max: usize) -> ReadIntervals {
+        ReadIntervals {
+            index: 0,
+            next: std::cmp::min(min, max),
+            max,
+        }
+    }
+
+    /// Start the next interval at `index`, used after a short read at the end of the file
+    fn rewind(&mut self, index: usize) {
+        self.index = index;
+    }
+}
+
+impl Iterator for ReadIntervals {
+    type Item = Interval;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let interval = Interval(self.index, self.index + self.next);
+        self.index += self.next;
+        self.next = std::cmp::min(self.max, self.next * 2);
+        Some(interval)
+    }
+}
+
+const MAX_BATCH_SIZE: usize = 1024;
+/// Appended to lines cut short at the engine's maximum line length
+const TRUNCATED_LINE_MARKER: &str = "…";
+/// Number of the most expensive Lua chunks listed by the Lua profile
+const LUA_PROFILE_TOP: usize = 10;
+/// The default maximum size of a compiled regex, the same as the regex crate's
+const REGEX_SIZE_LIMIT: usize = 10 * (1 << 20);
+/// Cargo features of this crate and whether they were compiled in, as reported by `info`
+const ENABLED_FEATURES: &[(&str, bool)] = &[("testing", cfg!(feature = "testing"))];
+/// Helpers loaded into every Lua state, so scripts, transforms and scripted filters can use them:
+///
+/// - `trim(s)`, also `string.trim` and `s:trim()`: `s` without leading and trailing whitespace
+/// - `split(s, sep)`: a table of the fields of `s` between occurrences of the plain text `sep`,
+///   empty fields included, or of its whitespace separated words when `sep` is nil or empty
+/// - `to_number_or_nil(v)`: `v` as a number, nil when it isn't a number or numeric string
+///
+/// They are ordinary globals, so a script can redefine them.
+const LUA_PRELUDE: &str = r#"
+function string.trim(s)
+  return (string.gsub(s, "^%s*(.-)%s*$", "%1"))
+end
+trim = string.trim
+
+function split(s, sep)
+  local fields = {}
+  if sep == nil or sep == "" then
+    for field in string.gmatch(s, "%S+") do
+      fields[#fields + 1] = field
+    end
+    return fields
+  end
+  local start = 1
+  while true do
+    local first, last = string.find(s, sep, start, true)
+    if first == nil then
+      break
+    end
+    fields[#fields + 1] = string.sub(s, start, first - 1)
+    start = last + 1
+  end
+  fields[#fields + 1] = string.sub(s, start)
+  return fields
+end
+
+function to_number_or_nil(v)
+  if type(v) ~= "number" and type(v) ~= "string" then
+    return nil
+  end
+  return tonumber(v)
+end
+"#;
+const MAX_DISTINCT_VALUES: usize = 1000;
+const MATCH_RATE_SAMPLE_SIZE: usize = 10;
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_COLUMN_WIDTH: usize = 80;
+
+/// How the stats tracked in debug mode are printed after each query
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StatsFormat {
+    Human,
+    Json,
+}
+
+/// How `take` renders the rows it selects
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// Each line followed by an indented block of its tag values
+    Lines,
+    /// An aligned table with one column per tag and a final column for the line
+    Columns,
+    /// One JSON object per row keyed by tag name, with the line under `_line`
+    Json,
+    /// A header of tag names and `_line`, followed by one record per row
+    Csv,
+}
+
+impl OutputFormat {
+    /// Formats meant to be parsed by other tools, which shouldn't be mixed with stats
+    pub fn is_machine_readable(self) -> bool {
+        match self {
+            OutputFormat::Lines | OutputFormat::Columns => false,
+            OutputFormat::Json | OutputFormat::Csv => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Plan {
+    steps: Vec<Id>,
+    skip: usize,
+    /// Lines of the file the plan is limited to, before any filter
+    range: Interval,
+    /// Tag the selected rows are ordered by, the skip then applies to the sorted rows
+    sort: Option<(TagId, Order)>,
+}
+
+impl Plan {
+    fn new(steps: Vec<Id>, skip: usize, range: Interval, sort: Option<(TagId, Order)>) -> Plan {
+        Plan {
+            steps,
+            skip,
+            range,
+            sort,
+        }
+    }
+
+    fn file_id(&self) -> FileId {
+        match self.steps[0] {
+            Id::File(file_id) => file_id,
+            _ => panic!(),
+        }
+    }
+
+    fn filter_ids(&self) -> Vec<FilterId> {
+        self.steps
+            .iter()
+            .filter_map(|step| match step {
+                Id::Filter(filter_id) => Some(*filter_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn distinct_ids(&self) -> Vec<DistinctId> {
+        self.steps
+            .iter()
+            .filter_map(|step| match step {
+                Id::Distinct(distinct_id) => Some(*distinct_id),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub struct TakeIter<'a> {
+    engine: &'a mut Engine,
+    plan: Plan,
+    filter_ids: Vec<FilterId>,
+    distinct_ids: Vec<DistinctId>,
+    count: usize,
+    stats: Stats,
+    batches: ReadIntervals,
+    interval: Interval,
+    cursor: usize,
+    current_count: usize,
+    skipped: usize,
+    pending: VecDeque<String>,
+    previous: HashMap<String, TagValue>,
+    /// Selected rows in sorted order, or the last ones when `last` is set, read all at once the
+    /// first time the take is pulled
+    buffered: Option<VecDeque<usize>>,
+    /// Yield the last `count` rows rather than the first
+    last: bool,
+    /// Header and buffered rows of the table rendered once the take completes in column mode
+    columns: Vec<String>,
+    table: Vec<Vec<String>>,
+    follow: bool,
+    /// Yield the selected lines as they were read, ignoring the output format and output limit
+    raw: bool,
+    /// Apply the engine's output limit to the rendered rows
+    limited: bool,
+    /// Collect the indices of the selected lines here instead of yielding any row
+    selection: Option<bit_set::BitSet>,
+    /// Bytes yielded so far, counted against the engine's output limit
+    output_bytes: usize,
+    truncated: bool,
+    done: bool,
+}
+
+impl<'a> TakeIter<'a> {
+    fn new(engine: &'a mut Engine, plan: Plan, count: usize, follow: bool) -> TakeIter<'a> {
+        let stats = engine.new_stats();
+        let start = plan.range.0;
+        let mut batches = ReadIntervals::new(count.saturating_add(plan.skip), MAX_BATCH_SIZE);
+        batches.rewind(start);
+        engine.interrupted.store(false, atomic::Ordering::SeqCst);
+        if let Some(file) = engine.files.get_mut(&plan.file_id()) {
+            file.follow = follow;
+        }
+
+        TakeIter {
+            engine,
+            filter_ids: plan.filter_ids(),
+            distinct_ids: plan.distinct_ids(),
+            plan,
+            count,
+            stats,
+            batches,
+            interval: Interval(start, start),
+            cursor: start,
+            current_count: 0,
+            skipped: 0,
+            pending: VecDeque::new(),
+            previous: HashMap::new(),
+            buffered: None,
+            last: false,
+            columns: vec![],
+            table: vec![],
+            follow,
+            raw: false,
+            limited: true,
+            selection: None,
+            output_bytes: 0,
+            truncated: false,
+            done: false,
+        }
+    }
+
+    /// Stop reading, returning the stats collected so far
+    pub fn finish(mut self) -> Stats {
+        self.engine.evict_caches();
+        self.stats.stop();
+        self.stats
+    }
+
+    /// Extend every step of the plan by another batch, returns `false` once the file is exhausted
+    /// or a batch adds no lines, so a source that never reports its end can't stall the take
+    fn load_batch(&mut self) -> Result<bool> {
+        let loaded = self.interval.1;
+        let batch_interval = self
+            .batches
+            .next()
+            .unwrap()
+            .intersect(Interval(0, self.plan.range.1));
+        // A lone distinct selects exactly the lines with a new value, so it can stop reading as
+        // soon as it has found the rows the take still needs, unless they are about to be sorted
+        let limit = if self.plan.sort.is_none()
+            && self.filter_ids.is_empty()
+            && self.distinct_ids.len() == 1
+        {
+            let needed = self.count.saturating_add(self.plan.skip);
+            Some(needed.saturating_sub(self.current_count + self.skipped))
+        } else {
+            None
+        };
+        let engine = &mut *self.engine;
+        let stats = &mut self.stats;
+
+        for id in &self.plan.steps {
+            match id {
+                Id::File(file_id) => {
+                    let read_count = engine.ensure_file(stats, *file_id, batch_interval)?;
+                    self.interval.1 += read_count;
+                    if read_count < batch_interval.len() {
+                        self.batches.rewind(self.interval.1);
+                    }
+                    if read_count == 0 {
+                        return Ok(false);
+                    }
+                }
+                Id::Distinct(distinct_id) => {
+                    let tag_id = engine
+                        .find_parent_tag(Id::Distinct(*distinct_id))
+                        .ok_or_else(|| Error::InvalidTarget(format!("{:?}", id)))?;
+                    let covered = engine.ensure_distinct(
+                        stats,
+                        tag_id,
+                        *distinct_id,
+                        self.interval,
+                        limit,
+                    )?;
+                    if covered.1 < self.interval.1 {
+                        self.interval.1 = covered.1;
+                        self.batches.rewind(covered.1);
+                    }
+                }
+                Id::Filter(filter_id) => {
+                    engine.ensure_any_filter(stats, *filter_id, self.interval)?;
+                }
+                Id::Group(_) | Id::Range(_) | Id::Set(_) | Id::Skip(_) | Id::Sort(_) => {}
+                Id::Tag(tag_id) => {
+                    engine.ensure_tag(stats, engine.tag_to_file[tag_id], *tag_id, self.interval)?;
+                }
+            }
+        }
+
+        engine.ensure_all_tags(stats, self.plan.file_id(), self.interval)?;
+        Ok(self.interval.1 > loaded)
+    }
+
+    /// Count `line` against the output limit. The line reaching the limit is cut short, or
+    /// dropped in machine readable formats, and the rest of the take is replaced by a marker.
+    fn limit_output(&mut self, mut line: String) -> String {
+        let limit = match self.engine.output_limit {
+            Some(limit) if self.limited && !self.raw && !self.truncated => limit,
+            _ => return line,
+        };
+        let remaining = limit - self.output_bytes;
+        if line.len() < remaining {
+            self.output_bytes += line.len() + 1;
+            return line;
+        }
+
+        self.truncated = true;
+        self.done = true;
+        self.pending.clear();
+        self.table.clear();
+        let marker = format!("output truncated at {} bytes", limit);
+        if self.engine.output_format.is_machine_readable() {
+            return marker;
+        }
+
+        let mut end = remaining.saturating_sub(1);
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        line.truncate(end);
+        if line.is_empty() {
+            return marker;
+        }
+        self.pending.push_back(marker);
+        line
+    }
+
+    /// Read every batch of the plan's range, returning the selected rows before any skip. Stops
+    /// with the rows selected so far if the take is interrupted.
+    fn read_selected(&mut self) -> Result<Vec<usize>> {
+        let mut rows = vec![];
+        while self.cursor < self.plan.range.1 {
+            if self.engine.interrupted.load(atomic::Ordering::SeqCst) {
+                break;
+            }
+            if self.cursor == self.interval.1 && !self.load_batch()? {
+                break;
+            }
+
+            let idx = self.cursor;
+            self.cursor += 1;
+            if self
+                .engine
+                .is_selected(&self.filter_ids, &self.distinct_ids, idx)
+            {
+                rows.push(idx);
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Like `read_selected`, but an interrupted read fails rather than returning part of the
+    /// rows, for commands whose result would be wrong rather than short
+    fn read_whole_selection(&mut self) -> Result<Vec<usize>> {
+        let rows = self.read_selected()?;
+        self.engine.check_interrupted()?;
+        Ok(rows)
+    }
+
+    /// Read the plan's range backward from its end one batch at a time, returning the selected
+    /// rows in file order once there are enough of them to skip and then take `count`, or once
+    /// the start of the range is reached. Stops with the rows selected so far if the take is
+    /// interrupted.
+    fn read_selected_backward(&mut self) -> Result<Vec<usize>> {
+        let needed = self.count.saturating_add(self.plan.skip);
+        let line_count = self.engine.line_count(self.plan.file_id())?;
+        let mut end = std::cmp::min(self.plan.range.1, line_count);
+
+        let mut rows = vec![];
+        while end > self.plan.range.0 && rows.len() < needed {
+            if self.engine.interrupted.load(atomic::Ordering::SeqCst) {
+                break;
+            }
+            let start = std::cmp::max(end.saturating_sub(MAX_BATCH_SIZE), self.plan.range.0);
+            self.load_interval(Interval(start, end))?;
+
+            for idx in (start..end).rev() {
+                if rows.len() == needed {
+                    break;
+                }
+                if self
+                    .engine
+                    .is_selected(&self.filter_ids, &self.distinct_ids, idx)
+                {
+                    rows.push(idx);
+                }
+            }
+            end = start;
+        }
+        rows.reverse();
+        Ok(rows)
+    }
+
+    /// Extend every step of the plan and every tag of its file to `interval`, which may come
+    /// before the lines already cached
+    fn load_interval(&mut self, interval: Interval) -> Result<()> {
+        let engine = &mut *self.engine;
+        let stats = &mut self.stats;
+        for id in &self.plan.steps {
+            match id {
+                Id::File(file_id) => {
+                    engine.ensure_file(stats, *file_id, interval)?;
+                }
+                Id::Filter(filter_id) => {
+                    engine.ensure_any_filter(stats, *filter_id, interval)?;
+                }
+                Id::Tag(tag_id) => {
+                    engine.ensure_tag(stats, engine.tag_to_file[tag_id], *tag_id, interval)?;
+                }
+                // A distinct depends on every line before it, so it is never read backward
+                Id::Distinct(_)
+                | Id::Group(_)
+                | Id::Range(_)
+                | Id::Set(_)
+                | Id::Skip(_)
+                | Id::Sort(_) => {}
+            }
+        }
+        engine.ensure_all_tags(stats, self.plan.file_id(), interval)
+    }
+
+    /// Whether the rows are read backward from the end of the range: a take of the last rows
+    /// without a sort or a distinct, on a file that can seek back to earlier lines
+    fn reads_backward(&self) -> bool {
+        self.last
+            && self.plan.sort.is_none()
+            && self.distinct_ids.is_empty()
+            && self
+                .engine
+                .files
+                .get(&self.plan.file_id())
+                .is_some_and(File::seeks_backward)
+    }
+
+    /// The next row of a sorted take or of a take of the last rows. The first call reads every
+    /// batch of the plan's range, as the first row to yield may be its last line, so a whole file
+    /// keeps all of its lines and tags cached until the take finishes no matter the count. A take
+    /// of the last rows that reads backward only reads and keeps the batches it needs.
+    fn next_buffered(&mut self) -> Result<Option<usize>> {
+        if self.buffered.is_none() {
+            let mut rows = if self.reads_backward() {
+                self.read_selected_backward()?
+            } else {
+                self.read_selected()?
+            };
+            if self.engine.interrupted.load(atomic::Ordering::SeqCst) {
+                return Ok(None);
+            }
+            if let Some((tag_id, order)) = self.plan.sort {
+                self.engine.sort_rows(tag_id, order, &mut rows);
+            }
+
+            let mut rows = VecDeque::from(rows);
+            if self.last {
+                // The skip still drops the first rows, only the remaining tail is kept
+                let skip = std::cmp::min(self.plan.skip, rows.len());
+                rows.drain(..skip);
+                self.skipped = self.plan.skip;
+                let extra = rows.len().saturating_sub(self.count);
+                rows.drain(..extra);
+            }
+            self.buffered = Some(rows);
+        }
+        Ok(self.buffered.as_mut().and_then(|rows| rows.pop_front()))
+    }
+}
+
+impl<'a> Iterator for TakeIter<'a> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(line) = self.pending.pop_front() {
+                return Some(Ok(self.limit_output(line)));
+            }
+            if self
+                .engine
+                .interrupted
+                .swap(false, atomic::Ordering::SeqCst)
+            {
+                self.done = true;
+            }
+            if self.done || self.current_count >= self.count {
+                if !self.table.is_empty() {
+                    let table = std::mem::take(&mut self.table);
+                    self.pending.extend(render_table(&self.columns, &table));
+                    continue;
+                }
+                return None;
+            }
+
+            let idx = if self.plan.sort.is_some() || self.last {
+                match self.next_buffered() {
+                    Ok(Some(idx)) => idx,
+                    Ok(None) => {
+                        self.done = true;
+                        continue;
+                    }
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(err));
+                    }
+                }
+            } else {
+                if self.cursor >= self.plan.range.1 {
+                    self.done = true;
+                    continue;
+                }
+
+                if self.cursor == self.interval.1 {
+                    match self.load_batch() {
+                        Ok(true) => continue,
+                        Ok(false) if self.follow => {
+                            thread::sleep(FOLLOW_POLL_INTERVAL);
+                            continue;
+                        }
+                        Ok(false) => {
+                            self.done = true;
+                            continue;
+                        }
+                        Err(err) => {
+                            self.done = true;
+                            return Some(Err(err));
+                        }
+                    }
+                }
+
+                let idx = self.cursor;
+                self.cursor += 1;
+
+                if !self
+                    .engine
+                    .is_selected(&self.filter_ids, &self.distinct_ids, idx)
+                {
+                    continue;
+                }
+                idx
+            };
+
+            if self.skipped < self.plan.skip {
+                self.skipped += 1;
+                continue;
+            }
+            if let Some(selection) = &mut self.selection {
+                selection.insert(idx);
+                self.current_count += 1;
+                continue;
+            }
+
+            match self.engine.output_format {
+                _ if self.raw => {
+                    let line = &self
+                        .engine
+                        .read_lines(self.plan.file_id(), Interval(idx, idx + 1))[0];
+                    self.pending.push_back(line.clone());
+                }
+                OutputFormat::Lines => self.engine.render_row(
+                    self.plan.file_id(),
+                    idx,
+                    &mut self.previous,
+                    &mut self.pending,
+                ),
+                OutputFormat::Columns => {
+                    let cells =
+                        self.engine
+                            .render_cells(self.plan.file_id(), idx, &mut self.previous);
+                    if self.columns.is_empty() {
+                        self.columns = cells.iter().map(|(name, _)| name.clone()).collect();
+                    }
+                    self.table
+                        .push(cells.into_iter().map(|(_, cell)| cell).collect());
+                }
+                OutputFormat::Json => {
+                    let object = self.engine.render_json(self.plan.file_id(), idx);
+                    self.pending.push_back(object);
+                }
+                OutputFormat::Csv => {
+                    let (names, record) = self.engine.render_csv(self.plan.file_id(), idx);
+                    if self.columns.is_empty() {
+                        self.pending.push_back(names.join(","));
+                        self.columns = names;
+                    }
+                    self.pending.push_back(record);
+                }
+            }
+            self.current_count += 1;
+        }
+    }
+}
+
+pub struct Engine {
+    debug: bool,
+    last_id: usize,
+    lua: rlua::Lua,
+    interrupted: Arc<AtomicBool>,
+    sparse_tags: bool,
+    /// Anchor the patterns of `regex` tags at the start of the line
+    anchor_tag_regexes: bool,
+    /// Only print query results on stdout, messages go to stderr and stats are dropped
+    results_only: bool,
+    /// Line printed after every row in lines mode, if any
+    separator: Option<String>,
+    /// Highlight captured spans and tag names with ANSI escapes in lines mode
+    color: bool,
+    output_format: OutputFormat,
+    stats_format: StatsFormat,
+    /// Maximum number of bytes rendered by a single take, counting a newline after every line
+    output_limit: Option<usize>,
+    /// Lines of files loaded from now on are truncated past this many bytes
+    max_line_bytes: Option<usize>,
+    /// Files loaded from now on read CRLF line endings as LF
+    normalize_crlf: bool,
+    /// Patterns compiling to a larger program than this many bytes are rejected
+    regex_size_limit: usize,
+    /// Treat a row whose transform or scripted filter raises a Lua error as having no value
+    /// instead of failing the query
+    skip_lua_errors: bool,
+    /// Time the Lua chunks run by each query and report the most expensive ones
+    profile_lua: bool,
+    /// Every successful command that defined or changed a file, tag, filter, distinct or skip,
+    /// along with the ID it produced
+    history: Vec<(Command, Option<Id>)>,
+
+    cache_budget: Option<usize>,
+    cache_clock: usize,
+    cache_last_used: HashMap<Id, usize>,
+
+    files: HashMap<FileId, File>,
+    /// Everything read so far from standard input, loaded by the path `-`. The whole input is
+    /// kept in memory, next to the file caches of the files reading it.
+    stdin: Arc<Mutex<Vec<u8>>>,
+    file_caches: HashMap<FileId, FileCache>,
+    max_open_files: Option<usize>,
+    open_files: VecDeque<FileId>,
+
+    tags: HashMap<TagId, Tag>,
+    tag_caches: HashMap<TagId, TagCache>,
+    tag_to_file: HashMap<TagId, FileId>,
+    /// Tags of every file, in the order they were created
+    file_to_tags: HashMap<FileId, Vec<TagId>>,
+
+    filters: HashMap<FilterId, Filter>,
+    filter_caches: HashMap<FilterId, FilterCache>,
+    filter_to_parent: HashMap<FilterId, Id>,
+
+    distinct_caches: HashMap<DistinctId, DistinctCache>,
+    distinct_to_parent: HashMap<DistinctId, Id>,
+
+    ranges: HashMap<RangeId, Interval>,
+    range_to_parent: HashMap<RangeId, Id>,
+
+    skips: HashMap<SkipId, usize>,
+    skip_to_parent: HashMap<SkipId, Id>,
+
+    sorts: HashMap<SortId, (TagId, Order)>,
+    sort_to_parent: HashMap<SortId, Id>,
+
+    /// Values produced by `top`, copied into `in` filters
+    sets: HashMap<SetId, Vec<String>>,
+
+    /// Buckets produced by `group` and `filter_groups`
+    groups: HashMap<GroupId, GroupCache>,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine {
+            debug: false,
+            last_id: 0,
+            lua: Engine::with_prelude(rlua::Lua::new()).expect("the Lua prelude loads"),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            sparse_tags: false,
+            anchor_tag_regexes: false,
+            results_only: false,
+            separator: Some(String::new()),
+            color: false,
+            output_format: OutputFormat::Lines,
+            stats_format: StatsFormat::Human,
+            output_limit: None,
+            max_line_bytes: None,
+            normalize_crlf: false,
+            regex_size_limit: REGEX_SIZE_LIMIT,
+            skip_lua_errors: false,
+            profile_lua: false,
+            history: vec![],
+
+            cache_budget: None,
+            cache_clock: 0,
+            cache_last_used: HashMap::new(),
+
+            files: HashMap::new(),
+            stdin: Arc::new(Mutex::new(Vec::new())),
+            file_caches: HashMap::new(),
+            max_open_files: None,
+            open_files: VecDeque::new(),
+
+            tags: HashMap::new(),
+            tag_caches: HashMap::new(),
+            tag_to_file: HashMap::new(),
+            file_to_tags: HashMap::new(),
+
+            filters: HashMap::new(),
+            filter_caches: HashMap::new(),
+            filter_to_parent: HashMap::new(),
+
+            distinct_caches: HashMap::new(),
+            distinct_to_parent: HashMap::new(),
+
+            ranges: HashMap::new(),
+            range_to_parent: HashMap::new(),
+
+            skips: HashMap::new(),
+            skip_to_parent: HashMap::new(),
+
+            sorts: HashMap::new(),
+            sort_to_parent: HashMap::new(),
+
+            sets: HashMap::new(),
+
+            groups: HashMap::new(),
+        }
+    }
+
+    pub fn new_debug() -> Self {
+        let mut engine = Self::new();
+        engine.debug = true;
+        engine
+    }
+
+    /// Replace the Lua state with one only loading the `base`, `coroutine`, `table`, `string`,
+    /// `utf8` and `math` libraries, without `dofile` and `loadfile`, so scripts can't reach the
+    /// filesystem or run commands. Chunks compiled in the previous state are lost, so this has
+    /// to be called before any script, transform or scripted filter is defined.
+    pub fn sandbox_lua(&mut self) -> Result<()> {
+        let lua = rlua::Lua::new_with(
+            rlua::StdLib::BASE
+                | rlua::StdLib::COROUTINE
+                | rlua::StdLib::TABLE
+                | rlua::StdLib::STRING
+                | rlua::StdLib::UTF8
+                | rlua::StdLib::MATH,
+        );
+        lua.context(|lua_ctx| {
+            let globals = lua_ctx.globals();
+            globals.set("dofile", rlua::Value::Nil)?;
+            globals.set("loadfile", rlua::Value::Nil)
+        })?;
+        self.lua = Engine::with_prelude(lua)?;
+        Ok(())
+    }
+
+    /// Load the `LUA_PRELUDE` helpers into `lua`
+    fn with_prelude(lua: rlua::Lua) -> Result<rlua::Lua> {
+        lua.context(|lua_ctx| lua_ctx.load(LUA_PRELUDE).set_name("prelude")?.exec())?;
+        Ok(lua)
+    }
+
+    /// Bound the number of file handles kept open at once, least recently read files are
+    /// closed first and transparently reopened when they are next read
+    pub fn set_max_open_files(&mut self, max_open_files: usize) {
+        self.max_open_files = Some(std::cmp::max(1, max_open_files));
+        self.close_unused_files();
+    }
+
+    /// Bound the total size of all caches, after each `take` the least recently used caches are
+    /// dropped until the total fits within the budget
+    pub fn set_cache_budget(&mut self, bytes: usize) {
+        self.cache_budget = Some(bytes);
+        self.evict_caches();
+    }
+
+    /// Blank a tag's value when it is identical to the value in the previously rendered row
+    pub fn set_sparse_tags(&mut self, sparse_tags: bool) {
+        self.sparse_tags = sparse_tags;
+    }
+
+    /// Only let `regex` tags match from the start of the line, so non-matching lines fail
+    /// after a single attempt instead of one at every offset. Changes what unanchored patterns
+    /// match, so it is opt-in.
+    pub fn set_anchor_tag_regexes(&mut self, anchor_tag_regexes: bool) {
+        self.anchor_tag_regexes = anchor_tag_regexes;
+    }
+
+    /// The pattern a `regex` tag should use for `regex`, wrapped to match at the start of the
+    /// line when tag regexes are anchored and it isn't anchored already. The wrapping group
+    /// doesn't capture, so capture group indexes are unchanged.
+    pub fn tag_regex(&self, regex: String) -> String {
+        if !self.anchor_tag_regexes || regex.starts_with('^') || regex.starts_with("\\A") {
+            regex
+        } else {
+            format!("^(?:{})", regex)
+        }
+    }
+
+    pub fn set_results_only(&mut self, results_only: bool) {
+        self.results_only = results_only;
+    }
+
+    pub fn results_only(&self) -> bool {
+        self.results_only
+    }
+
+    /// Whether stdout is kept for query results, because only they were asked for or because
+    /// they are printed in a format meant to be parsed
+    pub fn results_only_on_stdout(&self) -> bool {
+        self.results_only || self.output_format.is_machine_readable()
+    }
+
+    pub fn set_separator(&mut self, separator: Option<String>) {
+        self.separator = separator;
+    }
+
+    /// Highlight the spans captured by regex tags and the names of tags in lines output.
+    /// Columns, JSON and CSV output are never colored.
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    /// Cut the output of every take at `bytes`, ending it with a marker line instead. Machine
+    /// readable rows are never cut in half, the first one that doesn't fit is dropped.
+    pub fn set_output_limit(&mut self, bytes: usize) {
+        self.output_limit = Some(bytes);
+    }
+
+    /// Cut lines of the files loaded afterwards at `bytes`, marking them as truncated and
+    /// skipping the rest of them, so a malformed log can't make a single huge line
+    pub fn set_max_line_bytes(&mut self, bytes: usize) {
+        self.max_line_bytes = Some(bytes);
+    }
+
+    /// Read `\r\n` as `\n` in the files loaded afterwards, so regexes match the same way on CRLF
+    /// and LF logs. Offsets and hexdumps still see the bytes of the file.
+    pub fn set_normalize_crlf(&mut self, normalize_crlf: bool) {
+        self.normalize_crlf = normalize_crlf;
+    }
+
+    /// Reject regexes compiling to more than `bytes`, which also bounds the memory used to match
+    /// each of them, so a pathological pattern fails at once instead of stalling the session
+    pub fn set_regex_size_limit(&mut self, bytes: usize) {
+        self.regex_size_limit = bytes;
+    }
+
+    pub fn set_skip_lua_errors(&mut self, skip_lua_errors: bool) {
+        self.skip_lua_errors = skip_lua_errors;
+    }
+
+    pub fn set_profile_lua(&mut self, profile_lua: bool) {
+        self.profile_lua = profile_lua;
+    }
+
+    /// Stats for a query, tracking what debug mode and Lua profiling ask for
+    fn new_stats(&self) -> Stats {
+        let mut stats = if self.debug {
+            Stats::enabled()
+        } else {
+            Stats::disabled()
+        };
+        if self.profile_lua {
+            stats.profile_lua();
+        }
+        stats
+    }
+
+    pub fn set_output_format(&mut self, output_format: OutputFormat) {
+        self.output_format = output_format;
+    }
+
+    pub fn set_stats_format(&mut self, stats_format: StatsFormat) {
+        self.stats_format = stats_format;
+    }
+
+    pub fn stats_format(&self) -> StatsFormat {
+        self.stats_format
+    }
+
+    /// Flag checked between rows of a `take` or `follow` and between the batches of commands
+    /// reading a whole file, setting it stops the current one early
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupted)
+    }
+
+    /// Tags created on `file_id`, sorted by ID
+    pub fn tags_of(&self, file_id: FileId) -> &[TagId] {
+        self.file_to_tags
+            .get(&file_id)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    pub fn history(&self) -> &[(Command, Option<Id>)] {
+        &self.history
+    }
+
+    pub fn run_command(&mut self, command: &Command) -> Result<Output> {
+        let output = self.apply_command(command)?;
+        match command {
+            Command::DistinctValues(_, _)
+            | Command::Aggregate(_, _, _)
+            | Command::MatchRate(_, _, _, _)
+            | Command::Take(_, _)
+            | Command::TakeLast(_, _)
+            | Command::Hexdump(_, _)
+            | Command::Write(_, _, _, _)
+            | Command::Follow(_, _)
+            | Command::Explain(_)
+            | Command::Info => {}
+            _ => self.history.push((command.clone(), output.id)),
+        }
+        Ok(output)
+    }
+
+    fn apply_command(&mut self, command: &Command) -> Result<Output> {
+        match command {
+            Command::Load(path) => {
+                let mut file = if path.as_os_str() == "-" {
+                    File::stdin(self.stdin.clone(), self.max_line_bytes)?
+                } else {
+                    File::new(path.clone(), self.max_line_bytes)?
+                };
+                file.set_normalize_crlf(self.normalize_crlf);
+                let id = self.next_file_id();
+                self.files.insert(id, file);
+                self.touch_file(id);
+                Ok(Output::with_message(
+                    Some(Id::File(id)),
+                    format!("file loaded: {:?} {:?}", id, path),
+                ))
+            }
+            Command::LoadMany(paths) => {
+                let id = self.next_file_id();
+                let mut file = File::concat(paths, self.max_line_bytes)?;
+                file.set_normalize_crlf(self.normalize_crlf);
+                self.files.insert(id, file);
+                self.touch_file(id);
+                Ok(Output::with_message(
+                    Some(Id::File(id)),
+                    format!("files loaded: {:?} {:?}", id, paths),
+                ))
+            }
+            Command::Materialize(id) => {
+                let lines = self.materialize(*id)?;
+                let file_id = self.next_file_id();
+                self.files.insert(file_id, File::from_lines(&lines)?);
+                self.touch_file(file_id);
+                Ok(Output::with_message(
+                    Some(Id::File(file_id)),
+                    format!("file materialized: {:?} ({} lines)", file_id, lines.len()),
+                ))
+            }
+            Command::Script(script) => {
+                self.run_script(script)?;
+                Ok(Output::with_message(None, "script loaded".to_string()))
+            }
+
+            Command::RecordStart(file_id, regex) => {
+                let record_start = self.compile_regex(regex)?;
+                self.files
+                    .get_mut(file_id)
+                    .ok_or_else(|| Error::MissingId(Id::File(*file_id)))?
+                    .set_record_start(Some(record_start));
+                self.evict_cache(Id::File(*file_id));
+                Ok(Output::with_message(
+                    Some(Id::File(*file_id)),
+                    format!("record start added to: {}", file_id.0),
+                ))
+            }
+
+            Command::Tag(file_id, tag_name) => {
+                let tag_id = self.next_tag_id();
+                self.tags.insert(tag_id, Tag::new(tag_name));
+                self.tag_to_file.insert(tag_id, *file_id);
+                // IDs only increase, so pushing keeps every file's tags sorted
+                self.file_to_tags.entry(*file_id).or_default().push(tag_id);
+                Ok(Output::with_message(
+                    Some(Id::Tag(tag_id)),
+                    format!("tag loaded: {} {}", tag_id.0, tag_name),
+                ))
+            }
+            Command::Regex(tag_id, regex, group) => {
+                let regex = self.compile_tag_regex(*tag_id, regex)?;
+                let tag = self
+                    .tags
+                    .get_mut(tag_id)
+                    .ok_or_else(|| Error::MissingId(Id::Tag(*tag_id)))?;
+                tag.with_regex(regex, group.clone())?;
+                self.invalidate_tag(*tag_id);
+                Ok(Output::with_message(
+                    Some(Id::Tag(*tag_id)),
+                    format!("regex added to: {}", tag_id.0),
+                ))
+            }
+            Command::RegexAll(tag_id, regex, group) => {
+                let regex = self.compile_tag_regex(*tag_id, regex)?;
+                let tag = self
+                    .tags
+                    .get_mut(tag_id)
+                    .ok_or_else(|| Error::MissingId(Id::Tag(*tag_id)))?;
+                tag.with_regex_all(regex, group.clone())?;
+                self.invalidate_tag(*tag_id);
+                Ok(Output::with_message(
+                    Some(Id::Tag(*tag_id)),
+                    format!("repeated regex added to: {}", tag_id.0),
+                ))
+            }
+            Command::SplitRegex(tag_id, delimiter, index) => {
+                let delimiter = self.compile_tag_regex(*tag_id, delimiter)?;
+                let tag = self
+                    .tags
+                    .get_mut(tag_id)
+                    .ok_or_else(|| Error::MissingId(Id::Tag(*tag_id)))?;
+                tag.with_split(delimiter, *index);
+                self.invalidate_tag(*tag_id);
+                Ok(Output::with_message(
+                    Some(Id::Tag(*tag_id)),
+                    format!("split added to: {}", tag_id.0),
+                ))
+            }
+            Command::Tokenize(tag_id) => {
+                let tag = self
+                    .tags
+                    .get_mut(tag_id)
+                    .ok_or_else(|| Error::MissingId(Id::Tag(*tag_id)))?;
+                tag.with_tokens();
+                self.invalidate_tag(*tag_id);
+                Ok(Output::with_message(
+                    Some(Id::Tag(*tag_id)),
+                    format!("tokenize added to: {}", tag_id.0),
+                ))
+            }
+            Command::JsonPath(tag_id, path) => {
+                let tag = self
+                    .tags
+                    .get_mut(tag_id)
+                    .ok_or_else(|| Error::MissingId(Id::Tag(*tag_id)))?;
+                tag.with_json_path(path)?;
+                self.invalidate_tag(*tag_id);
+                Ok(Output::with_message(
+                    Some(Id::Tag(*tag_id)),
+                    format!("json path added to: {}", tag_id.0),
+                ))
+            }
+            Command::Columns(tag_id, start, end) => {
+                let tag = self
+                    .tags
+                    .get_mut(tag_id)
+                    .ok_or_else(|| Error::MissingId(Id::Tag(*tag_id)))?;
+                tag.with_columns(*start, *end)?;
+                self.invalidate_tag(*tag_id);
+                Ok(Output::with_message(
+                    Some(Id::Tag(*tag_id)),
+                    format!("columns added to: {}", tag_id.0),
+                ))
+            }
+            Command::Transform(tag_id, transform) => {
+                let compiled = CompiledChunk::new(&self.lua, transform)?;
+                let tag = self
+                    .tags
+                    .get_mut(tag_id)
+                    .ok_or_else(|| Error::MissingId(Id::Tag(*tag_id)))?;
+                tag.with_transform(compiled);
+                self.invalidate_tag(*tag_id);
+                Ok(Output::with_message(
+                    Some(Id::Tag(*tag_id)),
+                    format!("transform added to: {}", tag_id.0),
+                ))
+            }
+            Command::Retag(tag_id, regex, transform) => {
+                let name = self
+                    .tags
+                    .get(tag_id)
+                    .map(|tag| tag.name.clone())
+                    .ok_or_else(|| Error::MissingId(Id::Tag(*tag_id)))?;
+
+                let mut tag = Tag::new(name);
+                let regex = self.compile_tag_regex(*tag_id, regex)?;
+                tag.with_regex(regex, CaptureGroup::default())?;
+                if let Some(transform) = transform {
+                    tag.with_transform(CompiledChunk::new(&self.lua, transform)?);
+                }
+
+                self.tags.insert(*tag_id, tag);
+                self.invalidate_tag(*tag_id);
+                Ok(Output::with_message(
+                    Some(Id::Tag(*tag_id)),
+                    format!("tag redefined: {}", tag_id.0),
+                ))
+            }
+
+            Command::DirectFilter(id, comparator, mode, value) => {
+                Self::check_row_source(*id)?;
+                let filter = Filter::Direct(*comparator, *mode, value.clone());
+                Ok(self.add_filter(*id, filter))
+            }
+            Command::CustomFilter(id, func, value) => {
+                Self::check_row_source(*id)?;
+                // Reported now rather than on the first take, the function is still looked up
+                // again on every use so it can be redefined
+                let defined = self.lua.context(|lua_ctx| {
+                    lua_ctx
+                        .globals()
+                        .get::<_, rlua::Value>(func.as_str())
+                        .map(|value| matches!(value, rlua::Value::Function(_)))
+                })?;
+                if !defined {
+                    return Err(Error::InvalidArgument(format!(
+                        "no Lua function named {}",
+                        func
+                    )));
+                }
+                let filter = Filter::Custom(func.clone(), value.clone());
+                Ok(self.add_filter(*id, filter))
+            }
+            Command::ScriptedFilter(id, test) => {
+                Self::check_row_source(*id)?;
+                let filter = Filter::Scripted(CompiledChunk::new(&self.lua, test)?);
+                Ok(self.add_filter(*id, filter))
+            }
+            Command::RegexFilter(id, regex) => {
+                Self::check_row_source(*id)?;
+                let filter = Filter::Regex(self.compile_regex(regex)?);
+                Ok(self.add_filter(*id, filter))
+            }
+            Command::InFilter(id, set_id) => {
+                Self::check_row_source(*id)?;
+                let values = self
+                    .sets
+                    .get(set_id)
+                    .ok_or_else(|| Error::MissingId(Id::Set(*set_id)))?;
+                let filter = Filter::In(values.iter().cloned().collect());
+                Ok(self.add_filter(*id, filter))
+            }
+            Command::LagFilter(id, lag, factor, comparator, mode) => {
+                Self::check_row_source(*id)?;
+                if *lag == 0 {
+                    return Err(Error::InvalidArgument("lag must be at least 1".to_string()));
+                }
+                let filter = Filter::Lag(*lag, *factor, *comparator, *mode);
+                Ok(self.add_filter(*id, filter))
+            }
+            Command::Or(left, right) => self.combine_filters(*left, *right, Filter::Or),
+            Command::And(left, right) => self.combine_filters(*left, *right, Filter::And),
+
+            Command::Distinct(id) => {
+                Self::check_row_source(*id)?;
+                let distinct_id = self.next_distinct_id();
+                self.distinct_to_parent.insert(distinct_id, *id);
+                Ok(Output::with_message(
+                    Some(Id::Distinct(distinct_id)),
+                    format!("distinct loaded: {}", distinct_id.0),
+                ))
+            }
+            Command::DistinctValues(id, with_counts) => {
+                self.list_distinct_values(*id, *with_counts)
+            }
+
+            Command::Group(tag_id, aggregator, value_tag_id, order) => {
+                self.group(*tag_id, *aggregator, *value_tag_id, *order)
+            }
+            Command::FilterGroups(group_id, comp, mode, value) => {
+                self.filter_groups(*group_id, *comp, *mode, value)
+            }
+            Command::Aggregate(id, aggregator, value_tag_id) => {
+                self.aggregate(*id, *aggregator, *value_tag_id)
+            }
+            Command::Top(tag_id, count) => self.top(*tag_id, *count),
+
+            Command::MatchRate(left, right, comp, mode) => {
+                self.match_rate(*left, *right, *comp, *mode)
+            }
+
+            Command::Range(id, start, end) => {
+                Self::check_row_source(*id)?;
+                if start > end {
+                    return Err(Error::InvalidArgument(format!("range: {}, {}", start, end)));
+                }
+                let range_id = self.next_range_id();
+                self.ranges.insert(range_id, Interval(*start, *end));
+                self.range_to_parent.insert(range_id, *id);
+                Ok(Output::with_message(
+                    Some(Id::Range(range_id)),
+                    format!("range loaded: {}", range_id.0),
+                ))
+            }
+            Command::Skip(id, count) => {
+                Self::check_row_source(*id)?;
+                let skip_id = self.next_skip_id();
+                self.skips.insert(skip_id, *count);
+                self.skip_to_parent.insert(skip_id, *id);
+                Ok(Output::with_message(
+                    Some(Id::Skip(skip_id)),
+                    format!("skip loaded: {}", skip_id.0),
+                ))
+            }
+            Command::Sort(id, tag_id, order) => {
+                Self::check_row_source(*id)?;
+                let plan = self.plan(*id)?;
+                // The skip of a plan applies to the sorted rows, a skip placed before the sort
+                // would silently be applied after it
+                if plan.skip > 0 {
+                    return Err(Error::InvalidArgument(format!(
+                        "sort after skip: {:?}, sort the rows before skipping",
+                        id
+                    )));
+                }
+                let file_id = plan.file_id();
+                match self.tag_to_file.get(tag_id) {
+                    Some(tag_file_id) if *tag_file_id == file_id => {}
+                    Some(_) => return Err(Error::InvalidTarget(format!("{:?}", tag_id))),
+                    None => return Err(Error::MissingId(Id::Tag(*tag_id))),
+                }
+                let sort_id = self.next_sort_id();
+                self.sorts.insert(sort_id, (*tag_id, *order));
+                self.sort_to_parent.insert(sort_id, *id);
+                Ok(Output::with_message(
+                    Some(Id::Sort(sort_id)),
+                    format!("sort loaded: {}", sort_id.0),
+                ))
+            }
+            Command::Take(id, count) => self.take(*id, *count),
+            Command::TakeLast(id, count) => self.take_last(*id, *count),
+            Command::Write(id, path, count, with_tags) => self.write(*id, path, *count, *with_tags),
+            Command::Follow(id, count) => {
+                let mut rows = self.follow_iter(*id, *count)?;
+                let lines = rows.by_ref().collect::<Result<Vec<String>>>();
+                let stats = rows.finish();
+                Ok(Output::with_results(lines?, stats))
+            }
+            Command::Explain(id) => self.explain(*id),
+            Command::SaveSelection(id) => self.save_selection(*id),
+            Command::Info => self.info(),
+            Command::Hexdump(file_id, index) => {
+                let file = self
+                    .files
+                    .get(file_id)
+                    .ok_or_else(|| Error::MissingId(Id::File(*file_id)))?;
+                let bytes = file.read_raw(*index)?.ok_or_else(|| {
+                    Error::InvalidArgument(format!("line {} of {:?}", index, file_id))
+                })?;
+                Ok(Output::with_results(hexdump(&bytes), Stats::disabled()))
+            }
+
+            Command::Unload(id) => {
+                let dropped = self.unload(*id)?;
+                Ok(Output::with_message(
+                    None,
+                    format!(
+                        "unloaded: {}",
+                        dropped
+                            .iter()
+                            .map(|id| format!("{:?}", id))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    ),
+                ))
+            }
+        }
+    }
+
+    /// Define `filter` under `parent`, unless an equivalent filter already exists there in which
+    /// case its ID, and so its cache, is returned instead
+    fn add_filter(&mut self, parent: Id, filter: Filter) -> Output {
+        let existing = self
+            .filter_to_parent
+            .iter()
+            .find(|(filter_id, other_parent)| {
+                **other_parent == parent && self.filters[filter_id].is_equivalent(&filter)
+            });
+        if let Some((&filter_id, _)) = existing {
+            return Output::with_message(
+                Some(Id::Filter(filter_id)),
+                format!("filter reused: {}", filter_id.0),
+            );
+        }
+
+        let filter_id = self.next_filter_id();
+        self.filters.insert(filter_id, filter);
+        self.filter_to_parent.insert(filter_id, parent);
+
+        Output::with_message(
+            Some(Id::Filter(filter_id)),
+            format!("filter loaded: {}", filter_id.0),
+        )
+    }
+
+    /// Compile a user supplied pattern within the engine's regex size limit
+    fn compile_regex(&self, pattern: &str) -> Result<regex::Regex> {
+        regex::RegexBuilder::new(pattern)
+            .size_limit(self.regex_size_limit)
+            .dfa_size_limit(self.regex_size_limit)
+            .build()
+            .map_err(|err| match err {
+                regex::Error::CompiledTooBig(limit) => {
+                    let mut preview = pattern.chars().take(40).collect::<String>();
+                    if preview.len() < pattern.len() {
+                        preview.push_str(TRUNCATED_LINE_MARKER);
+                    }
+                    Error::InvalidArgument(format!(
+                        "regex {:?} compiles to more than the {} byte limit",
+                        preview, limit
+                    ))
+                }
+                err => Error::Regex(err),
+            })
+    }
+
+    /// Compile a regex of the tag `tag_id`, naming the tag and the pattern when it doesn't
+    /// compile
+    fn compile_tag_regex(&self, tag_id: TagId, pattern: &str) -> Result<regex::Regex> {
+        self.compile_regex(pattern).map_err(|err| match err {
+            Error::Regex(err) => {
+                let name = match self.tags.get(&tag_id) {
+                    Some(tag) => tag.name.clone(),
+                    None => Id::Tag(tag_id).to_string(),
+                };
+                Error::TagRegex(name, pattern.to_string(), err)
+            }
+            err => err,
+        })
+    }
+
+    /// Add a filter combining two filters of the same file, its parent is that file
+    fn combine_filters(
+        &mut self,
+        left: Id,
+        right: Id,
+        combine: fn(FilterId, FilterId) -> Filter,
+    ) -> Result<Output> {
+        let filter_id = |id: Id| match id {
+            Id::Filter(filter_id) => Ok(filter_id),
+            _ => Err(Error::InvalidTarget(format!("{:?}", id))),
+        };
+        let (left_id, right_id) = (filter_id(left)?, filter_id(right)?);
+
+        let file_id = self.plan(left)?.file_id();
+        if self.plan(right)?.file_id() != file_id {
+            return Err(Error::InvalidArgument(format!(
+                "{:?} and {:?} filter different files",
+                left, right
+            )));
+        }
+        Ok(self.add_filter(Id::File(file_id), combine(left_id, right_id)))
+    }
+
     fn next_distinct_id(&mut self) -> DistinctId {
         self.last_id += 1;
         DistinctId(self.last_id)
     }
 
-    fn next_file_id(&mut self) -> FileId {
-        self.last_id += 1;
-        FileId(self.last_id)
-    }
+    fn next_file_id(&mut self) -> FileId {
+        self.last_id += 1;
+        FileId(self.last_id)
+    }
+
+    fn next_filter_id(&mut self) -> FilterId {
+        self.last_id += 1;
+        FilterId(self.last_id)
+    }
+
+    fn next_group_id(&mut self) -> GroupId {
+        self.last_id += 1;
+        GroupId(self.last_id)
+    }
+
+    fn next_range_id(&mut self) -> RangeId {
+        self.last_id += 1;
+        RangeId(self.last_id)
+    }
+
+    fn next_set_id(&mut self) -> SetId {
+        self.last_id += 1;
+        SetId(self.last_id)
+    }
+
+    fn next_skip_id(&mut self) -> SkipId {
+        self.last_id += 1;
+        SkipId(self.last_id)
+    }
+
+    fn next_sort_id(&mut self) -> SortId {
+        self.last_id += 1;
+        SortId(self.last_id)
+    }
+
+    fn next_tag_id(&mut self) -> TagId {
+        self.last_id += 1;
+        TagId(self.last_id)
+    }
+
+    fn touch_file(&mut self, file_id: FileId) {
+        self.open_files.retain(|id| *id != file_id);
+        self.open_files.push_back(file_id);
+        self.close_unused_files();
+    }
+
+    fn close_unused_files(&mut self) {
+        if let Some(max_open_files) = self.max_open_files {
+            while self.open_files.len() > max_open_files {
+                let file_id = self.open_files.pop_front().unwrap();
+                if let Some(file) = self.files.get_mut(&file_id) {
+                    file.close();
+                }
+            }
+        }
+    }
+
+    fn touch_cache(&mut self, id: Id) {
+        self.cache_clock += 1;
+        self.cache_last_used.insert(id, self.cache_clock);
+    }
+
+    fn cache_size(&self, id: Id) -> usize {
+        match id {
+            Id::Distinct(did) => self.distinct_caches.get(&did).map(|c| c.size()),
+            Id::File(fid) => self.file_caches.get(&fid).map(|c| c.size()),
+            Id::Filter(fid) => self.filter_caches.get(&fid).map(|c| c.size()),
+            Id::Group(_) | Id::Range(_) | Id::Set(_) | Id::Skip(_) | Id::Sort(_) => None,
+            Id::Tag(tid) => self.tag_caches.get(&tid).map(|c| c.size()),
+        }
+        .unwrap_or(0)
+    }
+
+    fn total_cache_size(&self) -> usize {
+        self.cache_last_used
+            .keys()
+            .map(|id| self.cache_size(*id))
+            .sum()
+    }
+
+    fn evict_caches(&mut self) {
+        let budget = match self.cache_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+
+        let mut by_last_used: Vec<(usize, Id)> = self
+            .cache_last_used
+            .iter()
+            .map(|(id, last_used)| (*last_used, *id))
+            .collect();
+        by_last_used.sort();
+
+        for (_, id) in by_last_used {
+            if self.total_cache_size() <= budget {
+                break;
+            }
+            self.evict_cache(id);
+        }
+    }
+
+    /// Drop a single cache, along with the caches derived from it that would now be stale
+    fn evict_cache(&mut self, id: Id) {
+        match id {
+            Id::Distinct(did) => {
+                self.distinct_caches.remove(&did);
+            }
+            Id::File(fid) => {
+                self.file_caches.remove(&fid);
+                for tag_id in self.tags_of(fid).to_vec() {
+                    self.invalidate_tag(tag_id);
+                }
+            }
+            Id::Filter(fid) => {
+                self.filter_caches.remove(&fid);
+            }
+            Id::Group(_) | Id::Range(_) | Id::Set(_) | Id::Skip(_) | Id::Sort(_) => {}
+            Id::Tag(tid) => {
+                self.tag_caches.remove(&tid);
+            }
+        }
+        self.cache_last_used.remove(&id);
+    }
+
+    /// Whether `id` is currently defined, it may have been unloaded
+    pub fn contains(&self, id: Id) -> bool {
+        match id {
+            Id::Distinct(did) => self.distinct_to_parent.contains_key(&did),
+            Id::File(fid) => self.files.contains_key(&fid),
+            Id::Filter(fid) => self.filters.contains_key(&fid),
+            Id::Group(gid) => self.groups.contains_key(&gid),
+            Id::Range(rid) => self.ranges.contains_key(&rid),
+            Id::Set(sid) => self.sets.contains_key(&sid),
+            Id::Skip(sid) => self.skips.contains_key(&sid),
+            Id::Sort(sid) => self.sorts.contains_key(&sid),
+            Id::Tag(tid) => self.tags.contains_key(&tid),
+        }
+    }
+
+    /// A short description of what `id` was defined as with its parent named from `names`,
+    /// `None` once it has been unloaded
+    pub fn describe(&self, id: Id, names: &Names) -> Option<String> {
+        match id {
+            Id::Distinct(did) => self
+                .distinct_to_parent
+                .get(&did)
+                .map(|parent| format!("of {}", id_key(names, *parent))),
+            Id::File(fid) => self.files.get(&fid).map(|file| match &file.record_start {
+                Some(record_start) => format!(
+                    "{} records starting {:?}",
+                    file.source.name(),
+                    record_start.as_str()
+                ),
+                None => file.source.name(),
+            }),
+            Id::Filter(fid) => self.filters.get(&fid).map(|filter| {
+                let kind = match filter {
+                    Filter::Direct(comp, mode, value) => {
+                        format!("{:?} {:?} {:?}", comp, mode, value)
+                    }
+                    Filter::Custom(func, value) => format!("custom {} {:?}", func, value),
+                    Filter::Scripted(test) => format!("scripted {:?}", test.source),
+                    Filter::Regex(regex) => format!("matches {:?}", regex.as_str()),
+                    Filter::In(values) => format!("in {} value(s)", values.len()),
+                    Filter::Or(left, right) => {
+                        format!("{} or {}", id_key(names, *left), id_key(names, *right))
+                    }
+                    Filter::And(left, right) => {
+                        format!("{} and {}", id_key(names, *left), id_key(names, *right))
+                    }
+                    Filter::Selection(lines) => format!("{} saved line(s)", lines.len()),
+                    Filter::Lag(lag, factor, comp, mode) => {
+                        format!(
+                            "{:?} {:?} {}x the value {} line(s) earlier",
+                            comp, mode, factor, lag
+                        )
+                    }
+                };
+                format!("{} of {}", kind, id_key(names, self.filter_to_parent[&fid]))
+            }),
+            Id::Group(gid) => self.groups.get(&gid).map(|group| {
+                format!(
+                    "{} {:?} bucket(s) of {}",
+                    group.buckets.len(),
+                    group.aggregator,
+                    id_key(names, group.tag_id)
+                )
+            }),
+            Id::Range(rid) => self
+                .ranges
+                .get(&rid)
+                .map(|range| format!("{} of {}", range, id_key(names, self.range_to_parent[&rid]))),
+            Id::Set(sid) => self
+                .sets
+                .get(&sid)
+                .map(|values| format!("{} value(s)", values.len())),
+            Id::Skip(sid) => self
+                .skips
+                .get(&sid)
+                .map(|count| format!("{} of {}", count, id_key(names, self.skip_to_parent[&sid]))),
+            Id::Sort(sid) => self.sorts.get(&sid).map(|(tag_id, order)| {
+                format!(
+                    "by {} {:?} of {}",
+                    id_key(names, *tag_id),
+                    order,
+                    id_key(names, self.sort_to_parent[&sid])
+                )
+            }),
+            Id::Tag(tid) => self.tags.get(&tid).map(|tag| {
+                let extractor = match tag.extractor {
+                    Extractor::Line => "line".to_string(),
+                    Extractor::Regex(ref regex, _) => format!("regex {:?}", regex.as_str()),
+                    Extractor::RegexAll(ref regex, _) => {
+                        format!("repeated regex {:?}", regex.as_str())
+                    }
+                    Extractor::Split(ref regex, index) => {
+                        format!("split {:?} {}", regex.as_str(), index)
+                    }
+                    Extractor::Tokens => "token count".to_string(),
+                    Extractor::Json(ref path) => format!("json {:?}", path.join(".")),
+                    Extractor::Columns(start, end) => format!("columns {}..{}", start, end),
+                };
+                let transform = match tag.transforms.len() {
+                    0 => "".to_string(),
+                    1 => ", transformed".to_string(),
+                    count => format!(", transformed {} times", count),
+                };
+                format!(
+                    "{}{} of {}",
+                    extractor,
+                    transform,
+                    id_key(names, self.tag_to_file[&tid])
+                )
+            }),
+        }
+    }
+
+    /// Remove `id` along with every tag, filter, distinct, range, skip and sort built on top of it,
+    /// returning the removed IDs. Sets and groups hold a copy of their values so they are left
+    /// alone unless they are the one being unloaded.
+    fn unload(&mut self, id: Id) -> Result<Vec<Id>> {
+        if !self.contains(id) {
+            return Err(Error::MissingId(id));
+        }
+
+        let mut dropped = match id {
+            Id::Group(_) | Id::Set(_) => vec![id],
+            _ => self.dependents(id),
+        };
+        // An `or` or `and` hangs off the file rather than its operands, so it is found by its
+        // operands instead, along with everything built on it
+        loop {
+            let combined = self
+                .filters
+                .iter()
+                .filter(|(filter_id, filter)| {
+                    !dropped.contains(&Id::Filter(**filter_id))
+                        && filter.operands().is_some_and(|(left, right)| {
+                            dropped.contains(&Id::Filter(left))
+                                || dropped.contains(&Id::Filter(right))
+                        })
+                })
+                .map(|(filter_id, _)| Id::Filter(*filter_id))
+                .collect::<Vec<Id>>();
+            if combined.is_empty() {
+                break;
+            }
+            for combined_id in combined {
+                for dependent in self.dependents(combined_id) {
+                    if !dropped.contains(&dependent) {
+                        dropped.push(dependent);
+                    }
+                }
+            }
+        }
+        dropped.sort();
+
+        // Everything derived from a dropped ID is dropped with it, so caches can be removed
+        // directly rather than invalidated
+        for dropped_id in &dropped {
+            match *dropped_id {
+                Id::Distinct(did) => {
+                    self.distinct_caches.remove(&did);
+                    self.distinct_to_parent.remove(&did);
+                }
+                Id::File(fid) => {
+                    self.files.remove(&fid);
+                    self.file_caches.remove(&fid);
+                    self.file_to_tags.remove(&fid);
+                    self.open_files.retain(|open_id| *open_id != fid);
+                }
+                Id::Filter(fid) => {
+                    self.filters.remove(&fid);
+                    self.filter_caches.remove(&fid);
+                    self.filter_to_parent.remove(&fid);
+                }
+                Id::Group(gid) => {
+                    self.groups.remove(&gid);
+                }
+                Id::Range(rid) => {
+                    self.ranges.remove(&rid);
+                    self.range_to_parent.remove(&rid);
+                }
+                Id::Set(sid) => {
+                    self.sets.remove(&sid);
+                }
+                Id::Skip(sid) => {
+                    self.skips.remove(&sid);
+                    self.skip_to_parent.remove(&sid);
+                }
+                Id::Sort(sid) => {
+                    self.sorts.remove(&sid);
+                    self.sort_to_parent.remove(&sid);
+                }
+                Id::Tag(tid) => {
+                    self.tags.remove(&tid);
+                    self.tag_caches.remove(&tid);
+                    if let Some(fid) = self.tag_to_file.remove(&tid) {
+                        if let Some(tags) = self.file_to_tags.get_mut(&fid) {
+                            tags.retain(|tag_id| *tag_id != tid);
+                        }
+                    }
+                }
+            }
+            self.cache_last_used.remove(dropped_id);
+        }
+
+        Ok(dropped)
+    }
+
+    /// Every ID whose plan goes through `id`, `id` included
+    fn dependents(&self, id: Id) -> Vec<Id> {
+        self.files
+            .keys()
+            .map(|fid| Id::File(*fid))
+            .chain(self.tags.keys().map(|tid| Id::Tag(*tid)))
+            .chain(self.filters.keys().map(|fid| Id::Filter(*fid)))
+            .chain(self.distinct_to_parent.keys().map(|did| Id::Distinct(*did)))
+            .chain(self.ranges.keys().map(|rid| Id::Range(*rid)))
+            .chain(self.skips.keys().map(|sid| Id::Skip(*sid)))
+            .chain(self.sorts.keys().map(|sid| Id::Sort(*sid)))
+            .filter(|other| {
+                self.plan_steps(*other)
+                    .map(|steps| steps.contains(&id))
+                    .unwrap_or(false)
+            })
+            .collect()
+    }
+
+    /// Drop the cached values of a tag along with every filter and distinct derived from it
+    fn invalidate_tag(&mut self, tag_id: TagId) {
+        self.tag_caches.remove(&tag_id);
+
+        let filter_ids: Vec<FilterId> = self
+            .filter_to_parent
+            .keys()
+            .filter(|fid| self.filter_tests_tag(**fid, tag_id))
+            .cloned()
+            .collect();
+        for filter_id in filter_ids {
+            self.filter_caches.remove(&filter_id);
+        }
+
+        let distinct_ids: Vec<DistinctId> = self
+            .distinct_to_parent
+            .keys()
+            .filter(|did| self.find_parent_tag(Id::Distinct(**did)) == Some(tag_id))
+            .cloned()
+            .collect();
+        for distinct_id in distinct_ids {
+            self.distinct_caches.remove(&distinct_id);
+        }
+    }
+
+    fn plan(&self, id: Id) -> Result<Plan> {
+        let steps = self.plan_steps(id)?;
+        let skip = steps
+            .iter()
+            .map(|step| match step {
+                Id::Skip(skip_id) => self.skips[skip_id],
+                _ => 0,
+            })
+            .sum();
+        let range = steps
+            .iter()
+            .filter_map(|step| match step {
+                Id::Range(range_id) => Some(self.ranges[range_id]),
+                _ => None,
+            })
+            .fold(Interval(0, usize::MAX), |range, other| {
+                range.intersect(other)
+            });
+        let sort = steps.iter().rev().find_map(|step| match step {
+            Id::Sort(sort_id) => Some(self.sorts[sort_id]),
+            _ => None,
+        });
+        Ok(Plan::new(steps, skip, range, sort))
+    }
+
+    /// The chain of IDs from a file down to `id`, an ID whose parent is missing or that is
+    /// reached twice while walking up the chain is an error rather than a panic
+    fn plan_steps(&self, id: Id) -> Result<Vec<Id>> {
+        let mut steps = vec![id];
+        let mut visited = HashSet::new();
+        visited.insert(id);
+
+        let mut current = id;
+        loop {
+            let missing = || Error::MissingId(current);
+            let parent = match current {
+                Id::File(file_id) if self.files.contains_key(&file_id) => break,
+                Id::File(_) => return Err(missing()),
+                Id::Distinct(distinct_id) => *self
+                    .distinct_to_parent
+                    .get(&distinct_id)
+                    .ok_or_else(missing)?,
+                Id::Filter(filter_id) => {
+                    *self.filter_to_parent.get(&filter_id).ok_or_else(missing)?
+                }
+                Id::Range(range_id) => *self.range_to_parent.get(&range_id).ok_or_else(missing)?,
+                Id::Group(_) | Id::Set(_) => {
+                    return Err(Error::InvalidTarget(format!("{:?}", current)))
+                }
+                Id::Skip(skip_id) => *self.skip_to_parent.get(&skip_id).ok_or_else(missing)?,
+                Id::Sort(sort_id) => *self.sort_to_parent.get(&sort_id).ok_or_else(missing)?,
+                Id::Tag(tag_id) => Id::File(*self.tag_to_file.get(&tag_id).ok_or_else(missing)?),
+            };
+
+            if !visited.insert(parent) {
+                return Err(Error::PlanCycle(parent));
+            }
+            steps.push(parent);
+            current = parent;
+        }
+
+        steps.reverse();
+        Ok(steps)
+    }
+
+    /// Lazily run `id`'s plan, reading batches as rows are pulled from the returned iterator
+    pub fn take_iter(&mut self, id: Id, count: usize) -> Result<TakeIter<'_>> {
+        Self::check_row_source(id)?;
+        let plan = self.plan(id)?;
+        Ok(TakeIter::new(self, plan, count, false))
+    }
+
+    /// Like `take_iter`, but once the end of the file is reached keep polling it for appended
+    /// lines until `count` rows have been produced or the take is interrupted.
+    ///
+    /// Every cache in the plan is only ever extended past its current bounds, so new lines are
+    /// tagged, filtered and deduplicated without rebuilding what was already loaded. Any change
+    /// that requires rebuilding a cache (e.g. a truncated or rotated file) is not detected.
+    pub fn follow_iter(&mut self, id: Id, count: usize) -> Result<TakeIter<'_>> {
+        Self::check_row_source(id)?;
+        let plan = self.plan(id)?;
+        if plan.sort.is_some() {
+            return Err(Error::InvalidTarget(format!("{:?}", id)));
+        }
+        Ok(TakeIter::new(self, plan, count, true))
+    }
+
+    /// The crate, Lua and regex versions and the enabled cargo features
+    fn info(&self) -> Result<Output> {
+        let lua_version = self
+            .lua
+            .context(|lua| lua.globals().get::<_, String>("_VERSION"))?;
+        let features = ENABLED_FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(feature, _)| *feature)
+            .collect::<Vec<&str>>();
+
+        let mut output =
+            Output::with_message(None, format!("log-tags {}", env!("CARGO_PKG_VERSION")));
+        output.lines.push(format!(
+            "  lua: {} (rlua {})",
+            lua_version,
+            env!("LOG_TAGS_RLUA_VERSION")
+        ));
+        output
+            .lines
+            .push(format!("  regex: {}", env!("LOG_TAGS_REGEX_VERSION")));
+        output.lines.push(format!(
+            "  features: {}",
+            if features.is_empty() {
+                "none".to_string()
+            } else {
+                features.join(", ")
+            }
+        ));
+        Ok(output)
+    }
+
+    /// Describe the plan of `id` without reading anything, the chain of steps followed by the
+    /// tag each filter and distinct tests and the lines the plan is limited to. A group ends
+    /// the plan of the tag it was computed from.
+    fn explain(&self, id: Id) -> Result<Output> {
+        if let Id::Group(group_id) = id {
+            return self.explain_group(group_id);
+        }
+        Self::check_row_source(id)?;
+        let plan = self.plan(id)?;
+        let mut output = Output::with_message(
+            None,
+            plan.steps
+                .iter()
+                .map(|step| step.to_string())
+                .collect::<Vec<String>>()
+                .join(" -> "),
+        );
+
+        for step in &plan.steps {
+            if let Id::Filter(_) | Id::Distinct(_) = step {
+                let filter = match step {
+                    Id::Filter(filter_id) => self.filters.get(filter_id),
+                    _ => None,
+                };
+                let tested = match (filter, self.find_parent_tag(*step)) {
+                    (Some(Filter::Or(left, right)), _) => {
+                        format!("keeps {} or {}", Id::Filter(*left), Id::Filter(*right))
+                    }
+                    (Some(Filter::And(left, right)), _) => {
+                        format!("keeps {} and {}", Id::Filter(*left), Id::Filter(*right))
+                    }
+                    (Some(Filter::Selection(lines)), _) => {
+                        format!("keeps {} saved line(s)", lines.len())
+                    }
+                    (_, Some(tag_id)) => format!("tests {}", Id::Tag(tag_id)),
+                    (_, None) => "tests whole lines".to_string(),
+                };
+                output.lines.push(format!("  {} {}", step, tested));
+            }
+        }
+        if plan.range != Interval(0, usize::MAX) {
+            output.lines.push(format!("  lines {}", plan.range));
+        }
+        if let Some((tag_id, order)) = plan.sort {
+            output
+                .lines
+                .push(format!("  sort by {} {:?}", Id::Tag(tag_id), order));
+        }
+        if plan.skip > 0 {
+            output.lines.push(format!("  skip {} row(s)", plan.skip));
+        }
+        Ok(output)
+    }
+
+    fn explain_group(&self, group_id: GroupId) -> Result<Output> {
+        let group = self
+            .groups
+            .get(&group_id)
+            .ok_or(Error::MissingId(Id::Group(group_id)))?;
+        let steps = self.plan_steps(Id::Tag(group.tag_id))?;
+        let mut output = Output::with_message(
+            None,
+            steps
+                .iter()
+                .chain(std::iter::once(&Id::Group(group_id)))
+                .map(|step| step.to_string())
+                .collect::<Vec<String>>()
+                .join(" -> "),
+        );
+        output.lines.push(format!(
+            "  {} {:?} bucket(s) of {}",
+            group.buckets.len(),
+            group.aggregator,
+            Id::Tag(group.tag_id)
+        ));
+        Ok(output)
+    }
+
+    /// Sets of values can be used to filter lines and groups hold buckets rather than lines,
+    /// neither has rows of its own
+    fn check_row_source(id: Id) -> Result<()> {
+        match id {
+            Id::Group(_) | Id::Set(_) => Err(Error::InvalidTarget(format!("{:?}", id))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run `id`'s plan to the end of its file and save the lines it selects as a filter of the
+    /// file, which later queries reuse without running the plan again
+    fn save_selection(&mut self, id: Id) -> Result<Output> {
+        let mut rows = self.take_iter(id, usize::MAX)?;
+        rows.selection = Some(bit_set::BitSet::new());
+        let file_id = rows.plan.file_id();
+        let read = rows.by_ref().try_for_each(|row| row.map(|_| ()));
+        let lines = rows.selection.take().unwrap_or_default();
+        rows.finish();
+        read?;
+        Ok(self.add_filter(Id::File(file_id), Filter::Selection(lines)))
+    }
+
+    /// Run `id`'s plan to the end of its file, returning every selected line as it was read
+    /// without color
+    fn materialize(&mut self, id: Id) -> Result<Vec<String>> {
+        let mut rows = self.take_iter(id, usize::MAX)?;
+        rows.raw = true;
+        let color = std::mem::replace(&mut rows.engine.color, false);
+        let lines = rows.by_ref().collect::<Result<Vec<String>>>();
+        rows.engine.color = color;
+        rows.finish();
+        lines
+    }
+
+    fn take(&mut self, id: Id, count: usize) -> Result<Output> {
+        let mut rows = self.take_iter(id, count)?;
+        let lines = rows.by_ref().collect::<Result<Vec<String>>>();
+        let stats = rows.finish();
+        Ok(Output::with_results(lines?, stats))
+    }
+
+    /// Like `take`, but the last `count` selected rows. Files that can seek are read backward
+    /// from the end of the plan's range in batches until the rows are found. Compressed and
+    /// concatenated files, and plans with a sort or a distinct, read the whole range forward.
+    fn take_last(&mut self, id: Id, count: usize) -> Result<Output> {
+        let mut rows = self.take_iter(id, count)?;
+        rows.last = true;
+        let lines = rows.by_ref().collect::<Result<Vec<String>>>();
+        let stats = rows.finish();
+        Ok(Output::with_results(lines?, stats))
+    }
+
+    /// Stream up to `count` selected rows to a new file at `path`, what was written before an
+    /// error is still flushed. Rows are never colored, escapes don't belong in files.
+    fn write(
+        &mut self,
+        id: Id,
+        path: &path::Path,
+        count: usize,
+        with_tags: bool,
+    ) -> Result<Output> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        let mut rows = self.take_iter(id, count)?;
+        rows.raw = !with_tags;
+        rows.limited = false;
+        let color = std::mem::replace(&mut rows.engine.color, false);
+
+        let written = rows.by_ref().try_for_each(|line| -> Result<()> {
+            let line = line?;
+            file.write_all(line.as_bytes())?;
+            if !line.ends_with('\n') {
+                file.write_all(b"\n")?;
+            }
+            Ok(())
+        });
+        let flushed = file.flush();
+        let count = rows.current_count;
+        rows.engine.color = color;
+        let stats = rows.finish();
+        written?;
+        flushed?;
+
+        Ok(Output {
+            id: None,
+            lines: vec![format!("wrote {} rows to {}", count, path.display())],
+            stats,
+            is_message: true,
+        })
+    }
+
+    fn is_selected(
+        &self,
+        filter_ids: &[FilterId],
+        distinct_ids: &[DistinctId],
+        idx: usize,
+    ) -> bool {
+        filter_ids
+            .iter()
+            .all(|filter_id| self.read_filter(*filter_id).contains(idx))
+            && distinct_ids
+                .iter()
+                .all(|distinct_id| self.read_distinct(*distinct_id).contains(idx))
+    }
+
+    /// Render a line and its tags, `previous` holds the tag values of the last rendered row and
+    /// is used to blank repeated values when sparse tags are enabled
+    fn render_row(
+        &self,
+        file_id: FileId,
+        idx: usize,
+        previous: &mut HashMap<String, TagValue>,
+        results: &mut VecDeque<String>,
+    ) {
+        let interval = Interval(idx, idx + 1);
+
+        // The separator, if any, is the only thing printed between rows
+        let line = self.read_lines(file_id, interval)[0].trim_end_matches(&['\n', '\r'][..]);
+        if self.color {
+            results.push_back(self.highlight_matches(file_id, line));
+        } else {
+            results.push_back(line.to_string());
+        }
+        for (name, value, more) in self.read_row_tags(file_id, idx) {
+            let repeated = previous.get(&name) == Some(value);
+            previous.insert(name.clone(), value.clone());
+
+            if self.sparse_tags && repeated {
+                results.push_back(format!(
+                    "    {}",
+                    self.paint(TAG_COLOR, &format!("[{}]", name))
+                ))
+            } else if let Some(value) = value {
+                let values = std::iter::once(value)
+                    .chain(more)
+                    .map(|value| format!("{:?}", value))
+                    .collect::<Vec<String>>();
+                results.push_back(format!(
+                    "    {} {}",
+                    self.paint(TAG_COLOR, &format!("{: <15}", format!("[{}]", name))),
+                    values.join(", ")
+                ))
+            } else {
+                let name = format!("[{: <15}]", name);
+                results.push_back(format!("    {} N/A", self.paint(TAG_COLOR, &name)))
+            }
+        }
+        if let Some(separator) = &self.separator {
+            results.push_back(separator.clone());
+        }
+    }
+
+    /// `text` wrapped in the ANSI escapes of `color` when color is enabled
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", color, text, RESET_COLOR)
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// `line` with the spans captured by the regex tags of its file highlighted, overlapping
+    /// spans are highlighted once
+    fn highlight_matches(&self, file_id: FileId, line: &str) -> String {
+        let mut spans = self
+            .tags_of(file_id)
+            .iter()
+            .flat_map(|tag_id| self.tags[tag_id].extractor.spans(line))
+            .collect::<Vec<(usize, usize)>>();
+        spans.sort();
+
+        let mut highlighted = String::with_capacity(line.len());
+        let mut end = 0;
+        for (start, stop) in spans {
+            let start = std::cmp::max(start, end);
+            if stop <= start {
+                continue;
+            }
+            highlighted.push_str(&line[end..start]);
+            highlighted.push_str(&self.paint(MATCH_COLOR, &line[start..stop]));
+            end = stop;
+        }
+        highlighted.push_str(&line[end..]);
+        highlighted
+    }
+
+    /// Cells of a row in column mode, one per tag followed by the line, missing values are empty
+    fn render_cells(
+        &self,
+        file_id: FileId,
+        idx: usize,
+        previous: &mut HashMap<String, TagValue>,
+    ) -> Vec<(String, String)> {
+        let interval = Interval(idx, idx + 1);
+
+        let mut cells = vec![];
+        for (name, value, more) in self.read_row_tags(file_id, idx) {
+            let repeated = previous.get(&name) == Some(value);
+            previous.insert(name.clone(), value.clone());
+
+            let cell = match value {
+                Some(value) if !(self.sparse_tags && repeated) => join_values(value, more),
+                _ => String::new(),
+            };
+            cells.push((name, cell));
+        }
+
+        let line = self.read_lines(file_id, interval)[0].trim_end_matches(&['\n', '\r'][..]);
+        cells.push(("line".to_string(), line.to_string()));
+        cells
+    }
+
+    fn render_json(&self, file_id: FileId, idx: usize) -> String {
+        let interval = Interval(idx, idx + 1);
+
+        let mut object = serde_json::Map::new();
+        for (name, value, more) in self.read_row_tags(file_id, idx) {
+            match value {
+                Some(value) if !more.is_empty() => {
+                    let values = std::iter::once(value).chain(more).collect::<Vec<&String>>();
+                    object.insert(name, serde_json::json!(values))
+                }
+                _ => object.insert(name, serde_json::json!(value)),
+            };
+        }
+        let line = self.read_lines(file_id, interval)[0].trim_end_matches(&['\n', '\r'][..]);
+        object.insert("_line".to_string(), serde_json::json!(line));
+
+        serde_json::Value::Object(object).to_string()
+    }
+
+    /// The header fields and the record of a row, missing values are empty fields
+    fn render_csv(&self, file_id: FileId, idx: usize) -> (Vec<String>, String) {
+        let interval = Interval(idx, idx + 1);
+        let escape = |field: &str| {
+            if field.contains(&[',', '"', '\n', '\r'][..]) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        };
+
+        let mut names = vec![];
+        let mut fields = vec![];
+        for (name, value, more) in self.read_row_tags(file_id, idx) {
+            let field = match value {
+                Some(value) => join_values(value, more),
+                None => String::new(),
+            };
+            fields.push(escape(&field));
+            names.push(escape(&name));
+        }
+        let line = self.read_lines(file_id, interval)[0].trim_end_matches(&['\n', '\r'][..]);
+        fields.push(escape(line));
+        names.push("_line".to_string());
+
+        (names, fields.join(","))
+    }
+
+    /// Count every value of a tag across the whole file and keep the `count` most frequent as a
+    /// set, ties are broken by value
+    fn top(&mut self, tag_id: TagId, count: usize) -> Result<Output> {
+        let mut stats = self.new_stats();
+        let interval = self.ensure_whole_tag(&mut stats, tag_id)?;
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (_, value) in self.iter_tag(tag_id, interval) {
+            *counts.entry(value).or_insert(0) += 1;
+        }
+        let mut counts: Vec<(&str, usize)> = counts.into_iter().collect();
+        counts.sort_by(|left, right| right.1.cmp(&left.1).then(left.0.cmp(right.0)));
+        counts.truncate(count);
+
+        let lines = counts
+            .iter()
+            .map(|(value, count)| format!("{: <15} {}", format!("{:?}", value), count))
+            .collect();
+        let values = counts.iter().map(|(value, _)| value.to_string()).collect();
+
+        let set_id = self.next_set_id();
+        self.sets.insert(set_id, values);
+        self.evict_caches();
+        stats.stop();
+
+        Ok(Output {
+            id: Some(Id::Set(set_id)),
+            lines,
+            stats,
+            is_message: false,
+        })
+    }
+
+    /// Sorted unique values of the tag tested by `id` over the rows its plan selects, at most
+    /// `MAX_DISTINCT_VALUES` of them followed by how many more there were
+    fn list_distinct_values(&mut self, id: Id, with_counts: bool) -> Result<Output> {
+        Self::check_row_source(id)?;
+        let source = match id {
+            Id::Distinct(distinct_id) => *self
+                .distinct_to_parent
+                .get(&distinct_id)
+                .ok_or(Error::MissingId(id))?,
+            _ => id,
+        };
+        self.plan_steps(source)?;
+        let tag_id = self
+            .find_parent_tag(source)
+            .ok_or_else(|| Error::InvalidTarget(format!("{:?}", id)))?;
+
+        let mut rows = self.take_iter(source, usize::MAX)?;
+        let selected = match rows.read_whole_selection() {
+            Ok(selected) => selected,
+            Err(err) => {
+                rows.finish();
+                return Err(err);
+            }
+        };
+
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for idx in selected {
+            for (_, value) in rows.engine.iter_tag(tag_id, Interval(idx, idx + 1)) {
+                *counts.entry(value).or_insert(0) += 1;
+            }
+        }
+
+        let mut lines: Vec<String> = counts
+            .iter()
+            .take(MAX_DISTINCT_VALUES)
+            .map(|(value, count)| {
+                if with_counts {
+                    format!("{: <15} {}", format!("{:?}", value), count)
+                } else {
+                    format!("{:?}", value)
+                }
+            })
+            .collect();
+        if counts.len() > MAX_DISTINCT_VALUES {
+            lines.push(format!("+{} more", counts.len() - MAX_DISTINCT_VALUES));
+        }
+        Ok(Output::with_results(lines, rows.finish()))
+    }
+
+    /// Aggregate the lines of the whole file by the value of a tag, one bucket per value. A bucket
+    /// without any numeric value aggregates to `N/A` and is ordered after the others.
+    fn group(
+        &mut self,
+        tag_id: TagId,
+        aggregator: Aggregator,
+        value_tag_id: Option<TagId>,
+        order: GroupOrder,
+    ) -> Result<Output> {
+        let mut stats = self.new_stats();
+        let interval = self.ensure_whole_tag(&mut stats, tag_id)?;
+        if let Some(value_tag_id) = value_tag_id {
+            self.check_same_file(Id::Tag(tag_id), value_tag_id)?;
+            self.ensure_whole_tag(&mut stats, value_tag_id)?;
+        }
+
+        let mut buckets: HashMap<&str, Accumulator> = HashMap::new();
+        for (idx, value) in self.iter_tag(tag_id, interval) {
+            let bucket = buckets.entry(value).or_default();
+            match value_tag_id {
+                Some(value_tag_id) => bucket.add_number(self.read_number(value_tag_id, idx)),
+                None => bucket.add_line(),
+            }
+        }
+
+        let mut buckets: Vec<(&str, Option<f64>)> = buckets
+            .into_iter()
+            .map(|(value, bucket)| (value, bucket.result(aggregator)))
+            .collect();
+        buckets.sort_by(|left, right| left.0.cmp(right.0));
+        let by_aggregate = |left: &Option<f64>, right: &Option<f64>| match (left, right) {
+            (Some(left), Some(right)) => {
+                left.partial_cmp(right).unwrap_or(std::cmp::Ordering::Equal)
+            }
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        };
+        match order {
+            GroupOrder::Key => {}
+            GroupOrder::Ascending => buckets.sort_by(|left, right| by_aggregate(&left.1, &right.1)),
+            GroupOrder::Descending => buckets.sort_by(|left, right| match (left.1, right.1) {
+                (Some(_), Some(_)) => by_aggregate(&right.1, &left.1),
+                _ => by_aggregate(&left.1, &right.1),
+            }),
+        }
+
+        let buckets = buckets
+            .into_iter()
+            .map(|(value, aggregate)| (value.to_string(), aggregate))
+            .collect();
+        let group = GroupCache {
+            tag_id,
+            aggregator,
+            buckets,
+        };
+        self.evict_caches();
+        stats.stop();
+        Ok(self.add_group(group, stats))
+    }
+
+    /// Keep the buckets of a group whose aggregate passes the comparison. Aggregates are
+    /// numbers, so a lexical comparison compares them numerically, and a bucket without one
+    /// compares as a missing value.
+    fn filter_groups(
+        &mut self,
+        group_id: GroupId,
+        comp: Comparator,
+        mode: CompareMode,
+        value: &str,
+    ) -> Result<Output> {
+        let mut stats = self.new_stats();
+        let group = self
+            .groups
+            .get(&group_id)
+            .ok_or(Error::MissingId(Id::Group(group_id)))?;
+        let mode = match mode {
+            CompareMode::Lexical => CompareMode::Numeric,
+            _ => mode,
+        };
+        let operand = Some(value.to_string());
+        let buckets = group
+            .buckets
+            .iter()
+            .filter(|(_, aggregate)| {
+                let aggregate = aggregate.map(|aggregate| aggregate.to_string());
+                Self::compare_values(comp, mode, &aggregate, &operand)
+            })
+            .cloned()
+            .collect();
+        let group = GroupCache {
+            tag_id: group.tag_id,
+            aggregator: group.aggregator,
+            buckets,
+        };
+        stats.stop();
+        Ok(self.add_group(group, stats))
+    }
+
+    /// Save a group under a new ID, printing one line per bucket
+    fn add_group(&mut self, group: GroupCache, stats: Stats) -> Output {
+        let lines = group
+            .buckets
+            .iter()
+            .map(|(value, aggregate)| {
+                let value = format!("{:?}", value);
+                format!("{: <15} {}", value, format_aggregate(*aggregate))
+            })
+            .collect();
+        let group_id = self.next_group_id();
+        self.groups.insert(group_id, group);
+        Output {
+            id: Some(Id::Group(group_id)),
+            lines,
+            stats,
+            is_message: false,
+        }
+    }
+
+    /// Aggregate the rows selected by `id` into a single line, `N/A` when none had a number
+    fn aggregate(
+        &mut self,
+        id: Id,
+        aggregator: Aggregator,
+        value_tag_id: Option<TagId>,
+    ) -> Result<Output> {
+        Self::check_row_source(id)?;
+        if let Some(value_tag_id) = value_tag_id {
+            self.check_same_file(id, value_tag_id)?;
+        }
+
+        let mut rows = self.take_iter(id, usize::MAX)?;
+        let selected = match rows.read_whole_selection() {
+            Ok(selected) => selected,
+            Err(err) => {
+                rows.finish();
+                return Err(err);
+            }
+        };
+
+        let mut total = Accumulator::default();
+        for idx in selected {
+            match value_tag_id {
+                Some(value_tag_id) => total.add_number(rows.engine.read_number(value_tag_id, idx)),
+                None => total.add_line(),
+            }
+        }
+
+        let lines = vec![format_aggregate(total.result(aggregator))];
+        Ok(Output::with_results(lines, rows.finish()))
+    }
+
+    /// The first value of a tag at line `idx` when it is a finite number
+    fn read_number(&self, tag_id: TagId, idx: usize) -> Option<f64> {
+        self.read_tag(tag_id, Interval(idx, idx + 1))
+            .first()
+            .and_then(|value| value.as_ref())
+            .and_then(|value| value.trim().parse::<f64>().ok())
+            .filter(|number| number.is_finite())
+    }
+
+    /// Tags read alongside the rows of `id` must come from the same file
+    fn check_same_file(&self, id: Id, tag_id: TagId) -> Result<()> {
+        let file_id = self.plan(id)?.file_id();
+        match self.tag_to_file.get(&tag_id) {
+            Some(tag_file_id) if *tag_file_id == file_id => Ok(()),
+            Some(_) => Err(Error::InvalidTarget(format!("{:?}", tag_id))),
+            None => Err(Error::MissingId(Id::Tag(tag_id))),
+        }
+    }
+
+    /// Fails with `Interrupted` if Ctrl-C was pressed since the flag was last cleared, for
+    /// commands whose partial result would be wrong rather than short
+    fn check_interrupted(&self) -> Result<()> {
+        if self.interrupted.swap(false, atomic::Ordering::SeqCst) {
+            return Err(Error::Interrupted);
+        }
+        Ok(())
+    }
+
+    /// Read the whole file of a tag and extract its values, returning the lines they cover
+    fn ensure_whole_tag(&mut self, stats: &mut Stats, tag_id: TagId) -> Result<Interval> {
+        let file_id = *self
+            .tag_to_file
+            .get(&tag_id)
+            .ok_or_else(|| Error::MissingId(Id::Tag(tag_id)))?;
+
+        self.interrupted.store(false, atomic::Ordering::SeqCst);
+        let mut interval = Interval(0, 0);
+        for batch in ReadIntervals::new(MAX_BATCH_SIZE, MAX_BATCH_SIZE) {
+            self.check_interrupted()?;
+            let read_count = self.ensure_file(stats, file_id, batch)?;
+            interval.1 += read_count;
+            if read_count < batch.len() {
+                break;
+            }
+        }
+        self.ensure_tag(stats, file_id, tag_id, interval)?;
+        Ok(interval)
+    }
+
+    /// Compare two tags of the same file line by line over the interval materialized for both,
+    /// lines where both values are missing only count as matches under `NullSafeEqual`
+    fn match_rate(
+        &self,
+        left: TagId,
+        right: TagId,
+        comp: Comparator,
+        mode: CompareMode,
+    ) -> Result<Output> {
+        let file_of = |tag_id: TagId| {
+            self.tag_to_file
+                .get(&tag_id)
+                .ok_or(Error::MissingId(Id::Tag(tag_id)))
+        };
+        if file_of(left)? != file_of(right)? {
+            return Err(Error::InvalidArgument(format!(
+                "match rate of tags of different files: {:?}, {:?}",
+                left, right
+            )));
+        }
+        let bounds = |tag_id: TagId| {
+            self.tag_caches
+                .get(&tag_id)
+                .map(|cache| cache.bounds())
+                .ok_or_else(|| Error::MissingId(Id::Tag(tag_id)))
+        };
+        let (left_bounds, right_bounds) = (bounds(left)?, bounds(right)?);
+        let interval = Interval(
+            std::cmp::max(left_bounds.0, right_bounds.0),
+            std::cmp::min(left_bounds.1, right_bounds.1),
+        );
+        if interval.0 >= interval.1 {
+            return Ok(Output::with_message(
+                None,
+                "no lines materialized for both tags".to_string(),
+            ));
+        }
+
+        let left_values = self.read_tag(left, interval);
+        let right_values = self.read_tag(right, interval);
+
+        let mismatches: Vec<usize> = interval
+            .iter()
+            .zip(left_values.iter().zip(right_values.iter()))
+            .filter(|(_, (l, r))| !Self::compare_values(comp, mode, l, r))
+            .map(|(idx, _)| idx)
+            .collect();
+        let matches = interval.len() - mismatches.len();
+
+        let mut lines = vec![
+            format!(
+                "match rate: {:.2}% ({}/{}) over {}",
+                100.0 * matches as f64 / interval.len() as f64,
+                matches,
+                interval.len(),
+                interval,
+            ),
+            format!("mismatches: {}", mismatches.len()),
+        ];
+        if !mismatches.is_empty() {
+            let sample: Vec<String> = mismatches
+                .iter()
+                .take(MATCH_RATE_SAMPLE_SIZE)
+                .map(|idx| idx.to_string())
+                .collect();
+            lines.push(format!("mismatched lines: {}", sample.join(", ")));
+        }
+
+        Ok(Output::with_results(lines, Stats::disabled()))
+    }
+
+    fn run_script(&mut self, script: &str) -> Result<()> {
+        self.lua.context(|lua_ctx| {
+            lua_ctx.load(script).eval::<()>()?;
+            Ok(())
+        })
+    }
+
+    fn ensure_file(
+        &mut self,
+        stats: &mut Stats,
+        file_id: FileId,
+        interval: Interval,
+    ) -> Result<usize> {
+        self.touch_cache(Id::File(file_id));
+        let cache = self
+            .file_caches
+            .entry(file_id)
+            .or_insert_with(FileCache::default);
+        let cache_bounds = cache.bounds();
+
+        if cache_bounds.contains(interval) {
+            stats.add_size(Id::File(file_id), cache.size());
+            return Ok(cache_bounds.intersect(interval).len());
+        }
+        let cache_bounds = extended_bounds(Some(cache_bounds), interval);
+
+        if self.files.contains_key(&file_id) {
+            self.touch_file(file_id);
+        }
+
+        let cache = self.file_caches.get_mut(&file_id).unwrap();
+        if let Some(file) = self.files.get_mut(&file_id) {
+            if cache.bounds() != cache_bounds {
+                *cache = FileCache::starting_at(cache_bounds.0);
+            }
+
+            let missing_before = cache_bounds.missing_before(interval);
+            if !missing_before.is_empty() {
+                stats.add_interval(Id::File(file_id), missing_before);
+
+                let mut lines = file.read(missing_before)?;
+                lines.extend(cache.loaded.iter().cloned());
+                cache.loaded = lines;
+                cache.start = missing_before.0;
+            }
+
+            let missing_after = cache_bounds.missing_after(interval);
+            if !missing_after.is_empty() {
+                stats.add_interval(Id::File(file_id), missing_after);
+
+                let lines = file.read(missing_after)?;
+                cache.loaded.extend(lines.into_iter());
+            }
+
+            stats.add_size(Id::File(file_id), cache.size());
+            // Reading stops at the end of the file, so the cache may still end before `interval`
+            Ok(cache.bounds().intersect(interval).len())
+        } else {
+            Err(Error::FileNotLoaded(format!("{:?}", file_id)))
+        }
+    }
+
+    /// The number of lines of the file, read to its end without caching them
+    fn line_count(&mut self, file_id: FileId) -> Result<usize> {
+        if !self.files.contains_key(&file_id) {
+            return Err(Error::FileNotLoaded(format!("{:?}", file_id)));
+        }
+        self.touch_file(file_id);
+        self.files.get_mut(&file_id).unwrap().line_count()
+    }
+
+    /// Lines of the cached part of `interval`
+    fn read_lines(&self, file_id: FileId, interval: Interval) -> &[String] {
+        let cache = &self.file_caches[&file_id];
+        let interval = interval.clamp_to(cache.bounds());
+        &cache.loaded[interval.0 - cache.start..interval.1 - cache.start]
+    }
+
+    fn ensure_tag(
+        &mut self,
+        stats: &mut Stats,
+        file_id: FileId,
+        tag_id: TagId,
+        interval: Interval,
+    ) -> Result<()> {
+        self.touch_cache(Id::Tag(tag_id));
+        let cache_opt = self.tag_caches.get(&tag_id);
+        let cache_bounds = cache_opt
+            .map(|cache| cache.bounds())
+            .unwrap_or(Interval(0, 0));
+
+        if cache_bounds.contains(interval) {
+            stats.add_size(
+                Id::Tag(tag_id),
+                cache_opt.map(|cache| cache.size()).unwrap_or(0),
+            );
+            return Ok(());
+        }
+        let cache_bounds = extended_bounds(cache_opt.map(|cache| cache.bounds()), interval);
+
+        // The file cache may have been evicted since this tag was last extended
+        self.ensure_file(stats, file_id, interval)?;
+
+        let tag = self
+            .tags
+            .get(&tag_id)
+            .ok_or_else(|| Error::MissingId(Id::Tag(tag_id)))?;
+
+        let mut prefix = None;
+        let mut suffix = None;
+
+        let missing_before = cache_bounds.missing_before(interval);
+        if !missing_before.is_empty() {
+            stats.add_interval(Id::Tag(tag_id), missing_before);
+            let lines = self.read_lines(file_id, missing_before);
+            prefix = Some(Engine::parse_tag_from_lines(
+                &self.lua,
+                tag,
+                lines,
+                missing_before.0,
+                self.skip_lua_errors,
+                stats,
+            )?);
+        }
+
+        let missing_after = cache_bounds.missing_after(interval);
+        if !missing_after.is_empty() {
+            stats.add_interval(Id::Tag(tag_id), missing_after);
+            let lines = self.read_lines(file_id, missing_after);
+            suffix = Some(Engine::parse_tag_from_lines(
+                &self.lua,
+                tag,
+                lines,
+                missing_after.0,
+                self.skip_lua_errors,
+                stats,
+            )?);
+        }
+
+        let cache = self
+            .tag_caches
+            .entry(tag_id)
+            .or_insert_with(TagCache::default);
+        if cache.bounds() != cache_bounds {
+            *cache = TagCache::starting_at(cache_bounds.0);
+        }
+
+        if let Some((mut prefix, repeated)) = prefix {
+            prefix.extend(cache.loaded.iter().cloned());
+            cache.loaded = prefix;
+            cache.repeated.extend(repeated);
+            cache.start = cache_bounds.union(interval).0;
+        }
+
+        if let Some((suffix, repeated)) = suffix {
+            cache.loaded.extend(suffix.into_iter());
+            cache.repeated.extend(repeated);
+        }
+
+        stats.add_size(Id::Tag(tag_id), cache.size());
+        Ok(())
+    }
+
+    fn ensure_all_tags(
+        &mut self,
+        stats: &mut Stats,
+        file_id: FileId,
+        interval: Interval,
+    ) -> Result<()> {
+        for tag_id in self.tags_of(file_id).to_vec() {
+            self.ensure_tag(stats, file_id, tag_id, interval)?;
+        }
+        Ok(())
+    }
+
+    fn ensure_parent_tag(
+        &mut self,
+        stats: &mut Stats,
+        tag_id: TagId,
+        interval: Interval,
+    ) -> Result<()> {
+        let file_id = *self
+            .tag_to_file
+            .get(&tag_id)
+            .ok_or_else(|| Error::MissingId(Id::Tag(tag_id)))?;
+        self.ensure_tag(stats, file_id, tag_id, interval)
+    }
+
+    /// Values of the cached part of `interval`
+    fn read_tag(&self, tag_id: TagId, interval: Interval) -> &[TagValue] {
+        let cache = &self.tag_caches[&tag_id];
+        let interval = interval.clamp_to(cache.bounds());
+        &cache.loaded[interval.0 - cache.start..interval.1 - cache.start]
+    }
+
+    /// Every value of the cached part of `interval` along with its line, each line's values in
+    /// order but the further values of repeated tags only after all first values
+    fn iter_tag(&self, tag_id: TagId, interval: Interval) -> impl Iterator<Item = (usize, &str)> {
+        self.iter_tag_values(tag_id, interval)
+            .filter_map(|(idx, value)| value.map(|value| (idx, value)))
+    }
+
+    /// Like `iter_tag`, but lines without a value are included as `None`
+    fn iter_tag_values(
+        &self,
+        tag_id: TagId,
+        interval: Interval,
+    ) -> impl Iterator<Item = (usize, Option<&str>)> {
+        let cache = &self.tag_caches[&tag_id];
+        let interval = interval.clamp_to(cache.bounds());
+
+        let first = cache.loaded[interval.0 - cache.start..interval.1 - cache.start]
+            .iter()
+            .enumerate()
+            .map(move |(offset, value)| (interval.0 + offset, value.as_deref()));
+        let rest = cache
+            .repeated
+            .range(interval.0..interval.1)
+            .flat_map(|(idx, values)| values.iter().map(move |v| (*idx, Some(v.as_str()))));
+        first.chain(rest)
+    }
+
+    /// The name, first value and further values of every tag of the file at line `idx`
+    fn read_row_tags(&self, file_id: FileId, idx: usize) -> Vec<(String, &TagValue, &[String])> {
+        let mut result = vec![];
+        for tag_id in self.tags_of(file_id) {
+            let cache = &self.tag_caches[tag_id];
+            let repeated = cache.repeated.get(&idx).map(Vec::as_slice).unwrap_or(&[]);
+            result.push((
+                self.tags[tag_id].name.clone(),
+                &self.read_tag(*tag_id, Interval(idx, idx + 1))[0],
+                repeated,
+            ));
+        }
+        result
+    }
+
+    fn ensure_filter(
+        &mut self,
+        stats: &mut Stats,
+        tag_id: TagId,
+        filter_id: FilterId,
+        interval: Interval,
+    ) -> Result<()> {
+        self.touch_cache(Id::Filter(filter_id));
+        let cache_opt = self.filter_caches.get(&filter_id);
+        let cache_bounds = cache_opt
+            .map(|cache| cache.bounds())
+            .unwrap_or(Interval(0, 0));
+
+        if cache_bounds.contains(interval) {
+            stats.add_size(
+                Id::Filter(filter_id),
+                cache_opt.map(|cache| cache.size()).unwrap_or(0),
+            );
+            if let Some(cache) = cache_opt {
+                stats.add_selectivity(filter_id, cache.selectivity());
+            }
+            return Ok(());
+        }
+        let cache_bounds = extended_bounds(cache_opt.map(|cache| cache.bounds()), interval);
+
+        let lookback = self
+            .filters
+            .get(&filter_id)
+            .ok_or_else(|| Error::MissingId(Id::Filter(filter_id)))?
+            .lookback();
+        // The parent tag cache may have been evicted since this filter was last extended
+        let tag_interval = Interval(interval.0.saturating_sub(lookback), interval.1);
+        self.ensure_parent_tag(stats, tag_id, tag_interval)?;
+
+        let filter = &self.filters[&filter_id];
+
+        let mut prefix = None;
+        let mut suffix = None;
+
+        let missing_before = cache_bounds.missing_before(interval);
+        if !missing_before.is_empty() {
+            stats.add_interval(Id::Filter(filter_id), missing_before);
+            prefix = Some(self.filter_interval(stats, filter, tag_id, missing_before)?)
+        }
+
+        let missing_after = cache_bounds.missing_after(interval);
+        if !missing_after.is_empty() {
+            stats.add_interval(Id::Filter(filter_id), missing_after);
+            suffix = Some(self.filter_interval(stats, filter, tag_id, missing_after)?)
+        }
+
+        let cache = self
+            .filter_caches
+            .entry(filter_id)
+            .or_insert_with(FilterCache::default);
+        if cache.bounds() != cache_bounds {
+            *cache = FilterCache::starting_at(cache_bounds.0);
+        }
+        cache.extend(interval, prefix, suffix);
+
+        stats.add_size(Id::Filter(filter_id), cache.size());
+        stats.add_selectivity(filter_id, cache.selectivity());
+        Ok(())
+    }
+
+    /// Extend any filter to cover `interval`, a combined filter extends its operands first and a
+    /// selection copies its saved lines
+    fn ensure_any_filter(
+        &mut self,
+        stats: &mut Stats,
+        filter_id: FilterId,
+        interval: Interval,
+    ) -> Result<()> {
+        let filter = self
+            .filters
+            .get(&filter_id)
+            .ok_or_else(|| Error::MissingId(Id::Filter(filter_id)))?;
+        if !filter.tests_values() {
+            return self.ensure_combined_filter(stats, filter_id, interval);
+        }
+
+        let tag_id = self
+            .find_parent_tag(Id::Filter(filter_id))
+            .ok_or_else(|| Error::InvalidTarget(format!("{:?}", Id::Filter(filter_id))))?;
+        self.ensure_filter(stats, tag_id, filter_id, interval)
+    }
+
+    fn ensure_combined_filter(
+        &mut self,
+        stats: &mut Stats,
+        filter_id: FilterId,
+        interval: Interval,
+    ) -> Result<()> {
+        self.touch_cache(Id::Filter(filter_id));
+        let cache_opt = self.filter_caches.get(&filter_id);
+        let cache_bounds = cache_opt
+            .map(|cache| cache.bounds())
+            .unwrap_or(Interval(0, 0));
+
+        if cache_bounds.contains(interval) {
+            stats.add_size(
+                Id::Filter(filter_id),
+                cache_opt.map(|cache| cache.size()).unwrap_or(0),
+            );
+            if let Some(cache) = cache_opt {
+                stats.add_selectivity(filter_id, cache.selectivity());
+            }
+            return Ok(());
+        }
+        let cache_bounds = extended_bounds(cache_opt.map(|cache| cache.bounds()), interval);
+
+        if let Some((left, right)) = self.filters[&filter_id].operands() {
+            self.ensure_any_filter(stats, left, interval)?;
+            self.ensure_any_filter(stats, right, interval)?;
+        }
+
+        let filter = &self.filters[&filter_id];
+        let combine = |interval: Interval| {
+            interval
+                .iter()
+                .filter(|idx| match filter {
+                    Filter::Or(left, right) => {
+                        self.read_filter(*left).contains(*idx)
+                            || self.read_filter(*right).contains(*idx)
+                    }
+                    Filter::And(left, right) => {
+                        self.read_filter(*left).contains(*idx)
+                            && self.read_filter(*right).contains(*idx)
+                    }
+                    Filter::Selection(lines) => lines.contains(*idx),
+                    _ => unreachable!("filters testing values are run by ensure_filter"),
+                })
+                .collect::<bit_set::BitSet>()
+        };
+
+        let mut prefix = None;
+        let mut suffix = None;
+
+        let missing_before = cache_bounds.missing_before(interval);
+        if !missing_before.is_empty() {
+            stats.add_interval(Id::Filter(filter_id), missing_before);
+            prefix = Some(combine(missing_before));
+        }
+
+        let missing_after = cache_bounds.missing_after(interval);
+        if !missing_after.is_empty() {
+            stats.add_interval(Id::Filter(filter_id), missing_after);
+            suffix = Some(combine(missing_after));
+        }
+
+        let cache = self.filter_caches.entry(filter_id).or_default();
+        if cache.bounds() != cache_bounds {
+            *cache = FilterCache::starting_at(cache_bounds.0);
+        }
+        cache.extend(interval, prefix, suffix);
+
+        stats.add_size(Id::Filter(filter_id), cache.size());
+        stats.add_selectivity(filter_id, cache.selectivity());
+        Ok(())
+    }
+
+    /// Lines of `interval` passing `filter`, the parent tag must be cached from `lookback` lines
+    /// before the interval
+    fn filter_interval(
+        &self,
+        stats: &mut Stats,
+        filter: &Filter,
+        tag_id: TagId,
+        interval: Interval,
+    ) -> Result<bit_set::BitSet> {
+        match filter {
+            Filter::Lag(lag, factor, comp, mode) => {
+                let start = interval.0.saturating_sub(*lag);
+                let values = self.read_tag(tag_id, Interval(start, interval.1));
+                Ok(Engine::lag_values(
+                    values, start, *lag, *factor, *comp, *mode,
+                ))
+            }
+            _ => {
+                let tag_values = self.iter_tag_values(tag_id, interval);
+                let skip_errors = self.skip_lua_errors;
+                Engine::filter_values(&self.lua, filter, tag_values, skip_errors, stats)
+            }
+        }
+    }
+
+    fn read_filter(&self, filter_id: FilterId) -> &bit_set::BitSet {
+        &self.filter_caches[&filter_id].loaded
+    }
+
+    /// Extend the distinct to cover `interval`, returning the part actually covered. With a
+    /// `limit` the lines after the cache stop being read once that many new values are found,
+    /// the interval then ends right after the last of them
+    fn ensure_distinct(
+        &mut self,
+        stats: &mut Stats,
+        tag_id: TagId,
+        distinct_id: DistinctId,
+        interval: Interval,
+        limit: Option<usize>,
+    ) -> Result<Interval> {
+        self.touch_cache(Id::Distinct(distinct_id));
+        let cache_opt = self.distinct_caches.get(&distinct_id);
+        let cache_bounds = cache_opt
+            .map(|cache| cache.bounds())
+            .unwrap_or(Interval(0, 0));
+
+        if cache_bounds.contains(interval) {
+            stats.add_size(
+                Id::Distinct(distinct_id),
+                cache_opt.map(|cache| cache.size()).unwrap_or(0),
+            );
+            return Ok(interval);
+        }
+
+        let (mut bloom, mut seen) = self
+            .distinct_caches
+            .get_mut(&distinct_id)
+            .map(|cache| (cache.bloom, std::mem::take(&mut cache.seen)))
+            .unwrap_or_else(|| (ethbloom::Bloom::zero(), HashSet::new()));
+
+        let mut prefix = None;
+        let mut suffix = None;
+
+        // The parent tag cache may have been evicted since this distinct was last extended, and
+        // a distinct always reads from the first line on
+        let missing_before = cache_bounds.missing_before(interval);
+        if !missing_before.is_empty() {
+            self.ensure_parent_tag(stats, tag_id, missing_before)?;
+            stats.add_interval(Id::Distinct(distinct_id), missing_before);
+            let tag_values = self.read_tag(tag_id, missing_before);
+            let (values, _) = Engine::distinct_values(
+                &mut bloom,
+                &mut seen,
+                tag_values,
+                missing_before.0,
+                usize::MAX,
+                missing_before.0,
+            );
+            prefix = Some(values);
+        }
+
+        // Only the lines after the cache can be cut short, the cache must stay contiguous. New
+        // values before `interval` don't count towards the limit, the rows are taken from it.
+        let limit = match limit {
+            Some(limit) if missing_before.is_empty() => limit,
+            _ => usize::MAX,
+        };
+        let mut end = interval.1;
 
-    fn next_filter_id(&mut self) -> FilterId {
-        self.last_id += 1;
-        FilterId(self.last_id)
-    }
+        let missing_after = cache_bounds.missing_after(interval);
+        if !missing_after.is_empty() {
+            self.ensure_parent_tag(stats, tag_id, missing_after)?;
+            let tag_values = self.read_tag(tag_id, missing_after);
+            let (values, read_count) = Engine::distinct_values(
+                &mut bloom,
+                &mut seen,
+                tag_values,
+                missing_after.0,
+                limit,
+                interval.0,
+            );
+            end = missing_after.0 + read_count;
+            stats.add_interval(Id::Distinct(distinct_id), Interval(missing_after.0, end));
+            suffix = Some(values);
+        }
 
-    fn next_tag_id(&mut self) -> TagId {
-        self.last_id += 1;
-        TagId(self.last_id)
+        let cache = self
+            .distinct_caches
+            .entry(distinct_id)
+            .or_insert_with(DistinctCache::default);
+
+        if let Some(mut prefix) = prefix {
+            prefix.union_with(&cache.loaded);
+            cache.loaded = prefix;
+        }
+
+        if let Some(suffix) = suffix {
+            cache.loaded.union_with(&suffix);
+        }
+
+        // A limited read may stop short of `interval`, never of the old bounds
+        let bounds = cache_bounds.union(Interval(interval.0, end));
+        cache.start = bounds.0;
+        cache.end = bounds.1;
+        debug_assert!(cache.start <= cache.end, "distinct cache bounds {}", bounds);
+
+        cache.bloom = bloom;
+        cache.seen = seen;
+
+        stats.add_size(Id::Distinct(distinct_id), cache.size());
+        // The file may end before `interval` starts
+        Ok(Interval(interval.0, std::cmp::max(interval.0, end)))
     }
 
-    fn plan(&self, id: Id) -> Plan {
-        Plan::new(self.plan_steps(id))
+    fn read_distinct(&self, distinct_id: DistinctId) -> &bit_set::BitSet {
+        &self.distinct_caches[&distinct_id].loaded
     }
 
-    fn plan_steps(&self, id: Id) -> Vec<Id> {
-        match id {
-            Id::File(_) => vec![id],
-            Id::Distinct(distinct_id) => {
-                let mut parent = self.plan_steps(self.distinct_to_parent[&distinct_id]);
-                parent.push(id);
-                parent
-            }
-            Id::Filter(filter_id) => {
-                let mut parent = self.plan_steps(self.filter_to_parent[&filter_id]);
-                parent.push(id);
-                parent
+    /// The first value of each line starting at line `start`, along with the further values of
+    /// lines with more than one
+    fn parse_tag_from_lines(
+        lua: &rlua::Lua,
+        tag: &Tag,
+        lines: &[String],
+        start: usize,
+        skip_errors: bool,
+        stats: &mut Stats,
+    ) -> Result<(Vec<TagValue>, RepeatedValues)> {
+        lua.context(|lua_ctx| {
+            let transforms = tag
+                .transforms
+                .iter()
+                .map(|compiled| lua_ctx.registry_value::<rlua::Function>(&compiled.key))
+                .collect::<rlua::Result<Vec<_>>>()?;
+            let profile = stats.profiles_lua();
+            let mut timings = vec![LuaTiming::default(); transforms.len()];
+
+            let mut values = Vec::with_capacity(lines.len());
+            let mut repeated = BTreeMap::new();
+            for (idx, line) in lines.iter().enumerate() {
+                let chunks: Vec<String> = if transforms.is_empty() {
+                    tag.extractor
+                        .extract(line)
+                        .into_iter()
+                        .map(Cow::into_owned)
+                        .collect()
+                } else {
+                    let mut chunks = vec![];
+                    for (chunk, groups) in tag.extractor.extract_with_groups(line) {
+                        let transformed = groups.to_table(lua_ctx).and_then(|groups| {
+                            Engine::transform_chunk(
+                                &transforms,
+                                &chunk,
+                                groups,
+                                profile,
+                                &mut timings,
+                            )
+                        });
+                        let row = Engine::row_result(transformed, skip_errors, start + idx, &chunk);
+                        chunks.extend(row?.flatten());
+                    }
+                    chunks
+                };
+                let mut chunks = chunks.into_iter();
+                values.push(chunks.next());
+
+                let rest = chunks.collect::<Vec<String>>();
+                if !rest.is_empty() {
+                    repeated.insert(start + idx, rest);
+                }
             }
-            Id::Tag(tag_id) => {
-                let mut parent = self.plan_steps(Id::File(self.tag_to_file[&tag_id]));
-                parent.push(id);
-                parent
+            for (compiled, timing) in tag.transforms.iter().zip(timings) {
+                stats.add_lua(&compiled.source, timing);
             }
-        }
+            Ok((values, repeated))
+        })
     }
 
-    fn take(&mut self, plan: &Plan, count: usize) -> Result<Output> {
-        let mut interval = Interval(0, 0);
-        let mut stats = if self.debug {
-            Stats::enabled()
-        } else {
-            Stats::disabled()
-        };
-
-        'outer: for batch_interval in ReadIntervals::new(count, MAX_BATCH_SIZE) {
-            for id in &plan.steps {
-                match id {
-                    Id::File(file_id) => {
-                        let read_count = self.ensure_file(&mut stats, *file_id, batch_interval)?;
-                        if read_count == 0 {
-                            break 'outer;
-                        }
-                        interval.1 += read_count;
-                    }
-                    Id::Distinct(distinct_id) => {
-                        self.ensure_distinct(
-                            &mut stats,
-                            self.find_parent_tag(Id::Distinct(*distinct_id)).unwrap(),
-                            *distinct_id,
-                            interval,
-                        )?;
+    /// Lines with a value passing `filter`, a line of a repeated tag passes when any of its
+    /// values does
+    fn filter_values<'a>(
+        lua: &rlua::Lua,
+        filter: &Filter,
+        values: impl Iterator<Item = (usize, Option<&'a str>)>,
+        skip_errors: bool,
+        stats: &mut Stats,
+    ) -> Result<bit_set::BitSet> {
+        let profile = stats.profiles_lua();
+        let mut timing = LuaTiming::default();
+        let mut result = bit_set::BitSet::new();
+        let present = |(idx, value): (usize, Option<&'a str>)| value.map(|value| (idx, value));
+        match filter {
+            Filter::Direct(Comparator::IsNull, _, _) => {
+                for (idx, left_option) in values {
+                    if left_option.is_none() {
+                        result.insert(idx);
                     }
-                    Id::Filter(filter_id) => {
-                        self.ensure_filter(
-                            &mut stats,
-                            self.find_parent_tag(Id::Filter(*filter_id)).unwrap(),
-                            *filter_id,
-                            interval,
-                        )?;
+                }
+            }
+            Filter::Direct(Comparator::IsNotNull, _, _) => {
+                for (idx, left_option) in values {
+                    if left_option.is_some() {
+                        result.insert(idx);
                     }
-                    Id::Tag(tag_id) => {
-                        self.ensure_tag(&mut stats, self.tag_to_file[tag_id], *tag_id, interval)?;
+                }
+            }
+            Filter::Direct(comp, mode, right) => {
+                for (idx, left) in values.filter_map(present) {
+                    if Self::compare(*comp, *mode, left, right) {
+                        result.insert(idx);
                     }
                 }
             }
-
-            match plan.steps.last().unwrap() {
-                Id::Distinct(distinct_id) => {
-                    if self.distinct_caches[distinct_id].count() >= count {
-                        break;
+            Filter::Custom(func, right) => lua.context(|lua_ctx| {
+                let function: rlua::Function = lua_ctx.globals().get(func.as_str())?;
+                for (idx, left) in values.filter_map(present) {
+                    let passed =
+                        timing.time(profile, || function.call::<_, bool>((left, right.as_str())));
+                    if Self::row_result(passed, skip_errors, idx, left)? == Some(true) {
+                        result.insert(idx);
                     }
                 }
-                Id::File(file_id) => {
-                    if self.file_caches[file_id].bounds().len() >= count {
-                        break;
+                stats.add_lua(func, timing);
+                Ok::<_, Error>(())
+            })?,
+            Filter::Scripted(script) => lua.context(|lua_ctx| {
+                let test: rlua::Function = lua_ctx.registry_value(&script.key)?;
+                for (idx, value) in values.filter_map(present) {
+                    let passed = timing.time(profile, || test.call::<_, bool>(value));
+                    if Self::row_result(passed, skip_errors, idx, value)? == Some(true) {
+                        result.insert(idx);
                     }
                 }
-                Id::Filter(filter_id) => {
-                    if self.filter_caches[filter_id].count() >= count {
-                        break;
+                stats.add_lua(&script.source, timing);
+                Ok::<_, Error>(())
+            })?,
+            Filter::Regex(regex) => {
+                for (idx, value) in values.filter_map(present) {
+                    if regex.is_match(value) {
+                        result.insert(idx);
                     }
                 }
-                Id::Tag(tag_id) => {
-                    if self.tag_caches[tag_id].bounds().len() >= count {
-                        break;
+            }
+            Filter::In(set) => {
+                for (idx, value) in values.filter_map(present) {
+                    if set.contains(value) {
+                        result.insert(idx);
                     }
                 }
             }
+            Filter::Lag(_, _, _, _) => unreachable!("lag filters are run by filter_interval"),
+            Filter::Or(_, _) | Filter::And(_, _) | Filter::Selection(_) => {
+                unreachable!("combined filters and selections are run by ensure_combined_filter")
+            }
         }
+        Ok(result)
+    }
 
-        let file_id = plan.file_id();
-        self.ensure_all_tags(&mut stats, plan.file_id(), interval)?;
-
-        let lines = self.read_lines(file_id, interval);
-        let tags = self.read_all_tags(file_id, interval);
+    /// Order `rows` by the first value of `tag_id`, numerically when both values parse and
+    /// lexically otherwise. Rows without a value come last in either order and ties keep the
+    /// order of their lines.
+    fn sort_rows(&self, tag_id: TagId, order: Order, rows: &mut [usize]) {
+        let mut keyed: Vec<(Option<SortKey>, usize)> = rows
+            .iter()
+            .map(|&idx| {
+                let value = self.read_tag(tag_id, Interval(idx, idx + 1)).first();
+                let key = value.and_then(|value| value.as_deref()).map(SortKey::new);
+                (key, idx)
+            })
+            .collect();
+        keyed.sort_by(|(left, _), (right, _)| match (left, right) {
+            (Some(left), Some(right)) => match order {
+                Order::Ascending => left.cmp(right),
+                Order::Descending => right.cmp(left),
+            },
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        });
+        for (row, (_, idx)) in rows.iter_mut().zip(keyed) {
+            *row = idx;
+        }
+    }
 
-        let mut combined_filter: Option<bit_set::BitSet> = None;
-        for filter_id in plan.filter_ids() {
-            match combined_filter {
-                Some(ref mut filter) => filter.intersect_with(self.read_filter(filter_id)),
-                None => combined_filter = Some(self.read_filter(filter_id).clone()),
-            }
+    /// Compare two tag values, a missing value only matches another one under `NullSafeEqual`.
+    /// `IsNull` and `IsNotNull` only test the left value.
+    fn compare_values(
+        comp: Comparator,
+        mode: CompareMode,
+        left: &TagValue,
+        right: &TagValue,
+    ) -> bool {
+        match comp {
+            Comparator::IsNull => return left.is_none(),
+            Comparator::IsNotNull => return left.is_some(),
+            _ => {}
         }
-        for distinct_id in plan.distinct_ids() {
-            match combined_filter {
-                Some(ref mut filter) => filter.intersect_with(self.read_distinct(distinct_id)),
-                None => combined_filter = Some(self.read_distinct(distinct_id).clone()),
-            }
+        match (left, right) {
+            (Some(left), Some(right)) => Self::compare(comp, mode, left, right),
+            (None, None) => comp == Comparator::NullSafeEqual,
+            _ => false,
+        }
+    }
+
+    fn compare(comp: Comparator, mode: CompareMode, left: &str, right: &str) -> bool {
+        if mode == CompareMode::IgnoreCase {
+            let (left, right) = (left.to_ascii_lowercase(), right.to_ascii_lowercase());
+            return Self::compare(comp, CompareMode::Lexical, &left, &right);
         }
 
-        let mut results = vec![];
-        let mut current_count = 0;
+        match comp {
+            Comparator::Contains => return left.contains(right),
+            Comparator::NotContains => return !left.contains(right),
+            Comparator::IsNull => return false,
+            Comparator::IsNotNull => return true,
+            _ => {}
+        }
 
-        for (idx, line) in lines.iter().enumerate() {
-            if let Some(filter) = &combined_filter {
-                if !filter.contains(idx) {
-                    continue;
+        let ordering = match mode {
+            CompareMode::Lexical | CompareMode::IgnoreCase => Some(left.cmp(right)),
+            CompareMode::Numeric => {
+                match (left.trim().parse::<f64>(), right.trim().parse::<f64>()) {
+                    (Ok(left), Ok(right)) => left.partial_cmp(&right),
+                    _ => Some(left.cmp(right)),
                 }
             }
+            CompareMode::Size => match (parse_size(left), parse_size(right)) {
+                (Some(left), Some(right)) => left.partial_cmp(&right),
+                _ => None,
+            },
+        };
 
-            results.push(line.to_string());
-            for (name, tag_values) in &tags {
-                if let Some(value) = &tag_values[idx] {
-                    results.push(format!("    {: <15} {:?}", format!("[{}]", name), value,))
-                } else {
-                    results.push(format!("    [{: <15}] N/A", name))
+        match ordering {
+            Some(ordering) => comp.matches(ordering),
+            None => false,
+        }
+    }
+
+    /// Mark the first occurrence of each value, the bloom skips the set lookup for values that
+    /// were never seen while the set settles the bloom's false positives. Stops after `limit`
+    /// new values from line `counted_from` on, returning how many of the values were read
+    fn distinct_values(
+        bloom: &mut ethbloom::Bloom,
+        seen: &mut HashSet<String>,
+        tag_values: &[Option<String>],
+        start: usize,
+        limit: usize,
+        counted_from: usize,
+    ) -> (bit_set::BitSet, usize) {
+        let mut result = bit_set::BitSet::new();
+        let mut found = 0;
+        for (idx, value_option) in tag_values.iter().enumerate() {
+            if found >= limit && start + idx >= counted_from {
+                return (result, idx);
+            }
+            if let Some(value) = value_option {
+                let bytes = value.as_bytes();
+                let maybe_seen = bloom.contains_input(ethbloom::Input::Raw(bytes));
+                if !maybe_seen || !seen.contains(value) {
+                    result.insert(start + idx);
+                    bloom.accrue(ethbloom::Input::Raw(bytes));
+                    seen.insert(value.clone());
+                    if start + idx >= counted_from {
+                        found += 1;
+                    }
                 }
             }
-            results.push("".to_string());
+        }
+        (result, tag_values.len())
+    }
 
-            current_count += 1;
-            if current_count >= count {
-                break;
+    /// Lines of `values`, starting at line `start`, whose value compares to the value `lag` lines
+    /// earlier scaled by `factor`. A factor other than 1 only applies to numeric earlier values.
+    fn lag_values(
+        values: &[TagValue],
+        start: usize,
+        lag: usize,
+        factor: f64,
+        comp: Comparator,
+        mode: CompareMode,
+    ) -> bit_set::BitSet {
+        let mut result = bit_set::BitSet::new();
+        for (offset, pair) in values.windows(lag + 1).enumerate() {
+            let (earlier, left) = match (&pair[0], &pair[lag]) {
+                (Some(earlier), Some(left)) => (earlier, left),
+                _ => continue,
+            };
+            let right = if factor == 1.0 {
+                earlier.clone()
+            } else {
+                match earlier.trim().parse::<f64>() {
+                    Ok(earlier) => (earlier * factor).to_string(),
+                    Err(_) => continue,
+                }
+            };
+            if Self::compare(comp, mode, left, &right) {
+                result.insert(start + offset + lag);
             }
         }
+        result
+    }
 
-        Ok(Output::with_results(results, stats))
+    /// Run the transforms in order on `chunk`, each of them also seeing the same `groups`. A
+    /// transform returning nil drops the value. Calls are timed into the transform's entry of
+    /// `timings` when profiling.
+    fn transform_chunk<'lua>(
+        transforms: &[rlua::Function<'lua>],
+        chunk: &str,
+        groups: rlua::Table<'lua>,
+        profile: bool,
+        timings: &mut [LuaTiming],
+    ) -> rlua::Result<Option<String>> {
+        let mut chunk = Some(chunk.to_string());
+        for (function, timing) in transforms.iter().zip(timings) {
+            chunk = match chunk {
+                Some(chunk) => timing.time(profile, || function.call((chunk, groups.clone())))?,
+                None => break,
+            };
+        }
+        Ok(chunk)
     }
 
-    fn run_script(&mut self, script: &str) -> Result<()> {
-        self.lua.context(|lua_ctx| {
-            lua_ctx.load(script).eval()?;
-            Ok(())
-        })
+    /// The result of running Lua on the value `chunk` of line `line`, an error either fails the
+    /// query pointing at the line or, when skipping errors, leaves the row without a result
+    fn row_result<T>(
+        result: rlua::Result<T>,
+        skip_errors: bool,
+        line: usize,
+        chunk: &str,
+    ) -> Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(_) if skip_errors => Ok(None),
+            Err(err) => Err(Error::LuaAt(line, chunk.to_string(), err)),
+        }
     }
 
-    fn ensure_file(
-        &mut self,
-        stats: &mut Stats,
-        file_id: FileId,
-        interval: Interval,
-    ) -> Result<usize> {
-        let cache = self
-            .file_caches
-            .entry(file_id)
-            .or_insert_with(FileCache::default);
-        let cache_bounds = cache.bounds();
+    /// Whether the filter tests the tag, directly or through the filters it combines
+    fn filter_tests_tag(&self, filter_id: FilterId, tag_id: TagId) -> bool {
+        match self.filters.get(&filter_id).and_then(Filter::operands) {
+            Some((left, right)) => {
+                self.filter_tests_tag(left, tag_id) || self.filter_tests_tag(right, tag_id)
+            }
+            None => self.find_parent_tag(Id::Filter(filter_id)) == Some(tag_id),
+        }
+    }
 
-        if cache_bounds.contains(interval) {
-            stats.add_size(Id::File(file_id), cache.size());
-            return Ok(std::cmp::min(cache_bounds.1 - interval.0, interval.len()));
+    fn find_parent_tag(&self, id: Id) -> Option<TagId> {
+        match id {
+            Id::Distinct(did) => self.find_parent_tag(self.distinct_to_parent[&did]),
+            Id::Filter(fid) => self.find_parent_tag(self.filter_to_parent[&fid]),
+            Id::Range(rid) => self.find_parent_tag(self.range_to_parent[&rid]),
+            Id::Skip(sid) => self.find_parent_tag(self.skip_to_parent[&sid]),
+            Id::Sort(sid) => self.find_parent_tag(self.sort_to_parent[&sid]),
+            Id::Tag(tid) => Some(tid),
+            _ => None,
         }
+    }
+}
 
-        if let Some(file) = self.files.get_mut(&file_id) {
-            let missing_before = cache_bounds.missing_before(interval);
-            if !missing_before.is_empty() {
-                stats.add_interval(Id::File(file_id), missing_before);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                let mut lines = file.read(missing_before)?;
-                lines.extend(cache.loaded.iter().cloned());
-                cache.loaded = lines;
-                cache.start = missing_before.0;
-            }
+    /// Write `lines` to a file of the temp directory named after the test
+    fn fixture(name: &str, lines: &[&str]) -> path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("log-tags-{}-{}.log", std::process::id(), name));
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+        path
+    }
 
-            let missing_after = cache_bounds.missing_after(interval);
-            if !missing_after.is_empty() {
-                stats.add_interval(Id::File(file_id), missing_after);
+    fn run(engine: &mut Engine, command: Command) -> Output {
+        engine.run_command(&command).unwrap()
+    }
 
-                let lines = file.read(missing_after)?;
-                cache.loaded.extend(lines.into_iter());
-            }
+    fn load(engine: &mut Engine, name: &str, lines: &[&str]) -> FileId {
+        match run(engine, Command::Load(fixture(name, lines))).id {
+            Some(Id::File(file_id)) => file_id,
+            id => panic!("expected a file, got {:?}", id),
+        }
+    }
 
-            stats.add_size(Id::File(file_id), cache.size());
-            Ok(std::cmp::min(
-                cache.bounds().1 - std::cmp::min(cache.bounds().1, interval.0),
-                interval.len(),
-            ))
-        } else {
-            Err(Error::FileNotLoaded(format!("{:?}", file_id)))
+    fn tag(engine: &mut Engine, file_id: FileId, name: &str) -> TagId {
+        match run(engine, Command::Tag(file_id, name.to_string())).id {
+            Some(Id::Tag(tag_id)) => tag_id,
+            id => panic!("expected a tag, got {:?}", id),
         }
     }
 
-    fn read_lines(&self, file_id: FileId, interval: Interval) -> &[String] {
-        &self.file_caches[&file_id].loaded[interval.0..interval.1]
+    fn regex_tag(engine: &mut Engine, file_id: FileId, name: &str, regex: &str) -> TagId {
+        let tag_id = tag(engine, file_id, name);
+        let regex = Command::Regex(tag_id, regex.to_string(), CaptureGroup::default());
+        run(engine, regex);
+        tag_id
     }
 
-    fn ensure_tag(
-        &mut self,
-        stats: &mut Stats,
-        file_id: FileId,
+    /// The lines selected by up to `count` rows of `id`, without their line endings
+    fn selected(engine: &mut Engine, id: Id, count: usize) -> Vec<String> {
+        let mut rows = engine.take_iter(id, count).unwrap();
+        rows.raw = true;
+        let lines = rows
+            .by_ref()
+            .map(|line| line.unwrap().trim_end().to_string())
+            .collect();
+        rows.finish();
+        lines
+    }
+
+    /// Values of the tag on the first `count` lines of its file
+    fn tag_values(engine: &mut Engine, tag_id: TagId, count: usize) -> Vec<TagValue> {
+        let mut stats = Stats::disabled();
+        engine
+            .ensure_parent_tag(&mut stats, tag_id, Interval(0, count))
+            .unwrap();
+        engine.read_tag(tag_id, Interval(0, count)).to_vec()
+    }
+
+    fn direct_filter(
+        engine: &mut Engine,
         tag_id: TagId,
-        interval: Interval,
-    ) -> Result<()> {
-        let cache_opt = self.tag_caches.get(&tag_id);
-        let cache_bounds = cache_opt
-            .map(|cache| cache.bounds())
-            .unwrap_or(Interval(0, 0));
+        comp: Comparator,
+        value: &str,
+    ) -> FilterId {
+        let filter = Command::DirectFilter(
+            Id::Tag(tag_id),
+            comp,
+            CompareMode::Numeric,
+            value.to_string(),
+        );
+        match run(engine, filter).id {
+            Some(Id::Filter(filter_id)) => filter_id,
+            id => panic!("expected a filter, got {:?}", id),
+        }
+    }
+
+    #[test]
+    fn identical_filters_share_a_cache() {
+        let mut engine = Engine::new();
+        let file_id = load(&mut engine, "identical-filters", &["a 1", "b 2", "c 3"]);
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"(\d)");
+        let filter = Command::DirectFilter(
+            Id::Tag(tag_id),
+            Comparator::GreaterThan,
+            CompareMode::Numeric,
+            "1".to_string(),
+        );
+
+        let first = run(&mut engine, filter.clone()).id.unwrap();
+        assert_eq!(selected(&mut engine, first, 10), vec!["b 2", "c 3"]);
+        assert_eq!(run(&mut engine, filter).id, Some(first));
+        assert_eq!(engine.filter_caches.len(), 1);
+    }
+
+    #[test]
+    fn lua_filters_are_redefined_with_their_functions() {
+        let mut engine = Engine::new();
+        let file_id = load(&mut engine, "lua-filters", &["a 1", "b 2", "c 3"]);
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"(\d)");
+        let filter = Command::ScriptedFilter(Id::Tag(tag_id), "pick(chunk)".to_string());
+
+        run(
+            &mut engine,
+            Command::Script("function pick(n) return n == '1' end".to_string()),
+        );
+        let first = run(&mut engine, filter.clone()).id.unwrap();
+        assert_eq!(selected(&mut engine, first, 10), vec!["a 1"]);
+
+        run(
+            &mut engine,
+            Command::Script("function pick(n) return n == '3' end".to_string()),
+        );
+        let second = run(&mut engine, filter).id.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(selected(&mut engine, second, 10), vec!["c 3"]);
+    }
+
+    #[test]
+    fn plain_files_seek_between_interval_reads() {
+        let lines: Vec<String> = (0..5000).map(|idx| format!("line {}", idx)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let mut file = File::new(fixture("seek", &lines), None).unwrap();
+        let expected = |start: usize, end: usize| {
+            (start..end)
+                .map(|idx| format!("line {}\n", idx))
+                .collect::<Vec<String>>()
+        };
+
+        for start in (0..3000).step_by(1000) {
+            assert_eq!(
+                file.read(Interval(start, start + 1000)).unwrap(),
+                expected(start, start + 1000)
+            );
+        }
+        assert_eq!(
+            file.read(Interval(2990, 3010)).unwrap(),
+            expected(2990, 3010)
+        );
+        assert_eq!(file.read(Interval(10, 20)).unwrap(), expected(10, 20));
+        assert_eq!(
+            file.read(Interval(4990, 5000)).unwrap(),
+            expected(4990, 5000)
+        );
+    }
+
+    #[test]
+    fn gzip_files_read_backward_by_decompressing_again() {
+        let path =
+            std::env::temp_dir().join(format!("log-tags-{}-backward.log.gz", std::process::id()));
+        let mut encoder = flate2::write::GzEncoder::new(
+            fs::File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        );
+        for idx in 0..100 {
+            writeln!(encoder, "line {}", idx).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let mut file = File::new(path, None).unwrap();
+        assert_eq!(file.read(Interval(50, 51)).unwrap(), vec!["line 50\n"]);
+        assert_eq!(
+            file.read(Interval(0, 2)).unwrap(),
+            vec!["line 0\n", "line 1\n"]
+        );
+        assert_eq!(file.read(Interval(99, 100)).unwrap(), vec!["line 99\n"]);
+    }
+
+    #[test]
+    fn rotated_logs_read_backward_by_reading_again() {
+        let dir = std::env::temp_dir().join(format!("log-tags-{}-rotated", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(
+            fs::File::create(dir.join("app.log.1.gz")).unwrap(),
+            flate2::Compression::default(),
+        );
+        writeln!(encoder, "old 0\nold 1").unwrap();
+        encoder.finish().unwrap();
+        fs::write(dir.join("app.log"), "new 0\nnew 1\n").unwrap();
+
+        let mut file = File::new(dir.join("app.log*"), None).unwrap();
+        assert_eq!(file.read(Interval(3, 4)).unwrap(), vec!["new 1\n"]);
+        assert_eq!(
+            file.read(Interval(1, 3)).unwrap(),
+            vec!["old 1\n", "new 0\n"]
+        );
+    }
+
+    #[test]
+    fn filter_caches_extend_backward_and_forward() {
+        let mut engine = Engine::new();
+        let file_id = load(
+            &mut engine,
+            "filter-bounds",
+            &["1", "5", "2", "6", "3", "7"],
+        );
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"(\d)");
+        let filter_id = direct_filter(&mut engine, tag_id, Comparator::GreaterThan, "4");
+        let mut stats = Stats::disabled();
+        let passed = |engine: &Engine| engine.read_filter(filter_id).iter().collect::<Vec<usize>>();
+
+        // Left by a read starting at line 3, only the lines before it are missing
+        let cache = FilterCache {
+            start: 3,
+            end: 3,
+            ..FilterCache::default()
+        };
+        engine.filter_caches.insert(filter_id, cache);
+        engine
+            .ensure_filter(&mut stats, tag_id, filter_id, Interval(0, 3))
+            .unwrap();
+        assert_eq!(engine.filter_caches[&filter_id].bounds(), Interval(0, 3));
+        assert_eq!(passed(&engine), vec![1]);
+
+        engine
+            .ensure_filter(&mut stats, tag_id, filter_id, Interval(2, 6))
+            .unwrap();
+        assert_eq!(engine.filter_caches[&filter_id].bounds(), Interval(0, 6));
+        assert_eq!(passed(&engine), vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn filter_cache_extend_keeps_the_other_bound() {
+        let mut cache = FilterCache {
+            start: 4,
+            end: 8,
+            loaded: vec![5].into_iter().collect(),
+        };
+        cache.extend(Interval(2, 6), Some(vec![2].into_iter().collect()), None);
+        assert_eq!(cache.bounds(), Interval(2, 8));
+        assert_eq!(cache.count(), 2);
+
+        cache.extend(Interval(6, 10), None, Some(vec![9].into_iter().collect()));
+        assert_eq!(cache.bounds(), Interval(2, 10));
+        assert_eq!(cache.loaded.iter().collect::<Vec<usize>>(), vec![2, 5, 9]);
+    }
+
+    #[test]
+    fn column_tags_slice_characters() {
+        let mut engine = Engine::new();
+        let lines = ["2024 INFO  ok", "2024 WARN", "2024", "été ÉRROR ü"];
+        let file_id = load(&mut engine, "column-tags", &lines);
+        let tag_id = tag(&mut engine, file_id, "level");
+        run(&mut engine, Command::Columns(tag_id, 4, 9));
+
+        assert_eq!(
+            tag_values(&mut engine, tag_id, 4),
+            vec![
+                Some(" INFO".to_string()),
+                Some(" WARN".to_string()),
+                None,
+                Some("ÉRROR".to_string()),
+            ]
+        );
+        assert_eq!(Extractor::columns("ab\r\n", 1, 2), Some("b"));
+        assert_eq!(Extractor::columns("ab\n", 1, 3), None);
+    }
+
+    #[test]
+    fn materialized_subsets_are_queried_like_files() {
+        let mut engine = Engine::new();
+        let lines = ["GET /a 200", "GET /b 500", "POST /c 500", "POST /d 200"];
+        let file_id = load(&mut engine, "materialize", &lines);
+        let status = regex_tag(&mut engine, file_id, "status", r" (\d{3})");
+        let errors = direct_filter(&mut engine, status, Comparator::Equal, "500");
+
+        let subset = match run(&mut engine, Command::Materialize(Id::Filter(errors))).id {
+            Some(Id::File(subset)) => subset,
+            id => panic!("expected a file, got {:?}", id),
+        };
+        assert_eq!(
+            selected(&mut engine, Id::File(subset), 10),
+            vec!["GET /b 500", "POST /c 500"]
+        );
+
+        let method = regex_tag(&mut engine, subset, "method", r"^(\w+)");
+        let posts = Command::DirectFilter(
+            Id::Tag(method),
+            Comparator::Equal,
+            CompareMode::Lexical,
+            "POST".to_string(),
+        );
+        let posts = run(&mut engine, posts).id.unwrap();
+        assert_eq!(selected(&mut engine, posts, 10), vec!["POST /c 500"]);
+    }
+
+    fn range(engine: &mut Engine, id: Id, start: usize, end: usize) -> Id {
+        run(engine, Command::Range(id, start, end)).id.unwrap()
+    }
+
+    #[test]
+    fn ranges_select_lines_by_position() {
+        let mut engine = Engine::new();
+        let lines = ["0 a", "1 b", "2 a", "3 b", "4 a", "5 b"];
+        let file_id = load(&mut engine, "ranges", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "letter", r" (\w)");
+
+        let middle = range(&mut engine, Id::File(file_id), 1, 4);
+        assert_eq!(selected(&mut engine, middle, 10), vec!["1 b", "2 a", "3 b"]);
+
+        let empty = range(&mut engine, Id::File(file_id), 3, 3);
+        assert!(selected(&mut engine, empty, 10).is_empty());
+
+        let past_end = range(&mut engine, Id::File(file_id), 4, 100);
+        assert_eq!(selected(&mut engine, past_end, 10), vec!["4 a", "5 b"]);
+        let after_end = range(&mut engine, Id::File(file_id), 50, 100);
+        assert!(selected(&mut engine, after_end, 10).is_empty());
+
+        let filter = Command::DirectFilter(
+            Id::Tag(tag_id),
+            Comparator::Equal,
+            CompareMode::Lexical,
+            "a".to_string(),
+        );
+        let a = run(&mut engine, filter).id.unwrap();
+        let filtered = range(&mut engine, a, 1, 4);
+        assert_eq!(selected(&mut engine, filtered, 10), vec!["2 a"]);
+    }
+
+    #[test]
+    fn distinct_keeps_values_the_bloom_reports_as_seen() {
+        // Fill a bloom until a value it never saw is reported as seen
+        let mut bloom = ethbloom::Bloom::zero();
+        let mut values = vec![];
+        let collision = (0..)
+            .map(|idx| format!("value {}", idx))
+            .find(|value| {
+                if bloom.contains_input(ethbloom::Input::Raw(value.as_bytes())) {
+                    return true;
+                }
+                bloom.accrue(ethbloom::Input::Raw(value.as_bytes()));
+                values.push(Some(value.clone()));
+                false
+            })
+            .unwrap();
+        values.push(Some(collision));
+        values.push(values[0].clone());
+
+        let (passed, read) = Engine::distinct_values(
+            &mut ethbloom::Bloom::zero(),
+            &mut HashSet::new(),
+            &values,
+            0,
+            usize::MAX,
+            0,
+        );
+        assert_eq!(read, values.len());
+        assert_eq!(
+            passed.iter().collect::<Vec<usize>>(),
+            (0..values.len() - 1).collect::<Vec<usize>>()
+        );
+    }
+
+    #[test]
+    fn groups_order_by_count_with_ties_by_value() {
+        let mut engine = Engine::new();
+        let lines = ["b", "a", "c", "b", "a", "d", "b", "c"];
+        let file_id = load(&mut engine, "group-order", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "letter", r"(\w)");
+        let group = |engine: &mut Engine, order| {
+            let group = Command::Group(tag_id, Aggregator::Count, None, order);
+            let group_id = match run(engine, group).id {
+                Some(Id::Group(group_id)) => group_id,
+                id => panic!("expected a group, got {:?}", id),
+            };
+            engine.groups[&group_id]
+                .buckets
+                .iter()
+                .map(|(value, count)| (value.clone(), count.unwrap()))
+                .collect::<Vec<(String, f64)>>()
+        };
+        let buckets = |order: &[(&str, f64)]| {
+            order
+                .iter()
+                .map(|(value, count)| (value.to_string(), *count))
+                .collect::<Vec<(String, f64)>>()
+        };
+
+        assert_eq!(
+            group(&mut engine, GroupOrder::Descending),
+            buckets(&[("b", 3.0), ("a", 2.0), ("c", 2.0), ("d", 1.0)])
+        );
+        assert_eq!(
+            group(&mut engine, GroupOrder::Ascending),
+            buckets(&[("d", 1.0), ("a", 2.0), ("c", 2.0), ("b", 3.0)])
+        );
+    }
+
+    #[test]
+    fn output_limit_truncates_long_lines() {
+        let mut engine = Engine::new();
+        engine.set_separator(None);
+        engine.set_output_limit(250);
+        let line = "x".repeat(100);
+        let file_id = load(&mut engine, "output-limit", &[line.as_str(); 10]);
+
+        let output = run(&mut engine, Command::Take(Id::File(file_id), 10));
+        let (marker, rows) = output.lines.split_last().unwrap();
+        assert_eq!(marker, "output truncated at 250 bytes");
+        assert_eq!(rows.len(), 3);
+        assert!(rows[2].len() < line.len());
+        assert!(rows.iter().map(|row| row.len() + 1).sum::<usize>() <= 250);
+    }
+
+    #[test]
+    fn sandboxed_lua_has_no_os_library() {
+        let check = Command::Script("assert(type(os.execute) == 'function')".to_string());
+
+        let mut engine = Engine::new();
+        assert!(engine.run_command(&check).is_ok());
+
+        engine.sandbox_lua().unwrap();
+        assert!(engine.run_command(&check).is_err());
+        let dofile = Command::Script("assert(dofile == nil and io == nil)".to_string());
+        assert!(engine.run_command(&dofile).is_ok());
+    }
+
+    #[test]
+    fn hexdump_shows_crlf_line_endings() {
+        let mut engine = Engine::new();
+        let file_id = load(&mut engine, "hexdump", &["first\r", "ok\r", "last"]);
+
+        let output = run(&mut engine, Command::Hexdump(file_id, 1));
+        assert_eq!(
+            output.lines,
+            vec!["00000000  6f 6b 0d 0a                                       |ok..|"]
+        );
+    }
+
+    #[test]
+    fn transforms_compose_in_order() {
+        let mut engine = Engine::new();
+        let file_id = load(&mut engine, "transforms", &["[ a ]", "[b]"]);
+        let tag_id = regex_tag(&mut engine, file_id, "value", r"\[(.*)\]");
+        for transform in &["trim(chunk) .. 'x'", "string.upper(chunk)"] {
+            run(
+                &mut engine,
+                Command::Transform(tag_id, transform.to_string()),
+            );
+        }
+        assert_eq!(
+            tag_values(&mut engine, tag_id, 2),
+            vec![Some("AX".to_string()), Some("BX".to_string())]
+        );
+
+        let retag = Command::Retag(tag_id, r"\[(.*)\]".to_string(), None);
+        run(&mut engine, retag);
+        assert_eq!(
+            tag_values(&mut engine, tag_id, 2),
+            vec![Some(" a ".to_string()), Some("b".to_string())]
+        );
+    }
 
-        if cache_bounds.contains(interval) {
-            stats.add_size(
+    #[test]
+    fn retagging_drops_the_caches_of_filters_on_the_tag() {
+        let mut engine = Engine::new();
+        let file_id = load(&mut engine, "retag", &["a=1 b=5", "a=7 b=2", "a=3 b=9"]);
+        let tag_id = regex_tag(&mut engine, file_id, "value", r"a=(\d+)");
+        let filter_id = direct_filter(&mut engine, tag_id, Comparator::GreaterThan, "2");
+        assert_eq!(
+            selected(&mut engine, Id::Filter(filter_id), 10),
+            vec!["a=7 b=2", "a=3 b=9"]
+        );
+        assert!(engine.filter_caches.contains_key(&filter_id));
+
+        run(
+            &mut engine,
+            Command::Retag(tag_id, r"b=(\d+)".to_string(), None),
+        );
+        assert!(!engine.filter_caches.contains_key(&filter_id));
+        assert_eq!(
+            selected(&mut engine, Id::Filter(filter_id), 10),
+            vec!["a=1 b=5", "a=3 b=9"]
+        );
+    }
+
+    #[test]
+    fn lag_filters_compare_with_earlier_lines() {
+        let mut engine = Engine::new();
+        let lines = ["0 30", "1 10", "2 10", "3 25", "4 10", "5 10", "6 12"];
+        let file_id = load(&mut engine, "lag", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "latency", r" (\d+)");
+        let lag = |engine: &mut Engine, factor| {
+            let lag = Command::LagFilter(
                 Id::Tag(tag_id),
-                cache_opt.map(|cache| cache.size()).unwrap_or(0),
+                2,
+                factor,
+                Comparator::GreaterThan,
+                CompareMode::Numeric,
             );
-            return Ok(());
-        }
+            let filter_id = run(engine, lag).id.unwrap();
+            selected(engine, filter_id, 10)
+        };
 
-        let tag = self
-            .tags
-            .get(&tag_id)
-            .ok_or_else(|| Error::MissingId(Id::Tag(tag_id)))?;
+        assert_eq!(lag(&mut engine, 2.0), vec!["3 25"]);
+        assert_eq!(lag(&mut engine, 1.0), vec!["3 25", "6 12"]);
+    }
 
-        let mut prefix = None;
-        let mut suffix = None;
+    #[test]
+    fn written_rows_are_never_colored() {
+        let mut engine = Engine::new();
+        engine.set_color(true);
+        let file_id = load(&mut engine, "write-color", &["[error] failed", "[info] ok"]);
+        regex_tag(&mut engine, file_id, "level", r"\[(\w+)\]");
+        let colored = run(&mut engine, Command::Take(Id::File(file_id), 1));
+        assert!(colored.lines[0].contains(MATCH_COLOR));
+
+        let path = fixture("write-color-out", &[]);
+        let write = Command::Write(Id::File(file_id), path.clone(), 2, true);
+        run(&mut engine, write);
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.contains("[error] failed"));
+        assert!(!written.contains('\x1b'));
+        assert!(engine.color);
+    }
 
-        let missing_before = cache_bounds.missing_before(interval);
-        if !missing_before.is_empty() {
-            stats.add_interval(Id::Tag(tag_id), missing_before);
-            let lines = self.read_lines(file_id, missing_before);
-            prefix = Some(Engine::parse_tag_from_lines(&self.lua, tag, lines));
-        }
+    #[test]
+    fn separators_are_the_only_lines_between_rows() {
+        let take = |separator: Option<&str>| {
+            let mut engine = Engine::new();
+            engine.set_separator(separator.map(str::to_string));
+            let file_id = load(&mut engine, "separators", &["a", "b"]);
+            run(&mut engine, Command::Take(Id::File(file_id), 2)).lines
+        };
 
-        let missing_after = cache_bounds.missing_after(interval);
-        if !missing_after.is_empty() {
-            stats.add_interval(Id::Tag(tag_id), missing_after);
-            let lines = self.read_lines(file_id, missing_after);
-            suffix = Some(Engine::parse_tag_from_lines(&self.lua, tag, lines));
-        }
+        assert_eq!(take(Some("")), vec!["a", "", "b", ""]);
+        assert_eq!(take(None), vec!["a", "b"]);
+        assert_eq!(take(Some("---")), vec!["a", "---", "b", "---"]);
+    }
 
-        let cache = self
-            .tag_caches
-            .entry(tag_id)
-            .or_insert_with(TagCache::default);
+    #[test]
+    fn unloading_an_operand_drops_combined_filters() {
+        let mut engine = Engine::new();
+        let file_id = load(&mut engine, "unload-operand", &["1", "2", "3"]);
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"(\d)");
+        let a = direct_filter(&mut engine, tag_id, Comparator::Equal, "1");
+        let b = direct_filter(&mut engine, tag_id, Comparator::Equal, "3");
+        let ab = run(&mut engine, Command::Or(Id::Filter(a), Id::Filter(b)))
+            .id
+            .unwrap();
+        let ab_skip = run(&mut engine, Command::Skip(ab, 1)).id.unwrap();
+        assert_eq!(selected(&mut engine, ab, 10), vec!["1", "3"]);
+
+        run(&mut engine, Command::Unload(Id::Filter(b)));
+        assert!(!engine.contains(ab));
+        assert!(!engine.contains(ab_skip));
+        assert!(engine.contains(Id::Filter(a)));
+        assert_eq!(selected(&mut engine, Id::Filter(a), 10), vec!["1"]);
+    }
 
-        if let Some(mut prefix) = prefix {
-            prefix.extend(cache.loaded.iter().cloned());
-            cache.loaded = prefix;
-            cache.start = interval.0;
+    #[test]
+    fn split_regex_indexes_fields_between_runs_of_whitespace() {
+        let mut engine = Engine::new();
+        let lines = ["GET  /a\t200", "POST /b   500", "PUT"];
+        let file_id = load(&mut engine, "split-regex", &lines);
+        let tag_id = tag(&mut engine, file_id, "status");
+        run(
+            &mut engine,
+            Command::SplitRegex(tag_id, r"\s+".to_string(), 2),
+        );
+
+        assert_eq!(
+            tag_values(&mut engine, tag_id, 3),
+            vec![Some("200".to_string()), Some("500".to_string()), None]
+        );
+    }
+
+    #[test]
+    fn interrupts_fail_whole_file_commands_once() {
+        let mut engine = Engine::new();
+        let file_id = load(&mut engine, "interrupt", &["a", "b", "a"]);
+        let tag_id = regex_tag(&mut engine, file_id, "letter", r"(\w)");
+
+        // A Ctrl-C pressed between commands doesn't stop the next one
+        engine
+            .interrupt_handle()
+            .store(true, atomic::Ordering::SeqCst);
+        let output = run(&mut engine, Command::Top(tag_id, 2));
+        assert!(!output.lines.is_empty());
+
+        engine
+            .interrupt_handle()
+            .store(true, atomic::Ordering::SeqCst);
+        match engine.check_interrupted() {
+            Err(Error::Interrupted) => {}
+            result => panic!("expected an interruption, got {:?}", result),
         }
+        assert!(engine.check_interrupted().is_ok());
+    }
 
-        if let Some(suffix) = suffix {
-            cache.loaded.extend(suffix.into_iter());
+    #[test]
+    fn sorts_put_numbers_before_text_and_reject_earlier_skips() {
+        let mut engine = Engine::new();
+        let lines = ["v=10", "v=b", "v=9", "none", "v=NaN", "v=a", "v=-1"];
+        let file_id = load(&mut engine, "sort", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "v", r"v=(\S+)");
+
+        let sorted = run(
+            &mut engine,
+            Command::Sort(Id::File(file_id), tag_id, Order::Ascending),
+        );
+        assert_eq!(
+            selected(&mut engine, sorted.id.unwrap(), 10),
+            vec!["v=-1", "v=9", "v=10", "v=NaN", "v=a", "v=b", "none"]
+        );
+        let sorted = run(
+            &mut engine,
+            Command::Sort(Id::File(file_id), tag_id, Order::Descending),
+        );
+        assert_eq!(
+            selected(&mut engine, sorted.id.unwrap(), 10),
+            vec!["v=b", "v=a", "v=NaN", "v=10", "v=9", "v=-1", "none"]
+        );
+
+        let skip_id = run(&mut engine, Command::Skip(Id::File(file_id), 2))
+            .id
+            .unwrap();
+        match engine.run_command(&Command::Sort(skip_id, tag_id, Order::Ascending)) {
+            Err(Error::InvalidArgument(_)) => {}
+            result => panic!(
+                "expected an invalid argument, got {:?}",
+                result.map(|o| o.lines)
+            ),
         }
+    }
 
-        stats.add_size(Id::Tag(tag_id), cache.size());
-        Ok(())
+    #[test]
+    fn last_rows_are_read_backward_from_the_end() {
+        let mut engine = Engine::new();
+        let lines: Vec<String> = (0..3000)
+            .map(|idx| format!("{} code={}", idx, idx % 7))
+            .collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let file_id = load(&mut engine, "take-last", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "code", r"code=(\d+)");
+        let filter_id = direct_filter(&mut engine, tag_id, Comparator::Equal, "3");
+        let last = |engine: &mut Engine, id: Id, count: usize| {
+            let mut rows = engine.take_iter(id, count).unwrap();
+            rows.raw = true;
+            rows.last = true;
+            let lines: Vec<String> = rows
+                .by_ref()
+                .map(|line| line.unwrap().trim_end().to_string())
+                .collect();
+            rows.finish();
+            lines
+        };
+
+        assert_eq!(
+            last(&mut engine, Id::Filter(filter_id), 2),
+            vec!["2992 code=3", "2999 code=3"]
+        );
+        // Only the last batch was read
+        assert_eq!(engine.file_caches[&file_id].bounds(), Interval(1976, 3000));
+        assert_eq!(
+            engine.filter_caches[&filter_id].bounds(),
+            Interval(1976, 3000)
+        );
+
+        // Caches that don't reach the start are started over by a forward take
+        assert_eq!(
+            selected(&mut engine, Id::Filter(filter_id), 2),
+            vec!["3 code=3", "10 code=3"]
+        );
+
+        // A skip of all but the first row still reads back to the start
+        let skip_id = run(&mut engine, Command::Skip(Id::Filter(filter_id), 428))
+            .id
+            .unwrap();
+        assert_eq!(last(&mut engine, skip_id, 5), vec!["2999 code=3"]);
     }
 
-    fn ensure_all_tags(
-        &mut self,
-        stats: &mut Stats,
-        file_id: FileId,
-        interval: Interval,
-    ) -> Result<()> {
-        for tag_id in self.file_to_tags(file_id) {
-            self.ensure_tag(stats, file_id, tag_id, interval)?;
+    #[test]
+    fn custom_filters_need_a_defined_function() {
+        let mut engine = Engine::new();
+        let file_id = load(&mut engine, "custom", &["a 1", "b 2", "c 3"]);
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"(\d)");
+        let filter = Command::CustomFilter(Id::Tag(tag_id), "above".to_string(), "1".to_string());
+
+        match engine.run_command(&filter) {
+            Err(Error::InvalidArgument(_)) => {}
+            result => panic!(
+                "expected an invalid argument, got {:?}",
+                result.map(|o| o.id)
+            ),
         }
-        Ok(())
+        run(
+            &mut engine,
+            Command::Script(
+                "function above(l, r) return tonumber(l) > tonumber(r) end".to_string(),
+            ),
+        );
+        let id = run(&mut engine, filter).id.unwrap();
+        assert_eq!(selected(&mut engine, id, 10), vec!["b 2", "c 3"]);
     }
 
-    fn read_tag(&self, tag_id: TagId, interval: Interval) -> &[TagValue] {
-        &self.tag_caches[&tag_id].loaded[interval.0..interval.1]
+    #[test]
+    fn failed_takes_still_evict_their_caches() {
+        let mut engine = Engine::new();
+        engine.set_cache_budget(0);
+        let file_id = load(&mut engine, "failed-take", &["a 1", "b 2", "c 3"]);
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"(\d)");
+        let test = "chunk ~= '3' or error('boom')".to_string();
+        let id = run(&mut engine, Command::ScriptedFilter(Id::Tag(tag_id), test))
+            .id
+            .unwrap();
+
+        assert!(engine.run_command(&Command::Take(id, 10)).is_err());
+        assert_eq!(engine.total_cache_size(), 0);
     }
 
-    fn read_all_tags(&self, file_id: FileId, interval: Interval) -> Vec<(String, &[TagValue])> {
-        let tags_name_and_id = self
-            .file_to_tags(file_id)
-            .into_iter()
-            .map(|tag_id| (self.tags[&tag_id].name.clone(), tag_id))
-            .collect::<Vec<(String, TagId)>>();
+    #[test]
+    fn distincts_recompute_their_evicted_parent_tags() {
+        let mut engine = Engine::new();
+        engine.set_cache_budget(0);
+        let lines = ["v=1", "v=2", "v=1", "v=3", "v=2", "v=4"];
+        let file_id = load(&mut engine, "distinct-evicted", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "v", r"v=(\d+)");
+        let distinct = run(&mut engine, Command::Distinct(Id::Tag(tag_id)))
+            .id
+            .unwrap();
+
+        let first = selected(&mut engine, distinct, 10);
+        assert_eq!(engine.total_cache_size(), 0);
+        assert_eq!(first, vec!["v=1", "v=2", "v=3", "v=4"]);
+        assert_eq!(selected(&mut engine, distinct, 10), first);
+    }
 
-        let mut result = Vec::with_capacity(tags_name_and_id.len());
-        for (name, tag_id) in tags_name_and_id {
-            result.push((name, self.read_tag(tag_id, interval)));
-        }
-        result
+    #[test]
+    fn stats_display_bound_names_and_fall_back_to_ids() {
+        let mut engine = Engine::new_debug();
+        let file_id = load(&mut engine, "named-stats", &["a 1", "b 2", "c 3"]);
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"(\d)");
+        let output = run(&mut engine, Command::Take(Id::Tag(tag_id), 2));
+        let mut names = Names::new();
+        names.insert(Id::Tag(tag_id), "n".to_string());
+
+        let named = output.stats.named(&names).to_string();
+        assert!(named.contains("  'n: [[0, 2), ]\n"));
+        assert!(named.contains(&format!("  {:?}: [[0, 2), ]\n", file_id)));
+
+        let unnamed = output.stats.to_string();
+        assert!(!unnamed.contains("'n"));
+        assert!(unnamed.contains(&format!("  {:?}: [[0, 2), ]\n", tag_id)));
     }
 
-    fn ensure_filter(
-        &mut self,
-        stats: &mut Stats,
-        tag_id: TagId,
-        filter_id: FilterId,
-        interval: Interval,
-    ) -> Result<()> {
-        let cache_opt = self.filter_caches.get(&filter_id);
-        let cache_bounds = cache_opt
-            .map(|cache| cache.bounds())
-            .unwrap_or(Interval(0, 0));
+    #[test]
+    fn sparse_tags_blank_repeated_values() {
+        let mut engine = Engine::new();
+        engine.set_sparse_tags(true);
+        let lines = ["a 1", "a 2", "b 3", "a 4"];
+        let file_id = load(&mut engine, "sparse-tags", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "letter", r"([a-z])");
 
-        if cache_bounds.contains(interval) {
-            stats.add_size(
-                Id::Filter(filter_id),
-                cache_opt.map(|cache| cache.size()).unwrap_or(0),
-            );
-            return Ok(());
-        }
+        let rows = run(&mut engine, Command::Take(Id::Tag(tag_id), 10)).lines;
+        let values: Vec<&str> = rows
+            .iter()
+            .filter(|row| row.trim_start().starts_with("[letter]"))
+            .map(|row| row.trim())
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                "[letter]        \"a\"",
+                "[letter]",
+                "[letter]        \"b\"",
+                "[letter]        \"a\"",
+            ]
+        );
+    }
 
-        let filter = self
-            .filters
-            .get(&filter_id)
-            .ok_or_else(|| Error::MissingId(Id::Filter(filter_id)))?;
+    #[test]
+    fn in_filters_keep_the_values_of_a_top_set() {
+        let mut engine = Engine::new();
+        let lines = [
+            "200 /a", "404 /b", "200 /c", "500 /d", "301 /e", "404 /f", "200 /g", "500 /h",
+            "302 /i",
+        ];
+        let file_id = load(&mut engine, "top-in", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "status", r"(\d+)");
+        let set_id = run(&mut engine, Command::Top(tag_id, 3)).id.unwrap();
+        let set_id = match set_id {
+            Id::Set(set_id) => set_id,
+            id => panic!("expected a set, got {:?}", id),
+        };
 
-        let mut prefix = None;
-        let mut suffix = None;
+        let filter = Command::InFilter(Id::Tag(tag_id), set_id);
+        let filter_id = run(&mut engine, filter).id.unwrap();
+        assert_eq!(
+            selected(&mut engine, filter_id, 10),
+            vec!["200 /a", "404 /b", "200 /c", "500 /d", "404 /f", "200 /g", "500 /h"]
+        );
+    }
 
-        let missing_before = cache_bounds.missing_before(interval);
-        if !missing_before.is_empty() {
-            stats.add_interval(Id::Filter(filter_id), missing_before);
-            let tag_values = self.read_tag(tag_id, missing_before);
-            prefix = Some(Engine::filter_values(
-                &self.lua,
-                filter,
-                tag_values,
-                missing_before.0,
-            )?)
-        }
+    #[test]
+    fn stats_json_lists_sizes_and_intervals_by_id() {
+        let mut engine = Engine::new_debug();
+        let file_id = load(&mut engine, "json-stats", &["a 1", "b 2", "c 3"]);
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"(\d)");
+        let output = run(&mut engine, Command::Take(Id::Tag(tag_id), 3));
+        let mut names = Names::new();
+        names.insert(Id::Tag(tag_id), "n".to_string());
+
+        let json = output.stats.to_json(&names).unwrap().to_string();
+        let stats: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let file_key = format!("{:?}", file_id);
+        assert_eq!(
+            stats["intervals"]["tags"]["'n"],
+            serde_json::json!([[0, 3]])
+        );
+        assert_eq!(
+            stats["intervals"]["files"][&file_key],
+            serde_json::json!([[0, 3]])
+        );
+        assert!(stats["sizes"]["tags"]["'n"].as_u64().unwrap() > 0);
+        assert!(stats["sizes"]["files"][&file_key].as_u64().unwrap() > 0);
+        assert!(Stats::disabled().to_json(&names).is_none());
+    }
 
-        let missing_after = cache_bounds.missing_after(interval);
-        if !missing_after.is_empty() {
-            stats.add_interval(Id::Filter(filter_id), missing_after);
-            let tag_values = self.read_tag(tag_id, missing_after);
-            suffix = Some(Engine::filter_values(
-                &self.lua,
-                filter,
-                tag_values,
-                missing_after.0,
-            )?)
-        }
+    #[test]
+    fn large_skips_land_on_the_right_line() {
+        let mut engine = Engine::new();
+        let lines: Vec<String> = (0..20_000).map(|idx| format!("{} x", idx)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let file_id = load(&mut engine, "large-skip", &lines);
+        let skip = run(&mut engine, Command::Skip(Id::File(file_id), 12_345))
+            .id
+            .unwrap();
+        assert_eq!(selected(&mut engine, skip, 2), vec!["12345 x", "12346 x"]);
+
+        // Skipped rows are counted after filtering, not as lines of the file
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"(\d+)");
+        let even = Command::ScriptedFilter(Id::Tag(tag_id), "tonumber(chunk) % 2 == 0".to_string());
+        let filter_id = run(&mut engine, even).id.unwrap();
+        let skip = run(&mut engine, Command::Skip(filter_id, 5_000))
+            .id
+            .unwrap();
+        assert_eq!(selected(&mut engine, skip, 2), vec!["10000 x", "10002 x"]);
+    }
 
-        let cache = self
-            .filter_caches
-            .entry(filter_id)
-            .or_insert_with(FilterCache::default);
+    #[test]
+    fn tags_of_follow_added_and_unloaded_tags() {
+        let mut engine = Engine::new();
+        let file_id = load(&mut engine, "tags-of", &["a 1", "b 2"]);
+        let other_id = load(&mut engine, "tags-of-other", &["c 3"]);
+        let letter = regex_tag(&mut engine, file_id, "letter", r"(\w)");
+        let number = regex_tag(&mut engine, file_id, "number", r"(\d)");
+        let other = regex_tag(&mut engine, other_id, "other", r"(\w)");
+        assert_eq!(engine.tags_of(file_id), &[letter, number]);
+        assert_eq!(engine.tags_of(other_id), &[other]);
+
+        run(&mut engine, Command::Unload(Id::Tag(letter)));
+        assert_eq!(engine.tags_of(file_id), &[number]);
+        let again = regex_tag(&mut engine, file_id, "letter", r"(\w)");
+        assert_eq!(engine.tags_of(file_id), &[number, again]);
+
+        run(&mut engine, Command::Unload(Id::File(file_id)));
+        assert!(engine.tags_of(file_id).is_empty());
+        assert_eq!(engine.tags_of(other_id), &[other]);
+    }
 
-        if let Some(mut prefix) = prefix {
-            prefix.union_with(&cache.loaded);
-            cache.loaded = prefix;
-            cache.start = interval.0;
-        }
+    #[test]
+    fn takes_end_at_the_end_of_short_files() {
+        let mut engine = Engine::new();
+        let file_id = load(&mut engine, "never-matching", &["a 1", "b 2", "c 3"]);
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"(\d)");
+        let filter_id = direct_filter(&mut engine, tag_id, Comparator::GreaterThan, "100");
 
-        if let Some(suffix) = suffix {
-            cache.loaded.union_with(&suffix);
-            cache.end = interval.1;
-        }
+        assert!(selected(&mut engine, Id::Filter(filter_id), 10).is_empty());
+        assert!(selected(&mut engine, Id::Filter(filter_id), MAX_BATCH_SIZE * 4).is_empty());
+        assert_eq!(selected(&mut engine, Id::Tag(tag_id), 10).len(), 3);
+    }
 
-        stats.add_size(Id::Filter(filter_id), cache.size());
-        Ok(())
+    #[test]
+    fn substring_comparisons_are_case_sensitive_unless_ignoring_case() {
+        let contains = |mode, left, right| Engine::compare(Comparator::Contains, mode, left, right);
+        let not_contains =
+            |mode, left, right| Engine::compare(Comparator::NotContains, mode, left, right);
+
+        assert!(contains(CompareMode::Lexical, "GET /Index", "Index"));
+        assert!(!contains(CompareMode::Lexical, "GET /Index", "index"));
+        assert!(not_contains(CompareMode::Lexical, "GET /Index", "index"));
+        assert!(contains(CompareMode::IgnoreCase, "GET /Index", "index"));
+        assert!(!not_contains(
+            CompareMode::IgnoreCase,
+            "GET /Index",
+            "INDEX"
+        ));
+
+        // Every value contains the empty string, even an empty one
+        assert!(contains(CompareMode::Lexical, "GET", ""));
+        assert!(contains(CompareMode::Lexical, "", ""));
+        assert!(!not_contains(CompareMode::Lexical, "GET", ""));
     }
 
-    fn read_filter(&self, filter_id: FilterId) -> &bit_set::BitSet {
-        &self.filter_caches[&filter_id].loaded
+    #[test]
+    fn null_safe_equal_only_matches_two_missing_values() {
+        let mut engine = Engine::new();
+        let lines = ["a=1 b=1", "a=2", "b=3", "none"];
+        let file_id = load(&mut engine, "null-safe", &lines);
+        let a = regex_tag(&mut engine, file_id, "a", r"a=(\d)");
+        let b = regex_tag(&mut engine, file_id, "b", r"b=(\d)");
+        selected(&mut engine, Id::File(file_id), 10);
+
+        let rate = Command::MatchRate(a, b, Comparator::NullSafeEqual, CompareMode::Lexical);
+        assert_eq!(
+            run(&mut engine, rate).lines,
+            vec![
+                "match rate: 50.00% (2/4) over [0, 4)",
+                "mismatches: 2",
+                "mismatched lines: 1, 2",
+            ]
+        );
+
+        let value = Some("1".to_string());
+        let compare = |comp, left: &TagValue, right: &TagValue| {
+            Engine::compare_values(comp, CompareMode::Lexical, left, right)
+        };
+        assert!(compare(Comparator::NullSafeEqual, &None, &None));
+        assert!(!compare(Comparator::NullSafeEqual, &value, &None));
+        assert!(!compare(Comparator::NullSafeEqual, &None, &value));
+        assert!(!compare(Comparator::Equal, &None, &None));
     }
 
-    fn ensure_distinct(
-        &mut self,
-        stats: &mut Stats,
-        tag_id: TagId,
-        distinct_id: DistinctId,
-        interval: Interval,
-    ) -> Result<()> {
-        let cache_opt = self.distinct_caches.get(&distinct_id);
-        let cache_bounds = cache_opt
-            .map(|cache| cache.bounds())
-            .unwrap_or(Interval(0, 0));
+    #[test]
+    fn ignore_case_only_folds_ascii_letters() {
+        let equal =
+            |left, right| Engine::compare(Comparator::Equal, CompareMode::IgnoreCase, left, right);
+
+        assert!(equal("ERROR", "error"));
+        assert!(equal("Caf\u{e9}", "cAF\u{e9}"));
+        assert!(!equal("CAF\u{c9}", "caf\u{e9}"));
+        assert!(!equal("STRASSE", "stra\u{df}e"));
+        assert!(Engine::compare(
+            Comparator::LessThan,
+            CompareMode::IgnoreCase,
+            "apple",
+            "BANANA"
+        ));
+    }
 
-        if cache_bounds.contains(interval) {
-            stats.add_size(
-                Id::Distinct(distinct_id),
-                cache_opt.map(|cache| cache.size()).unwrap_or(0),
-            );
-            return Ok(());
+    #[test]
+    fn anchored_tag_regexes_only_match_at_line_start() {
+        let lines = ["level=info a", "b level=warn", "level=error c"];
+        let values = |anchored: bool, regex: &str| {
+            let mut engine = Engine::new();
+            engine.set_anchor_tag_regexes(anchored);
+            let file_id = load(&mut engine, "anchored", &lines);
+            // Patterns are anchored as the interpreter builds the command
+            let regex = engine.tag_regex(regex.to_string());
+            let tag_id = regex_tag(&mut engine, file_id, "level", &regex);
+            tag_values(&mut engine, tag_id, 3)
+        };
+        let level = |value: &str| Some(value.to_string());
+
+        assert_eq!(
+            values(false, r"level=(\w+)"),
+            vec![level("info"), level("warn"), level("error")]
+        );
+        assert_eq!(
+            values(true, r"level=(\w+)"),
+            vec![level("info"), None, level("error")]
+        );
+        for regex in &[r"^level=(\w+)", r"\Alevel=(\w+)"] {
+            assert_eq!(values(true, regex), values(false, regex));
+            assert_eq!(values(true, regex), values(true, r"level=(\w+)"));
         }
+    }
 
-        let mut bloom = self
-            .distinct_caches
-            .get(&distinct_id)
-            .map(|cache| cache.bloom)
-            .unwrap_or_else(ethbloom::Bloom::zero);
-
-        let mut prefix = None;
-        let mut suffix = None;
-
-        let missing_before = cache_bounds.missing_before(interval);
-        if !missing_before.is_empty() {
-            stats.add_interval(Id::Distinct(distinct_id), missing_before);
-            let tag_values = self.read_tag(tag_id, missing_before);
-            prefix = Some(Engine::distinct_values(
-                &mut bloom,
-                tag_values,
-                missing_before.0,
+    #[test]
+    fn sizes_compare_across_units() {
+        let less =
+            |left, right| Engine::compare(Comparator::LessThan, CompareMode::Size, left, right);
+
+        assert!(less("512KB", "1MB"));
+        assert!(!less("1MB", "512KB"));
+        assert!(!less("1000KiB", "1MB"));
+        assert!(less("1MB", "1MiB"));
+        assert!(less("2 gb", "3G"));
+        assert!(Engine::compare(
+            Comparator::Equal,
+            CompareMode::Size,
+            "1.5KB",
+            "1500"
+        ));
+        assert!(Engine::compare(
+            Comparator::Equal,
+            CompareMode::Size,
+            "1kib",
+            "1024B"
+        ));
+
+        assert_eq!(parse_size("1.5 MiB"), Some(1.5 * 1024.0 * 1024.0));
+        for malformed in &["", "MB", "12XB", "1.2.3KB", "-1KB", "1 KB extra"] {
+            assert_eq!(parse_size(malformed), None, "{}", malformed);
+            assert!(!less(malformed, "1TB"));
+            assert!(!Engine::compare(
+                Comparator::NotEqual,
+                CompareMode::Size,
+                malformed,
+                "1TB"
             ));
         }
+    }
 
-        let missing_after = cache_bounds.missing_after(interval);
-        if !missing_after.is_empty() {
-            stats.add_interval(Id::Distinct(distinct_id), missing_after);
-            let tag_values = self.read_tag(tag_id, missing_after);
-            suffix = Some(Engine::distinct_values(
-                &mut bloom,
-                tag_values,
-                missing_after.0,
-            ));
-        }
+    #[test]
+    fn tokenized_tags_count_whitespace_separated_fields() {
+        let mut engine = Engine::new();
+        let lines = [
+            "one",
+            "two  fields",
+            "\tthree\tspaced   fields ",
+            "",
+            "a b c d e",
+        ];
+        let file_id = load(&mut engine, "tokenize", &lines);
+        let tag_id = tag(&mut engine, file_id, "tokens");
+        run(&mut engine, Command::Tokenize(tag_id));
+
+        let counts = |counts: &[&str]| {
+            counts
+                .iter()
+                .map(|c| Some(c.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tag_values(&mut engine, tag_id, 5),
+            counts(&["1", "2", "3", "0", "5"])
+        );
+    }
 
-        let cache = self
-            .distinct_caches
-            .entry(distinct_id)
-            .or_insert_with(DistinctCache::default);
+    #[test]
+    fn debug_stats_report_the_pass_rate_of_filters() {
+        let mut engine = Engine::new_debug();
+        let lines: Vec<String> = (0..40).map(|idx| format!("n={}", idx)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let file_id = load(&mut engine, "selectivity", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"n=(\d+)");
+        // One line in four passes
+        let filter_id = direct_filter(&mut engine, tag_id, Comparator::LessThan, "10");
+
+        let output = run(&mut engine, Command::Take(Id::Filter(filter_id), 100));
+        let stats = output.stats.to_json(&Names::new()).unwrap();
+        let selectivity = &stats["selectivity"]["filters"][format!("{:?}", filter_id)];
+        assert_eq!(selectivity["examined"], 40);
+        assert_eq!(selectivity["passed"], 10);
+        assert_eq!(selectivity["ratio"], 0.25);
+        assert!(output
+            .stats
+            .to_string()
+            .contains(&format!("  {:?}: 10 / 40 (25.00%)\n", filter_id)));
+    }
 
-        if let Some(mut prefix) = prefix {
-            prefix.union_with(&cache.loaded);
-            cache.loaded = prefix;
-            cache.start = interval.0;
+    #[test]
+    fn regexes_over_the_size_limit_are_rejected() {
+        let mut engine = Engine::new();
+        engine.set_regex_size_limit(10_000);
+        let file_id = load(&mut engine, "regex-limit", &["a 1", "b 2"]);
+        let tag_id = regex_tag(&mut engine, file_id, "letter", r"(\w)");
+        let pattern = r"(\w+ \w+ \w+ \w+ \w+ \w+ \w+ \w+ \w+ \w+){10}".to_string();
+
+        match engine.run_command(&Command::RegexFilter(Id::Tag(tag_id), pattern.clone())) {
+            Err(Error::InvalidArgument(message)) => {
+                let preview = format!("{}{}", &pattern[..40], TRUNCATED_LINE_MARKER);
+                assert_eq!(
+                    message,
+                    format!(
+                        "regex {:?} compiles to more than the 10000 byte limit",
+                        preview
+                    )
+                );
+            }
+            result => panic!(
+                "expected an invalid argument, got {:?}",
+                result.map(|o| o.id)
+            ),
         }
+        let small = Command::RegexFilter(Id::Tag(tag_id), "[ab]".to_string());
+        let filter_id = run(&mut engine, small).id.unwrap();
+        assert_eq!(selected(&mut engine, filter_id, 10), vec!["a 1", "b 2"]);
+    }
 
-        if let Some(suffix) = suffix {
-            cache.loaded.union_with(&suffix);
-            cache.end = interval.1;
-        }
+    #[test]
+    fn info_reports_the_version_and_enabled_features() {
+        let mut engine = Engine::new();
+        let lines = run(&mut engine, Command::Info).lines;
 
-        cache.bloom = bloom;
+        assert_eq!(lines[0], format!("log-tags {}", env!("CARGO_PKG_VERSION")));
+        let features = if cfg!(feature = "testing") {
+            "  features: testing"
+        } else {
+            "  features: none"
+        };
+        assert_eq!(lines.last().unwrap(), features);
+        assert!(engine.history().is_empty());
+    }
 
-        stats.add_size(Id::Distinct(distinct_id), cache.size());
-        Ok(())
+    #[test]
+    fn record_starts_join_continuation_lines() {
+        let mut engine = Engine::new();
+        let lines = [
+            "2020 ERROR boom",
+            "  at parse",
+            "  at main",
+            "2020 INFO ok",
+            "2020 WARN slow",
+            "  took 3s",
+        ];
+        let file_id = load(&mut engine, "records", &lines);
+        run(
+            &mut engine,
+            Command::RecordStart(file_id, r"^\d{4} ".to_string()),
+        );
+        let level = regex_tag(&mut engine, file_id, "level", r"^\d{4} (\w+)");
+        let frame = regex_tag(&mut engine, file_id, "frame", r"at (\w+)");
+
+        assert_eq!(
+            selected(&mut engine, Id::File(file_id), 10),
+            vec![
+                "2020 ERROR boom\n  at parse\n  at main",
+                "2020 INFO ok",
+                "2020 WARN slow\n  took 3s"
+            ]
+        );
+        let value = |value: &str| Some(value.to_string());
+        assert_eq!(
+            tag_values(&mut engine, level, 3),
+            vec![value("ERROR"), value("INFO"), value("WARN")]
+        );
+        assert_eq!(
+            tag_values(&mut engine, frame, 3),
+            vec![value("parse"), None, None]
+        );
     }
 
-    fn read_distinct(&self, distinct_id: DistinctId) -> &bit_set::BitSet {
-        &self.distinct_caches[&distinct_id].loaded
+    #[test]
+    fn lua_profiles_put_the_slowest_script_first() {
+        let mut engine = Engine::new();
+        engine.set_profile_lua(true);
+        let file_id = load(&mut engine, "lua-profile", &["a 1", "b 2", "c 3", "d 4"]);
+        let tag_id = regex_tag(&mut engine, file_id, "n", r"(\d)");
+        let fast = "chunk .. '0'".to_string();
+        run(&mut engine, Command::Transform(tag_id, fast.clone()));
+        let slow = "local s = 0 for i = 1, 200000 do s = s + i end return s > 0".to_string();
+        let filter = Command::ScriptedFilter(Id::Tag(tag_id), slow.clone());
+        let filter_id = run(&mut engine, filter).id.unwrap();
+
+        let output = run(&mut engine, Command::Take(filter_id, 10));
+        assert_eq!(output.lines.len(), 12);
+        let stats = output.stats.to_json(&Names::new()).unwrap();
+        let scripts = stats["lua"]["scripts"].as_array().unwrap();
+        assert_eq!(scripts.len(), 2);
+        assert_eq!(scripts[0]["script"], slow);
+        assert_eq!(scripts[0]["calls"], 4);
+        assert_eq!(scripts[1]["script"], fast);
+        assert!(stats.get("intervals").is_none());
     }
 
-    fn parse_tag_from_lines(lua: &rlua::Lua, tag: &Tag, lines: &[String]) -> Vec<TagValue> {
-        let transform = tag.transform.as_ref().map(|s| s.as_str());
-        lines
-            .iter()
-            .map(|line| {
-                if let Some(ref regex) = tag.regex {
-                    regex.captures(line).and_then(|captures| {
-                        captures
-                            .get(1)
-                            .and_then(|m| Engine::transform_chunk(&lua, transform, m.as_str()).ok())
-                    })
-                } else {
-                    Engine::transform_chunk(&lua, transform, line).ok()
-                }
-            })
-            .collect()
+    #[test]
+    fn lua_helpers_and_script_globals_are_available_to_transforms() {
+        let mut engine = Engine::new();
+        let file_id = load(&mut engine, "lua-helpers", &["[  a b ]", "[c\t]"]);
+        let trimmed = regex_tag(&mut engine, file_id, "trimmed", r"\[(.*)\]");
+        run(
+            &mut engine,
+            Command::Transform(trimmed, "trim(chunk)".to_string()),
+        );
+        assert_eq!(
+            tag_values(&mut engine, trimmed, 2),
+            vec![Some("a b".to_string()), Some("c".to_string())]
+        );
+
+        run(
+            &mut engine,
+            Command::Script(
+                "suffix = '!' function shout(s) return s:upper() .. suffix end".to_string(),
+            ),
+        );
+        let shouted = regex_tag(&mut engine, file_id, "shouted", r"\[(.*)\]");
+        run(
+            &mut engine,
+            Command::Transform(shouted, "shout(chunk:trim())".to_string()),
+        );
+        assert_eq!(
+            tag_values(&mut engine, shouted, 2),
+            vec![Some("A B!".to_string()), Some("C!".to_string())]
+        );
     }
 
-    fn filter_values(
-        lua: &rlua::Lua,
-        filter: &Filter,
-        values: &[TagValue],
-        start: usize,
-    ) -> Result<bit_set::BitSet> {
-        match filter {
-            Filter::Direct(comp, right) => {
-                let mut result = bit_set::BitSet::new();
-                for (idx, left_option) in values.iter().enumerate() {
-                    match (comp, left_option) {
-                        (Comparator::Equal, Some(left)) if left == right => {
-                            result.insert(start + idx)
-                        }
-                        (Comparator::NotEqual, Some(left)) if left != right => {
-                            result.insert(start + idx)
-                        }
-                        (Comparator::GreaterThan, Some(left)) if left > right => {
-                            result.insert(start + idx)
-                        }
-                        (Comparator::GreaterThanEqual, Some(left)) if left >= right => {
-                            result.insert(start + idx)
-                        }
-                        (Comparator::LessThan, Some(left)) if left < right => {
-                            result.insert(start + idx)
-                        }
-                        (Comparator::LessThanEqual, Some(left)) if left <= right => {
-                            result.insert(start + idx)
-                        }
-                        (_, None) => continue,
-                        (_, Some(_)) => continue,
-                    };
-                }
-                Ok(result)
-            }
-            Filter::Scripted(script) => {
-                let mut result = bit_set::BitSet::new();
-                for (idx, value_option) in values.iter().enumerate() {
-                    if let Some(value) = value_option {
-                        if Self::test_chunk(lua, script, value)? {
-                            result.insert(start + idx);
-                        }
-                    }
-                }
-                Ok(result)
-            }
-        }
+    #[test]
+    fn normalized_crlf_files_match_like_lf_files() {
+        let values = |normalize: bool, name: &str, lines: &[&str]| {
+            let mut engine = Engine::new();
+            engine.set_normalize_crlf(normalize);
+            let file_id = load(&mut engine, name, lines);
+            let tag_id = regex_tag(&mut engine, file_id, "value", r"=(.*)");
+            tag_values(&mut engine, tag_id, 3)
+        };
+        let lf = values(false, "lf", &["a=1", "b=two words", "c="]);
+        let crlf_lines = ["a=1\r", "b=two words\r", "c=\r"];
+
+        assert_eq!(values(true, "crlf", &crlf_lines), lf);
+        assert_ne!(values(false, "crlf", &crlf_lines), lf);
     }
 
-    fn distinct_values(
-        bloom: &mut ethbloom::Bloom,
-        tag_values: &[Option<String>],
-        start: usize,
-    ) -> bit_set::BitSet {
-        let mut result = bit_set::BitSet::new();
-        for (idx, value_option) in tag_values.iter().enumerate() {
-            if let Some(value) = value_option {
-                let bytes = value.as_bytes();
-                if !bloom.contains_input(ethbloom::Input::Raw(bytes)) {
-                    result.insert(start + idx);
-                    bloom.accrue(ethbloom::Input::Raw(bytes));
-                }
-            }
-        }
-        result
+    #[test]
+    fn saved_selections_intersect_with_new_filters() {
+        let mut engine = Engine::new();
+        let lines = ["error 1", "info 2", "error 3", "info 4", "error 5"];
+        let file_id = load(&mut engine, "saved-selection", &lines);
+        let level = regex_tag(&mut engine, file_id, "level", r"(\w+) ");
+        let errors = Command::RegexFilter(Id::Tag(level), "^error$".to_string());
+        let errors = run(&mut engine, errors).id.unwrap();
+        let saved = run(&mut engine, Command::SaveSelection(errors)).id.unwrap();
+        assert_eq!(
+            selected(&mut engine, saved, 10),
+            vec!["error 1", "error 3", "error 5"]
+        );
+
+        let n = regex_tag(&mut engine, file_id, "n", r" (\d)");
+        let above = direct_filter(&mut engine, n, Comparator::GreaterThan, "2");
+        let both = run(&mut engine, Command::And(saved, Id::Filter(above)))
+            .id
+            .unwrap();
+        assert_eq!(selected(&mut engine, both, 10), vec!["error 3", "error 5"]);
     }
 
-    fn transform_chunk(lua: &rlua::Lua, transform: Option<&str>, chunk: &str) -> Result<String> {
-        match transform {
-            Some(eval_src) => Ok(lua.context(|lua_ctx| {
-                lua_ctx.globals().set("chunk", chunk)?;
-                lua_ctx.load(eval_src).eval()
-            })?),
-            None => Ok(chunk.to_string()),
+    #[test]
+    fn filtered_groups_render_the_surviving_buckets() {
+        let mut engine = Engine::new();
+        let lines = ["b", "a", "c", "b", "a", "d", "b", "c"];
+        let file_id = load(&mut engine, "filter-groups", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "letter", r"(\w)");
+        let group = Command::Group(tag_id, Aggregator::Count, None, GroupOrder::Descending);
+        let group_id = match run(&mut engine, group).id {
+            Some(Id::Group(group_id)) => group_id,
+            id => panic!("expected a group, got {:?}", id),
+        };
+
+        let filter = Command::FilterGroups(
+            group_id,
+            Comparator::GreaterThanEqual,
+            CompareMode::Numeric,
+            "2".to_string(),
+        );
+        let output = run(&mut engine, filter);
+        assert_eq!(
+            output.lines,
+            vec![
+                "\"b\"             3",
+                "\"a\"             2",
+                "\"c\"             2"
+            ]
+        );
+        match output.id {
+            Some(Id::Group(filtered)) => assert_eq!(engine.groups[&filtered].buckets.len(), 3),
+            id => panic!("expected a group, got {:?}", id),
         }
     }
 
-    fn test_chunk(lua: &rlua::Lua, test: &str, chunk: &str) -> Result<bool> {
-        Ok(lua.context(|lua_ctx| {
-            lua_ctx.globals().set("chunk", chunk)?;
-            lua_ctx.load(test).eval()
-        })?)
+    #[test]
+    fn lone_distinct_takes_stop_after_their_values() {
+        let mut engine = Engine::new();
+        let lines: Vec<String> = (0..5000).map(|idx| format!("v={}", idx)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let file_id = load(&mut engine, "distinct-limit", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "v", r"v=(\d+)");
+
+        let distinct = run(&mut engine, Command::Distinct(Id::Tag(tag_id)))
+            .id
+            .unwrap();
+        assert_eq!(
+            selected(&mut engine, distinct, 3),
+            vec!["v=0", "v=1", "v=2"]
+        );
+        let distinct_id = match distinct {
+            Id::Distinct(distinct_id) => distinct_id,
+            id => panic!("expected a distinct, got {:?}", id),
+        };
+        assert_eq!(
+            engine.distinct_caches[&distinct_id].bounds(),
+            Interval(0, 3)
+        );
+    }
+
+    #[test]
+    fn distincts_over_a_range_count_values_from_its_start() {
+        let mut engine = Engine::new();
+        let lines: Vec<String> = (0..300)
+            .map(|idx| format!("v={}", if idx < 150 { idx } else { 0 }))
+            .collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let file_id = load(&mut engine, "distinct-range", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "v", r"v=(\d+)");
+
+        let middle = range(&mut engine, Id::Tag(tag_id), 100, 200);
+        let distinct = run(&mut engine, Command::Distinct(middle)).id.unwrap();
+        assert_eq!(selected(&mut engine, distinct, 2), vec!["v=100", "v=101"]);
+        assert_eq!(selected(&mut engine, distinct, 200).len(), 50);
     }
 
-    fn file_to_tags(&self, file_id: FileId) -> Vec<TagId> {
-        self.tag_to_file
+    #[test]
+    fn sparse_filters_read_on_until_enough_rows() {
+        let mut engine = Engine::new();
+        let corpus = crate::testing::generate_corpus(20_000, 1, 1000, 790);
+        let lines: Vec<&str> = corpus.iter().map(String::as_str).collect();
+        let file_id = load(&mut engine, "sparse", &lines);
+
+        // Only the last lines match, far past the first batches
+        let idx_tag = regex_tag(&mut engine, file_id, "idx", r"\[(\d+)\]");
+        let last = direct_filter(&mut engine, idx_tag, Comparator::GreaterThanEqual, "19995");
+        let rows = selected(&mut engine, Id::Filter(last), 100);
+        assert_eq!(rows, &corpus[19_995..]);
+
+        // A value spread thinly over the whole file
+        let field_tag = regex_tag(&mut engine, file_id, "field0", r"field0=(v\d+)");
+        let filter = Command::DirectFilter(
+            Id::Tag(field_tag),
+            Comparator::Equal,
+            CompareMode::Lexical,
+            "v7".to_string(),
+        );
+        let sparse = run(&mut engine, filter).id.unwrap();
+        let expected: Vec<String> = corpus
             .iter()
-            .filter(|(_, &fid)| fid == file_id)
-            .map(|(tid, _)| *tid)
-            .collect()
+            .filter(|line| line.ends_with(" field0=v7"))
+            .cloned()
+            .collect();
+        assert!(expected.len() > 5);
+        assert_eq!(selected(&mut engine, sparse, 100), expected);
+        assert_eq!(selected(&mut engine, sparse, 5), &expected[..5]);
     }
 
-    fn find_parent_tag(&self, id: Id) -> Option<TagId> {
-        match id {
-            Id::Distinct(did) => self.find_parent_tag(self.distinct_to_parent[&did]),
-            Id::Filter(fid) => self.find_parent_tag(self.filter_to_parent[&fid]),
-            Id::Tag(tid) => Some(tid),
-            _ => None,
+    #[test]
+    fn match_rates_count_mismatched_lines() {
+        let mut engine = Engine::new();
+        let lines = ["a=1 b=1", "a=2 b=3", "a=3 b=3", "a=4 b=5", "none"];
+        let file_id = load(&mut engine, "match-rate", &lines);
+        let a = regex_tag(&mut engine, file_id, "a", r"a=(\d)");
+        let b = regex_tag(&mut engine, file_id, "b", r"b=(\d)");
+        selected(&mut engine, Id::File(file_id), 10);
+        let rate = |engine: &mut Engine, comp| {
+            run(engine, Command::MatchRate(a, b, comp, CompareMode::Numeric)).lines
+        };
+
+        assert_eq!(
+            rate(&mut engine, Comparator::Equal),
+            vec![
+                "match rate: 40.00% (2/5) over [0, 5)",
+                "mismatches: 3",
+                "mismatched lines: 1, 3, 4",
+            ]
+        );
+        assert_eq!(
+            rate(&mut engine, Comparator::NullSafeEqual)[0],
+            "match rate: 60.00% (3/5) over [0, 5)"
+        );
+
+        let other_id = load(&mut engine, "match-rate-other", &["a=1"]);
+        let other = regex_tag(&mut engine, other_id, "a", r"a=(\d)");
+        match engine.run_command(&Command::MatchRate(
+            a,
+            other,
+            Comparator::Equal,
+            CompareMode::Numeric,
+        )) {
+            Err(Error::InvalidArgument(_)) => {}
+            result => panic!(
+                "expected an invalid argument, got {:?}",
+                result.map(|o| o.lines)
+            ),
+        }
+    }
+
+    #[test]
+    fn numeric_filters_compare_numbers_and_fall_back_for_text() {
+        let mut engine = Engine::new();
+        let lines = ["v=-5", "v=10", "v=abc", "v=-12", "v=9"];
+        let file_id = load(&mut engine, "numeric", &lines);
+        let tag_id = regex_tag(&mut engine, file_id, "v", r"v=(\S+)");
+
+        // `10` is above `9` as a number though not as text, `abc` doesn't parse and is compared
+        // as text, after every digit and `-`
+        let above_nine = direct_filter(&mut engine, tag_id, Comparator::GreaterThan, "9");
+        assert_eq!(
+            selected(&mut engine, Id::Filter(above_nine), 10),
+            vec!["v=10", "v=abc"]
+        );
+        let above_negative = direct_filter(&mut engine, tag_id, Comparator::GreaterThan, "-6");
+        assert_eq!(
+            selected(&mut engine, Id::Filter(above_negative), 10),
+            vec!["v=-5", "v=10", "v=abc", "v=9"]
+        );
+    }
+
+    #[test]
+    fn files_past_the_open_file_bound_are_reopened() {
+        let mut engine = Engine::new();
+        engine.set_max_open_files(2);
+        let files: Vec<(FileId, Vec<String>)> = (0..4)
+            .map(|file| {
+                let lines: Vec<String> = (0..3000)
+                    .map(|idx| format!("file {} line {}", file, idx))
+                    .collect();
+                let refs: Vec<&str> = lines.iter().map(String::as_str).collect();
+                let file_id = load(&mut engine, &format!("open-files-{}", file), &refs);
+                (file_id, lines)
+            })
+            .collect();
+
+        // Every file is left part way through its first batch, then read on in turn
+        for (file_id, lines) in &files {
+            assert_eq!(selected(&mut engine, Id::File(*file_id), 1), &lines[..1]);
+        }
+        let open = engine
+            .files
+            .values()
+            .filter(|file| file.reader.is_some())
+            .count();
+        assert!(open <= 2, "{} files open", open);
+        for (file_id, lines) in &files {
+            assert_eq!(selected(&mut engine, Id::File(*file_id), 3000), *lines);
         }
     }
 }
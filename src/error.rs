@@ -5,21 +5,36 @@ use crate::base::Id;
 #[derive(Debug)]
 pub enum SyntaxError {
     ExpectedApplication,
+    ExpectedCount,
+    /// A variadic tail with an argument of a different type than the others
+    MixedArguments,
     UnknownFunction,
 }
 
 #[derive(Debug)]
 pub enum Error {
     Lua(rlua::Error),
+    /// A Lua error raised on a row, with the line index and the value it was running on
+    LuaAt(usize, String, rlua::Error),
     Io(std::io::Error),
+    Json(serde_json::Error),
     Readline(rustyline::error::ReadlineError),
     Regex(regex::Error),
+    /// A regex of a tag that doesn't compile, with the tag's name and the pattern
+    TagRegex(String, String, regex::Error),
     ApplicationOrder,
+    BatchFailed(usize),
     FileNotLoaded(String),
+    InvalidArgument(String),
+    InvalidCaptureGroup(String),
     InvalidTarget(String),
+    /// A command stopped by Ctrl-C before it could produce its whole result
+    Interrupted,
     MissingId(Id),
     OutputWithoutId,
-    Parser(String),
+    /// What the parser expected, the statement and the byte offset it stopped at when known
+    Parser(String, String, Option<usize>),
+    PlanCycle(Id),
     SymbolNotFound(String),
     Syntax(SyntaxError, String),
 }
@@ -36,6 +51,12 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::Json(err)
+    }
+}
+
 impl From<rustyline::error::ReadlineError> for Error {
     fn from(err: rustyline::error::ReadlineError) -> Error {
         Error::Readline(err)
@@ -52,15 +73,45 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Lua(ref err) => write!(f, "{}", err),
+            Error::LuaAt(line, ref chunk, ref err) => {
+                write!(f, "Lua error at line {} on {:?}: {}", line, chunk, err)
+            }
             Error::Io(ref err) => write!(f, "{}", err),
+            Error::Json(ref err) => write!(f, "{}", err),
             Error::Readline(ref err) => write!(f, "{}", err),
             Error::Regex(ref err) => write!(f, "{}", err),
+            Error::TagRegex(ref tag, ref pattern, ref err) => {
+                write!(f, "Invalid regex {:?} of tag '{}: {}", pattern, tag, err)
+            }
             Error::ApplicationOrder => write!(f, "Invalid application order"),
+            Error::BatchFailed(count) => write!(f, "{} batch statement(s) failed", count),
             Error::FileNotLoaded(ref path) => write!(f, "File not loaded: {}", path),
+            Error::InvalidArgument(ref arg) => write!(f, "Invalid argument: {}", arg),
+            Error::InvalidCaptureGroup(ref group) => {
+                write!(f, "Invalid capture group: {}", group)
+            }
             Error::InvalidTarget(ref target) => write!(f, "Invalid target: {}", target),
+            Error::Interrupted => write!(f, "Interrupted"),
             Error::MissingId(ref id) => write!(f, "Missing ID: {:?}", id),
             Error::OutputWithoutId => write!(f, "Output without ID"),
-            Error::Parser(ref err) => write!(f, "Parser error:\n{}", err),
+            Error::Parser(ref expected, ref source, offset) => match offset {
+                Some(offset) => {
+                    let (line, column) = line_and_column(source, offset);
+                    let text = source.lines().nth(line - 1).unwrap_or("");
+                    write!(
+                        f,
+                        "Parser error at line {}, column {}: {}\n{}\n{:>width$}",
+                        line,
+                        column,
+                        expected,
+                        text,
+                        "^",
+                        width = column
+                    )
+                }
+                None => write!(f, "Parser error: {} in {}", expected, source),
+            },
+            Error::PlanCycle(ref id) => write!(f, "Plan cycle at ID: {:?}", id),
             Error::SymbolNotFound(ref symbol) => write!(f, "Symbol not found: {}", symbol),
             Error::Syntax(ref kind, ref message) => {
                 write!(f, "Syntax error: {:?} in {}", kind, message)
@@ -69,4 +120,65 @@ impl fmt::Display for Error {
     }
 }
 
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::Lua(_) | Error::LuaAt(_, _, _) => "lua",
+            Error::Io(_) => "io",
+            Error::Json(_) => "json",
+            Error::Readline(_) => "readline",
+            Error::Regex(_) | Error::TagRegex(_, _, _) => "regex",
+            Error::ApplicationOrder => "application_order",
+            Error::BatchFailed(_) => "batch_failed",
+            Error::FileNotLoaded(_) => "file_not_loaded",
+            Error::InvalidArgument(_) => "invalid_argument",
+            Error::InvalidCaptureGroup(_) => "invalid_capture_group",
+            Error::InvalidTarget(_) => "invalid_target",
+            Error::Interrupted => "interrupted",
+            Error::MissingId(_) => "missing_id",
+            Error::OutputWithoutId => "output_without_id",
+            Error::Parser(_, _, _) => "parser",
+            Error::PlanCycle(_) => "plan_cycle",
+            Error::SymbolNotFound(_) => "symbol_not_found",
+            Error::Syntax(_, _) => "syntax",
+        }
+    }
+
+    /// Byte offset of the error in the source it was raised from, when known
+    pub fn position(&self) -> Option<usize> {
+        match *self {
+            Error::Parser(_, _, offset) => offset,
+            _ => None,
+        }
+    }
+
+    /// The source text the error was raised from, when known
+    pub fn context(&self) -> Option<&str> {
+        match *self {
+            Error::Parser(_, ref line, _) | Error::Syntax(_, ref line) => Some(line),
+            Error::LuaAt(_, ref chunk, _) => Some(chunk),
+            Error::TagRegex(_, ref pattern, _) => Some(pattern),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "level": "error",
+            "code": self.code(),
+            "message": self.to_string(),
+            "position": self.position(),
+            "context": self.context(),
+        })
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// 1-based line and column, counted in characters, of the byte `offset` in `source`
+fn line_and_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+    (line, before[line_start..].chars().count() + 1)
+}
@@ -1,42 +1,273 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use nom;
+use nom::error::{ErrorKind, VerboseError, VerboseErrorKind};
 
-use crate::base::{Comparator, Id};
-use crate::engine::{Command, Engine, Output};
+use crate::base::{
+    Aggregator, CaptureGroup, CompareMode, Comparator, GroupId, GroupOrder, Id, Order, SetId, TagId,
+};
+use crate::engine::{Command, Engine, Names, Output, Stats, StatsFormat};
 use crate::error::{Error, Result, SyntaxError};
 use crate::parser::{self, Expression};
+use crate::presets::{self, Preset, TagSource};
+use crate::session::Session;
 
 #[derive(Debug)]
 pub enum Application {
-    Load(String, String),
+    Load(String, String, Option<String>),
+    LoadMany(String, Vec<String>, Option<String>),
     Script(String),
 
+    Save(String),
+    LoadSession(String),
+    Symbols,
+
+    RecordStart(String, String),
+    RecordStartPiped(String),
+
     Tag(String, String),
     TagPiped(String),
 
-    Regex(String, String),
-    RegexPiped(String),
+    Regex(String, String, CaptureGroup),
+    RegexPiped(String, CaptureGroup),
+
+    RegexAll(String, String, CaptureGroup),
+    RegexAllPiped(String, CaptureGroup),
+
+    SplitRegex(String, String, usize),
+    SplitRegexPiped(String, usize),
+
+    Tokenize(String),
+    TokenizePiped,
+
+    JsonTag(String, String),
+    JsonTagPiped(String),
+
+    ColumnTag(String, usize, usize),
+    ColumnTagPiped(usize, usize),
 
     Transform(String, String),
     TransformPiped(String),
 
-    DirectFilter(String, Comparator, String),
-    DirectFilterNamed(String, String, Comparator, String),
-    DirectFilterPiped(Comparator, String),
-    DirectFilterPipedNamed(String, Comparator, String),
+    Retag(String, String, Option<String>),
+    RetagPiped(String, Option<String>),
+
+    DirectFilter(String, Comparator, CompareMode, String),
+    DirectFilterNamed(String, String, Comparator, CompareMode, String),
+    DirectFilterPiped(Comparator, CompareMode, String),
+    DirectFilterPipedNamed(String, Comparator, CompareMode, String),
+
+    CustomFilter(String, String, String),
+    CustomFilterNamed(String, String, String, String),
+    CustomFilterPiped(String, String),
+    CustomFilterPipedNamed(String, String, String),
 
     ScriptedFilter(String, String),
     ScriptedFilterNamed(String, String, String),
     ScriptedFilterPiped(String),
     ScriptedFilterPipedNamed(String, String),
 
+    RegexFilter(String, String),
+    RegexFilterNamed(String, String, String),
+    RegexFilterPiped(String),
+    RegexFilterPipedNamed(String, String),
+
+    InFilter(String, String),
+    InFilterNamed(String, String, String),
+    InFilterPiped(String),
+    InFilterPipedNamed(String, String),
+
+    LagFilter(String, usize, f64, Comparator, CompareMode),
+    LagFilterNamed(String, String, usize, f64, Comparator, CompareMode),
+    LagFilterPiped(usize, f64, Comparator, CompareMode),
+    LagFilterPipedNamed(String, usize, f64, Comparator, CompareMode),
+
+    Or(String, String, Option<String>),
+    OrPiped(String),
+
+    And(String, String, Option<String>),
+    AndPiped(String),
+
     Distinct(String),
     DistinctPiped,
 
+    DistinctValues(String, Option<Aggregator>),
+    DistinctValuesPiped(Option<Aggregator>),
+
+    MatchRate(String, String, Comparator, CompareMode),
+
+    Group(
+        String,
+        Aggregator,
+        Option<String>,
+        GroupOrder,
+        Option<String>,
+    ),
+    GroupPiped(Aggregator, Option<String>, GroupOrder, Option<String>),
+
+    FilterGroups(String, Comparator, CompareMode, String, Option<String>),
+    FilterGroupsPiped(Comparator, CompareMode, String, Option<String>),
+
+    Aggregate(String, Aggregator, Option<String>),
+    AggregatePiped(Aggregator, Option<String>),
+
+    Top(String, String, usize),
+    TopPiped(String, usize),
+
+    Range(String, usize, usize),
+    RangePiped(usize, usize),
+
+    Skip(String, usize),
+    SkipPiped(usize),
+
+    Sort(String, String, Order),
+    SortPiped(String, Order),
+
     Take(String, usize),
     TakePiped(usize),
+
+    TakeLast(String, usize),
+    TakeLastPiped(usize),
+
+    Follow(String, usize),
+    FollowPiped(usize),
+
+    Write(String, String, usize, bool),
+    WritePiped(String, usize, bool),
+
+    Materialize(String, String),
+    MaterializePiped(String),
+
+    SaveSelection(String, String),
+    SaveSelectionPiped(String),
+
+    Hexdump(String, usize),
+    HexdumpPiped(usize),
+
+    Explain(String),
+    ExplainPiped,
+    Info,
+
+    Unload(String),
+}
+
+/// The value compared against by a direct filter, a numeric literal always compares numerically
+fn filter_operand(
+    comp: Comparator,
+    mode: CompareMode,
+    value: &Expression,
+) -> std::result::Result<(CompareMode, String), SyntaxError> {
+    match value {
+        _ if !comp.takes_operand() => Err(SyntaxError::UnknownFunction),
+        Expression::String(value) => Ok((mode, value.clone())),
+        Expression::Int(value) => Ok((CompareMode::Numeric, value.to_string())),
+        Expression::Float(value) => Ok((CompareMode::Numeric, value.to_string())),
+        _ => Err(SyntaxError::UnknownFunction),
+    }
+}
+
+fn aggregator(name: &str) -> std::result::Result<Aggregator, SyntaxError> {
+    match name {
+        "count" => Ok(Aggregator::Count),
+        "min" => Ok(Aggregator::Min),
+        "max" => Ok(Aggregator::Max),
+        "sum" => Ok(Aggregator::Sum),
+        "avg" => Ok(Aggregator::Avg),
+        _ => Err(SyntaxError::UnknownFunction),
+    }
+}
+
+/// An aggregator argument, either the bare `count` keyword or an aggregator applied to the
+/// numeric tag it reads such as `avg('latency)`
+fn aggregation(exp: &Expression) -> std::result::Result<(Aggregator, Option<String>), SyntaxError> {
+    match exp {
+        Expression::Keyword(name) if name == "count" => Ok((Aggregator::Count, None)),
+        Expression::Application(name, args) => match args.as_slice() {
+            [Expression::Symbol(tag)] => Ok((aggregator(name)?, Some(tag.clone()))),
+            _ => Err(SyntaxError::UnknownFunction),
+        },
+        _ => Err(SyntaxError::UnknownFunction),
+    }
+}
+
+fn group_order(name: &str) -> std::result::Result<GroupOrder, SyntaxError> {
+    match name {
+        "asc" => Ok(GroupOrder::Ascending),
+        "desc" => Ok(GroupOrder::Descending),
+        _ => Err(SyntaxError::UnknownFunction),
+    }
+}
+
+fn sort_order(name: &str) -> std::result::Result<Order, SyntaxError> {
+    match name {
+        "asc" => Ok(Order::Ascending),
+        "desc" => Ok(Order::Descending),
+        _ => Err(SyntaxError::UnknownFunction),
+    }
+}
+
+/// The row count and whether to include tags of a `write`, from its optional trailing count
+/// and `tags` keyword
+fn write_options(args: &[Expression]) -> std::result::Result<(usize, bool), SyntaxError> {
+    match args {
+        [] => Ok((usize::MAX, false)),
+        [Expression::Int(count)] => Ok((*count, false)),
+        [Expression::Keyword(keyword)] if keyword == "tags" => Ok((usize::MAX, true)),
+        [Expression::Int(count), Expression::Keyword(keyword)] if keyword == "tags" => {
+            Ok((*count, true))
+        }
+        [.., Expression::Float(_)] | [Expression::Float(_), _] => Err(SyntaxError::ExpectedCount),
+        _ => Err(SyntaxError::UnknownFunction),
+    }
+}
+
+/// The lag and factor of a `lag(lines)` or `lag(lines, factor)` filter argument. A factor is
+/// either a number or a string such as "2x", and makes the comparison numeric.
+fn lag_options(
+    args: &[Expression],
+    comp: Comparator,
+    mode: CompareMode,
+) -> std::result::Result<(usize, f64, CompareMode), SyntaxError> {
+    if !comp.takes_operand() {
+        return Err(SyntaxError::UnknownFunction);
+    }
+    let (lag, factor) = match args {
+        [Expression::Int(lag)] => return Ok((*lag, 1.0, mode)),
+        [Expression::Int(lag), Expression::Int(factor)] => (*lag, *factor as f64),
+        [Expression::Int(lag), Expression::Float(factor)] => (*lag, *factor),
+        [Expression::Int(lag), Expression::String(factor)] => {
+            let factor = factor
+                .strip_suffix('x')
+                .and_then(|factor| factor.trim().parse::<f64>().ok())
+                .ok_or(SyntaxError::UnknownFunction)?;
+            (*lag, factor)
+        }
+        [Expression::Float(_), ..] => return Err(SyntaxError::ExpectedCount),
+        _ => return Err(SyntaxError::UnknownFunction),
+    };
+    Ok((lag, factor, CompareMode::Numeric))
+}
+
+/// Every argument of a variadic tail, which must all be strings
+/// The preset named by a `format("apache")` argument of `load`
+fn input_format(exp: &Expression) -> std::result::Result<String, SyntaxError> {
+    match exp {
+        Expression::Application(name, args) if name == "format" => match args.as_slice() {
+            [Expression::String(format)] => Ok(format.clone()),
+            _ => Err(SyntaxError::UnknownFunction),
+        },
+        _ => Err(SyntaxError::UnknownFunction),
+    }
+}
+
+fn variadic_strings(args: &[Expression]) -> std::result::Result<Vec<String>, SyntaxError> {
+    args.iter()
+        .map(|arg| match arg {
+            Expression::String(value) => Ok(value.clone()),
+            _ => Err(SyntaxError::MixedArguments),
+        })
+        .collect()
 }
 
 impl Application {
@@ -49,13 +280,54 @@ impl Application {
             match (func.as_str(), args.as_slice()) {
                 ("load",
                  [Expression::Symbol(file), Expression::String(path)]) => {
-                    Ok(Application::Load(file.clone(), path.clone()))
+                    Ok(Application::Load(file.clone(), path.clone(), None))
+                }
+                ("load",
+                 [Expression::Symbol(file), Expression::String(path), format @ Expression::Application(_, _)]) => {
+                    Ok(Application::Load(file.clone(), path.clone(), Some(input_format(format)?)))
+                }
+                ("load",
+                 [Expression::Symbol(file), paths @ .., format @ Expression::Application(_, _)]) if !paths.is_empty() => {
+                    let format = input_format(format)?;
+                    variadic_strings(paths)
+                        .map(|paths| Application::LoadMany(file.clone(), paths, Some(format)))
+                }
+                ("load",
+                 [Expression::Symbol(file), paths @ ..]) if !paths.is_empty() => {
+                    variadic_strings(paths)
+                        .map(|paths| Application::LoadMany(file.clone(), paths, None))
                 }
                 ("script",
                  [Expression::String(script)]) => {
                     Ok(Application::Script(script.clone()))
                 }
 
+                ("save",
+                 [Expression::String(path)]) => {
+                    Ok(Application::Save(path.clone()))
+                }
+                ("load_session",
+                 [Expression::String(path)]) => {
+                    Ok(Application::LoadSession(path.clone()))
+                }
+                ("symbols", [])
+                | ("list", []) => {
+                    Ok(Application::Symbols)
+                }
+
+                ("record_start",
+                 [Expression::Symbol(file), Expression::String(regex)])
+                | ("join_lines",
+                 [Expression::Symbol(file), Expression::String(regex)]) => {
+                    Ok(Application::RecordStart(file.clone(), regex.clone()))
+                }
+                ("record_start",
+                 [Expression::String(regex)])
+                | ("join_lines",
+                 [Expression::String(regex)]) => {
+                    Ok(Application::RecordStartPiped(regex.clone()))
+                }
+
                 ("tag",
                  [Expression::Symbol(file), Expression::Symbol(tag)]) => {
                     Ok(Application::Tag(file.clone(), tag.clone()))
@@ -67,11 +339,88 @@ impl Application {
 
                 ("regex",
                  [Expression::Symbol(tag), Expression::String(path)]) => {
-                    Ok(Application::Regex(tag.clone(), path.clone()))
+                    Ok(Application::Regex(tag.clone(), path.clone(), CaptureGroup::default()))
+                }
+                ("regex",
+                 [Expression::Symbol(tag), Expression::String(path), Expression::String(group)]) => {
+                    Ok(Application::Regex(tag.clone(), path.clone(), CaptureGroup::Name(group.clone())))
+                }
+                ("regex",
+                 [Expression::Symbol(tag), Expression::String(path), Expression::Int(group)]) => {
+                    Ok(Application::Regex(tag.clone(), path.clone(), CaptureGroup::Index(*group)))
                 }
                 ("regex",
                  [Expression::String(path)]) => {
-                    Ok(Application::RegexPiped(path.clone()))
+                    Ok(Application::RegexPiped(path.clone(), CaptureGroup::default()))
+                }
+                ("regex",
+                 [Expression::String(path), Expression::String(group)]) => {
+                    Ok(Application::RegexPiped(path.clone(), CaptureGroup::Name(group.clone())))
+                }
+                ("regex",
+                 [Expression::String(path), Expression::Int(group)]) => {
+                    Ok(Application::RegexPiped(path.clone(), CaptureGroup::Index(*group)))
+                }
+
+                ("regex_all",
+                 [Expression::Symbol(tag), Expression::String(regex)]) => {
+                    Ok(Application::RegexAll(tag.clone(), regex.clone(), CaptureGroup::default()))
+                }
+                ("regex_all",
+                 [Expression::Symbol(tag), Expression::String(regex), Expression::String(group)]) => {
+                    Ok(Application::RegexAll(tag.clone(), regex.clone(), CaptureGroup::Name(group.clone())))
+                }
+                ("regex_all",
+                 [Expression::Symbol(tag), Expression::String(regex), Expression::Int(group)]) => {
+                    Ok(Application::RegexAll(tag.clone(), regex.clone(), CaptureGroup::Index(*group)))
+                }
+                ("regex_all",
+                 [Expression::String(regex)]) => {
+                    Ok(Application::RegexAllPiped(regex.clone(), CaptureGroup::default()))
+                }
+                ("regex_all",
+                 [Expression::String(regex), Expression::String(group)]) => {
+                    Ok(Application::RegexAllPiped(regex.clone(), CaptureGroup::Name(group.clone())))
+                }
+                ("regex_all",
+                 [Expression::String(regex), Expression::Int(group)]) => {
+                    Ok(Application::RegexAllPiped(regex.clone(), CaptureGroup::Index(*group)))
+                }
+
+                ("split_regex",
+                 [Expression::Symbol(tag), Expression::String(delimiter), Expression::Int(index)]) => {
+                    Ok(Application::SplitRegex(tag.clone(), delimiter.clone(), *index))
+                }
+                ("split_regex",
+                 [Expression::String(delimiter), Expression::Int(index)]) => {
+                    Ok(Application::SplitRegexPiped(delimiter.clone(), *index))
+                }
+
+                ("tokenize",
+                 [Expression::Symbol(tag)]) => {
+                    Ok(Application::Tokenize(tag.clone()))
+                }
+                ("tokenize",
+                 []) => {
+                    Ok(Application::TokenizePiped)
+                }
+
+                ("jsontag",
+                 [Expression::Symbol(tag), Expression::String(path)]) => {
+                    Ok(Application::JsonTag(tag.clone(), path.clone()))
+                }
+                ("jsontag",
+                 [Expression::String(path)]) => {
+                    Ok(Application::JsonTagPiped(path.clone()))
+                }
+
+                ("columntag",
+                 [Expression::Symbol(tag), Expression::Int(start), Expression::Int(end)]) => {
+                    Ok(Application::ColumnTag(tag.clone(), *start, *end))
+                }
+                ("columntag",
+                 [Expression::Int(start), Expression::Int(end)]) => {
+                    Ok(Application::ColumnTagPiped(*start, *end))
                 }
 
                 ("transform",
@@ -83,21 +432,73 @@ impl Application {
                     Ok(Application::TransformPiped(transform.clone()))
                 }
 
+                ("retag",
+                 [Expression::Symbol(tag), Expression::String(regex)]) => {
+                    Ok(Application::Retag(tag.clone(), regex.clone(), None))
+                }
+                ("retag",
+                 [Expression::Symbol(tag), Expression::String(regex), Expression::String(transform)]) => {
+                    Ok(Application::Retag(tag.clone(), regex.clone(), Some(transform.clone())))
+                }
+                ("retag",
+                 [Expression::String(regex)]) => {
+                    Ok(Application::RetagPiped(regex.clone(), None))
+                }
+                ("retag",
+                 [Expression::String(regex), Expression::String(transform)]) => {
+                    Ok(Application::RetagPiped(regex.clone(), Some(transform.clone())))
+                }
+
+                ("filter",
+                 [Expression::Symbol(parent_or_name), Expression::Comparator(comp, mode), value]) => {
+                    let (mode, value) = filter_operand(*comp, *mode, value)?;
+                    if is_pipelined {
+                        Ok(Application::DirectFilterPipedNamed(parent_or_name.clone(), *comp, mode, value))
+                    } else {
+                        Ok(Application::DirectFilter(parent_or_name.clone(), *comp, mode, value))
+                    }
+                }
+                ("filter",
+                 [Expression::Symbol(parent), Expression::Symbol(name), Expression::Comparator(comp, mode), value]) => {
+                    let (mode, value) = filter_operand(*comp, *mode, value)?;
+                    Ok(Application::DirectFilterNamed(parent.clone(), name.clone(), *comp, mode, value))
+                }
+                ("filter",
+                 [Expression::Comparator(comp, mode), value]) => {
+                    let (mode, value) = filter_operand(*comp, *mode, value)?;
+                    Ok(Application::DirectFilterPiped(*comp, mode, value))
+                }
+                ("filter",
+                 [Expression::Symbol(parent_or_name), Expression::Comparator(comp, mode)]) if !comp.takes_operand() => {
+                    if is_pipelined {
+                        Ok(Application::DirectFilterPipedNamed(parent_or_name.clone(), *comp, *mode, String::new()))
+                    } else {
+                        Ok(Application::DirectFilter(parent_or_name.clone(), *comp, *mode, String::new()))
+                    }
+                }
+                ("filter",
+                 [Expression::Symbol(parent), Expression::Symbol(name), Expression::Comparator(comp, mode)]) if !comp.takes_operand() => {
+                    Ok(Application::DirectFilterNamed(parent.clone(), name.clone(), *comp, *mode, String::new()))
+                }
                 ("filter",
-                 [Expression::Symbol(parent_or_name), Expression::Comparator(comp), Expression::String(value)]) => {
+                 [Expression::Comparator(comp, mode)]) if !comp.takes_operand() => {
+                    Ok(Application::DirectFilterPiped(*comp, *mode, String::new()))
+                }
+                ("filter",
+                 [Expression::Symbol(parent_or_name), Expression::CustomComparator(func), Expression::String(value)]) => {
                     if is_pipelined {
-                        Ok(Application::DirectFilterPipedNamed(parent_or_name.clone(), *comp, value.clone()))
+                        Ok(Application::CustomFilterPipedNamed(parent_or_name.clone(), func.clone(), value.clone()))
                     } else {
-                        Ok(Application::DirectFilter(parent_or_name.clone(), *comp, value.clone()))
+                        Ok(Application::CustomFilter(parent_or_name.clone(), func.clone(), value.clone()))
                     }
                 }
                 ("filter",
-                 [Expression::Symbol(parent), Expression::Symbol(name), Expression::Comparator(comp), Expression::String(value)]) => {
-                    Ok(Application::DirectFilterNamed(parent.clone(), name.clone(), *comp, value.clone()))
+                 [Expression::Symbol(parent), Expression::Symbol(name), Expression::CustomComparator(func), Expression::String(value)]) => {
+                    Ok(Application::CustomFilterNamed(parent.clone(), name.clone(), func.clone(), value.clone()))
                 }
                 ("filter",
-                 [Expression::Comparator(comp), Expression::String(value)]) => {
-                    Ok(Application::DirectFilterPiped(*comp, value.clone()))
+                 [Expression::CustomComparator(func), Expression::String(value)]) => {
+                    Ok(Application::CustomFilterPiped(func.clone(), value.clone()))
                 }
                 ("filter",
                  [Expression::Symbol(parent_or_name), Expression::String(test)]) => {
@@ -115,6 +516,98 @@ impl Application {
                     Ok(Application::ScriptedFilterPiped(test.clone()))
                 }
 
+                ("filter",
+                 [Expression::Symbol(parent_or_name), Expression::RegexComparator, Expression::String(regex)]) => {
+                    if is_pipelined {
+                        Ok(Application::RegexFilterPipedNamed(parent_or_name.clone(), regex.clone()))
+                    } else {
+                        Ok(Application::RegexFilter(parent_or_name.clone(), regex.clone()))
+                    }
+                }
+                ("filter",
+                 [Expression::Symbol(parent), Expression::Symbol(name), Expression::RegexComparator, Expression::String(regex)]) => {
+                    Ok(Application::RegexFilterNamed(parent.clone(), name.clone(), regex.clone()))
+                }
+                ("filter",
+                 [Expression::RegexComparator, Expression::String(regex)]) => {
+                    Ok(Application::RegexFilterPiped(regex.clone()))
+                }
+
+                ("filter",
+                 [Expression::Symbol(parent_or_name), Expression::Application(func, set)]) if func == "in" => {
+                    match set.as_slice() {
+                        [Expression::Symbol(set)] if is_pipelined => {
+                            Ok(Application::InFilterPipedNamed(parent_or_name.clone(), set.clone()))
+                        }
+                        [Expression::Symbol(set)] => {
+                            Ok(Application::InFilter(parent_or_name.clone(), set.clone()))
+                        }
+                        _ => Err(SyntaxError::UnknownFunction),
+                    }
+                }
+                ("filter",
+                 [Expression::Symbol(parent), Expression::Symbol(name), Expression::Application(func, set)]) if func == "in" => {
+                    match set.as_slice() {
+                        [Expression::Symbol(set)] => {
+                            Ok(Application::InFilterNamed(parent.clone(), name.clone(), set.clone()))
+                        }
+                        _ => Err(SyntaxError::UnknownFunction),
+                    }
+                }
+                ("filter",
+                 [Expression::Application(func, set)]) if func == "in" => {
+                    match set.as_slice() {
+                        [Expression::Symbol(set)] => Ok(Application::InFilterPiped(set.clone())),
+                        _ => Err(SyntaxError::UnknownFunction),
+                    }
+                }
+
+                ("filter",
+                 [Expression::Symbol(parent_or_name), Expression::Application(func, lag), Expression::Comparator(comp, mode)]) if func == "lag" => {
+                    let (lag, factor, mode) = lag_options(lag, *comp, *mode)?;
+                    if is_pipelined {
+                        Ok(Application::LagFilterPipedNamed(parent_or_name.clone(), lag, factor, *comp, mode))
+                    } else {
+                        Ok(Application::LagFilter(parent_or_name.clone(), lag, factor, *comp, mode))
+                    }
+                }
+                ("filter",
+                 [Expression::Symbol(parent), Expression::Symbol(name), Expression::Application(func, lag), Expression::Comparator(comp, mode)]) if func == "lag" => {
+                    let (lag, factor, mode) = lag_options(lag, *comp, *mode)?;
+                    Ok(Application::LagFilterNamed(parent.clone(), name.clone(), lag, factor, *comp, mode))
+                }
+                ("filter",
+                 [Expression::Application(func, lag), Expression::Comparator(comp, mode)]) if func == "lag" => {
+                    let (lag, factor, mode) = lag_options(lag, *comp, *mode)?;
+                    Ok(Application::LagFilterPiped(lag, factor, *comp, mode))
+                }
+
+                ("or",
+                 [Expression::Symbol(right)]) => {
+                    Ok(Application::OrPiped(right.clone()))
+                }
+                ("or",
+                 [Expression::Symbol(left), Expression::Symbol(right)]) => {
+                    Ok(Application::Or(left.clone(), right.clone(), None))
+                }
+                ("or",
+                 [Expression::Symbol(left), Expression::Symbol(right), Expression::Symbol(name)]) => {
+                    Ok(Application::Or(left.clone(), right.clone(), Some(name.clone())))
+                }
+
+                ("and",
+                 [Expression::Symbol(right)]) => {
+                    Ok(Application::AndPiped(right.clone()))
+                }
+                ("and",
+                 [Expression::Symbol(left), Expression::Symbol(right)]) => {
+                    Ok(Application::And(left.clone(), right.clone(), None))
+                }
+                ("and",
+                 [Expression::Symbol(left), Expression::Symbol(right), Expression::Symbol(name)]) => {
+                    Ok(Application::And(left.clone(), right.clone(), Some(name.clone())))
+                }
+
                 ("distinct",
                  [Expression::Symbol(parent)]) => {
                     Ok(Application::Distinct(parent.clone()))
@@ -124,6 +617,142 @@ impl Application {
                     Ok(Application::DistinctPiped)
                 }
 
+                ("values",
+                 [Expression::Symbol(parent)]) => {
+                    Ok(Application::DistinctValues(parent.clone(), None))
+                }
+                ("values",
+                 [Expression::Symbol(parent), Expression::Keyword(agg)]) => {
+                    Ok(Application::DistinctValues(parent.clone(), Some(aggregator(agg)?)))
+                }
+                ("values",
+                 []) => {
+                    Ok(Application::DistinctValuesPiped(None))
+                }
+                ("values",
+                 [Expression::Keyword(agg)]) => {
+                    Ok(Application::DistinctValuesPiped(Some(aggregator(agg)?)))
+                }
+
+                ("match_rate",
+                 [Expression::Symbol(left), Expression::Symbol(right)]) => {
+                    Ok(Application::MatchRate(left.clone(), right.clone(), Comparator::NullSafeEqual, CompareMode::Lexical))
+                }
+                ("match_rate",
+                 [Expression::Symbol(left), Expression::Symbol(right), Expression::Comparator(comp, mode)]) => {
+                    Ok(Application::MatchRate(left.clone(), right.clone(), *comp, *mode))
+                }
+
+                ("group",
+                 [Expression::Symbol(tag), Expression::Symbol(name), agg]) => {
+                    let (agg, value_tag) = aggregation(agg)?;
+                    Ok(Application::Group(tag.clone(), agg, value_tag, GroupOrder::Key, Some(name.clone())))
+                }
+                ("group",
+                 [Expression::Symbol(tag), Expression::Symbol(name), agg, Expression::Keyword(order)]) => {
+                    let (agg, value_tag) = aggregation(agg)?;
+                    Ok(Application::Group(tag.clone(), agg, value_tag, group_order(order)?, Some(name.clone())))
+                }
+                ("group",
+                 [Expression::Symbol(tag_or_name), agg]) => {
+                    let (agg, value_tag) = aggregation(agg)?;
+                    if is_pipelined {
+                        Ok(Application::GroupPiped(agg, value_tag, GroupOrder::Key, Some(tag_or_name.clone())))
+                    } else {
+                        Ok(Application::Group(tag_or_name.clone(), agg, value_tag, GroupOrder::Key, None))
+                    }
+                }
+                ("group",
+                 [Expression::Symbol(tag_or_name), agg, Expression::Keyword(order)]) => {
+                    let (agg, value_tag) = aggregation(agg)?;
+                    if is_pipelined {
+                        Ok(Application::GroupPiped(agg, value_tag, group_order(order)?, Some(tag_or_name.clone())))
+                    } else {
+                        Ok(Application::Group(tag_or_name.clone(), agg, value_tag, group_order(order)?, None))
+                    }
+                }
+                ("group",
+                 [agg]) => {
+                    let (agg, value_tag) = aggregation(agg)?;
+                    Ok(Application::GroupPiped(agg, value_tag, GroupOrder::Key, None))
+                }
+                ("group",
+                 [agg, Expression::Keyword(order)]) => {
+                    let (agg, value_tag) = aggregation(agg)?;
+                    Ok(Application::GroupPiped(agg, value_tag, group_order(order)?, None))
+                }
+
+                ("filter_groups",
+                 [Expression::Symbol(group_or_name), Expression::Comparator(comp, mode), value]) => {
+                    let (mode, value) = filter_operand(*comp, *mode, value)?;
+                    if is_pipelined {
+                        Ok(Application::FilterGroupsPiped(*comp, mode, value, Some(group_or_name.clone())))
+                    } else {
+                        Ok(Application::FilterGroups(group_or_name.clone(), *comp, mode, value, None))
+                    }
+                }
+                ("filter_groups",
+                 [Expression::Symbol(group), Expression::Symbol(name), Expression::Comparator(comp, mode), value]) => {
+                    let (mode, value) = filter_operand(*comp, *mode, value)?;
+                    Ok(Application::FilterGroups(group.clone(), *comp, mode, value, Some(name.clone())))
+                }
+                ("filter_groups",
+                 [Expression::Comparator(comp, mode), value]) => {
+                    let (mode, value) = filter_operand(*comp, *mode, value)?;
+                    Ok(Application::FilterGroupsPiped(*comp, mode, value, None))
+                }
+
+                ("aggregate",
+                 [Expression::Symbol(parent), agg]) => {
+                    let (agg, value_tag) = aggregation(agg)?;
+                    Ok(Application::Aggregate(parent.clone(), agg, value_tag))
+                }
+                ("aggregate",
+                 [agg]) => {
+                    let (agg, value_tag) = aggregation(agg)?;
+                    Ok(Application::AggregatePiped(agg, value_tag))
+                }
+
+                ("top",
+                 [Expression::Symbol(tag), Expression::Symbol(set), Expression::Int(count)]) => {
+                    Ok(Application::Top(tag.clone(), set.clone(), *count))
+                }
+                ("top",
+                 [Expression::Symbol(set), Expression::Int(count)]) => {
+                    Ok(Application::TopPiped(set.clone(), *count))
+                }
+
+                ("range", [Expression::Symbol(log), Expression::Int(start), Expression::Int(end)]) => {
+                    Ok(Application::Range(log.clone(), *start, *end))
+                }
+                ("range", [Expression::Int(start), Expression::Int(end)]) => {
+                    Ok(Application::RangePiped(*start, *end))
+                }
+
+                ("skip", [Expression::Symbol(log), Expression::Int(count)]) => {
+                    Ok(Application::Skip(log.clone(), *count))
+                }
+                ("skip", [Expression::Int(count)]) => {
+                    Ok(Application::SkipPiped(*count))
+                }
+
+                ("sort",
+                 [Expression::Symbol(log), Expression::Symbol(tag), Expression::Keyword(order)]) => {
+                    Ok(Application::Sort(log.clone(), tag.clone(), sort_order(order)?))
+                }
+                ("sort",
+                 [Expression::Symbol(log), Expression::Symbol(tag)]) => {
+                    Ok(Application::Sort(log.clone(), tag.clone(), Order::Ascending))
+                }
+                ("sort",
+                 [Expression::Symbol(tag), Expression::Keyword(order)]) => {
+                    Ok(Application::SortPiped(tag.clone(), sort_order(order)?))
+                }
+                ("sort",
+                 [Expression::Symbol(tag)]) => {
+                    Ok(Application::SortPiped(tag.clone(), Order::Ascending))
+                }
+
                 ("take", [Expression::Symbol(log), Expression::Int(count)]) => {
                     Ok(Application::Take(log.clone(), *count))
                 }
@@ -131,6 +760,95 @@ impl Application {
                     Ok(Application::TakePiped(*count))
                 }
 
+                ("take_last", [Expression::Symbol(log), Expression::Int(count)]) => {
+                    Ok(Application::TakeLast(log.clone(), *count))
+                }
+                ("take_last", [Expression::Int(count)]) => {
+                    Ok(Application::TakeLastPiped(*count))
+                }
+
+                ("follow", [Expression::Symbol(log), Expression::Int(count)]) => {
+                    Ok(Application::Follow(log.clone(), *count))
+                }
+                ("follow", [Expression::Int(count)]) => {
+                    Ok(Application::FollowPiped(*count))
+                }
+
+                ("write",
+                 [Expression::Symbol(query), Expression::String(path), options @ ..])
+                | ("export",
+                 [Expression::Symbol(query), Expression::String(path), options @ ..]) => {
+                    write_options(options).map(|(count, with_tags)| {
+                        Application::Write(query.clone(), path.clone(), count, with_tags)
+                    })
+                }
+                ("write",
+                 [Expression::String(path), options @ ..])
+                | ("export",
+                 [Expression::String(path), options @ ..]) => {
+                    write_options(options).map(|(count, with_tags)| {
+                        Application::WritePiped(path.clone(), count, with_tags)
+                    })
+                }
+
+                ("materialize",
+                 [Expression::Symbol(query), Expression::Symbol(file)]) => {
+                    Ok(Application::Materialize(query.clone(), file.clone()))
+                }
+                ("materialize",
+                 [Expression::Symbol(file)]) => {
+                    Ok(Application::MaterializePiped(file.clone()))
+                }
+
+                ("save_selection",
+                 [Expression::Symbol(query), Expression::Symbol(name)]) => {
+                    Ok(Application::SaveSelection(query.clone(), name.clone()))
+                }
+                ("save_selection",
+                 [Expression::Symbol(name)]) => {
+                    Ok(Application::SaveSelectionPiped(name.clone()))
+                }
+
+                ("hexdump",
+                 [Expression::Symbol(file), Expression::Int(index)]) => {
+                    Ok(Application::Hexdump(file.clone(), *index))
+                }
+                ("hexdump",
+                 [Expression::Int(index)]) => {
+                    Ok(Application::HexdumpPiped(*index))
+                }
+
+                ("explain",
+                 [Expression::Symbol(name)]) => {
+                    Ok(Application::Explain(name.clone()))
+                }
+                ("explain",
+                 []) => {
+                    Ok(Application::ExplainPiped)
+                }
+
+                ("info",
+                 []) => {
+                    Ok(Application::Info)
+                }
+
+                ("unload",
+                 [Expression::Symbol(name)])
+                | ("drop",
+                 [Expression::Symbol(name)]) => {
+                    Ok(Application::Unload(name.clone()))
+                }
+
+                ("top", [.., Expression::Float(_)])
+                | ("range", [.., Expression::Float(_), _])
+                | ("range", [.., Expression::Float(_)])
+                | ("skip", [.., Expression::Float(_)])
+                | ("take", [.., Expression::Float(_)])
+                | ("take_last", [.., Expression::Float(_)])
+                | ("follow", [.., Expression::Float(_)]) => {
+                    Err(SyntaxError::ExpectedCount)
+                }
+
                 _ => Err(SyntaxError::UnknownFunction),
             }
         } else {
@@ -140,27 +858,98 @@ impl Application {
 
     fn is_pipelined(&self) -> bool {
         match self {
-            Application::Load(_, _) => false,
+            Application::Load(_, _, _) => false,
+            Application::LoadMany(_, _, _) => false,
             Application::Script(_) => false,
+            Application::Save(_) => false,
+            Application::LoadSession(_) => false,
+            Application::Symbols => false,
+            Application::RecordStart(_, _) => false,
             Application::Tag(_, _) => false,
-            Application::Regex(_, _) => false,
+            Application::Regex(_, _, _) => false,
+            Application::RegexAll(_, _, _) => false,
+            Application::SplitRegex(_, _, _) => false,
+            Application::Tokenize(_) => false,
+            Application::JsonTag(_, _) => false,
+            Application::ColumnTag(_, _, _) => false,
             Application::Transform(_, _) => false,
-            Application::DirectFilter(_, _, _) => false,
-            Application::DirectFilterNamed(_, _, _, _) => false,
+            Application::Retag(_, _, _) => false,
+            Application::DirectFilter(_, _, _, _) => false,
+            Application::DirectFilterNamed(_, _, _, _, _) => false,
+            Application::CustomFilter(_, _, _) => false,
+            Application::CustomFilterNamed(_, _, _, _) => false,
             Application::ScriptedFilter(_, _) => false,
             Application::ScriptedFilterNamed(_, _, _) => false,
+            Application::RegexFilter(_, _) => false,
+            Application::RegexFilterNamed(_, _, _) => false,
+            Application::InFilter(_, _) => false,
+            Application::InFilterNamed(_, _, _) => false,
+            Application::LagFilter(_, _, _, _, _) => false,
+            Application::LagFilterNamed(_, _, _, _, _, _) => false,
+            Application::Or(_, _, _) => false,
+            Application::And(_, _, _) => false,
             Application::Distinct(_) => false,
+            Application::DistinctValues(_, _) => false,
+            Application::MatchRate(_, _, _, _) => false,
+            Application::Group(_, _, _, _, _) => false,
+            Application::FilterGroups(_, _, _, _, _) => false,
+            Application::Aggregate(_, _, _) => false,
+            Application::Top(_, _, _) => false,
+            Application::Range(_, _, _) => false,
+            Application::Skip(_, _) => false,
+            Application::Sort(_, _, _) => false,
             Application::Take(_, _) => false,
+            Application::TakeLast(_, _) => false,
+            Application::Follow(_, _) => false,
+            Application::Write(_, _, _, _) => false,
+            Application::Materialize(_, _) => false,
+            Application::SaveSelection(_, _) => false,
+            Application::Hexdump(_, _) => false,
+            Application::Explain(_) => false,
+            Application::Unload(_) => false,
 
+            Application::RecordStartPiped(_) => true,
             Application::TagPiped(_) => true,
-            Application::RegexPiped(_) => true,
+            Application::RegexPiped(_, _) => true,
+            Application::RegexAllPiped(_, _) => true,
+            Application::SplitRegexPiped(_, _) => true,
+            Application::TokenizePiped => true,
+            Application::JsonTagPiped(_) => true,
+            Application::ColumnTagPiped(_, _) => true,
             Application::TransformPiped(_) => true,
-            Application::DirectFilterPiped(_, _) => true,
-            Application::DirectFilterPipedNamed(_, _, _) => true,
+            Application::RetagPiped(_, _) => true,
+            Application::DirectFilterPiped(_, _, _) => true,
+            Application::DirectFilterPipedNamed(_, _, _, _) => true,
+            Application::CustomFilterPiped(_, _) => true,
+            Application::CustomFilterPipedNamed(_, _, _) => true,
             Application::ScriptedFilterPiped(_) => true,
             Application::ScriptedFilterPipedNamed(_, _) => true,
+            Application::RegexFilterPiped(_) => true,
+            Application::RegexFilterPipedNamed(_, _) => true,
+            Application::InFilterPiped(_) => true,
+            Application::InFilterPipedNamed(_, _) => true,
+            Application::LagFilterPiped(_, _, _, _) => true,
+            Application::LagFilterPipedNamed(_, _, _, _, _) => true,
+            Application::OrPiped(_) => true,
+            Application::AndPiped(_) => true,
             Application::DistinctPiped => true,
+            Application::DistinctValuesPiped(_) => true,
+            Application::GroupPiped(_, _, _, _) => true,
+            Application::FilterGroupsPiped(_, _, _, _) => true,
+            Application::AggregatePiped(_, _) => true,
+            Application::TopPiped(_, _) => true,
+            Application::RangePiped(_, _) => true,
+            Application::SkipPiped(_) => true,
+            Application::SortPiped(_, _) => true,
             Application::TakePiped(_) => true,
+            Application::TakeLastPiped(_) => true,
+            Application::FollowPiped(_) => true,
+            Application::WritePiped(_, _, _) => true,
+            Application::MaterializePiped(_) => true,
+            Application::SaveSelectionPiped(_) => true,
+            Application::HexdumpPiped(_) => true,
+            Application::ExplainPiped => true,
+            Application::Info => false,
         }
     }
 }
@@ -184,17 +973,32 @@ fn parse_line(line: &str, is_pipelined: bool) -> Result<ParseState> {
             Err(err) => Err(Error::Syntax(err, line.to_string())),
         },
         Err(err) => match err {
-            nom::Err::Error(e) | nom::Err::Failure(e) => {
-                // FIXME: https://github.com/Geal/nom/issues/1027
-                let default = format!("{:#?}", e);
-                let converted = std::panic::catch_unwind(|| nom::error::convert_error(&line, e));
-                Err(Error::Parser(converted.unwrap_or(default)))
-            }
+            nom::Err::Error(e) | nom::Err::Failure(e) => Err(parser_error(line, e)),
             nom::Err::Incomplete(_) => Ok(ParseState::Incomplete),
         },
     }
 }
 
+/// Locate the innermost error, whose remaining input is a suffix of `line`
+fn parser_error(line: &str, err: VerboseError<&str>) -> Error {
+    match err.errors.first() {
+        Some((remaining, kind)) => {
+            let expected = match kind {
+                VerboseErrorKind::Char(c) => format!("expected '{}'", c),
+                VerboseErrorKind::Context(context) => context.to_string(),
+                VerboseErrorKind::Nom(ErrorKind::Escaped) => "invalid escape sequence".to_string(),
+                VerboseErrorKind::Nom(kind) => format!("unexpected input ({:?})", kind),
+            };
+            let offset = line
+                .len()
+                .checked_sub(remaining.len())
+                .filter(|offset| line.is_char_boundary(*offset));
+            Error::Parser(expected, line.to_string(), offset)
+        }
+        None => Error::Parser("invalid input".to_string(), line.to_string(), None),
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum CursorState {
     Root,
@@ -206,6 +1010,10 @@ pub struct Interpreter {
     buffer: Vec<Application>,
     line: String,
     symbols: HashMap<String, Id>,
+    /// Comments of the loaded sessions, written again when the session is saved
+    comments: Vec<String>,
+    /// Preset applied by `load` when it isn't given a format
+    input_format: Option<&'static Preset>,
 }
 
 impl Interpreter {
@@ -214,9 +1022,17 @@ impl Interpreter {
             buffer: vec![],
             line: String::new(),
             symbols: HashMap::new(),
+            comments: vec![],
+            input_format: None,
         }
     }
 
+    /// Define the tags of the named preset on every file loaded without a format
+    pub fn set_input_format(&mut self, format: &str) -> Result<()> {
+        self.input_format = Some(presets::find(format)?);
+        Ok(())
+    }
+
     pub fn add_line_segment(&mut self, segment: &str) -> Result<CursorState> {
         let is_continuation = !self.line.is_empty();
         self.line.push_str(segment);
@@ -246,18 +1062,94 @@ impl Interpreter {
         }
     }
 
-    pub fn execute(&mut self, mut engine: &mut Engine) -> Result<Vec<String>> {
+    /// Discard any partially entered expression or pipeline
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.line.clear();
+    }
+
+    /// Run the buffered applications, passing each line of the final output to `emit` as soon
+    /// as it is available
+    pub fn execute<F: FnMut(&str)>(&mut self, mut engine: &mut Engine, mut emit: F) -> Result<()> {
         let mut target = None;
-        let mut lines = vec![];
         let applications = std::mem::replace(&mut self.buffer, vec![]);
+        let last = applications.len().saturating_sub(1);
 
-        for app in applications {
-            let output = self.apply(&mut engine, app, target)?;
-            target = output.id;
-            lines = output.lines;
-            lines.push(format!("\n  {}", output.stats));
+        for (idx, app) in applications.into_iter().enumerate() {
+            if idx == last {
+                if let Some((id, count, follow)) = self.streamed_take(&app, target)? {
+                    let mut rows = if follow {
+                        engine.follow_iter(id, count)?
+                    } else {
+                        engine.take_iter(id, count)?
+                    };
+                    let streamed = rows
+                        .by_ref()
+                        .try_for_each(|line| line.map(|line| emit(&line)));
+                    let stats = rows.finish();
+                    streamed?;
+                    self.emit_stats(engine, &stats, &mut emit);
+                    return Ok(());
+                }
+            }
+
+            let output = self.apply(&mut engine, app, target)?;
+            target = output.id;
+
+            if idx == last {
+                for line in &output.lines {
+                    if output.is_message && engine.results_only_on_stdout() {
+                        eprintln!("{}", line);
+                    } else {
+                        emit(line);
+                    }
+                }
+                self.emit_stats(engine, &output.stats, &mut emit);
+            }
+        }
+        Ok(())
+    }
+
+    fn emit_stats<F: FnMut(&str)>(&self, engine: &Engine, stats: &Stats, emit: &mut F) {
+        if engine.results_only_on_stdout() {
+            return;
+        }
+        match engine.stats_format() {
+            StatsFormat::Human => emit(&format!("\n  {}", stats.named(&self.names()))),
+            StatsFormat::Json => {
+                if let Some(json) = stats.to_json(&self.names()) {
+                    emit(&json.to_string());
+                }
+            }
+        }
+    }
+
+    /// The target, row count and whether to keep following the file for a trailing `take` or
+    /// `follow`
+    fn streamed_take(
+        &self,
+        app: &Application,
+        target: Option<Id>,
+    ) -> Result<Option<(Id, usize, bool)>> {
+        match app {
+            Application::Take(name, count) => match self.symbols.get(name) {
+                Some(id) => Ok(Some((*id, *count, false))),
+                None => Err(Error::SymbolNotFound(name.clone())),
+            },
+            Application::TakePiped(count) => match target {
+                Some(id) => Ok(Some((id, *count, false))),
+                None => Err(Error::InvalidTarget(format!("{:?}", target))),
+            },
+            Application::Follow(name, count) => match self.symbols.get(name) {
+                Some(id) => Ok(Some((*id, *count, true))),
+                None => Err(Error::SymbolNotFound(name.clone())),
+            },
+            Application::FollowPiped(count) => match target {
+                Some(id) => Ok(Some((id, *count, true))),
+                None => Err(Error::InvalidTarget(format!("{:?}", target))),
+            },
+            _ => Ok(None),
         }
-        Ok(lines)
     }
 
     fn apply(
@@ -267,13 +1159,46 @@ impl Interpreter {
         target: Option<Id>,
     ) -> Result<Output> {
         match app {
-            Application::Load(file_name, path_str) => {
+            Application::Load(file_name, path_str, format) => {
+                let preset = self.preset(format)?;
                 let output = engine.run_command(&Command::Load(PathBuf::from(path_str)))?;
                 self.add_symbol(file_name, output.id)?;
-                Ok(output)
+                self.add_preset_tags(engine, preset, output)
+            }
+            Application::LoadMany(file_name, paths, format) => {
+                let preset = self.preset(format)?;
+                let paths = paths.into_iter().map(PathBuf::from).collect();
+                let output = engine.run_command(&Command::LoadMany(paths))?;
+                self.add_symbol(file_name, output.id)?;
+                self.add_preset_tags(engine, preset, output)
             }
             Application::Script(script) => engine.run_command(&Command::Script(script)),
 
+            Application::Save(path) => {
+                Session::new(engine, &self.symbols, &self.comments).write(Path::new(&path))?;
+                Ok(Output::with_message(
+                    None,
+                    format!("session saved: {:?}", path),
+                ))
+            }
+            Application::LoadSession(path) => self.load_session(engine, &path),
+            Application::Symbols => Ok(self.symbols(engine)),
+
+            Application::RecordStart(file_name, regex) => {
+                if let Some(Id::File(file_id)) = self.symbols.get(&file_name) {
+                    engine.run_command(&Command::RecordStart(*file_id, regex))
+                } else {
+                    Err(Error::FileNotLoaded(file_name))
+                }
+            }
+            Application::RecordStartPiped(regex) => {
+                if let Some(Id::File(file_id)) = target {
+                    engine.run_command(&Command::RecordStart(file_id, regex))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
             Application::Tag(file_name, tag_name) => {
                 if let Some(Id::File(file_id)) = self.symbols.get(&file_name) {
                     let output = engine.run_command(&Command::Tag(*file_id, tag_name.clone()))?;
@@ -294,16 +1219,91 @@ impl Interpreter {
                 }
             }
 
-            Application::Regex(tag_name, regex) => {
+            Application::Regex(tag_name, regex, group) => {
+                if let Some(Id::Tag(tag_id)) = self.symbols.get(&tag_name) {
+                    engine.run_command(&Command::Regex(*tag_id, engine.tag_regex(regex), group))
+                } else {
+                    Err(Error::SymbolNotFound(tag_name))
+                }
+            }
+            Application::RegexPiped(regex, group) => {
+                if let Some(Id::Tag(tag_id)) = target {
+                    engine.run_command(&Command::Regex(tag_id, engine.tag_regex(regex), group))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::RegexAll(tag_name, regex, group) => {
                 if let Some(Id::Tag(tag_id)) = self.symbols.get(&tag_name) {
-                    engine.run_command(&Command::Regex(*tag_id, regex))
+                    engine.run_command(&Command::RegexAll(*tag_id, regex, group))
                 } else {
                     Err(Error::SymbolNotFound(tag_name))
                 }
             }
-            Application::RegexPiped(regex) => {
+            Application::RegexAllPiped(regex, group) => {
                 if let Some(Id::Tag(tag_id)) = target {
-                    engine.run_command(&Command::Regex(tag_id, regex))
+                    engine.run_command(&Command::RegexAll(tag_id, regex, group))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::SplitRegex(tag_name, delimiter, index) => {
+                if let Some(Id::Tag(tag_id)) = self.symbols.get(&tag_name) {
+                    engine.run_command(&Command::SplitRegex(*tag_id, delimiter, index))
+                } else {
+                    Err(Error::SymbolNotFound(tag_name))
+                }
+            }
+            Application::SplitRegexPiped(delimiter, index) => {
+                if let Some(Id::Tag(tag_id)) = target {
+                    engine.run_command(&Command::SplitRegex(tag_id, delimiter, index))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Tokenize(tag_name) => {
+                if let Some(Id::Tag(tag_id)) = self.symbols.get(&tag_name) {
+                    engine.run_command(&Command::Tokenize(*tag_id))
+                } else {
+                    Err(Error::SymbolNotFound(tag_name))
+                }
+            }
+            Application::TokenizePiped => {
+                if let Some(Id::Tag(tag_id)) = target {
+                    engine.run_command(&Command::Tokenize(tag_id))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::JsonTag(tag_name, path) => {
+                if let Some(Id::Tag(tag_id)) = self.symbols.get(&tag_name) {
+                    engine.run_command(&Command::JsonPath(*tag_id, path))
+                } else {
+                    Err(Error::SymbolNotFound(tag_name))
+                }
+            }
+            Application::JsonTagPiped(path) => {
+                if let Some(Id::Tag(tag_id)) = target {
+                    engine.run_command(&Command::JsonPath(tag_id, path))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::ColumnTag(tag_name, start, end) => {
+                if let Some(Id::Tag(tag_id)) = self.symbols.get(&tag_name) {
+                    engine.run_command(&Command::Columns(*tag_id, start, end))
+                } else {
+                    Err(Error::SymbolNotFound(tag_name))
+                }
+            }
+            Application::ColumnTagPiped(start, end) => {
+                if let Some(Id::Tag(tag_id)) = target {
+                    engine.run_command(&Command::Columns(tag_id, start, end))
                 } else {
                     Err(Error::InvalidTarget(format!("{:?}", target)))
                 }
@@ -324,34 +1324,82 @@ impl Interpreter {
                 }
             }
 
-            Application::DirectFilter(parent_name, comparator, value) => {
+            Application::Retag(tag_name, regex, transform) => {
+                if let Some(Id::Tag(tag_id)) = self.symbols.get(&tag_name) {
+                    engine.run_command(&Command::Retag(*tag_id, regex, transform))
+                } else {
+                    Err(Error::SymbolNotFound(tag_name))
+                }
+            }
+            Application::RetagPiped(regex, transform) => {
+                if let Some(Id::Tag(tag_id)) = target {
+                    engine.run_command(&Command::Retag(tag_id, regex, transform))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::DirectFilter(parent_name, comparator, mode, value) => {
                 if let Some(id) = self.symbols.get(&parent_name) {
-                    engine.run_command(&Command::DirectFilter(*id, comparator, value))
+                    engine.run_command(&Command::DirectFilter(*id, comparator, mode, value))
                 } else {
                     Err(Error::SymbolNotFound(parent_name))
                 }
             }
-            Application::DirectFilterNamed(parent_name, filter_name, comparator, value) => {
+            Application::DirectFilterNamed(parent_name, filter_name, comparator, mode, value) => {
                 if let Some(id) = self.symbols.get(&parent_name) {
                     let output =
-                        engine.run_command(&Command::DirectFilter(*id, comparator, value))?;
+                        engine.run_command(&Command::DirectFilter(*id, comparator, mode, value))?;
                     self.add_symbol(filter_name, output.id)?;
                     Ok(output)
                 } else {
                     Err(Error::SymbolNotFound(parent_name))
                 }
             }
-            Application::DirectFilterPiped(comparator, value) => {
+            Application::DirectFilterPiped(comparator, mode, value) => {
                 if let Some(id) = target {
-                    engine.run_command(&Command::DirectFilter(id, comparator, value))
+                    engine.run_command(&Command::DirectFilter(id, comparator, mode, value))
                 } else {
                     Err(Error::InvalidTarget(format!("{:?}", target)))
                 }
             }
-            Application::DirectFilterPipedNamed(filter_name, comparator, value) => {
+            Application::DirectFilterPipedNamed(filter_name, comparator, mode, value) => {
                 if let Some(id) = target {
                     let output =
-                        engine.run_command(&Command::DirectFilter(id, comparator, value))?;
+                        engine.run_command(&Command::DirectFilter(id, comparator, mode, value))?;
+                    self.add_symbol(filter_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::CustomFilter(parent_name, func, value) => {
+                if let Some(id) = self.symbols.get(&parent_name) {
+                    engine.run_command(&Command::CustomFilter(*id, func, value))
+                } else {
+                    Err(Error::SymbolNotFound(parent_name))
+                }
+            }
+            Application::CustomFilterNamed(parent_name, filter_name, func, value) => {
+                if let Some(id) = self.symbols.get(&parent_name) {
+                    let output = engine.run_command(&Command::CustomFilter(*id, func, value))?;
+                    self.add_symbol(filter_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::SymbolNotFound(parent_name))
+                }
+            }
+            Application::CustomFilterPiped(func, value) => {
+                if let Some(id) = target {
+                    engine.run_command(&Command::CustomFilter(id, func, value))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+            Application::CustomFilterPipedNamed(filter_name, func, value) => {
+                if let Some(id) = target {
+                    let output = engine.run_command(&Command::CustomFilter(id, func, value))?;
                     self.add_symbol(filter_name, output.id)?;
                     Ok(output)
                 } else {
@@ -392,6 +1440,135 @@ impl Interpreter {
                 }
             }
 
+            Application::RegexFilter(parent_name, regex) => {
+                if let Some(id) = self.symbols.get(&parent_name) {
+                    engine.run_command(&Command::RegexFilter(*id, regex))
+                } else {
+                    Err(Error::SymbolNotFound(parent_name))
+                }
+            }
+            Application::RegexFilterNamed(parent_name, filter_name, regex) => {
+                if let Some(id) = self.symbols.get(&parent_name) {
+                    let output = engine.run_command(&Command::RegexFilter(*id, regex))?;
+                    self.add_symbol(filter_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::SymbolNotFound(parent_name))
+                }
+            }
+            Application::RegexFilterPiped(regex) => {
+                if let Some(id) = target {
+                    engine.run_command(&Command::RegexFilter(id, regex))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+            Application::RegexFilterPipedNamed(filter_name, regex) => {
+                if let Some(id) = target {
+                    let output = engine.run_command(&Command::RegexFilter(id, regex))?;
+                    self.add_symbol(filter_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::InFilter(parent_name, set_name) => {
+                let set_id = self.set_symbol(set_name)?;
+                if let Some(id) = self.symbols.get(&parent_name) {
+                    engine.run_command(&Command::InFilter(*id, set_id))
+                } else {
+                    Err(Error::SymbolNotFound(parent_name))
+                }
+            }
+            Application::InFilterNamed(parent_name, filter_name, set_name) => {
+                let set_id = self.set_symbol(set_name)?;
+                if let Some(id) = self.symbols.get(&parent_name) {
+                    let output = engine.run_command(&Command::InFilter(*id, set_id))?;
+                    self.add_symbol(filter_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::SymbolNotFound(parent_name))
+                }
+            }
+            Application::InFilterPiped(set_name) => {
+                let set_id = self.set_symbol(set_name)?;
+                if let Some(id) = target {
+                    engine.run_command(&Command::InFilter(id, set_id))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+            Application::InFilterPipedNamed(filter_name, set_name) => {
+                let set_id = self.set_symbol(set_name)?;
+                if let Some(id) = target {
+                    let output = engine.run_command(&Command::InFilter(id, set_id))?;
+                    self.add_symbol(filter_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::LagFilter(parent_name, lag, factor, comparator, mode) => {
+                if let Some(id) = self.symbols.get(&parent_name) {
+                    engine.run_command(&Command::LagFilter(*id, lag, factor, comparator, mode))
+                } else {
+                    Err(Error::SymbolNotFound(parent_name))
+                }
+            }
+            Application::LagFilterNamed(
+                parent_name,
+                filter_name,
+                lag,
+                factor,
+                comparator,
+                mode,
+            ) => {
+                if let Some(id) = self.symbols.get(&parent_name) {
+                    let output = engine
+                        .run_command(&Command::LagFilter(*id, lag, factor, comparator, mode))?;
+                    self.add_symbol(filter_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::SymbolNotFound(parent_name))
+                }
+            }
+            Application::LagFilterPiped(lag, factor, comparator, mode) => {
+                if let Some(id) = target {
+                    engine.run_command(&Command::LagFilter(id, lag, factor, comparator, mode))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+            Application::LagFilterPipedNamed(filter_name, lag, factor, comparator, mode) => {
+                if let Some(id) = target {
+                    let output = engine
+                        .run_command(&Command::LagFilter(id, lag, factor, comparator, mode))?;
+                    self.add_symbol(filter_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Or(left_name, right_name, name) => {
+                let left = self.symbol(left_name)?;
+                self.combine_filters(engine, Command::Or, left, right_name, name)
+            }
+            Application::OrPiped(right_name) => {
+                let left = target.ok_or_else(|| Error::InvalidTarget(format!("{:?}", target)))?;
+                self.combine_filters(engine, Command::Or, left, right_name, None)
+            }
+            Application::And(left_name, right_name, name) => {
+                let left = self.symbol(left_name)?;
+                self.combine_filters(engine, Command::And, left, right_name, name)
+            }
+            Application::AndPiped(right_name) => {
+                let left = target.ok_or_else(|| Error::InvalidTarget(format!("{:?}", target)))?;
+                self.combine_filters(engine, Command::And, left, right_name, None)
+            }
+
             Application::Distinct(parent_name) => {
                 if let Some(id) = self.symbols.get(&parent_name) {
                     engine.run_command(&Command::Distinct(*id))
@@ -407,6 +1584,149 @@ impl Interpreter {
                 }
             }
 
+            Application::DistinctValues(parent_name, aggregator) => {
+                if let Some(id) = self.symbols.get(&parent_name) {
+                    engine.run_command(&Command::DistinctValues(*id, aggregator.is_some()))
+                } else {
+                    Err(Error::SymbolNotFound(parent_name))
+                }
+            }
+            Application::DistinctValuesPiped(aggregator) => {
+                if let Some(id) = target {
+                    engine.run_command(&Command::DistinctValues(id, aggregator.is_some()))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::MatchRate(left_name, right_name, comp, mode) => {
+                match (self.symbols.get(&left_name), self.symbols.get(&right_name)) {
+                    (Some(Id::Tag(left)), Some(Id::Tag(right))) => {
+                        engine.run_command(&Command::MatchRate(*left, *right, comp, mode))
+                    }
+                    (Some(Id::Tag(_)), _) => Err(Error::SymbolNotFound(right_name)),
+                    _ => Err(Error::SymbolNotFound(left_name)),
+                }
+            }
+
+            Application::Group(tag_name, aggregator, value_tag_name, order, group_name) => {
+                let value_tag_id = self.value_tag(value_tag_name)?;
+                if let Some(Id::Tag(tag_id)) = self.symbols.get(&tag_name) {
+                    let command = Command::Group(*tag_id, aggregator, value_tag_id, order);
+                    self.run_named(engine, &command, group_name)
+                } else {
+                    Err(Error::SymbolNotFound(tag_name))
+                }
+            }
+            Application::GroupPiped(aggregator, value_tag_name, order, group_name) => {
+                let value_tag_id = self.value_tag(value_tag_name)?;
+                if let Some(Id::Tag(tag_id)) = target {
+                    let command = Command::Group(tag_id, aggregator, value_tag_id, order);
+                    self.run_named(engine, &command, group_name)
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::FilterGroups(group_name, comparator, mode, value, name) => {
+                let group_id = self.group_symbol(group_name)?;
+                let command = Command::FilterGroups(group_id, comparator, mode, value);
+                self.run_named(engine, &command, name)
+            }
+            Application::FilterGroupsPiped(comparator, mode, value, name) => {
+                if let Some(Id::Group(group_id)) = target {
+                    let command = Command::FilterGroups(group_id, comparator, mode, value);
+                    self.run_named(engine, &command, name)
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Aggregate(parent_name, aggregator, value_tag_name) => {
+                let value_tag_id = self.value_tag(value_tag_name)?;
+                if let Some(id) = self.symbols.get(&parent_name) {
+                    engine.run_command(&Command::Aggregate(*id, aggregator, value_tag_id))
+                } else {
+                    Err(Error::SymbolNotFound(parent_name))
+                }
+            }
+            Application::AggregatePiped(aggregator, value_tag_name) => {
+                let value_tag_id = self.value_tag(value_tag_name)?;
+                if let Some(id) = target {
+                    engine.run_command(&Command::Aggregate(id, aggregator, value_tag_id))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Top(tag_name, set_name, count) => {
+                if let Some(Id::Tag(tag_id)) = self.symbols.get(&tag_name) {
+                    let output = engine.run_command(&Command::Top(*tag_id, count))?;
+                    self.add_symbol(set_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::SymbolNotFound(tag_name))
+                }
+            }
+            Application::TopPiped(set_name, count) => {
+                if let Some(Id::Tag(tag_id)) = target {
+                    let output = engine.run_command(&Command::Top(tag_id, count))?;
+                    self.add_symbol(set_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Range(name, start, end) => {
+                if let Some(id) = self.symbols.get(&name) {
+                    engine.run_command(&Command::Range(*id, start, end))
+                } else {
+                    Err(Error::SymbolNotFound(name))
+                }
+            }
+            Application::RangePiped(start, end) => {
+                if let Some(id) = target {
+                    engine.run_command(&Command::Range(id, start, end))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Skip(name, count) => {
+                if let Some(id) = self.symbols.get(&name) {
+                    engine.run_command(&Command::Skip(*id, count))
+                } else {
+                    Err(Error::SymbolNotFound(name))
+                }
+            }
+            Application::SkipPiped(count) => {
+                if let Some(id) = target {
+                    engine.run_command(&Command::Skip(id, count))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Sort(name, tag_name, order) => {
+                match (self.symbols.get(&name), self.symbols.get(&tag_name)) {
+                    (Some(id), Some(Id::Tag(tag_id))) => {
+                        engine.run_command(&Command::Sort(*id, *tag_id, order))
+                    }
+                    (Some(_), _) => Err(Error::SymbolNotFound(tag_name)),
+                    _ => Err(Error::SymbolNotFound(name)),
+                }
+            }
+            Application::SortPiped(tag_name, order) => {
+                match (target, self.symbols.get(&tag_name)) {
+                    (Some(id), Some(Id::Tag(tag_id))) => {
+                        engine.run_command(&Command::Sort(id, *tag_id, order))
+                    }
+                    (Some(_), _) => Err(Error::SymbolNotFound(tag_name)),
+                    _ => Err(Error::InvalidTarget(format!("{:?}", target))),
+                }
+            }
+
             Application::Take(name, count) => {
                 if let Some(id) = self.symbols.get(&name) {
                     engine.run_command(&Command::Take(*id, count))
@@ -421,6 +1741,292 @@ impl Interpreter {
                     Err(Error::InvalidTarget(format!("{:?}", target)))
                 }
             }
+
+            Application::TakeLast(name, count) => {
+                if let Some(id) = self.symbols.get(&name) {
+                    engine.run_command(&Command::TakeLast(*id, count))
+                } else {
+                    Err(Error::SymbolNotFound(name))
+                }
+            }
+            Application::TakeLastPiped(count) => {
+                if let Some(id) = target {
+                    engine.run_command(&Command::TakeLast(id, count))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Follow(name, count) => {
+                if let Some(id) = self.symbols.get(&name) {
+                    engine.run_command(&Command::Follow(*id, count))
+                } else {
+                    Err(Error::SymbolNotFound(name))
+                }
+            }
+            Application::FollowPiped(count) => {
+                if let Some(id) = target {
+                    engine.run_command(&Command::Follow(id, count))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Write(name, path, count, with_tags) => {
+                if let Some(id) = self.symbols.get(&name) {
+                    engine.run_command(&Command::Write(*id, PathBuf::from(path), count, with_tags))
+                } else {
+                    Err(Error::SymbolNotFound(name))
+                }
+            }
+            Application::WritePiped(path, count, with_tags) => {
+                if let Some(id) = target {
+                    engine.run_command(&Command::Write(id, PathBuf::from(path), count, with_tags))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Materialize(name, file_name) => {
+                if let Some(id) = self.symbols.get(&name) {
+                    let output = engine.run_command(&Command::Materialize(*id))?;
+                    self.add_symbol(file_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::SymbolNotFound(name))
+                }
+            }
+            Application::MaterializePiped(file_name) => {
+                if let Some(id) = target {
+                    let output = engine.run_command(&Command::Materialize(id))?;
+                    self.add_symbol(file_name, output.id)?;
+                    Ok(output)
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::SaveSelection(query_name, name) => {
+                let query = self.symbol(query_name)?;
+                let output = engine.run_command(&Command::SaveSelection(query))?;
+                self.add_symbol(name, output.id)?;
+                Ok(output)
+            }
+            Application::SaveSelectionPiped(name) => {
+                let query = target.ok_or_else(|| Error::InvalidTarget(format!("{:?}", target)))?;
+                let output = engine.run_command(&Command::SaveSelection(query))?;
+                self.add_symbol(name, output.id)?;
+                Ok(output)
+            }
+
+            Application::Hexdump(file_name, index) => {
+                if let Some(Id::File(file_id)) = self.symbols.get(&file_name) {
+                    engine.run_command(&Command::Hexdump(*file_id, index))
+                } else {
+                    Err(Error::FileNotLoaded(file_name))
+                }
+            }
+            Application::HexdumpPiped(index) => {
+                if let Some(Id::File(file_id)) = target {
+                    engine.run_command(&Command::Hexdump(file_id, index))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Explain(name) => {
+                if let Some(id) = self.symbols.get(&name) {
+                    engine.run_command(&Command::Explain(*id))
+                } else {
+                    Err(Error::SymbolNotFound(name))
+                }
+            }
+            Application::ExplainPiped => {
+                if let Some(id) = target {
+                    engine.run_command(&Command::Explain(id))
+                } else {
+                    Err(Error::InvalidTarget(format!("{:?}", target)))
+                }
+            }
+
+            Application::Info => engine.run_command(&Command::Info),
+
+            Application::Unload(name) => {
+                if let Some(id) = self.symbols.get(&name) {
+                    let mut output = engine.run_command(&Command::Unload(*id))?;
+                    let mut removed = self
+                        .symbols
+                        .iter()
+                        .filter(|(_, id)| !engine.contains(**id))
+                        .map(|(name, _)| name.clone())
+                        .collect::<Vec<String>>();
+                    removed.sort();
+                    self.symbols.retain(|_, id| engine.contains(*id));
+                    if !removed.is_empty() {
+                        output.lines.push(format!(
+                            "symbols removed: {}",
+                            removed
+                                .iter()
+                                .map(|name| format!("'{}", name))
+                                .collect::<Vec<String>>()
+                                .join(", ")
+                        ));
+                    }
+                    Ok(output)
+                } else {
+                    Err(Error::SymbolNotFound(name))
+                }
+            }
+        }
+    }
+
+    /// Replay a saved session in `engine`, binding its symbols to the newly created IDs
+    pub fn load_session(&mut self, engine: &mut Engine, path: &str) -> Result<Output> {
+        let session = Session::read(Path::new(path))?;
+        let symbols = session.replay(engine)?;
+        let count = symbols.len();
+        self.symbols.extend(symbols);
+        self.comments.extend(session.comments().iter().cloned());
+        Ok(Output::with_message(
+            None,
+            format!("session loaded: {:?} ({} symbols)", path, count),
+        ))
+    }
+
+    /// Every symbol sorted by name, with the kind of its ID and what it was defined as
+    fn symbols(&self, engine: &Engine) -> Output {
+        let mut symbols = self.symbols.iter().collect::<Vec<(&String, &Id)>>();
+        symbols.sort();
+
+        let names = self.names();
+        let mut output = Output::with_message(None, format!("{} symbol(s)", symbols.len()));
+        for (name, id) in symbols {
+            output.lines.push(format!(
+                "{: <15} {: <9} {}",
+                format!("'{}", name),
+                id.kind(),
+                engine.describe(*id, &names).unwrap_or_default()
+            ));
+        }
+        output
+    }
+
+    fn symbol(&self, name: String) -> Result<Id> {
+        match self.symbols.get(&name) {
+            Some(id) => Ok(*id),
+            None => Err(Error::SymbolNotFound(name)),
+        }
+    }
+
+    /// Run an `or` or an `and` of `left` and the filter bound to `right_name`, binding the
+    /// combined filter to `name` if given
+    fn combine_filters(
+        &mut self,
+        engine: &mut Engine,
+        command: fn(Id, Id) -> Command,
+        left: Id,
+        right_name: String,
+        name: Option<String>,
+    ) -> Result<Output> {
+        let right = self.symbol(right_name)?;
+        let output = engine.run_command(&command(left, right))?;
+        if let Some(name) = name {
+            self.add_symbol(name, output.id)?;
+        }
+        Ok(output)
+    }
+
+    fn set_symbol(&self, name: String) -> Result<SetId> {
+        match self.symbols.get(&name) {
+            Some(Id::Set(set_id)) => Ok(*set_id),
+            _ => Err(Error::SymbolNotFound(name)),
+        }
+    }
+
+    /// The preset named by a `load`, the default one when it doesn't name any
+    fn preset(&self, format: Option<String>) -> Result<Option<&'static Preset>> {
+        match format {
+            Some(format) => presets::find(&format).map(Some),
+            None => Ok(self.input_format),
+        }
+    }
+
+    /// Define and bind the tags of `preset` on the file just loaded by `output`, listing them
+    /// after its message
+    fn add_preset_tags(
+        &mut self,
+        engine: &mut Engine,
+        preset: Option<&Preset>,
+        mut output: Output,
+    ) -> Result<Output> {
+        let (preset, file_id) = match (preset, output.id) {
+            (Some(preset), Some(Id::File(file_id))) => (preset, file_id),
+            _ => return Ok(output),
+        };
+        for tag in preset.tags {
+            let tag_output = engine.run_command(&Command::Tag(file_id, tag.name.to_string()))?;
+            let tag_id = match tag_output.id {
+                Some(Id::Tag(tag_id)) => tag_id,
+                _ => return Err(Error::OutputWithoutId),
+            };
+            let command = match tag.source {
+                TagSource::Regex(regex) => Command::Regex(
+                    tag_id,
+                    engine.tag_regex(regex.to_string()),
+                    CaptureGroup::default(),
+                ),
+                TagSource::Json(path) => Command::JsonPath(tag_id, path.to_string()),
+            };
+            engine.run_command(&command)?;
+            self.add_symbol(tag.name.to_string(), tag_output.id)?;
+        }
+        let names: Vec<String> = preset
+            .tags
+            .iter()
+            .map(|tag| format!("'{}", tag.name))
+            .collect();
+        output
+            .lines
+            .push(format!("{} tags: {}", preset.name, names.join(", ")));
+        Ok(output)
+    }
+
+    fn group_symbol(&self, name: String) -> Result<GroupId> {
+        match self.symbols.get(&name) {
+            Some(Id::Group(group_id)) => Ok(*group_id),
+            _ => Err(Error::SymbolNotFound(name)),
+        }
+    }
+
+    /// Run `command`, binding the ID of its output to `name` if given
+    fn run_named(
+        &mut self,
+        engine: &mut Engine,
+        command: &Command,
+        name: Option<String>,
+    ) -> Result<Output> {
+        let output = engine.run_command(command)?;
+        if let Some(name) = name {
+            self.add_symbol(name, output.id)?;
+        }
+        Ok(output)
+    }
+
+    fn names(&self) -> Names {
+        self.symbols
+            .iter()
+            .map(|(name, id)| (*id, name.clone()))
+            .collect()
+    }
+
+    /// The numeric tag read by an aggregator, if it reads one
+    fn value_tag(&self, name: Option<String>) -> Result<Option<TagId>> {
+        match name {
+            Some(name) => match self.symbols.get(&name) {
+                Some(Id::Tag(tag_id)) => Ok(Some(*tag_id)),
+                _ => Err(Error::SymbolNotFound(name)),
+            },
+            None => Ok(None),
         }
     }
 
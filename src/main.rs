@@ -3,19 +3,52 @@ mod engine;
 mod error;
 mod interpreter;
 mod parser;
+mod presets;
 mod repl;
+mod rotation;
+mod session;
+#[cfg(any(test, feature = "testing"))]
+mod testing;
 
+use std::env;
 use std::fs;
 use std::io;
 use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::process;
+use std::sync::atomic;
 
 use clap;
 
-use engine::Engine;
-use error::Result;
+use engine::{Command, Engine, OutputFormat, StatsFormat};
+use error::{Error, Result};
 use interpreter::{CursorState, Interpreter};
 
-fn main() -> Result<()> {
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Diagnostics {
+    Human,
+    Json,
+}
+
+impl Diagnostics {
+    fn report(self, err: &Error) {
+        match self {
+            Diagnostics::Human => eprintln!("Error: {}", err),
+            Diagnostics::Json => eprintln!("{}", err.to_json()),
+        }
+    }
+}
+
+/// What the batch runner does when a statement in the file fails
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FailureMode {
+    /// Stop at the first failure
+    FailFast,
+    /// Run every statement and report all failures at the end
+    KeepGoing,
+}
+
+fn main() {
     let args = clap::App::new("Log-Tags")
         .arg(
             clap::Arg::with_name("file")
@@ -23,59 +56,597 @@ fn main() -> Result<()> {
                 .help("Parse and run expressions in this file before the interactive REPL")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("eval")
+                .short("e")
+                .long("eval")
+                .help("Run these statements after the file and exit instead of starting the REPL")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("session")
+                .long("session")
+                .help("Replay a session saved with save(...) before running the file or REPL")
+                .takes_value(true),
+        )
         .arg(
             clap::Arg::with_name("debug")
                 .short("d")
                 .help("Track and print execution stats"),
         )
+        .arg(
+            clap::Arg::with_name("profile-lua")
+                .long("profile-lua")
+                .help("Print the time spent in each transform and scripted filter by each query"),
+        )
+        .arg(
+            clap::Arg::with_name("unsafe-lua")
+                .long("unsafe-lua")
+                .help("Give scripts the whole Lua standard library, including os, io and package"),
+        )
+        .arg(
+            clap::Arg::with_name("max-open-files")
+                .long("max-open-files")
+                .help("Maximum number of log files to keep open at once")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("cache-budget")
+                .long("cache-budget")
+                .help("Maximum number of bytes to keep in caches between queries")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("skip-lua-errors")
+                .long("skip-lua-errors")
+                .help("Leave rows whose transform or scripted filter fails without a value"),
+        )
+        .arg(
+            clap::Arg::with_name("sparse-tags")
+                .long("sparse-tags")
+                .help("Only print a tag's value when it differs from the previous row"),
+        )
+        .arg(
+            clap::Arg::with_name("anchor-tag-regexes")
+                .long("anchor-tag-regexes")
+                .help("Only let regex tags match from the start of the line"),
+        )
+        .arg(
+            clap::Arg::with_name("echo-results-only")
+                .long("echo-results-only")
+                .help(
+                    "Only print query results on stdout, send messages to stderr and exit \
+                     after the session and file instead of starting the REPL",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("separator")
+                .long("separator")
+                .help("Line printed after each row in lines output: blank, none or any other text")
+                .default_value("blank")
+                .allow_hyphen_values(true)
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("output")
+                .long("output")
+                .help(
+                    "Format of the rows printed by take, json and csv leave the statements out \
+                     of file mode output and print messages to stderr",
+                )
+                .possible_values(&["lines", "columns", "json", "csv"])
+                .default_value("lines")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("output-limit-bytes")
+                .long("output-limit-bytes")
+                .help("Maximum number of bytes printed by a single take")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("max-line-bytes")
+                .long("max-line-bytes")
+                .help("Truncate longer lines of the loaded files, skipping the rest of them")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("normalize-crlf")
+                .long("normalize-crlf")
+                .help("Read CRLF line endings of the loaded files as LF, inside records too"),
+        )
+        .arg(
+            clap::Arg::with_name("info")
+                .long("info")
+                .help("Print the versions of log-tags, Lua and regex and the enabled features"),
+        )
+        .arg(
+            clap::Arg::with_name("input-format")
+                .long("input-format")
+                .help("Tags defined on every loaded file: apache, nginx, syslog or json")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("regex-size-limit")
+                .long("regex-size-limit")
+                .help("Maximum size in bytes of a compiled regex, larger patterns are rejected")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("color")
+                .long("color")
+                .help(
+                    "Highlight regex matches and tag names in lines output, auto colors when \
+                     stdout is a terminal and NO_COLOR isn't set",
+                )
+                .possible_values(&["auto", "always", "never"])
+                .default_value("auto")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("stats-format")
+                .long("stats-format")
+                .help("Format of the stats printed with -d")
+                .possible_values(&["human", "json"])
+                .default_value("human")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("diagnostics")
+                .long("diagnostics")
+                .help("Format of errors printed to stderr")
+                .possible_values(&["human", "json"])
+                .default_value("human")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("fail-fast")
+                .long("fail-fast")
+                .help("Stop running the file at the first failed statement (default)"),
+        )
+        .arg(
+            clap::Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Don't echo the statements of the file, only print their results"),
+        )
+        .arg(
+            clap::Arg::with_name("keep-going")
+                .long("keep-going")
+                .help("Run every statement in the file and report all failures at the end")
+                .conflicts_with("fail-fast"),
+        )
         .get_matches();
 
+    let diagnostics = match args.value_of("diagnostics") {
+        Some("json") => Diagnostics::Json,
+        _ => Diagnostics::Human,
+    };
+
+    if let Err(err) = run(&args, diagnostics) {
+        diagnostics.report(&err);
+        process::exit(1);
+    }
+}
+
+fn run(args: &clap::ArgMatches, diagnostics: Diagnostics) -> Result<()> {
     let mut engine = if args.is_present("debug") {
         Engine::new_debug()
     } else {
         Engine::new()
     };
+    if !args.is_present("unsafe-lua") {
+        engine.sandbox_lua()?;
+    }
+    if let Some(max_open_files) = args.value_of("max-open-files") {
+        let max_open_files = max_open_files
+            .parse()
+            .map_err(|_| Error::InvalidArgument(format!("max-open-files: {}", max_open_files)))?;
+        engine.set_max_open_files(max_open_files);
+    }
+    if let Some(cache_budget) = args.value_of("cache-budget") {
+        let cache_budget = cache_budget
+            .parse()
+            .map_err(|_| Error::InvalidArgument(format!("cache-budget: {}", cache_budget)))?;
+        engine.set_cache_budget(cache_budget);
+    }
+    engine.set_sparse_tags(args.is_present("sparse-tags"));
+    engine.set_skip_lua_errors(args.is_present("skip-lua-errors"));
+    engine.set_profile_lua(args.is_present("profile-lua"));
+    engine.set_anchor_tag_regexes(args.is_present("anchor-tag-regexes"));
+    engine.set_results_only(args.is_present("echo-results-only"));
+    engine.set_separator(match args.value_of("separator") {
+        Some("none") => None,
+        Some("blank") | None => Some(String::new()),
+        Some(separator) => Some(separator.to_string()),
+    });
+    engine.set_output_format(match args.value_of("output") {
+        Some("columns") => OutputFormat::Columns,
+        Some("json") => OutputFormat::Json,
+        Some("csv") => OutputFormat::Csv,
+        _ => OutputFormat::Lines,
+    });
+    engine.set_color(match args.value_of("color") {
+        Some("always") => true,
+        Some("never") => false,
+        _ => {
+            env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && io::stdout().is_terminal()
+        }
+    });
+    if let Some(output_limit) = args.value_of("output-limit-bytes") {
+        let output_limit = output_limit
+            .parse()
+            .map_err(|_| Error::InvalidArgument(format!("output-limit-bytes: {}", output_limit)))?;
+        engine.set_output_limit(output_limit);
+    }
+    if let Some(max_line_bytes) = args.value_of("max-line-bytes") {
+        let max_line_bytes = max_line_bytes
+            .parse()
+            .map_err(|_| Error::InvalidArgument(format!("max-line-bytes: {}", max_line_bytes)))?;
+        engine.set_max_line_bytes(max_line_bytes);
+    }
+    engine.set_normalize_crlf(args.is_present("normalize-crlf"));
+    if let Some(regex_size_limit) = args.value_of("regex-size-limit") {
+        let regex_size_limit = regex_size_limit.parse().map_err(|_| {
+            Error::InvalidArgument(format!("regex-size-limit: {}", regex_size_limit))
+        })?;
+        engine.set_regex_size_limit(regex_size_limit);
+    }
+    engine.set_stats_format(match args.value_of("stats-format") {
+        Some("json") => StatsFormat::Json,
+        _ => StatsFormat::Human,
+    });
+    if args.is_present("info") {
+        for line in engine.run_command(&Command::Info)?.lines {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    // Ctrl-C at the REPL prompt is handled by rustyline, while a query is running it stops the
+    // current command instead of exiting, for `-f` and `-e` statements as well
+    let interrupted = engine.interrupt_handle();
+    ctrlc::set_handler(move || interrupted.store(true, atomic::Ordering::SeqCst))
+        .map_err(io::Error::other)?;
+
     let mut interpreter = Interpreter::new();
+    if let Some(input_format) = args.value_of("input-format") {
+        interpreter.set_input_format(input_format)?;
+    }
+
+    if let Some(session) = args.value_of("session") {
+        let output = interpreter.load_session(&mut engine, session)?;
+        for line in output.lines {
+            if engine.results_only_on_stdout() {
+                eprintln!("{}", line);
+            } else {
+                println!("  {}", line);
+            }
+        }
+    }
 
+    let mode = if args.is_present("keep-going") {
+        FailureMode::KeepGoing
+    } else {
+        FailureMode::FailFast
+    };
+    let quiet = args.is_present("quiet");
     if let Some(file_name) = args.value_of("file") {
-        let file = io::BufReader::new(fs::File::open(file_name)?);
-        let mut state = CursorState::Root;
+        run_file(
+            file_name,
+            &mut engine,
+            &mut interpreter,
+            mode,
+            quiet,
+            diagnostics,
+        )?;
+    }
 
-        for segment in file.lines().map(|l| l.unwrap()) {
-            if !segment.is_empty() {
-                println!("{}", segment);
-            }
+    if let Some(statements) = args.values_of("eval") {
+        let source = eval_source(statements);
+        run_statements(
+            io::Cursor::new(source),
+            &mut engine,
+            &mut interpreter,
+            mode,
+            quiet,
+            diagnostics,
+            &mut |line| println!("{}", line),
+        )?;
+        return Ok(());
+    }
 
-            match state {
-                CursorState::Root => {
-                    if segment.len() > 2 && &segment[0..2] == "> " {
-                        state = interpreter.add_line_segment(&segment[2..])?
-                    }
-                }
-                CursorState::Pipelined => {
-                    if segment.len() > 2 && &segment[0..2] == "| " {
-                        state = interpreter.add_line_segment(&segment[2..])?
-                    }
-                    if segment.is_empty() {
-                        println!();
-                        for line in interpreter.execute(&mut engine)? {
-                            println!("  {}", line);
-                        }
-                        state = CursorState::Root;
+    if engine.results_only() {
+        return Ok(());
+    }
+    repl::start(&mut engine, &mut interpreter)
+}
+
+fn run_file(
+    file_name: &str,
+    engine: &mut Engine,
+    interpreter: &mut Interpreter,
+    mode: FailureMode,
+    quiet: bool,
+    diagnostics: Diagnostics,
+) -> Result<()> {
+    let file = io::BufReader::new(fs::File::open(file_name)?);
+    run_statements(
+        file,
+        engine,
+        interpreter,
+        mode,
+        quiet,
+        diagnostics,
+        &mut |line| println!("{}", line),
+    )
+}
+
+/// The `-e` statements in the format of a file. Every line starts a new statement unless it
+/// continues the pipeline of the previous one with `| `.
+fn eval_source<'a, I: Iterator<Item = &'a str>>(statements: I) -> String {
+    let mut source = String::new();
+    for line in statements.flat_map(str::lines) {
+        if line.starts_with("| ") {
+            source.push_str(line);
+        } else {
+            source.push_str("\n> ");
+            source.push_str(line.strip_prefix("> ").unwrap_or(line));
+        }
+        source.push('\n');
+    }
+    source
+}
+
+/// Run the statements read from `file`, passing every line meant for stdout to `print`. Unless
+/// `quiet` each statement is echoed before its results.
+fn run_statements<R: BufRead>(
+    file: R,
+    engine: &mut Engine,
+    interpreter: &mut Interpreter,
+    mode: FailureMode,
+    quiet: bool,
+    diagnostics: Diagnostics,
+    print: &mut dyn FnMut(&str),
+) -> Result<()> {
+    // Statements and indents would break the rows of machine readable formats apart
+    let echo = !engine.results_only_on_stdout();
+    let echo_source = echo && !quiet;
+    // Results are indented under the echoed statement, and kept indented when it is left out
+    let execute =
+        |interpreter: &mut Interpreter, engine: &mut Engine, print: &mut dyn FnMut(&str)| {
+            if echo_source {
+                print("");
+            }
+            interpreter
+                .execute(engine, |line| {
+                    if echo {
+                        print(&format!("  {}", line))
+                    } else {
+                        print(line)
                     }
-                }
-                CursorState::MultiLine => state = interpreter.add_line_segment(&segment)?,
+                })
+                .map(|_| CursorState::Root)
+        };
+    let mut state = CursorState::Root;
+    let mut failures = vec![];
+
+    let mut handle = |result: Result<CursorState>, interpreter: &mut Interpreter| match result {
+        Ok(state) => Ok(state),
+        Err(err) if mode == FailureMode::KeepGoing => {
+            diagnostics.report(&err);
+            failures.push(err);
+            interpreter.reset();
+            Ok(CursorState::Root)
+        }
+        Err(err) => Err(err),
+    };
+
+    for segment in file.lines() {
+        let segment = segment?;
+        if echo_source && !segment.is_empty() {
+            print(&segment);
+        }
+
+        let result = match state {
+            CursorState::Root if segment.len() > 2 && &segment[0..2] == "> " => {
+                interpreter.add_line_segment(&segment[2..])
+            }
+            CursorState::Pipelined if segment.len() > 2 && &segment[0..2] == "| " => {
+                interpreter.add_line_segment(&segment[2..])
             }
+            CursorState::Pipelined if segment.is_empty() => execute(interpreter, engine, print),
+            CursorState::MultiLine => interpreter.add_line_segment(&segment),
+            _ => Ok(state),
+        };
+        state = handle(result, interpreter)?;
+    }
+
+    let result = execute(interpreter, engine, print);
+    handle(result, interpreter)?;
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    // Out of the way of the results when only they are printed on stdout
+    let mut summary = |line: String| {
+        if echo {
+            print(&line)
+        } else {
+            eprintln!("{}", line)
         }
+    };
+    summary(format!("{} failed statement(s):", failures.len()));
+    for err in &failures {
+        summary(format!("  {}", err));
+    }
+    Err(Error::BatchFailed(failures.len()))
+}
 
-        println!();
-        for line in interpreter.execute(&mut engine)? {
-            println!("  {}", line);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn machine_readable_rows_are_printed_alone() {
+        let mut engine = Engine::new();
+        engine.set_output_format(OutputFormat::Csv);
+        let mut interpreter = Interpreter::new();
+        let program = "> load('log, \"apache.log\")\n\n> tag('log, 'level)\n\
+                       | regex(\"\\[(error|notice)\\]\")\n| take(2)\n";
+        let mut lines = vec![];
+
+        run_statements(
+            io::Cursor::new(program),
+            &mut engine,
+            &mut interpreter,
+            FailureMode::FailFast,
+            false,
+            Diagnostics::Human,
+            &mut |line| lines.push(line.to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                "level,_line",
+                "notice,[Sun Dec 04 04:47:44 2005] [notice] workerEnv.init() ok \
+                 /etc/httpd/conf/workers2.properties",
+                "error,[Sun Dec 04 04:47:44 2005] [error] mod_jk child workerEnv in error state 6",
+            ]
+        );
+    }
+
+    #[test]
+    fn syntax_errors_are_reported_as_json_with_their_position() {
+        let mut interpreter = Interpreter::new();
+        let err = interpreter.add_line_segment("tag('log, @)").unwrap_err();
+        let json: serde_json::Value = serde_json::from_str(&err.to_json().to_string()).unwrap();
+
+        assert_eq!(json["level"], "error");
+        assert_eq!(json["code"], "parser");
+        assert!(json["position"].is_u64());
+        assert_eq!(json["context"], "tag('log, @)");
+    }
+
+    #[test]
+    fn keep_going_reports_every_failed_statement() {
+        let mut engine = Engine::new();
+        let mut interpreter = Interpreter::new();
+        let program = "> load('log, \"missing.log\")\n\n> load('log, \"apache.log\")\n\n\
+                       > tag('nope, 'level)\n| take(1)\n\n> take('log, 1)\n";
+        let mut lines = vec![];
+
+        let result = run_statements(
+            io::Cursor::new(program),
+            &mut engine,
+            &mut interpreter,
+            FailureMode::KeepGoing,
+            true,
+            Diagnostics::Human,
+            &mut |line| lines.push(line.to_string()),
+        );
+        match result {
+            Err(Error::BatchFailed(2)) => {}
+            result => panic!("expected 2 failed statements, got {:?}", result),
         }
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("[Sun Dec 04 04:47:44 2005]")));
+        let summary = lines
+            .iter()
+            .position(|line| line == "2 failed statement(s):");
+        assert_eq!(
+            &lines[summary.unwrap() + 1..],
+            &[
+                "  No such file or directory (os error 2)",
+                "  File not loaded: nope"
+            ]
+        );
     }
 
-    repl::start(&mut engine, &mut interpreter).map_err(|e| {
-        println!("{}", e);
-        e
-    })
+    #[test]
+    fn echo_results_only_prints_nothing_but_results() {
+        let mut engine = Engine::new();
+        engine.set_results_only(true);
+        let mut interpreter = Interpreter::new();
+        let program = "> load('log, \"apache.log\")\n\n> tag('log, 'level)\n\
+                       | regex(\"\\[(error|notice)\\]\")\n| take(2)\n";
+        let mut lines = vec![];
+
+        run_statements(
+            io::Cursor::new(program),
+            &mut engine,
+            &mut interpreter,
+            FailureMode::FailFast,
+            false,
+            Diagnostics::Human,
+            &mut |line| lines.push(line.to_string()),
+        )
+        .unwrap();
+        // Neither the statements, the load message nor the indent under statements
+        assert_eq!(
+            lines,
+            vec![
+                "[Sun Dec 04 04:47:44 2005] [notice] workerEnv.init() ok \
+                 /etc/httpd/conf/workers2.properties",
+                "    [level]         \"notice\"",
+                "",
+                "[Sun Dec 04 04:47:44 2005] [error] mod_jk child workerEnv in error state 6",
+                "    [level]         \"error\"",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn input_format_defines_the_preset_tags_on_load() {
+        let path =
+            std::env::temp_dir().join(format!("log-tags-{}-combined.log", std::process::id()));
+        std::fs::write(
+            &path,
+            "1.2.3.4 - frank [10/Oct/2000:13:55:36 -0700] \"GET /a.gif HTTP/1.0\" 200 2326 \
+             \"http://example.com/\" \"Mozilla/4.08\"\n",
+        )
+        .unwrap();
+        let mut engine = Engine::new();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_input_format("apache").unwrap();
+        let program = format!("> load('log, {:?})\n\n> take('log, 1)\n", path);
+        let mut lines = vec![];
+
+        run_statements(
+            io::Cursor::new(program),
+            &mut engine,
+            &mut interpreter,
+            FailureMode::FailFast,
+            true,
+            Diagnostics::Human,
+            &mut |line| lines.push(line.to_string()),
+        )
+        .unwrap();
+        let tags: Vec<&str> = lines
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| line.starts_with('['))
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                "[ip]            \"1.2.3.4\"",
+                "[user]          \"frank\"",
+                "[time]          \"10/Oct/2000:13:55:36 -0700\"",
+                "[method]        \"GET\"",
+                "[path]          \"/a.gif\"",
+                "[protocol]      \"HTTP/1.0\"",
+                "[status]        \"200\"",
+                "[bytes]         \"2326\"",
+                "[referer]       \"http://example.com/\"",
+                "[user_agent]    \"Mozilla/4.08\"",
+            ]
+        );
+    }
 }
@@ -1,33 +1,41 @@
 use nom::{
     branch::alt,
-    bytes::complete::tag,
-    bytes::streaming::take_until,
+    bytes::complete::{tag, take_while},
     character::complete::{alpha1, char, digit1, multispace0},
-    combinator::{cut, map},
-    error::VerboseError,
+    combinator::{cut, map, recognize, verify},
+    error::{ErrorKind, ParseError, VerboseError},
     multi::separated_list,
-    sequence::{delimited, preceded, terminated, tuple},
+    sequence::{delimited, pair, preceded, terminated, tuple},
     IResult,
 };
 
-use crate::base::Comparator;
+use crate::base::{CompareMode, Comparator};
 
 type Err<'a> = VerboseError<&'a str>;
 
 #[derive(Clone, Debug)]
 pub enum Expression {
     Application(String, Vec<Expression>),
-    Comparator(Comparator),
+    Comparator(Comparator, CompareMode),
+    CustomComparator(String),
+    /// The `matches` keyword, testing values against a regex
+    RegexComparator,
+    /// A bare option name, such as the aggregator and order of a `group`
+    Keyword(String),
+    Float(f64),
     Int(usize),
     String(String),
     Symbol(String),
 }
 
-fn parse_comparator<'a>(i: &'a str) -> IResult<&'a str, Comparator, Err<'a>> {
+fn parse_operator<'a>(i: &'a str) -> IResult<&'a str, Comparator, Err<'a>> {
     map(
         alt((
             tag("=="),
+            tag("<=>"),
             tag("!="),
+            tag("!~"),
+            tag("~"),
             tag(">="),
             tag(">"),
             tag("<="),
@@ -35,7 +43,10 @@ fn parse_comparator<'a>(i: &'a str) -> IResult<&'a str, Comparator, Err<'a>> {
         )),
         |comp_str: &str| match comp_str {
             "==" => Comparator::Equal,
+            "<=>" => Comparator::NullSafeEqual,
             "!=" => Comparator::NotEqual,
+            "~" => Comparator::Contains,
+            "!~" => Comparator::NotContains,
             ">" => Comparator::GreaterThan,
             ">=" => Comparator::GreaterThanEqual,
             "<" => Comparator::LessThan,
@@ -45,25 +56,121 @@ fn parse_comparator<'a>(i: &'a str) -> IResult<&'a str, Comparator, Err<'a>> {
     )(i)
 }
 
+/// The comparators without an operand, written as keywords
+fn parse_null_comparator<'a>(i: &'a str) -> IResult<&'a str, Comparator, Err<'a>> {
+    map(
+        verify(parse_identifier, |name: &str| {
+            name == "isnull" || name == "notnull"
+        }),
+        |name: &str| match name {
+            "isnull" => Comparator::IsNull,
+            _ => Comparator::IsNotNull,
+        },
+    )(i)
+}
+
+fn parse_comparator<'a>(i: &'a str) -> IResult<&'a str, (Comparator, CompareMode), Err<'a>> {
+    alt((
+        map(parse_null_comparator, |comp| (comp, CompareMode::Lexical)),
+        map(
+            preceded(tag("numeric"), delimited(char('('), parse_operator, char(')'))),
+            |comp| (comp, CompareMode::Numeric),
+        ),
+        map(
+            preceded(tag("nocase"), delimited(char('('), parse_operator, char(')'))),
+            |comp| (comp, CompareMode::IgnoreCase),
+        ),
+        map(
+            preceded(tag("size"), delimited(char('('), parse_operator, char(')'))),
+            |comp| (comp, CompareMode::Size),
+        ),
+        map(parse_operator, |comp| (comp, CompareMode::Lexical)),
+    ))(i)
+}
+
+fn parse_custom_comparator<'a>(i: &'a str) -> IResult<&'a str, String, Err<'a>> {
+    preceded(
+        tag("custom"),
+        delimited(char('('), parse_double_quoted_str, char(')')),
+    )(i)
+}
+
+fn parse_float<'a>(i: &'a str) -> IResult<&'a str, f64, Err<'a>> {
+    map(
+        recognize(tuple((digit1, char('.'), digit1))),
+        |float_str: &str| float_str.parse::<f64>().unwrap(),
+    )(i)
+}
+
+fn parse_regex_comparator<'a>(i: &'a str) -> IResult<&'a str, &'a str, Err<'a>> {
+    verify(parse_identifier, |name: &str| name == "matches")(i)
+}
+
+/// Bare words accepted as arguments, anything else must be a symbol, string or application
+const KEYWORDS: [&str; 4] = ["count", "asc", "desc", "tags"];
+
+fn parse_keyword<'a>(i: &'a str) -> IResult<&'a str, &'a str, Err<'a>> {
+    verify(parse_identifier, |name: &str| KEYWORDS.contains(&name))(i)
+}
+
 fn parse_int<'a>(i: &'a str) -> IResult<&'a str, usize, Err<'a>> {
     map(digit1, |int_str: &str| int_str.parse::<usize>().unwrap())(i)
 }
 
 fn parse_symbol<'a>(i: &'a str) -> IResult<&'a str, String, Err<'a>> {
-    map(preceded(tag("'"), cut(alpha1)), |sym_str: &str| {
-        sym_str.to_string()
-    })(i)
+    map(
+        preceded(tag("'"), cut(parse_identifier)),
+        |sym_str: &str| sym_str.to_string(),
+    )(i)
 }
 
-fn parse_str<'a>(i: &'a str) -> IResult<&'a str, &str, Err<'a>> {
-    take_until("\"")(i)
+/// Unescape the contents of a string up to its closing quote, unknown escapes such as the `\[`
+/// or `\d` of a regex are kept as written. An unterminated string is incomplete so it can be
+/// continued on the next line.
+fn parse_str<'a>(i: &'a str) -> IResult<&'a str, String, Err<'a>> {
+    let mut result = String::new();
+    let mut chars = i.char_indices();
+
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => return Ok((&i[idx..], result)),
+            '\\' => match chars.next() {
+                Some((_, '"')) => result.push('"'),
+                Some((_, '\\')) => result.push('\\'),
+                Some((_, 'n')) => result.push('\n'),
+                Some((_, 't')) => result.push('\t'),
+                Some((start, 'u')) => {
+                    let hex = i[start + 1..].get(..4).unwrap_or("");
+                    let unicode = Some(hex)
+                        .filter(|hex| hex.len() == 4 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .and_then(std::char::from_u32);
+                    match unicode {
+                        Some(unicode) => result.push(unicode),
+                        None => {
+                            return Err(nom::Err::Failure(Err::from_error_kind(
+                                &i[idx..],
+                                ErrorKind::Escaped,
+                            )))
+                        }
+                    }
+                    chars.nth(3);
+                }
+                Some((_, other)) => {
+                    result.push('\\');
+                    result.push(other);
+                }
+                None => break,
+            },
+            _ => result.push(c),
+        }
+    }
+
+    Err(nom::Err::Incomplete(nom::Needed::Size(1)))
 }
 
 fn parse_double_quoted_str<'a>(i: &'a str) -> IResult<&'a str, String, Err<'a>> {
-    map(
-        preceded(char('\"'), cut(terminated(parse_str, char('\"')))),
-        |s: &str| s.to_string(),
-    )(i)
+    preceded(char('\"'), cut(terminated(parse_str, char('\"'))))(i)
 }
 
 fn parse_arguments<'a>(i: &'a str) -> IResult<&'a str, Vec<Expression>, Err<'a>> {
@@ -77,18 +184,76 @@ fn parse_arguments<'a>(i: &'a str) -> IResult<&'a str, Vec<Expression>, Err<'a>>
     )(i)
 }
 
+/// A letter followed by any letters, digits and underscores, used for function and symbol names
+fn parse_identifier<'a>(i: &'a str) -> IResult<&'a str, &'a str, Err<'a>> {
+    recognize(pair(
+        alpha1,
+        take_while(|c: char| c.is_alphanumeric() || c == '_'),
+    ))(i)
+}
+
 fn parse_application<'a>(i: &'a str) -> IResult<&'a str, (&'a str, Vec<Expression>), Err<'a>> {
-    tuple((alpha1, parse_arguments))(i)
+    tuple((parse_identifier, parse_arguments))(i)
 }
 
 pub fn parse_expression<'a>(i: &'a str) -> IResult<&'a str, Expression, Err<'a>> {
     alt((
+        map(parse_comparator, |(comp, mode)| {
+            Expression::Comparator(comp, mode)
+        }),
+        map(parse_custom_comparator, Expression::CustomComparator),
         map(parse_application, |(func, args)| {
             Expression::Application(func.to_string(), args)
         }),
-        map(parse_comparator, Expression::Comparator),
+        map(parse_regex_comparator, |_| Expression::RegexComparator),
+        map(parse_keyword, |name| Expression::Keyword(name.to_string())),
+        map(parse_float, Expression::Float),
         map(parse_int, Expression::Int),
         map(parse_double_quoted_str, Expression::String),
         map(parse_symbol, Expression::Symbol),
     ))(i)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::error::VerboseErrorKind;
+
+    #[test]
+    fn strings_unescape_quotes_and_keep_regex_escapes() {
+        assert_eq!(
+            parse_double_quoted_str(r#""\[(\d+)\] \"x\"\té" rest"#),
+            Ok((" rest", "\\[(\\d+)\\] \"x\"\t\u{e9}".to_string()))
+        );
+    }
+
+    #[test]
+    fn unterminated_strings_are_incomplete() {
+        match parse_double_quoted_str(r#""abc \"def"#) {
+            Err(nom::Err::Incomplete(_)) => {}
+            result => panic!("expected an incomplete string, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn invalid_unicode_escapes_fail() {
+        match parse_double_quoted_str(r#""a\u12g4""#) {
+            Err(nom::Err::Failure(err)) => {
+                assert_eq!(
+                    err.errors[0],
+                    (r#"\u12g4""#, VerboseErrorKind::Nom(ErrorKind::Escaped))
+                );
+            }
+            result => panic!("expected an invalid escape, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn identifiers_take_trailing_digits_and_underscores() {
+        assert_eq!(parse_identifier("tag2(x"), Ok(("(x", "tag2")));
+        assert_eq!(parse_identifier("take_last ("), Ok((" (", "take_last")));
+        assert_eq!(parse_identifier("MixedCase_2b,"), Ok((",", "MixedCase_2b")));
+        assert!(parse_identifier("2tag").is_err());
+        assert!(parse_identifier("_tag").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! Tag schemas of well-known log formats, defined on a file right after it is loaded
+use crate::error::{Error, Result};
+
+/// How a preset tag reads its value from a line
+#[derive(Clone, Copy, Debug)]
+pub enum TagSource {
+    /// The first capture group of the regex
+    Regex(&'static str),
+    /// A path into the line parsed as JSON, as taken by `jsontag`
+    Json(&'static str),
+}
+
+#[derive(Debug)]
+pub struct PresetTag {
+    pub name: &'static str,
+    pub source: TagSource,
+}
+
+#[derive(Debug)]
+pub struct Preset {
+    pub name: &'static str,
+    pub tags: &'static [PresetTag],
+}
+
+const fn regex(name: &'static str, regex: &'static str) -> PresetTag {
+    PresetTag {
+        name,
+        source: TagSource::Regex(regex),
+    }
+}
+
+const fn json(name: &'static str, path: &'static str) -> PresetTag {
+    PresetTag {
+        name,
+        source: TagSource::Json(path),
+    }
+}
+
+/// The combined log format shared by Apache and nginx,
+/// `1.2.3.4 - user [10/Oct/2000:13:55:36 -0700] "GET /a HTTP/1.0" 200 2326 "referer" "agent"`
+const COMBINED: &[PresetTag] = &[
+    regex("ip", r"^(\S+)"),
+    regex("user", r"^\S+ \S+ (\S+)"),
+    regex("time", r"^\S+ \S+ \S+ \[([^\]]+)\]"),
+    regex("method", r#"^[^"]*"(\S+) "#),
+    regex("path", r#"^[^"]*"\S+ (\S+)"#),
+    regex("protocol", r#"^[^"]*"\S+ \S+ ([^"]+)""#),
+    regex("status", r#"^[^"]*"[^"]*" (\d{3}) "#),
+    regex("bytes", r#"^[^"]*"[^"]*" \d{3} (\d+|-)"#),
+    regex("referer", r#"^[^"]*"[^"]*" \d{3} \S+ "([^"]*)""#),
+    regex("user_agent", r#"^[^"]*"[^"]*" \d{3} \S+ "[^"]*" "([^"]*)""#),
+];
+
+/// BSD syslog lines, `Oct 11 22:14:15 host program[123]: message`
+const SYSLOG: &[PresetTag] = &[
+    regex("time", r"^(\w{3} +\d+ \d\d:\d\d:\d\d)"),
+    regex("host", r"^\w{3} +\d+ \d\d:\d\d:\d\d (\S+)"),
+    regex("program", r"^\w{3} +\d+ \d\d:\d\d:\d\d \S+ ([^\[:\s]+)"),
+    regex("pid", r"^\w{3} +\d+ \d\d:\d\d:\d\d \S+ [^\[:\s]+\[(\d+)\]"),
+    regex("message", r"^\w{3} +\d+ \d\d:\d\d:\d\d \S+ [^:]+: (.*)"),
+];
+
+/// One JSON object per line with the usual structured logging fields
+const JSON_LINES: &[PresetTag] = &[
+    json("time", "time"),
+    json("level", "level"),
+    json("msg", "msg"),
+];
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "apache",
+        tags: COMBINED,
+    },
+    Preset {
+        name: "nginx",
+        tags: COMBINED,
+    },
+    Preset {
+        name: "syslog",
+        tags: SYSLOG,
+    },
+    Preset {
+        name: "json",
+        tags: JSON_LINES,
+    },
+];
+
+/// The preset called `name`, an unknown name is an error listing the available ones
+pub fn find(name: &str) -> Result<&'static Preset> {
+    PRESETS
+        .iter()
+        .find(|preset| preset.name == name)
+        .ok_or_else(|| {
+            let names: Vec<&str> = PRESETS.iter().map(|preset| preset.name).collect();
+            Error::InvalidArgument(format!(
+                "format {:?}, expected one of: {}",
+                name,
+                names.join(", ")
+            ))
+        })
+}
@@ -1,9 +1,12 @@
 use rustyline;
 
 use crate::engine::Engine;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::interpreter::{CursorState, Interpreter};
 
+/// A statement as it was entered, each segment along with the prompt it was typed at
+type Statement = Vec<(CursorState, String)>;
+
 pub fn start(mut engine: &mut Engine, interpreter: &mut Interpreter) -> Result<()> {
     let mut rl = rustyline::Editor::<()>::new();
     if rl.load_history("history.txt").is_err() {
@@ -11,8 +14,10 @@ pub fn start(mut engine: &mut Engine, interpreter: &mut Interpreter) -> Result<(
     }
 
     let mut state = CursorState::Root;
+    let mut statements: Vec<Statement> = vec![];
+    let mut current: Statement = vec![];
 
-    loop {
+    let result = loop {
         let readline = match state {
             CursorState::Root => rl.readline("> "),
             CursorState::Pipelined => rl.readline("| "),
@@ -21,27 +26,154 @@ pub fn start(mut engine: &mut Engine, interpreter: &mut Interpreter) -> Result<(
 
         match readline {
             Ok(segment) => {
-                state = interpreter.add_line_segment(&segment)?;
+                if state == CursorState::Root && segment.trim() == "history" {
+                    print_history(&statements);
+                    continue;
+                }
+                if state == CursorState::Root && ["quit", "exit"].contains(&segment.trim()) {
+                    break Ok(());
+                }
+                if state == CursorState::Root && segment.trim().starts_with('!') {
+                    match find_statement(&statements, &segment) {
+                        Some(statement) => {
+                            println!("> {}", display_statement(&statement));
+                            match run_statement(engine, interpreter, &statement) {
+                                Ok(()) => statements.push(statement),
+                                Err(err) => report(interpreter, &err),
+                            }
+                        }
+                        None => println!("  No such history entry: {}\n", segment.trim()),
+                    }
+                    continue;
+                }
 
-                if state == CursorState::Root {
-                    for line in interpreter.execute(&mut engine)? {
-                        println!("  {}", line);
+                if !segment.is_empty() {
+                    rl.add_history_entry(segment.as_str());
+                    current.push((state, segment.clone()));
+                }
+                let result = interpreter.add_line_segment(&segment).and_then(|state| {
+                    if state == CursorState::Root {
+                        interpreter.execute(&mut engine, |line| println!("  {}", line))?;
+                        println!();
+                    }
+                    Ok(state)
+                });
+                state = match result {
+                    Ok(state) => state,
+                    Err(err) => {
+                        report(interpreter, &err);
+                        current.clear();
+                        CursorState::Root
                     }
-                    println!();
+                };
+
+                if state == CursorState::Root && !current.is_empty() {
+                    statements.push(std::mem::take(&mut current));
                 }
             }
             Err(rustyline::error::ReadlineError::Interrupted) => {
                 println!("CTRL-C");
-                break;
+                break Ok(());
             }
             Err(rustyline::error::ReadlineError::Eof) => {
                 println!("CTRL-D");
-                break;
+                break Ok(());
             }
-            Err(err) => return Err(err.into())
+            Err(err) => break Err(err.into()),
         }
+    };
+
+    // Saved on every way out, a failure to save doesn't hide why the REPL stopped
+    if let Err(err) = rl.save_history("history.txt") {
+        eprintln!("Failed to save history: {}", err);
     }
+    result
+}
 
-    rl.save_history("history.txt").unwrap();
+/// Run a statement from the history again
+fn run_statement(
+    engine: &mut Engine,
+    interpreter: &mut Interpreter,
+    statement: &[(CursorState, String)],
+) -> Result<()> {
+    for (_, segment) in statement {
+        interpreter.add_line_segment(segment)?;
+    }
+    interpreter.execute(engine, |line| println!("  {}", line))?;
+    println!();
     Ok(())
 }
+
+/// Print a failed statement's error and drop what was entered of it, the REPL keeps going
+fn report(interpreter: &mut Interpreter, err: &Error) {
+    eprintln!("Error: {}\n", err);
+    interpreter.reset();
+}
+
+fn print_history(statements: &[Statement]) {
+    for (idx, statement) in statements.iter().enumerate() {
+        let text = display_statement(statement).replace('\n', "\n      ");
+        println!("  {:>3} {}", idx + 1, text);
+    }
+    println!();
+}
+
+/// The statement referred to by `!N`, numbered from 1 as listed by `history`
+fn find_statement(statements: &[Statement], command: &str) -> Option<Statement> {
+    let index = command.trim()[1..].parse::<usize>().ok()?;
+    index
+        .checked_sub(1)
+        .and_then(|index| statements.get(index))
+        .cloned()
+}
+
+fn display_statement(statement: &[(CursorState, String)]) -> String {
+    let mut text = String::new();
+    for (state, segment) in statement {
+        match state {
+            CursorState::Root => text.push_str(segment),
+            CursorState::Pipelined => {
+                text.push_str("\n| ");
+                text.push_str(segment);
+            }
+            CursorState::MultiLine => {
+                text.push('\n');
+                text.push_str(segment);
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::engine::Command;
+
+    fn statement(segment: &str) -> Statement {
+        vec![(CursorState::Root, segment.to_string())]
+    }
+
+    #[test]
+    fn history_entries_run_again() {
+        let statements = vec![
+            statement("load('first, \"apache.log\")"),
+            statement("load('second, \"example\")"),
+        ];
+        assert_eq!(find_statement(&statements, "!0"), None);
+        assert_eq!(find_statement(&statements, "!3"), None);
+        assert_eq!(find_statement(&statements, "!x"), None);
+
+        let mut engine = Engine::new();
+        let mut interpreter = Interpreter::new();
+        let second = find_statement(&statements, " !2 ").unwrap();
+        run_statement(&mut engine, &mut interpreter, &second).unwrap();
+
+        match engine.history() {
+            [(Command::Load(path), _)] => assert_eq!(path, &PathBuf::from("example")),
+            history => panic!("expected a single load, ran {:?}", history),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Expanding a load path with wildcards into the members of a rotated log, oldest first
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::Result;
+
+/// Every file matching the `*` and `?` wildcards in the last component of `pattern`, ordered
+/// so that rotated members (`app.log.2.gz`, `app.log.1`) come before the current `app.log`.
+/// A path without wildcards is returned as is.
+pub fn expand(pattern: &Path) -> Result<Vec<PathBuf>> {
+    let name = match pattern.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.contains(&['*', '?'][..]) => name,
+        _ => return Ok(vec![pattern.to_path_buf()]),
+    };
+    let dir = match pattern.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Some(file_name) = entry.file_name().to_str() {
+            if wildcard_match(name, file_name) {
+                paths.push(dir.join(file_name));
+            }
+        }
+    }
+
+    if paths.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no files match {}", pattern.display()),
+        )
+        .into());
+    }
+
+    paths.sort_by_cached_key(|path| {
+        let (base, index) = rotation(path);
+        (base, Reverse(index), path.clone())
+    });
+    Ok(paths)
+}
+
+/// The name a member was rotated from and how many times it was, `app.log.2.gz` is
+/// `("app.log", 2)` and `app.log` is `("app.log", 0)`
+fn rotation(path: &Path) -> (String, usize) {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let name = name.trim_end_matches(".gz");
+
+    match name.rfind('.') {
+        Some(dot) => match name[dot + 1..].parse::<usize>() {
+            Ok(index) => (name[..dot].to_string(), index),
+            Err(_) => (name.to_string(), 0),
+        },
+        None => (name.to_string(), 0),
+    }
+}
+
+/// Match `name` against `pattern`, where `*` matches any run of characters and `?` any one
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let name = name.chars().collect::<Vec<char>>();
+
+    // Position after the last `*` and the name position it is currently matched up to
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            star = Some((star_p, star_n + 1));
+            p = star_p;
+            n = star_n + 1;
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
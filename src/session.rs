@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::base::{FileId, GroupId, Id, SetId, TagId};
+use crate::engine::{Command, Engine};
+use crate::error::{Error, Result};
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    command: Command,
+    id: Option<Id>,
+}
+
+/// Start of the generated comment line written above every saved session
+const HEADER: &str = "# log-tags session";
+
+/// The command history of an engine along with the symbols bound to its IDs, replaying it in
+/// another engine rebuilds every file, tag and filter under the same names.
+///
+/// Lines starting with `#` are comments and are skipped when reading. Written comments are
+/// kept and saved again above the JSON, below a generated header.
+#[derive(Deserialize, Serialize)]
+pub struct Session {
+    commands: Vec<Entry>,
+    symbols: HashMap<String, Id>,
+    #[serde(skip)]
+    comments: Vec<String>,
+}
+
+impl Session {
+    pub fn new(engine: &Engine, symbols: &HashMap<String, Id>, comments: &[String]) -> Session {
+        Session {
+            commands: engine
+                .history()
+                .iter()
+                .map(|(command, id)| Entry {
+                    command: command.clone(),
+                    id: *id,
+                })
+                .collect(),
+            symbols: symbols.clone(),
+            comments: comments.to_vec(),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Session> {
+        let mut comments = vec![];
+        let mut json = String::new();
+
+        for line in io::BufReader::new(fs::File::open(path)?).lines() {
+            let line = line?;
+            // Pretty printed JSON never starts a line with `#`
+            match line.trim_start().strip_prefix('#') {
+                Some(_) if line.trim_start().starts_with(HEADER) => {}
+                Some(comment) => {
+                    comments.push(comment.strip_prefix(' ').unwrap_or(comment).to_string())
+                }
+                None => {
+                    json.push_str(&line);
+                    json.push('\n');
+                }
+            }
+        }
+
+        let mut session: Session = serde_json::from_str(&json)?;
+        session.comments = comments;
+        Ok(session)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        writeln!(
+            file,
+            "{} saved {} with {} command(s)",
+            HEADER,
+            timestamp(SystemTime::now()),
+            self.commands.len()
+        )?;
+        for comment in &self.comments {
+            writeln!(file, "# {}", comment)?;
+        }
+        serde_json::to_writer_pretty(&mut file, self)?;
+        writeln!(file)?;
+        Ok(())
+    }
+
+    /// Comments read from the session file, without their leading `#`
+    pub fn comments(&self) -> &[String] {
+        &self.comments
+    }
+
+    /// Re-run every command in `engine`, which assigns new IDs as it goes. The saved IDs are
+    /// translated to the new ones in later commands and in the returned symbols.
+    pub fn replay(&self, engine: &mut Engine) -> Result<HashMap<String, Id>> {
+        let mut ids = HashMap::new();
+
+        for entry in &self.commands {
+            let output = engine.run_command(&remap(&entry.command, &ids)?)?;
+            if let (Some(saved), Some(id)) = (entry.id, output.id) {
+                ids.insert(saved, id);
+            }
+        }
+
+        self.symbols
+            .iter()
+            .map(|(name, id)| match ids.get(id) {
+                Some(id) => Ok((name.clone(), *id)),
+                None => Err(Error::MissingId(*id)),
+            })
+            .collect()
+    }
+}
+
+/// `time` as a UTC date and time, `2020-03-14 15:09:26 UTC`
+fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since the epoch, counted in 400 year eras starting on March 1st
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+fn remap(command: &Command, ids: &HashMap<Id, Id>) -> Result<Command> {
+    let id = |id: &Id| ids.get(id).copied().ok_or(Error::MissingId(*id));
+    let file = |file_id: &FileId| match id(&Id::File(*file_id))? {
+        Id::File(file_id) => Ok(file_id),
+        other => Err(Error::MissingId(other)),
+    };
+    let tag = |tag_id: &TagId| match id(&Id::Tag(*tag_id))? {
+        Id::Tag(tag_id) => Ok(tag_id),
+        other => Err(Error::MissingId(other)),
+    };
+    let set = |set_id: &SetId| match id(&Id::Set(*set_id))? {
+        Id::Set(set_id) => Ok(set_id),
+        other => Err(Error::MissingId(other)),
+    };
+    let group = |group_id: &GroupId| match id(&Id::Group(*group_id))? {
+        Id::Group(group_id) => Ok(group_id),
+        other => Err(Error::MissingId(other)),
+    };
+
+    Ok(match command {
+        Command::Load(path) => Command::Load(path.clone()),
+        Command::LoadMany(paths) => Command::LoadMany(paths.clone()),
+        Command::Script(script) => Command::Script(script.clone()),
+
+        Command::RecordStart(file_id, regex) => Command::RecordStart(file(file_id)?, regex.clone()),
+        Command::Tag(file_id, name) => Command::Tag(file(file_id)?, name.clone()),
+        Command::Regex(tag_id, regex, group) => {
+            Command::Regex(tag(tag_id)?, regex.clone(), group.clone())
+        }
+        Command::RegexAll(tag_id, regex, group) => {
+            Command::RegexAll(tag(tag_id)?, regex.clone(), group.clone())
+        }
+        Command::SplitRegex(tag_id, delimiter, index) => {
+            Command::SplitRegex(tag(tag_id)?, delimiter.clone(), *index)
+        }
+        Command::Tokenize(tag_id) => Command::Tokenize(tag(tag_id)?),
+        Command::JsonPath(tag_id, path) => Command::JsonPath(tag(tag_id)?, path.clone()),
+        Command::Columns(tag_id, start, end) => Command::Columns(tag(tag_id)?, *start, *end),
+        Command::Transform(tag_id, transform) => {
+            Command::Transform(tag(tag_id)?, transform.clone())
+        }
+        Command::Retag(tag_id, regex, transform) => {
+            Command::Retag(tag(tag_id)?, regex.clone(), transform.clone())
+        }
+
+        Command::DirectFilter(parent, comp, mode, value) => {
+            Command::DirectFilter(id(parent)?, *comp, *mode, value.clone())
+        }
+        Command::CustomFilter(parent, func, value) => {
+            Command::CustomFilter(id(parent)?, func.clone(), value.clone())
+        }
+        Command::ScriptedFilter(parent, script) => {
+            Command::ScriptedFilter(id(parent)?, script.clone())
+        }
+        Command::RegexFilter(parent, regex) => Command::RegexFilter(id(parent)?, regex.clone()),
+        Command::InFilter(parent, set_id) => Command::InFilter(id(parent)?, set(set_id)?),
+        Command::LagFilter(parent, lag, factor, comp, mode) => {
+            Command::LagFilter(id(parent)?, *lag, *factor, *comp, *mode)
+        }
+        Command::Or(left, right) => Command::Or(id(left)?, id(right)?),
+        Command::And(left, right) => Command::And(id(left)?, id(right)?),
+
+        Command::Distinct(parent) => Command::Distinct(id(parent)?),
+        Command::DistinctValues(parent, with_counts) => {
+            Command::DistinctValues(id(parent)?, *with_counts)
+        }
+
+        Command::Group(tag_id, aggregator, value_tag_id, order) => Command::Group(
+            tag(tag_id)?,
+            *aggregator,
+            value_tag_id.as_ref().map(&tag).transpose()?,
+            *order,
+        ),
+        Command::FilterGroups(group_id, comp, mode, value) => {
+            Command::FilterGroups(group(group_id)?, *comp, *mode, value.clone())
+        }
+        Command::Aggregate(parent, aggregator, value_tag_id) => Command::Aggregate(
+            id(parent)?,
+            *aggregator,
+            value_tag_id.as_ref().map(&tag).transpose()?,
+        ),
+        Command::Top(tag_id, count) => Command::Top(tag(tag_id)?, *count),
+
+        Command::MatchRate(left, right, comp, mode) => {
+            Command::MatchRate(tag(left)?, tag(right)?, *comp, *mode)
+        }
+
+        Command::Range(parent, start, end) => Command::Range(id(parent)?, *start, *end),
+        Command::Skip(parent, count) => Command::Skip(id(parent)?, *count),
+        Command::Sort(parent, tag_id, order) => Command::Sort(id(parent)?, tag(tag_id)?, *order),
+        Command::Take(parent, count) => Command::Take(id(parent)?, *count),
+        Command::TakeLast(parent, count) => Command::TakeLast(id(parent)?, *count),
+        Command::Hexdump(file_id, index) => Command::Hexdump(file(file_id)?, *index),
+        Command::Write(parent, path, count, with_tags) => {
+            Command::Write(id(parent)?, path.clone(), *count, *with_tags)
+        }
+        Command::Follow(parent, count) => Command::Follow(id(parent)?, *count),
+        Command::Materialize(parent) => Command::Materialize(id(parent)?),
+        Command::SaveSelection(query) => Command::SaveSelection(id(query)?),
+        Command::Explain(target) => Command::Explain(id(target)?),
+        Command::Info => Command::Info,
+
+        Command::Unload(target) => Command::Unload(id(target)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_round_trip_with_their_comments() {
+        let dir = std::env::temp_dir();
+        let log = dir.join(format!("log-tags-{}-session.log", std::process::id()));
+        let path = dir.join(format!("log-tags-{}.session", std::process::id()));
+        fs::write(&log, "a 1\nb 2\n").unwrap();
+
+        let mut engine = Engine::new();
+        let file_id = engine.run_command(&Command::Load(log)).unwrap().id.unwrap();
+        let mut symbols = HashMap::new();
+        symbols.insert("log".to_string(), file_id);
+        let comments = vec!["first".to_string(), "  indented #2".to_string()];
+        Session::new(&engine, &symbols, &comments)
+            .write(&path)
+            .unwrap();
+
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with(HEADER));
+        assert!(written.contains("\n# first\n#   indented #2\n{"));
+
+        let session = Session::read(&path).unwrap();
+        assert_eq!(session.comments(), comments.as_slice());
+        let mut replayed = Engine::new();
+        let symbols = session.replay(&mut replayed).unwrap();
+        let take = Command::Take(symbols["log"], 10);
+        assert_eq!(
+            replayed.run_command(&take).unwrap().lines,
+            vec!["a 1", "", "b 2", ""]
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Reproducible fixtures for exercising tags, filters and distincts on large inputs, and the
+//! golden file harness, only built for tests or with the `testing` feature
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::engine::Engine;
+use crate::error::Result;
+use crate::interpreter::Interpreter;
+use crate::{Diagnostics, FailureMode};
+
+/// Set to any value to have `check_golden` rewrite golden files instead of comparing them
+pub const REGENERATE_GOLDENS: &str = "LOG_TAGS_REGENERATE_GOLDENS";
+
+/// splitmix64, small and good enough to spread field values without pulling in a dependency
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/// Generate `lines` log lines of the form `[n] field0=v3 field1=v0 ...`, the same `seed`
+/// always produces the same lines.
+///
+/// Each field takes `cardinality` distinct values, every one of them appears at least once when
+/// there are at least `cardinality` lines.
+pub fn generate_corpus(lines: usize, fields: usize, cardinality: usize, seed: u64) -> Vec<String> {
+    let cardinality = std::cmp::max(1, cardinality);
+    let mut rng = Rng(seed);
+
+    (0..lines)
+        .map(|idx| {
+            let mut line = format!("[{}]", idx);
+            for field in 0..fields {
+                let value = if idx < cardinality {
+                    (idx + field) % cardinality
+                } else {
+                    rng.below(cardinality)
+                };
+                line.push_str(&format!(" field{}=v{}", field, value));
+            }
+            line
+        })
+        .collect()
+}
+
+/// Write a corpus from `generate_corpus` to `path`, one line per row
+pub fn write_corpus(
+    path: &Path,
+    lines: usize,
+    fields: usize,
+    cardinality: usize,
+    seed: u64,
+) -> Result<()> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    for line in generate_corpus(lines, fields, cardinality, seed) {
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Run the `.lt` program at `program` on a fresh engine like `-f` with `--keep-going`, returning
+/// every line it prints on stdout, split at newlines within rows. A program with failed
+/// statements ends with the failure summary followed by the error `-f` exits with.
+pub fn replay(program: &Path) -> Result<Vec<String>> {
+    let mut engine = Engine::new();
+    let mut interpreter = Interpreter::new();
+    let mut lines = vec![];
+
+    let result = crate::run_statements(
+        io::BufReader::new(fs::File::open(program)?),
+        &mut engine,
+        &mut interpreter,
+        FailureMode::KeepGoing,
+        false,
+        Diagnostics::Human,
+        &mut |line| lines.extend(line.split('\n').map(str::to_string)),
+    );
+    if let Err(err) = result {
+        lines.push(format!("Error: {}", err));
+    }
+    Ok(lines)
+}
+
+/// Compare the output of `replay` with the golden file next to `program`, named after it with
+/// an `.out` extension. Returns `None` when they match, otherwise the first line that differs
+/// with what was expected and what was printed.
+///
+/// When `REGENERATE_GOLDENS` is set the golden file is rewritten with the output instead, and
+/// always matches.
+pub fn check_golden(program: &Path) -> Result<Option<String>> {
+    let lines = replay(program)?;
+    let golden = program.with_extension("out");
+
+    if env::var_os(REGENERATE_GOLDENS).is_some() {
+        let mut file = io::BufWriter::new(fs::File::create(&golden)?);
+        for line in &lines {
+            writeln!(file, "{}", line)?;
+        }
+        return Ok(None);
+    }
+
+    let expected = fs::read_to_string(&golden)?;
+    let expected = expected.lines().collect::<Vec<&str>>();
+    for idx in 0..std::cmp::max(expected.len(), lines.len()) {
+        let expected_line = expected.get(idx).copied();
+        let line = lines.get(idx).map(String::as_str);
+        if expected_line != line {
+            return Ok(Some(format!(
+                "{}:{}: expected {:?}, printed {:?}",
+                golden.display(),
+                idx + 1,
+                expected_line,
+                line
+            )));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use super::*;
+
+    /// Programs in `tests/golden` run against the logs of the repository, rewrite their `.out`
+    /// files after an intended change of output with
+    /// `LOG_TAGS_REGENERATE_GOLDENS=1 cargo test golden`
+    fn golden(name: &str) -> Option<String> {
+        let program = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(name)
+            .with_extension("lt");
+        check_golden(&program).unwrap()
+    }
+
+    #[test]
+    fn golden_filter() {
+        assert_eq!(golden("filter"), None);
+    }
+
+    #[test]
+    fn corpus_is_deterministic() {
+        let corpus = generate_corpus(500, 3, 7, 42);
+        assert_eq!(corpus, generate_corpus(500, 3, 7, 42));
+        assert_ne!(corpus, generate_corpus(500, 3, 7, 43));
+
+        for field in 0..3 {
+            let values = corpus
+                .iter()
+                .map(|line| line.split(' ').nth(field + 1).unwrap())
+                .collect::<HashSet<&str>>();
+            assert_eq!(values.len(), 7);
+        }
+    }
+
+    #[test]
+    fn written_corpus_has_a_line_per_row() {
+        let path = env::temp_dir().join(format!("log-tags-{}-corpus.log", std::process::id()));
+        write_corpus(&path, 50, 2, 5, 7).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            written.lines().collect::<Vec<&str>>(),
+            generate_corpus(50, 2, 5, 7)
+        );
+    }
+}